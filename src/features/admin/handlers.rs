@@ -0,0 +1,101 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::features::app::app_state::AppState;
+use crate::features::auth::resolver::extract_api_key;
+use crate::features::dify::handlers::openai::chat_completion::{build_client, ChatCompletionError};
+
+/// Admin routes are only available when `admin_keys` is set in the config.
+pub fn require_admin(req: &HttpRequest, state: &AppState) -> Result<(), ChatCompletionError> {
+    let config = state.config.snapshot();
+    let key = extract_api_key(req).ok_or(ChatCompletionError::InvalidApiKey)?;
+    if config.admin_keys.contains(&key) {
+        Ok(())
+    } else {
+        Err(ChatCompletionError::InvalidApiKey)
+    }
+}
+
+pub async fn reload_config(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ChatCompletionError> {
+    require_admin(&req, &state)?;
+
+    let status = state.config.reload_blocking("admin").await;
+    if status.success {
+        Ok(HttpResponse::Ok().json(status))
+    } else {
+        Ok(HttpResponse::UnprocessableEntity().json(status))
+    }
+}
+
+pub async fn reload_status(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, ChatCompletionError> {
+    require_admin(&req, &state)?;
+
+    let config = state.config.snapshot();
+    Ok(HttpResponse::Ok().json(json!({
+        "last_reload": state.config.last_reload(),
+        "models": config.models.len(),
+        "keys": config.keys.len(),
+    })))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ModelQuery {
+    pub model: Option<String>,
+}
+
+/// Registered models selected by `?model=`, or all of them, as `(alias, app_key)`.
+fn selected_models(state: &AppState, model: Option<&str>) -> Result<Vec<(String, String)>, ChatCompletionError> {
+    let config = state.config.snapshot();
+    match model {
+        Some(alias) => config.models.get(alias)
+            .map(|m| vec![(alias.to_string(), m.app_key.clone())])
+            .ok_or_else(|| ChatCompletionError::ModelNotFound(alias.to_string())),
+        None => Ok(config.models.iter().map(|(alias, m)| (alias.clone(), m.app_key.clone())).collect()),
+    }
+}
+
+pub async fn cached_parameters(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    query: web::Query<ModelQuery>,
+) -> Result<HttpResponse, ChatCompletionError> {
+    require_admin(&req, &state)?;
+
+    let entries: serde_json::Map<String, serde_json::Value> = selected_models(&state, query.model.as_deref())?
+        .into_iter()
+        .map(|(alias, app_key)| (alias, json!(state.parameters.cached(&app_key))))
+        .collect();
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+/// Refetches app parameters for one model (`?model=`) or every registered model.
+pub async fn refresh_parameters(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    query: web::Query<ModelQuery>,
+) -> Result<HttpResponse, ChatCompletionError> {
+    require_admin(&req, &state)?;
+
+    let models = selected_models(&state, query.model.as_deref())?;
+    if query.model.is_none() {
+        state.parameters.clear();
+    }
+
+    let client = build_client()?;
+    let mut results = serde_json::Map::new();
+    for (alias, app_key) in models {
+        let result = match state.parameters.refresh(&client, &state.dify_api_url, &app_key).await {
+            Ok(_) => json!({ "success": true }),
+            Err(e) => json!({ "success": false, "message": e.to_string() }),
+        };
+        results.insert(alias, result);
+    }
+    Ok(HttpResponse::Ok().json(results))
+}
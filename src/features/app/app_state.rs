@@ -1,5 +1,29 @@
+use std::sync::Arc;
 
+use crate::features::cache::response::ResponseCache;
+use crate::features::cache::semantic::SemanticCache;
+use crate::features::config::reload::ConfigHandle;
+use crate::features::dify::cancellation::ActiveStreams;
+use crate::features::dify::conversations::{ConversationTracker, MessageTracker};
+use crate::features::dify::parameters::ParametersCache;
+use crate::features::files::store::FileStore;
+use crate::features::threads::store::ThreadStore;
 
 pub struct AppState {
-    pub(crate) dify_api_url: String
-}
\ No newline at end of file
+    pub(crate) dify_api_url: String,
+    pub(crate) config: Arc<ConfigHandle>,
+    pub(crate) parameters: Arc<ParametersCache>,
+    pub(crate) streams: Arc<ActiveStreams>,
+    /// Opt-in chat completion cache, `None` when `RESPONSE_CACHE` is unset.
+    pub(crate) response_cache: Option<Arc<ResponseCache>>,
+    /// Per-model semantic caches, used by models with `semantic_cache` configured.
+    pub(crate) semantic_cache: Arc<SemanticCache>,
+    /// Files uploaded through `/v1/files`.
+    pub(crate) files: Arc<FileStore>,
+    /// Dify conversations behind response ids, for `previous_response_id`.
+    pub(crate) conversations: Arc<ConversationTracker>,
+    /// Who each Dify answer was given to, for feedback.
+    pub(crate) messages: Arc<MessageTracker>,
+    /// Threads created through `/v1/threads`.
+    pub(crate) threads: Arc<ThreadStore>,
+}
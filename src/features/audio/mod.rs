@@ -0,0 +1,2 @@
+pub mod speech;
+pub mod transcriptions;
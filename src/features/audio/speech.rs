@@ -0,0 +1,143 @@
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::{web, HttpRequest, HttpResponse};
+use futures_util::TryStreamExt;
+use serde_json::json;
+
+use crate::features::app::app_state::AppState;
+use crate::features::auth::resolver::{resolve_target, DifyTarget};
+use crate::features::common::types::OpenAISpeechRequest;
+use crate::features::dify::handlers::openai::chat_completion::{
+    build_client, post_to_dify, with_ignored_params, ChatCompletionError,
+};
+use crate::features::dify::handlers::openai::params::check_params;
+
+/// Longest accepted input, matching OpenAI's limit.
+const MAX_INPUT_CHARS: usize = 4096;
+
+/// OpenAI text to speech over Dify `/text-to-audio` of the app behind `model`.
+/// The audio is streamed back with Dify's content type.
+pub async fn speech(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: web::Json<OpenAISpeechRequest>,
+) -> Result<HttpResponse, ChatCompletionError> {
+    let config = state.config.snapshot();
+    let target = resolve_target(&req, &config, Some(&body.model))?;
+
+    if body.input.trim().is_empty() || body.input.chars().count() > MAX_INPUT_CHARS {
+        return Err(ChatCompletionError::RequestConstructionError(format!(
+            "'input' must contain between 1 and {} characters",
+            MAX_INPUT_CHARS
+        )));
+    }
+
+    let voice = dify_voice(&body, &target)?;
+    let ignored = check_params(unsupported_params(&body), config.param_policy(Some(&body.model)))?;
+
+    let dify_request = json!({
+        "text": body.input,
+        "voice": voice,
+        "user": speech_user(&body, &target),
+    });
+    let client = build_client()?;
+    let url = format!("{}/v1/text-to-audio", state.dify_api_url);
+    let response = post_to_dify(&client, &url, &target.api_key, &dify_request).await?;
+
+    let content_type = response.headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("audio/mpeg")
+        .to_string();
+    let audio = response.bytes_stream()
+        .map_err(|e| actix_web::error::ErrorBadGateway(e.to_string()));
+
+    let response = HttpResponse::Ok()
+        .insert_header((CONTENT_TYPE, content_type))
+        .streaming(audio);
+    Ok(with_ignored_params(response, &ignored))
+}
+
+/// The Dify voice for the requested one, through the model's `voices` map when
+/// it has one.
+fn dify_voice(body: &OpenAISpeechRequest, target: &DifyTarget) -> Result<String, ChatCompletionError> {
+    let voices = target.model_config.as_ref().map(|m| &m.voices).filter(|v| !v.is_empty());
+    let Some(voices) = voices else { return Ok(body.voice.clone()) };
+    voices.get(&body.voice).cloned().ok_or_else(|| {
+        let mut known: Vec<&str> = voices.keys().map(String::as_str).collect();
+        known.sort_unstable();
+        ChatCompletionError::RequestConstructionError(format!(
+            "Unsupported voice '{}' for model '{}'; expected one of {}",
+            body.voice,
+            body.model,
+            known.join(", ")
+        ))
+    })
+}
+
+/// The request's `user`, else the key's, like chat completions.
+fn speech_user(body: &OpenAISpeechRequest, target: &DifyTarget) -> String {
+    body.user.clone()
+        .or_else(|| target.default_user.clone())
+        .unwrap_or_else(|| "default_user".to_string())
+}
+
+/// Format, speed and style come from the app's TTS settings.
+fn unsupported_params(body: &OpenAISpeechRequest) -> Vec<&'static str> {
+    let mut unsupported = Vec::new();
+    if body.response_format.as_deref().is_some_and(|f| f != "mp3") {
+        unsupported.push("response_format");
+    }
+    if body.speed.is_some_and(|s| s != 1.0) {
+        unsupported.push("speed");
+    }
+    if body.instructions.as_deref().is_some_and(|i| !i.is_empty()) {
+        unsupported.push("instructions");
+    }
+    unsupported
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::features::config::proxy_config::ParamPolicy;
+
+    fn request(extra: serde_json::Value) -> OpenAISpeechRequest {
+        let mut body = json!({"model": "tts", "input": "Hello", "voice": "alloy"});
+        body.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+        serde_json::from_value(body).unwrap()
+    }
+
+    fn target(voices: serde_json::Value, default_user: Option<&str>) -> DifyTarget {
+        DifyTarget {
+            api_key: "app-key".to_string(),
+            model_config: Some(serde_json::from_value(json!({"app_key": "app-key", "voices": voices})).unwrap()),
+            default_user: default_user.map(String::from),
+        }
+    }
+
+    #[test]
+    fn voices_are_mapped_when_the_model_has_a_map() {
+        let mapped = target(json!({"alloy": "en-US-JennyNeural"}), None);
+        assert_eq!(dify_voice(&request(json!({})), &mapped).unwrap(), "en-US-JennyNeural");
+        assert!(dify_voice(&request(json!({"voice": "echo"})), &mapped).is_err());
+        assert_eq!(dify_voice(&request(json!({"voice": "echo"})), &target(json!({}), None)).unwrap(), "echo");
+    }
+
+    #[test]
+    fn format_speed_and_instructions_are_unsupported() {
+        assert!(unsupported_params(&request(json!({"response_format": "mp3", "speed": 1.0}))).is_empty());
+        let params = unsupported_params(&request(json!({"response_format": "wav", "speed": 1.5, "instructions": "Whisper"})));
+        assert_eq!(params, ["response_format", "speed", "instructions"]);
+
+        assert!(check_params(params.clone(), ParamPolicy::Strict).is_err());
+        assert_eq!(check_params(params, ParamPolicy::Lenient).unwrap(), ["response_format", "speed", "instructions"]);
+    }
+
+    #[test]
+    fn the_request_user_comes_before_the_key_user() {
+        let keyed = target(json!({}), Some("key-user"));
+        assert_eq!(speech_user(&request(json!({"user": "alice"})), &keyed), "alice");
+        assert_eq!(speech_user(&request(json!({})), &keyed), "key-user");
+        assert_eq!(speech_user(&request(json!({})), &target(json!({}), None)), "default_user");
+    }
+}
@@ -0,0 +1,184 @@
+use actix_multipart::Multipart;
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::{web, HttpRequest, HttpResponse};
+use reqwest::multipart::{Form, Part};
+use serde_json::json;
+
+use crate::features::app::app_state::AppState;
+use crate::features::auth::resolver::{authenticate, resolve_target};
+use crate::features::common::multipart::read_upload_form;
+use crate::features::dify::handlers::openai::chat_completion::{
+    build_client, read_dify_json, send_to_dify, with_ignored_params, ChatCompletionError,
+};
+use crate::features::dify::handlers::openai::params::check_params;
+
+/// Audio formats Dify's `/audio-to-text` accepts.
+const AUDIO_EXTENSIONS: &[&str] = &["amr", "m4a", "mp3", "mp4", "mpeg", "mpga", "wav", "webm"];
+
+/// Largest accepted audio file, matching OpenAI's limit (Dify allows a little more).
+const MAX_AUDIO_BYTES: usize = 25 * 1024 * 1024;
+
+const RESPONSE_FORMATS: &[&str] = &["json", "text", "srt", "verbose_json", "vtt"];
+
+/// Speaking rate used to estimate the duration reported in subtitles and
+/// `verbose_json`. Dify returns no timestamps, so these timings are guesses
+/// from the word count, not measured from the audio.
+const WORDS_PER_SECOND: f64 = 2.5;
+
+/// The MIME type Dify expects for an audio file name, or an OpenAI-style
+/// error for an unsupported format. Dify checks for `audio/{subtype}`, so
+/// container types like `video/webm` are sent as audio.
+fn audio_mime(filename: &str) -> Result<String, ChatCompletionError> {
+    let unsupported = || ChatCompletionError::RequestConstructionError(format!(
+        "Invalid file format. Supported formats: {}",
+        AUDIO_EXTENSIONS.join(", ")
+    ));
+    let extension = std::path::Path::new(filename)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase)
+        .filter(|e| AUDIO_EXTENSIONS.contains(&e.as_str()))
+        .ok_or_else(unsupported)?;
+    let mime = mime_guess::from_ext(&extension)
+        .iter()
+        .find(|m| m.type_() == mime_guess::mime::AUDIO || m.type_() == mime_guess::mime::VIDEO)
+        .ok_or_else(unsupported)?;
+    Ok(format!("audio/{}", mime.subtype()))
+}
+
+/// `HH:MM:SS` plus milliseconds after `separator`, as used by SRT (`,`) and WebVTT (`.`).
+fn timestamp(seconds: f64, separator: char) -> String {
+    let millis = (seconds * 1000.0).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}{}{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        separator,
+        millis % 1000
+    )
+}
+
+/// Estimated length in seconds of speech with this transcript, at least one second.
+fn estimated_duration(text: &str) -> f64 {
+    (text.split_whitespace().count() as f64 / WORDS_PER_SECOND).max(1.0)
+}
+
+/// Formats a transcript as `response_format`. Subtitles and `verbose_json`
+/// hold one segment whose end is `estimated_duration`.
+fn format_transcript(text: &str, response_format: &str, language: Option<&str>) -> HttpResponse {
+    let duration = estimated_duration(text);
+    match response_format {
+        "text" => HttpResponse::Ok().insert_header((CONTENT_TYPE, "text/plain; charset=utf-8")).body(text.to_string()),
+        "srt" => HttpResponse::Ok()
+            .insert_header((CONTENT_TYPE, "text/plain; charset=utf-8"))
+            .body(format!("1\n{} --> {}\n{}\n", timestamp(0.0, ','), timestamp(duration, ','), text)),
+        "vtt" => HttpResponse::Ok()
+            .insert_header((CONTENT_TYPE, "text/vtt; charset=utf-8"))
+            .body(format!("WEBVTT\n\n{} --> {}\n{}\n", timestamp(0.0, '.'), timestamp(duration, '.'), text)),
+        "verbose_json" => HttpResponse::Ok().json(json!({
+            "task": "transcribe",
+            "language": language,
+            "duration": duration,
+            "text": text,
+            "segments": [{
+                "id": 0,
+                "seek": 0,
+                "start": 0.0,
+                "end": duration,
+                "text": text,
+                "tokens": [],
+                "temperature": 0.0,
+            }],
+        })),
+        _ => HttpResponse::Ok().json(json!({ "text": text })),
+    }
+}
+
+/// OpenAI transcription over Dify `/audio-to-text` of the app behind `model`.
+pub async fn transcriptions(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    payload: Multipart,
+) -> Result<HttpResponse, ChatCompletionError> {
+    let config = state.config.snapshot();
+    // Reject unknown callers before buffering the upload
+    authenticate(&req, &config)?;
+    let form = read_upload_form(payload, MAX_AUDIO_BYTES).await?;
+    let model = form.field("model")
+        .ok_or_else(|| ChatCompletionError::RequestConstructionError("'model' is required".to_string()))?;
+    let target = resolve_target(&req, &config, Some(model))?;
+
+    let file = form.file.as_ref()
+        .ok_or_else(|| ChatCompletionError::RequestConstructionError("'file' is required".to_string()))?;
+    if file.data.is_empty() {
+        return Err(ChatCompletionError::RequestConstructionError("The audio file is empty".to_string()));
+    }
+    let mime = audio_mime(&file.filename)?;
+    let response_format = form.field("response_format").unwrap_or("json");
+    if !RESPONSE_FORMATS.contains(&response_format) {
+        return Err(ChatCompletionError::RequestConstructionError(format!(
+            "Unsupported response_format '{}'; expected one of {}",
+            response_format,
+            RESPONSE_FORMATS.join(", ")
+        )));
+    }
+
+    // Dify transcribes with the app's configured speech-to-text model only
+    let mut unsupported = Vec::new();
+    if form.field("language").is_some() {
+        unsupported.push("language");
+    }
+    if form.field("prompt").is_some() {
+        unsupported.push("prompt");
+    }
+    if form.field("temperature").is_some_and(|t| t.parse::<f32>() != Ok(0.0)) {
+        unsupported.push("temperature");
+    }
+    if form.fields.keys().any(|k| k.starts_with("timestamp_granularities")) {
+        unsupported.push("timestamp_granularities");
+    }
+    let ignored = check_params(unsupported, config.param_policy(Some(model)))?;
+
+    let part = Part::bytes(file.data.to_vec())
+        .file_name(file.filename.clone())
+        .mime_str(&mime)
+        .map_err(|e| ChatCompletionError::RequestConstructionError(e.to_string()))?;
+    let user = target.default_user.clone().unwrap_or_else(|| "default_user".to_string());
+    let dify_form = Form::new().part("file", part).text("user", user);
+
+    let client = build_client()?;
+    let url = format!("{}/v1/audio-to-text", state.dify_api_url);
+    let result = read_dify_json(send_to_dify(client.post(url).multipart(dify_form), &target.api_key).await?).await?;
+    let text = result["text"].as_str().unwrap_or_default();
+
+    let response = format_transcript(text, response_format, form.field("language"));
+    Ok(with_ignored_params(response, &ignored))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps_use_the_subtitle_separator() {
+        assert_eq!(timestamp(0.0, ','), "00:00:00,000");
+        assert_eq!(timestamp(3723.4567, ','), "01:02:03,457");
+        assert_eq!(timestamp(59.9996, '.'), "00:01:00.000");
+    }
+
+    #[test]
+    fn durations_are_estimated_from_the_word_count() {
+        assert_eq!(estimated_duration(""), 1.0);
+        assert_eq!(estimated_duration("one two"), 1.0);
+        assert_eq!(estimated_duration(&"word ".repeat(10)), 4.0);
+    }
+
+    #[test]
+    fn audio_mime_types_come_from_the_extension() {
+        assert_eq!(audio_mime("note.MP3").unwrap(), "audio/mpeg");
+        assert_eq!(audio_mime("clip.webm").unwrap(), "audio/webm");
+        assert!(audio_mime("notes.txt").is_err());
+        assert!(audio_mime("no-extension").is_err());
+    }
+}
@@ -0,0 +1,236 @@
+use actix_web::HttpRequest;
+use reqwest::{Client, StatusCode};
+
+use crate::features::app::app_state::AppState;
+use crate::features::config::proxy_config::{DatasetConfig, EmbeddingsBackendConfig, ModelConfig, ProxyConfig};
+use crate::features::dify::parameters::ParametersCache;
+use crate::features::dify::handlers::openai::chat_completion::ChatCompletionError;
+
+/// The Dify app a request is routed to, resolved from the caller's key and model.
+#[derive(Debug, Clone)]
+pub struct DifyTarget {
+    /// Dify app API key to send upstream.
+    pub api_key: String,
+    /// Registry entry for the model, if it is registered.
+    pub model_config: Option<ModelConfig>,
+    /// Default Dify `user` for the caller's key.
+    pub default_user: Option<String>,
+}
+
+/// The caller's key: the `Authorization: Bearer` token, or the `x-api-key`
+/// header Anthropic clients send.
+pub fn extract_api_key(req: &HttpRequest) -> Option<String> {
+    let headers = req.headers();
+    headers.get("Authorization")
+        .and_then(|auth_header| auth_header.to_str().ok())
+        .and_then(|auth_str| auth_str.strip_prefix("Bearer "))
+        .or_else(|| headers.get("x-api-key").and_then(|key| key.to_str().ok()))
+        .map(|key| key.to_string())
+}
+
+/// Resolves the Dify app for `model`.
+///
+/// Without proxy keys in the config, the bearer token is the Dify app key itself
+/// (legacy passthrough). With proxy keys, the token must be a known proxy key and
+/// the model must be in the registry and allowed for that key.
+pub fn resolve_target(
+    req: &HttpRequest,
+    config: &ProxyConfig,
+    model: Option<&str>,
+) -> Result<DifyTarget, ChatCompletionError> {
+    let api_key = extract_api_key(req).ok_or(ChatCompletionError::InvalidApiKey)?;
+    let model = model.unwrap_or_default().to_string();
+
+    if !config.uses_proxy_keys() {
+        return Ok(DifyTarget {
+            api_key,
+            model_config: config.models.get(&model).cloned(),
+            default_user: None,
+        });
+    }
+
+    let key_config = config.keys.get(&api_key).ok_or(ChatCompletionError::InvalidApiKey)?;
+    let model_config = config.models.get(&model)
+        .ok_or_else(|| ChatCompletionError::ModelNotFound(model.clone()))?;
+
+    if !key_config.models.is_empty() && !key_config.models.contains(&model) {
+        return Err(ChatCompletionError::ModelNotFound(model));
+    }
+
+    Ok(DifyTarget {
+        api_key: model_config.app_key.clone(),
+        model_config: Some(model_config.clone()),
+        default_user: key_config.user.clone(),
+    })
+}
+
+/// The caller's bearer token, which must be a known proxy key when proxy keys
+/// are configured. Identifies the tenant for per-caller data such as files.
+pub fn authenticate(req: &HttpRequest, config: &ProxyConfig) -> Result<String, ChatCompletionError> {
+    let api_key = extract_api_key(req).ok_or(ChatCompletionError::InvalidApiKey)?;
+    if config.uses_proxy_keys() && !config.keys.contains_key(&api_key) {
+        return Err(ChatCompletionError::InvalidApiKey);
+    }
+    Ok(api_key)
+}
+
+/// The caller's key, for requests that keep data on the proxy. Without proxy
+/// keys `authenticate` takes any bearer token, so the token must be a Dify
+/// app key that Dify accepts.
+pub(crate) async fn authenticate_storage(
+    req: &HttpRequest,
+    state: &AppState,
+    client: &Client,
+) -> Result<String, ChatCompletionError> {
+    let config = state.config.snapshot();
+    let api_key = authenticate(req, &config)?;
+    if !config.uses_proxy_keys() {
+        verify_app_key(&state.parameters, client, &state.dify_api_url, &api_key).await?;
+    }
+    Ok(api_key)
+}
+
+/// Checks `api_key` against Dify by fetching the app's parameters, which are
+/// cached, so a known key costs no extra call.
+async fn verify_app_key(
+    parameters: &ParametersCache,
+    client: &Client,
+    dify_api_url: &str,
+    api_key: &str,
+) -> Result<(), ChatCompletionError> {
+    match parameters.get(client, dify_api_url, api_key).await {
+        Ok(_) => Ok(()),
+        Err(ChatCompletionError::DifyApiError(StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN, _)) => {
+            Err(ChatCompletionError::InvalidApiKey)
+        },
+        Err(e) => Err(e),
+    }
+}
+
+/// Checks that the caller's proxy key may use `alias`. Without proxy keys any
+/// bearer token would pass, so registry entries are refused unless the config
+/// sets `passthrough_registry`.
+fn authorize_alias(req: &HttpRequest, config: &ProxyConfig, alias: &str) -> Result<(), ChatCompletionError> {
+    let api_key = authenticate(req, config)?;
+
+    if !config.uses_proxy_keys() && !config.passthrough_registry {
+        return Err(ChatCompletionError::DifyApiError(
+            StatusCode::FORBIDDEN,
+            format!("'{}' requires a proxy key; this server has none configured", alias),
+        ));
+    }
+
+    if let Some(key_config) = config.keys.get(&api_key) {
+        if !key_config.models.is_empty() && !key_config.models.iter().any(|m| m == alias) {
+            return Err(ChatCompletionError::ModelNotFound(alias.to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// Resolves the embeddings backend for `model`. Embedding models always come
+/// from the registry; with proxy keys the caller's key must also allow them.
+pub fn resolve_embeddings(
+    req: &HttpRequest,
+    config: &ProxyConfig,
+    model: &str,
+) -> Result<EmbeddingsBackendConfig, ChatCompletionError> {
+    authorize_alias(req, config, model)?;
+
+    config.embedding_models.get(model)
+        .cloned()
+        .ok_or_else(|| ChatCompletionError::ModelNotFound(model.to_string()))
+}
+
+/// Resolves the knowledge base registered as `alias`, under the same rules as
+/// embedding models. The dataset API key is never taken from the caller.
+pub fn resolve_dataset(
+    req: &HttpRequest,
+    config: &ProxyConfig,
+    alias: &str,
+) -> Result<DatasetConfig, ChatCompletionError> {
+    let not_found = || ChatCompletionError::DifyApiError(
+        StatusCode::NOT_FOUND,
+        format!("No vector store found with id '{}'", alias),
+    );
+    match authorize_alias(req, config, alias) {
+        Err(ChatCompletionError::ModelNotFound(_)) => return Err(not_found()),
+        result => result?,
+    }
+
+    config.datasets.get(alias).cloned().ok_or_else(not_found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use crate::features::config::proxy_config::KeyConfig;
+
+    fn config(keys: &[(&str, &[&str])]) -> ProxyConfig {
+        let mut config: ProxyConfig = serde_json::from_value(serde_json::json!({
+            "embedding_models": {"embed": {"backend": "openai", "base_url": "http://embed", "api_key": "sk-embed", "model": "e"}},
+            "datasets": {"kb": {"dataset_id": "ds", "api_key": "dataset-key"}},
+        })).unwrap();
+        for (key, models) in keys {
+            let models = models.iter().map(|m| m.to_string()).collect();
+            config.keys.insert(key.to_string(), KeyConfig { user: None, models });
+        }
+        config
+    }
+
+    fn bearer(token: &str) -> HttpRequest {
+        TestRequest::default().insert_header(("Authorization", format!("Bearer {}", token))).to_http_request()
+    }
+
+    fn status(result: Result<impl std::fmt::Debug, ChatCompletionError>) -> StatusCode {
+        match result.unwrap_err() {
+            ChatCompletionError::InvalidApiKey => StatusCode::UNAUTHORIZED,
+            ChatCompletionError::ModelNotFound(_) => StatusCode::NOT_FOUND,
+            ChatCompletionError::DifyApiError(status, _) => status,
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[actix_web::test]
+    async fn stored_data_needs_a_key_dify_accepts() {
+        use crate::features::dify::handlers::openai::suggested::tests::fake_dify;
+        let parameters = ParametersCache::new(std::time::Duration::from_secs(60));
+
+        let (url, _) = fake_dify(401, serde_json::json!({"code": "unauthorized"})).await;
+        let result = verify_app_key(&parameters, &Client::new(), &url, "made-up").await;
+        assert!(matches!(result, Err(ChatCompletionError::InvalidApiKey)));
+
+        let (url, request) = fake_dify(200, serde_json::json!({"user_input_form": []})).await;
+        verify_app_key(&parameters, &Client::new(), &url, "app-key").await.unwrap();
+        assert!(request.await.unwrap().contains("Bearer app-key"));
+        // Known keys are answered from the cache
+        verify_app_key(&parameters, &Client::new(), &url, "app-key").await.unwrap();
+    }
+
+    #[test]
+    fn passthrough_refuses_registry_entries() {
+        let config = config(&[]);
+        assert_eq!(status(resolve_embeddings(&bearer("anything"), &config, "embed")), StatusCode::FORBIDDEN);
+        assert_eq!(status(resolve_dataset(&bearer("anything"), &config, "kb")), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn passthrough_registry_opt_in() {
+        let mut config = config(&[]);
+        config.passthrough_registry = true;
+        assert!(resolve_embeddings(&bearer("anything"), &config, "embed").is_ok());
+        assert!(resolve_dataset(&bearer("anything"), &config, "kb").is_ok());
+    }
+
+    #[test]
+    fn proxy_keys_gate_registry_entries() {
+        let config = config(&[("open", &[]), ("embed-only", &["embed"])]);
+        assert!(resolve_embeddings(&bearer("open"), &config, "embed").is_ok());
+        assert!(resolve_dataset(&bearer("open"), &config, "kb").is_ok());
+        assert!(resolve_embeddings(&bearer("embed-only"), &config, "embed").is_ok());
+        assert_eq!(status(resolve_dataset(&bearer("embed-only"), &config, "kb")), StatusCode::NOT_FOUND);
+        assert_eq!(status(resolve_embeddings(&bearer("unknown"), &config, "embed")), StatusCode::UNAUTHORIZED);
+        assert_eq!(status(resolve_dataset(&bearer("open"), &config, "missing")), StatusCode::NOT_FOUND);
+    }
+}
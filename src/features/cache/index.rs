@@ -0,0 +1,400 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use crate::features::config::proxy_config::VectorIndexKind;
+
+/// Nearest-neighbour search over unit vectors by cosine similarity.
+pub trait VectorIndex: Send {
+    fn insert(&mut self, id: u64, vector: Vec<f32>);
+    fn remove(&mut self, id: u64);
+    /// Up to `k` `(id, similarity)` pairs, most similar first.
+    fn search(&self, query: &[f32], k: usize) -> Vec<(u64, f32)>;
+}
+
+pub fn new_index(kind: VectorIndexKind) -> Box<dyn VectorIndex> {
+    match kind {
+        VectorIndexKind::Flat => Box::<FlatIndex>::default(),
+        VectorIndexKind::Hnsw => Box::new(HnswIndex::new(16, 100, 64)),
+    }
+}
+
+/// Scales a vector to unit length so a dot product is its cosine similarity.
+pub fn normalize(mut vector: Vec<f32>) -> Vec<f32> {
+    let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|v| *v /= norm);
+    }
+    vector
+}
+
+fn similarity(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+/// Exact search over every vector.
+#[derive(Default)]
+pub struct FlatIndex {
+    vectors: HashMap<u64, Vec<f32>>,
+}
+
+impl VectorIndex for FlatIndex {
+    fn insert(&mut self, id: u64, vector: Vec<f32>) {
+        self.vectors.insert(id, vector);
+    }
+
+    fn remove(&mut self, id: u64) {
+        self.vectors.remove(&id);
+    }
+
+    fn search(&self, query: &[f32], k: usize) -> Vec<(u64, f32)> {
+        let mut scored: Vec<(u64, f32)> = self.vectors.iter()
+            .map(|(id, vector)| (*id, similarity(query, vector)))
+            .collect();
+        scored.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(Ordering::Equal));
+        scored.truncate(k);
+        scored
+    }
+}
+
+/// A node index scored by similarity, ordered so `BinaryHeap` pops the most similar.
+#[derive(Clone, Copy)]
+struct Scored(f32, usize);
+
+impl PartialEq for Scored {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Scored {}
+
+impl PartialOrd for Scored {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scored {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.partial_cmp(&other.0).unwrap_or(Ordering::Equal)
+    }
+}
+
+struct Node {
+    id: u64,
+    vector: Vec<f32>,
+    /// Neighbour node indices per layer, layer 0 first.
+    neighbors: Vec<Vec<usize>>,
+    deleted: bool,
+}
+
+/// Hierarchical navigable small world graph.
+///
+/// Removal only marks nodes deleted (they still route searches); the graph is
+/// rebuilt from the live nodes once half of it is deleted.
+pub struct HnswIndex {
+    m: usize,
+    ef_construction: usize,
+    ef_search: usize,
+    nodes: Vec<Node>,
+    ids: HashMap<u64, usize>,
+    entry: Option<usize>,
+    deleted: usize,
+    rng: u64,
+}
+
+impl HnswIndex {
+    pub fn new(m: usize, ef_construction: usize, ef_search: usize) -> Self {
+        HnswIndex {
+            m,
+            ef_construction,
+            ef_search,
+            nodes: Vec::new(),
+            ids: HashMap::new(),
+            entry: None,
+            deleted: 0,
+            rng: 0x9E37_79B9_7F4A_7C15,
+        }
+    }
+
+    fn max_neighbors(&self, layer: usize) -> usize {
+        if layer == 0 { self.m * 2 } else { self.m }
+    }
+
+    fn random_level(&mut self) -> usize {
+        // xorshift64; level distribution only needs to be roughly geometric
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        let uniform = (self.rng >> 11) as f64 / (1u64 << 53) as f64;
+        let ml = 1.0 / (self.m as f64).ln();
+        (-(uniform.max(f64::MIN_POSITIVE)).ln() * ml) as usize
+    }
+
+    fn top_layer(&self) -> usize {
+        self.entry.map(|e| self.nodes[e].neighbors.len() - 1).unwrap_or(0)
+    }
+
+    /// Best-first search of one layer, returning up to `ef` nodes, most similar first.
+    fn search_layer(&self, query: &[f32], entry_points: &[usize], ef: usize, layer: usize) -> Vec<Scored> {
+        let mut visited: HashSet<usize> = entry_points.iter().copied().collect();
+        let mut candidates: BinaryHeap<Scored> = BinaryHeap::new();
+        // Min-heap of the current results via reversed ordering
+        let mut results: BinaryHeap<std::cmp::Reverse<Scored>> = BinaryHeap::new();
+
+        for &ep in entry_points {
+            let scored = Scored(similarity(query, &self.nodes[ep].vector), ep);
+            candidates.push(scored);
+            results.push(std::cmp::Reverse(scored));
+        }
+
+        while let Some(Scored(sim, node)) = candidates.pop() {
+            let worst = results.peek().map(|r| r.0 .0).unwrap_or(f32::MIN);
+            if sim < worst && results.len() >= ef {
+                break;
+            }
+            for &neighbor in self.nodes[node].neighbors.get(layer).into_iter().flatten() {
+                if !visited.insert(neighbor) {
+                    continue;
+                }
+                let scored = Scored(similarity(query, &self.nodes[neighbor].vector), neighbor);
+                let worst = results.peek().map(|r| r.0 .0).unwrap_or(f32::MIN);
+                if results.len() < ef || scored.0 > worst {
+                    candidates.push(scored);
+                    results.push(std::cmp::Reverse(scored));
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+
+        let mut found: Vec<Scored> = results.into_iter().map(|r| r.0).collect();
+        found.sort_by(|a, b| b.cmp(a));
+        found
+    }
+
+    /// Greedy descent from the entry point down to `layer + 1`.
+    fn descend(&self, query: &[f32], to_layer: usize) -> Option<usize> {
+        let mut current = self.entry?;
+        for layer in (to_layer + 1..=self.top_layer()).rev() {
+            if let Some(best) = self.search_layer(query, &[current], 1, layer).first() {
+                current = best.1;
+            }
+        }
+        Some(current)
+    }
+
+    fn connect(&mut self, node: usize, neighbor: usize, layer: usize) {
+        let max = self.max_neighbors(layer);
+        let links = &mut self.nodes[neighbor].neighbors[layer];
+        links.push(node);
+        if links.len() > max {
+            let base = self.nodes[neighbor].vector.clone();
+            let mut scored: Vec<Scored> = self.nodes[neighbor].neighbors[layer].iter()
+                .map(|&n| Scored(similarity(&base, &self.nodes[n].vector), n))
+                .collect();
+            scored.sort_by(|a, b| b.cmp(a));
+            self.nodes[neighbor].neighbors[layer] = scored.into_iter().take(max).map(|s| s.1).collect();
+        }
+    }
+
+    fn rebuild(&mut self) {
+        let live: Vec<(u64, Vec<f32>)> = std::mem::take(&mut self.nodes)
+            .into_iter()
+            .filter(|node| !node.deleted)
+            .map(|node| (node.id, node.vector))
+            .collect();
+        self.ids.clear();
+        self.entry = None;
+        self.deleted = 0;
+        for (id, vector) in live {
+            self.insert(id, vector);
+        }
+    }
+}
+
+impl VectorIndex for HnswIndex {
+    fn insert(&mut self, id: u64, vector: Vec<f32>) {
+        self.remove(id);
+
+        let level = self.random_level();
+        let node = self.nodes.len();
+        self.nodes.push(Node {
+            id,
+            vector,
+            neighbors: vec![Vec::new(); level + 1],
+            deleted: false,
+        });
+        self.ids.insert(id, node);
+
+        let Some(entry) = self.descend(&self.nodes[node].vector.clone(), level) else {
+            self.entry = Some(node);
+            return;
+        };
+
+        let query = self.nodes[node].vector.clone();
+        let mut entry_points = vec![entry];
+        for layer in (0..=level.min(self.top_layer())).rev() {
+            let found = self.search_layer(&query, &entry_points, self.ef_construction, layer);
+            let neighbors: Vec<usize> = found.iter()
+                .filter(|s| s.1 != node)
+                .take(self.max_neighbors(layer))
+                .map(|s| s.1)
+                .collect();
+            for &neighbor in &neighbors {
+                self.connect(node, neighbor, layer);
+            }
+            self.nodes[node].neighbors[layer] = neighbors;
+            entry_points = found.into_iter().map(|s| s.1).collect();
+        }
+
+        if level > self.top_layer() {
+            self.entry = Some(node);
+        }
+    }
+
+    fn remove(&mut self, id: u64) {
+        if let Some(node) = self.ids.remove(&id) {
+            self.nodes[node].deleted = true;
+            self.deleted += 1;
+            if self.deleted * 2 > self.nodes.len() {
+                self.rebuild();
+            }
+        }
+    }
+
+    fn search(&self, query: &[f32], k: usize) -> Vec<(u64, f32)> {
+        let Some(entry) = self.descend(query, 0) else { return Vec::new() };
+        self.search_layer(query, &[entry], self.ef_search.max(k), 0)
+            .into_iter()
+            .filter(|s| !self.nodes[s.1].deleted)
+            .take(k)
+            .map(|s| (self.nodes[s.1].id, s.0))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic pseudo-random unit vectors.
+    fn vectors(count: usize, dim: usize) -> Vec<Vec<f32>> {
+        let mut state = 42u64;
+        let mut next = move || {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            ((state >> 33) as f32 / (1u64 << 31) as f32) - 0.5
+        };
+        (0..count).map(|_| normalize((0..dim).map(|_| next()).collect())).collect()
+    }
+
+    fn filled(index: &mut dyn VectorIndex, vectors: &[Vec<f32>]) {
+        for (id, vector) in vectors.iter().enumerate() {
+            index.insert(id as u64, vector.clone());
+        }
+    }
+
+    #[test]
+    fn normalize_gives_unit_length() {
+        let v = normalize(vec![3.0, 4.0]);
+        assert!((v[0] - 0.6).abs() < 1e-6 && (v[1] - 0.8).abs() < 1e-6);
+        assert_eq!(normalize(vec![0.0, 0.0]), vec![0.0, 0.0]);
+    }
+
+    #[test]
+    fn flat_returns_most_similar_first() {
+        let mut index = FlatIndex::default();
+        index.insert(1, normalize(vec![1.0, 0.0]));
+        index.insert(2, normalize(vec![1.0, 1.0]));
+        index.insert(3, normalize(vec![0.0, 1.0]));
+        let found = index.search(&normalize(vec![1.0, 0.2]), 2);
+        assert_eq!(found.iter().map(|f| f.0).collect::<Vec<_>>(), vec![1, 2]);
+        assert!(found[0].1 > found[1].1);
+    }
+
+    #[test]
+    fn hnsw_empty_index_finds_nothing() {
+        let index = HnswIndex::new(16, 100, 64);
+        assert!(index.search(&[1.0, 0.0], 4).is_empty());
+    }
+
+    #[test]
+    fn hnsw_finds_inserted_vectors() {
+        let data = vectors(500, 16);
+        let mut index = HnswIndex::new(16, 100, 64);
+        filled(&mut index, &data);
+        for (id, vector) in data.iter().enumerate() {
+            let found = index.search(vector, 1);
+            assert_eq!(found[0].0, id as u64);
+            assert!((found[0].1 - 1.0).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn hnsw_recall_matches_flat() {
+        let data = vectors(1000, 16);
+        let queries = vectors(1100, 16).split_off(1000);
+        let mut hnsw = HnswIndex::new(16, 100, 64);
+        let mut flat = FlatIndex::default();
+        filled(&mut hnsw, &data);
+        filled(&mut flat, &data);
+
+        let mut hits = 0;
+        for query in &queries {
+            let exact: HashSet<u64> = flat.search(query, 10).into_iter().map(|f| f.0).collect();
+            hits += hnsw.search(query, 10).iter().filter(|f| exact.contains(&f.0)).count();
+        }
+        let recall = hits as f32 / (queries.len() * 10) as f32;
+        assert!(recall > 0.9, "recall {}", recall);
+    }
+
+    #[test]
+    fn hnsw_removed_vectors_are_not_returned() {
+        let data = vectors(100, 8);
+        let mut index = HnswIndex::new(8, 50, 32);
+        filled(&mut index, &data);
+        index.remove(7);
+        assert!(index.search(&data[7], 5).iter().all(|f| f.0 != 7));
+        assert_eq!(index.search(&data[8], 1)[0].0, 8);
+    }
+
+    #[test]
+    fn hnsw_rebuilds_after_many_removals() {
+        let data = vectors(100, 8);
+        let mut index = HnswIndex::new(8, 50, 32);
+        filled(&mut index, &data);
+        for id in 0..60 {
+            index.remove(id);
+        }
+        assert!(index.nodes.len() < 100, "deleted nodes were dropped by a rebuild");
+        for (id, vector) in data.iter().enumerate().skip(60) {
+            assert_eq!(index.search(vector, 1)[0].0, id as u64);
+        }
+        for id in 60..100 {
+            index.remove(id);
+        }
+        assert!(index.search(&data[0], 1).is_empty());
+    }
+
+    #[test]
+    fn hnsw_reinserting_an_id_replaces_its_vector() {
+        let data = vectors(50, 8);
+        let mut index = HnswIndex::new(8, 50, 32);
+        filled(&mut index, &data);
+        index.insert(3, data[40].clone());
+        let found = index.search(&data[40], 2);
+        assert!(found.iter().any(|f| f.0 == 3));
+        assert!(index.search(&data[3], 1)[0].0 != 3);
+    }
+
+    #[test]
+    fn new_index_picks_the_kind() {
+        let mut index = new_index(VectorIndexKind::Flat);
+        index.insert(1, vec![1.0]);
+        assert_eq!(index.search(&[1.0], 1), vec![(1, 1.0)]);
+        let mut index = new_index(VectorIndexKind::Hnsw);
+        index.insert(1, vec![1.0]);
+        assert_eq!(index.search(&[1.0], 1), vec![(1, 1.0)]);
+    }
+}
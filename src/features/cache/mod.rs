@@ -0,0 +1,3 @@
+pub mod index;
+pub mod response;
+pub mod semantic;
@@ -0,0 +1,553 @@
+use actix_web::body::{to_bytes, BodyStream, BoxBody, MessageBody};
+use actix_web::http::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use actix_web::{web, HttpRequest, HttpResponse};
+use bytes::Bytes;
+use chrono::Utc;
+use futures_util::{future, stream, StreamExt};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::features::auth::resolver::extract_api_key;
+use crate::features::common::types::OpenAIRequest;
+use crate::features::dify::handlers::openai::chat_completion::{sse_response, StreamResponse};
+
+/// Request header that opts a request into the cache regardless of temperature.
+pub const CACHE_REQUEST_HEADER: &str = "x-proxy-cache";
+/// Response header reporting `hit` or `miss` for cacheable requests.
+pub const CACHE_STATUS_HEADER: &str = "x-cache";
+
+/// A stored response body. Streaming responses keep their SSE framing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedResponse {
+    pub content_type: String,
+    pub body: String,
+    pub stored_at: i64,
+}
+
+/// Storage behind the response cache. Calls may block; the cache makes them
+/// on the blocking thread pool.
+pub trait CacheBackend: Send + Sync {
+    fn get(&self, key: &str) -> Option<CachedResponse>;
+    fn put(&self, key: &str, response: CachedResponse);
+    fn remove(&self, key: &str);
+}
+
+/// In-memory LRU bounded by entry count.
+pub struct MemoryBackend {
+    capacity: usize,
+    state: Mutex<LruState>,
+}
+
+#[derive(Default)]
+struct LruState {
+    tick: u64,
+    entries: HashMap<String, (CachedResponse, u64)>,
+    /// Last-use tick -> key, oldest first.
+    order: BTreeMap<u64, String>,
+}
+
+impl MemoryBackend {
+    pub fn new(capacity: usize) -> Self {
+        MemoryBackend {
+            capacity: capacity.max(1),
+            state: Mutex::new(LruState::default()),
+        }
+    }
+}
+
+impl LruState {
+    fn touch(&mut self, key: &str) -> u64 {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some((_, last_used)) = self.entries.get_mut(key) {
+            self.order.remove(last_used);
+            *last_used = tick;
+        }
+        self.order.insert(tick, key.to_string());
+        tick
+    }
+}
+
+impl CacheBackend for MemoryBackend {
+    fn get(&self, key: &str) -> Option<CachedResponse> {
+        let mut state = self.state.lock().unwrap();
+        let response = state.entries.get(key)?.0.clone();
+        state.touch(key);
+        Some(response)
+    }
+
+    fn put(&self, key: &str, response: CachedResponse) {
+        let mut state = self.state.lock().unwrap();
+        if state.entries.contains_key(key) {
+            state.entries.get_mut(key).unwrap().0 = response;
+            state.touch(key);
+            return;
+        }
+        while state.entries.len() >= self.capacity {
+            let Some((_, oldest)) = state.order.pop_first() else { break };
+            state.entries.remove(&oldest);
+        }
+        let tick = state.touch(key);
+        state.entries.insert(key.to_string(), (response, tick));
+    }
+
+    fn remove(&self, key: &str) {
+        let mut state = self.state.lock().unwrap();
+        if let Some((_, tick)) = state.entries.remove(key) {
+            state.order.remove(&tick);
+        }
+    }
+}
+
+/// One JSON file per entry under a directory. Survives restarts. Every write
+/// sweeps the directory: expired files go first, then the oldest files until
+/// the entry and byte limits hold.
+pub struct DiskBackend {
+    dir: PathBuf,
+    ttl: Duration,
+    max_entries: usize,
+    max_bytes: u64,
+}
+
+impl DiskBackend {
+    pub fn new(dir: PathBuf, ttl: Duration, max_entries: usize, max_bytes: u64) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        let backend = DiskBackend { dir, ttl, max_entries: max_entries.max(1), max_bytes };
+        backend.sweep();
+        Ok(backend)
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", key))
+    }
+
+    /// Removes expired entries, then the least recently written ones over the limits.
+    fn sweep(&self) {
+        let Ok(dir) = std::fs::read_dir(&self.dir) else { return };
+        let now = SystemTime::now();
+        let mut entries = Vec::new();
+        for entry in dir.flatten() {
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let Ok(metadata) = entry.metadata() else { continue };
+            let written = metadata.modified().unwrap_or(now);
+            if now.duration_since(written).unwrap_or_default() > self.ttl {
+                let _ = std::fs::remove_file(&path);
+            } else {
+                entries.push((written, metadata.len(), path));
+            }
+        }
+
+        entries.sort_by_key(|(written, _, _)| std::cmp::Reverse(*written));
+        let mut bytes = 0;
+        for (count, (_, len, path)) in entries.into_iter().enumerate() {
+            bytes += len;
+            if count >= self.max_entries || bytes > self.max_bytes {
+                let _ = std::fs::remove_file(&path);
+            }
+        }
+    }
+}
+
+impl CacheBackend for DiskBackend {
+    fn get(&self, key: &str) -> Option<CachedResponse> {
+        let raw = std::fs::read_to_string(self.path(key)).ok()?;
+        serde_json::from_str(&raw).ok()
+    }
+
+    fn put(&self, key: &str, response: CachedResponse) {
+        let result = serde_json::to_vec(&response)
+            .map_err(|e| e.to_string())
+            .and_then(|raw| std::fs::write(self.path(key), raw).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            warn!("Failed to write cache entry {}: {}", key, e);
+        }
+        self.sweep();
+    }
+
+    fn remove(&self, key: &str) {
+        let _ = std::fs::remove_file(self.path(key));
+    }
+}
+
+/// Opt-in cache for deterministic chat completions.
+pub struct ResponseCache {
+    backend: Arc<dyn CacheBackend>,
+    ttl: Duration,
+}
+
+impl ResponseCache {
+    pub fn new(backend: Box<dyn CacheBackend>, ttl: Duration) -> Self {
+        ResponseCache { backend: Arc::from(backend), ttl }
+    }
+
+    /// Cache key for a request, or `None` if it isn't cacheable: only
+    /// `temperature: 0` requests and requests carrying `x-proxy-cache: true`
+    /// are. Entries are scoped to the caller's bearer key and the Dify `user`,
+    /// since a response carries the id of that user's Dify message.
+    pub(crate) fn key(
+        &self,
+        req: &HttpRequest,
+        openai_req: &OpenAIRequest,
+        app_key: &str,
+        user: &str,
+        inputs: &serde_json::Map<String, Value>,
+    ) -> Option<String> {
+        let opted_in = req.headers()
+            .get(CACHE_REQUEST_HEADER)
+            .and_then(|h| h.to_str().ok())
+            .is_some_and(|h| matches!(h.to_ascii_lowercase().as_str(), "true" | "1" | "on"));
+        if !opted_in && openai_req.temperature != Some(0.0) {
+            return None;
+        }
+
+        // serde_json maps are ordered, so this serialization is canonical
+        let canonical = json!({
+            "owner": extract_api_key(req).unwrap_or_default(),
+            "user": user,
+            "app_key": app_key,
+            "model": openai_req.model,
+            "messages": openai_req.messages,
+            "inputs": inputs,
+            "stream": openai_req.stream.unwrap_or(false),
+            "temperature": openai_req.temperature,
+            "top_p": openai_req.top_p,
+            "max_tokens": openai_req.max_tokens,
+            "n": openai_req.n,
+            "stop": openai_req.stop,
+            "response_format": openai_req.response_format,
+            "tools": openai_req.tools,
+            "modalities": openai_req.modalities,
+            "suggested_questions": openai_req.suggested_questions,
+            "citation_markers": openai_req.citation_markers,
+        });
+        let digest = Sha1::digest(canonical.to_string().as_bytes());
+        Some(digest.iter().map(|b| format!("{:02x}", b)).collect())
+    }
+
+    /// The cached response for `key`, unless it has expired.
+    pub(crate) async fn lookup(&self, key: &str) -> Option<HttpResponse> {
+        let (backend, ttl, owned_key) = (self.backend.clone(), self.ttl, key.to_string());
+        let cached = web::block(move || {
+            let cached = backend.get(&owned_key)?;
+            if Utc::now().timestamp() - cached.stored_at > ttl.as_secs() as i64 {
+                backend.remove(&owned_key);
+                return None;
+            }
+            Some(cached)
+        }).await.ok().flatten()?;
+        debug!("Response cache hit for {}", key);
+
+        let response = if is_sse(&cached.content_type) {
+            sse_response(replay_sse(cached.body))
+        } else {
+            HttpResponse::Ok()
+                .insert_header((CONTENT_TYPE, cached.content_type))
+                .body(cached.body)
+        };
+        Some(with_cache_status(response, "hit"))
+    }
+
+    /// Stores a successful response on its way to the client. Streams are
+    /// stored once they finish without an error event.
+    pub(crate) async fn store(self: &Arc<Self>, key: String, response: HttpResponse) -> HttpResponse {
+        if !response.status().is_success() {
+            return with_cache_status(response, "miss");
+        }
+        let cache = self.clone();
+        let response = capture_response(response, move |content_type, body| {
+            if !is_sse(&content_type) || stream_completed(&body) {
+                cache.put(&key, content_type, body);
+            }
+        }).await;
+        with_cache_status(response, "miss")
+    }
+
+    /// Writes an entry in the background; the response doesn't wait for it.
+    fn put(&self, key: &str, content_type: String, body: String) {
+        let (backend, key) = (self.backend.clone(), key.to_string());
+        let response = CachedResponse {
+            content_type,
+            body,
+            stored_at: Utc::now().timestamp(),
+        };
+        // The write starts right away; dropping the handle doesn't cancel it
+        drop(web::block(move || backend.put(&key, response)));
+    }
+}
+
+pub(crate) fn is_sse(content_type: &str) -> bool {
+    content_type.starts_with("text/event-stream")
+}
+
+/// Whether a captured chat completion stream ran to `[DONE]` with a finished
+/// choice and no error event.
+pub(crate) fn stream_completed(body: &str) -> bool {
+    let (mut done, mut finished) = (false, false);
+    for data in body.lines().filter_map(|line| line.strip_prefix("data: ")) {
+        if data == "[DONE]" {
+            done = true;
+            continue;
+        }
+        let Ok(event) = serde_json::from_str::<Value>(data) else { return false };
+        if event.get("error").is_some() {
+            return false;
+        }
+        finished |= event["choices"].as_array()
+            .is_some_and(|choices| choices.iter().any(|c| !c["finish_reason"].is_null()));
+    }
+    done && finished
+}
+
+/// Passes a response through, handing its content type and full body to
+/// `on_complete`. Streams are handed over once they end cleanly.
+pub(crate) async fn capture_response(
+    response: HttpResponse,
+    on_complete: impl FnOnce(String, String) + 'static,
+) -> HttpResponse {
+    let content_type = response.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or("application/json")
+        .to_string();
+    let (response, body) = response.into_parts();
+
+    if is_sse(&content_type) {
+        let tapped = tap_stream(body, move |events| on_complete(content_type, events));
+        return response.set_body(BoxBody::new(BodyStream::new(tapped)));
+    }
+
+    match to_bytes(body).await {
+        Ok(bytes) => {
+            on_complete(content_type, String::from_utf8_lossy(&bytes).into_owned());
+            response.set_body(BoxBody::new(bytes))
+        },
+        Err(_) => response.set_body(BoxBody::new(Bytes::new())),
+    }
+}
+
+pub(crate) fn with_cache_status(mut response: HttpResponse, status: &'static str) -> HttpResponse {
+    response.headers_mut().insert(HeaderName::from_static(CACHE_STATUS_HEADER), HeaderValue::from_static(status));
+    response
+}
+
+type TappedStream = Pin<Box<dyn futures_util::Stream<Item = Result<Bytes, actix_web::Error>>>>;
+
+/// Passes a response body through while collecting it; `on_complete` gets the
+/// whole body once the stream ends cleanly.
+fn tap_stream(body: BoxBody, on_complete: impl FnOnce(String) + 'static) -> TappedStream {
+    let mut body = Box::pin(body);
+    let chunks = stream::poll_fn(move |cx| body.as_mut().poll_next(cx));
+    let mut on_complete = Some(on_complete);
+
+    Box::pin(chunks
+        .map(Some)
+        .chain(stream::once(future::ready(None)))
+        .scan((Vec::new(), false), move |(collected, failed), chunk| {
+            let item = match chunk {
+                Some(Ok(bytes)) => {
+                    collected.extend_from_slice(&bytes);
+                    Some(Ok(bytes))
+                },
+                Some(Err(e)) => {
+                    *failed = true;
+                    Some(Err(actix_web::error::ErrorInternalServerError(e.to_string())))
+                },
+                None => {
+                    if !*failed {
+                        if let Some(on_complete) = on_complete.take() {
+                            on_complete(String::from_utf8_lossy(collected).into_owned());
+                        }
+                    }
+                    None
+                },
+            };
+            future::ready(Some(item))
+        })
+        .filter_map(future::ready))
+}
+
+/// Replays a stored SSE body one event per chunk.
+pub(crate) fn replay_sse(body: String) -> StreamResponse {
+    let events: Vec<Bytes> = body
+        .split("\n\n")
+        .filter(|event| !event.trim().is_empty())
+        .map(|event| Bytes::from(format!("{}\n\n", event)))
+        .collect();
+    Box::pin(stream::iter(events).map(Ok))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    use crate::features::common::types::OpenAIMessage;
+
+    fn cached(body: &str) -> CachedResponse {
+        CachedResponse { content_type: "application/json".to_string(), body: body.to_string(), stored_at: Utc::now().timestamp() }
+    }
+
+    fn request(temperature: Option<f32>) -> OpenAIRequest {
+        OpenAIRequest {
+            messages: vec![OpenAIMessage::text("user", "Hi".to_string())],
+            model: Some("m".to_string()),
+            temperature,
+            ..Default::default()
+        }
+    }
+
+    fn bearer(token: &str) -> TestRequest {
+        TestRequest::default().insert_header(("Authorization", format!("Bearer {}", token)))
+    }
+
+    #[test]
+    fn the_memory_backend_evicts_the_least_recently_used() {
+        let backend = MemoryBackend::new(2);
+        backend.put("a", cached("1"));
+        backend.put("b", cached("2"));
+        backend.get("a");
+        backend.put("c", cached("3"));
+
+        assert!(backend.get("a").is_some());
+        assert!(backend.get("b").is_none());
+        assert!(backend.get("c").is_some());
+        backend.remove("a");
+        assert!(backend.get("a").is_none());
+    }
+
+    fn cache_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("response-cache-{}", uuid::Uuid::new_v4().simple()))
+    }
+
+    /// Waits for a background write to land.
+    async fn stored(cache: &ResponseCache, key: &str) -> Option<HttpResponse> {
+        for _ in 0..100 {
+            if let Some(hit) = cache.lookup(key).await {
+                return Some(hit);
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        None
+    }
+
+    #[test]
+    fn the_disk_backend_round_trips() {
+        let dir = cache_dir();
+        let backend = DiskBackend::new(dir.clone(), Duration::from_secs(60), 10, 1024).unwrap();
+        backend.put("k", cached("{}"));
+
+        assert_eq!(backend.get("k").unwrap().body, "{}");
+        backend.remove("k");
+        assert!(backend.get("k").is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn the_disk_backend_keeps_to_its_limits() {
+        let dir = cache_dir();
+        let backend = DiskBackend::new(dir.clone(), Duration::from_secs(60), 2, 1024).unwrap();
+        for key in ["a", "b", "c"] {
+            backend.put(key, cached("{}"));
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert!(backend.get("a").is_none());
+        assert!(backend.get("b").is_some() && backend.get("c").is_some());
+
+        let small = DiskBackend::new(dir.clone(), Duration::from_secs(60), 10, 1).unwrap();
+        small.put("d", cached("{}"));
+        assert!(small.get("d").is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn the_disk_backend_sweeps_expired_files() {
+        let dir = cache_dir();
+        let backend = DiskBackend::new(dir.clone(), Duration::from_secs(60), 10, 1024).unwrap();
+        backend.put("old", cached("{}"));
+        let expired = DiskBackend::new(dir.clone(), Duration::ZERO, 10, 1024).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        expired.sweep();
+        assert!(backend.get("old").is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn only_deterministic_or_opted_in_requests_are_cacheable() {
+        let cache = ResponseCache::new(Box::new(MemoryBackend::new(10)), Duration::from_secs(60));
+        let inputs = serde_json::Map::new();
+        let key = |req: TestRequest, openai_req: &OpenAIRequest, user: &str| {
+            cache.key(&req.to_http_request(), openai_req, "app-key", user, &inputs)
+        };
+
+        assert!(key(bearer("a"), &request(None), "u").is_none());
+        assert!(key(bearer("a"), &request(Some(0.7)), "u").is_none());
+        assert!(key(bearer("a").insert_header((CACHE_REQUEST_HEADER, "true")), &request(Some(0.7)), "u").is_some());
+
+        let base = key(bearer("a"), &request(Some(0.0)), "u").unwrap();
+        assert_eq!(key(bearer("a"), &request(Some(0.0)), "u").unwrap(), base);
+        assert_ne!(key(bearer("b"), &request(Some(0.0)), "u").unwrap(), base);
+        assert_ne!(key(bearer("a"), &request(Some(0.0)), "v").unwrap(), base);
+    }
+
+    #[actix_web::test]
+    async fn expired_entries_are_dropped() {
+        let backend = MemoryBackend::new(10);
+        backend.put("old", CachedResponse { stored_at: Utc::now().timestamp() - 120, ..cached("{}") });
+        backend.put("new", cached("{}"));
+        let cache = ResponseCache::new(Box::new(backend), Duration::from_secs(60));
+
+        assert!(cache.lookup("old").await.is_none());
+        let hit = cache.lookup("new").await.unwrap();
+        assert_eq!(hit.headers().get(CACHE_STATUS_HEADER).unwrap(), "hit");
+    }
+
+    #[actix_web::test]
+    async fn successful_responses_are_stored() {
+        let cache = Arc::new(ResponseCache::new(Box::new(MemoryBackend::new(10)), Duration::from_secs(60)));
+        let response = cache.store("k".to_string(), HttpResponse::Ok().json(json!({"id": "m1"}))).await;
+        assert_eq!(response.headers().get(CACHE_STATUS_HEADER).unwrap(), "miss");
+        assert_eq!(to_bytes(response.into_body()).await.unwrap(), "{\"id\":\"m1\"}");
+        assert!(stored(&cache, "k").await.is_some());
+
+        cache.store("failed".to_string(), HttpResponse::BadGateway().finish()).await;
+        assert!(cache.lookup("failed").await.is_none());
+    }
+
+    #[actix_web::test]
+    async fn streams_replay_event_by_event() {
+        let body = "data: {\"a\":1}\n\ndata: [DONE]\n\n";
+        let events: Vec<_> = replay_sse(body.to_string()).collect().await;
+        let events: Vec<_> = events.into_iter().map(|e| e.unwrap()).collect();
+        assert_eq!(events, ["data: {\"a\":1}\n\n", "data: [DONE]\n\n"]);
+    }
+
+    #[test]
+    fn only_finished_streams_without_errors_are_complete() {
+        let chunk = |content: &str, finish_reason: Value| format!(
+            "data: {}\n\n",
+            json!({"choices": [{"index": 0, "delta": {"content": content}, "finish_reason": finish_reason}]})
+        );
+        let finished = chunk("Hi", Value::Null) + &chunk("", json!("stop")) + "data: [DONE]\n\n";
+        assert!(stream_completed(&finished));
+
+        // A truncated answer has no finish_reason, even when it ends with [DONE]
+        assert!(!stream_completed(&(chunk("Hi", Value::Null) + "data: [DONE]\n\n")));
+        assert!(!stream_completed(&(chunk("Hi", Value::Null) + &chunk("", json!("stop")))));
+        let failed = chunk("Hi", Value::Null) + "data: {\"error\":{\"message\":\"x\"}}\n\ndata: [DONE]\n\n";
+        assert!(!stream_completed(&failed));
+
+        // Answers that quote an error object are still complete
+        let quoting = chunk("{\"error\":{\"code\":1}}", Value::Null) + &chunk("", json!("stop")) + "data: [DONE]\n\n";
+        assert!(stream_completed(&quoting));
+    }
+}
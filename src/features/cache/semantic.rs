@@ -0,0 +1,377 @@
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::HttpResponse;
+use chrono::Utc;
+use log::{debug, warn};
+use reqwest::Client;
+use serde_json::{json, Value};
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use crate::features::common::types::{OpenAIChoice, OpenAIDelta, OpenAIRequest, OpenAIResponse};
+use crate::features::config::proxy_config::SemanticCacheConfig;
+use crate::features::dify::handlers::openai::chat_completion::{sse_response, sse_event, sse_done, StreamResponse};
+use crate::features::embeddings::backend::embed;
+use super::index::{new_index, normalize, VectorIndex};
+use super::response::{capture_response, is_sse, stream_completed, with_cache_status};
+
+/// Response header with the similarity of a semantic cache hit.
+pub const SIMILARITY_HEADER: &str = "x-cache-similarity";
+
+struct SemanticEntry {
+    answer: String,
+    /// Hash of what shaped the answer besides the question; see `context_key`.
+    context: String,
+    stored_at: i64,
+}
+
+/// Cached answers for one model alias and tenant.
+struct Scope {
+    /// The config the scope was built with; a changed config starts afresh.
+    config: SemanticCacheConfig,
+    index: Box<dyn VectorIndex>,
+    entries: HashMap<u64, SemanticEntry>,
+    /// Insertion order, for eviction.
+    order: VecDeque<u64>,
+    next_id: u64,
+}
+
+impl Scope {
+    fn new(config: &SemanticCacheConfig) -> Self {
+        Scope {
+            config: config.clone(),
+            index: new_index(config.index),
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            next_id: 0,
+        }
+    }
+
+    fn remove(&mut self, id: u64) {
+        self.entries.remove(&id);
+        self.index.remove(id);
+    }
+}
+
+/// What a lookup found: a cached answer, or where to store the eventual answer.
+pub(crate) enum SemanticLookup {
+    Hit { answer: String, similarity: f32 },
+    Miss(SemanticSlot),
+}
+
+/// Where a missed request's answer goes: its scope, context and query embedding.
+pub(crate) struct SemanticSlot {
+    scope: (String, String),
+    context: String,
+    embedding: Vec<f32>,
+}
+
+/// Nearest entries looked at per lookup. Entries with another context are
+/// skipped, so a few more than one are needed.
+const SEARCH_DEPTH: usize = 16;
+
+/// Answers to earlier questions, looked up by embedding similarity of the
+/// last user message. Scoped per model alias and tenant (the caller's key);
+/// within a scope, only answers given under the same context are served.
+#[derive(Default)]
+pub struct SemanticCache {
+    scopes: Mutex<HashMap<(String, String), Scope>>,
+}
+
+/// Only single-choice plain answers to a first question are cached. Earlier
+/// turns change what the question means, and a stop sequence may have cut the
+/// answer short, so requests with either neither read nor store entries.
+fn eligible(openai_req: &OpenAIRequest) -> bool {
+    let turns = openai_req.messages.iter().filter(|m| !is_instruction(&m.role)).count();
+    turns <= 1
+        && openai_req.n.unwrap_or(1) <= 1
+        && !openai_req.stop.as_ref().is_some_and(|s| s.to_vec().iter().any(|s| !s.is_empty()))
+        && openai_req.tools.is_none()
+        && openai_req.response_format.is_none()
+        && !openai_req.wants_audio()
+}
+
+fn is_instruction(role: &str) -> bool {
+    matches!(role, "system" | "developer")
+}
+
+/// Hash of the system prompt, the Dify `inputs` and the Dify user. The same
+/// question asked under a different one of these may need another answer.
+fn context_key(openai_req: &OpenAIRequest, user: &str, inputs: &serde_json::Map<String, Value>) -> String {
+    let instructions: Vec<String> = openai_req.messages.iter()
+        .filter(|m| is_instruction(&m.role))
+        .map(|m| m.content.to_text())
+        .collect();
+    // serde_json maps are ordered, so this serialization is canonical
+    let canonical = json!({"instructions": instructions, "inputs": inputs, "user": user});
+    let digest = Sha1::digest(canonical.to_string().as_bytes());
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl SemanticCache {
+    /// Embeds the request's last user message and searches its scope. Returns
+    /// `None` when the request isn't eligible or the embedding fails.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) async fn lookup(
+        &self,
+        client: &Client,
+        config: &SemanticCacheConfig,
+        model: &str,
+        tenant: &str,
+        openai_req: &OpenAIRequest,
+        user: &str,
+        inputs: &serde_json::Map<String, Value>,
+    ) -> Option<SemanticLookup> {
+        if !eligible(openai_req) {
+            return None;
+        }
+        let query = openai_req.messages.iter().rev().find(|m| m.role == "user")?.content.to_text();
+        if query.trim().is_empty() {
+            return None;
+        }
+
+        let embedding = match embed(client, &config.embeddings, vec![query], None).await {
+            Ok(embeddings) => normalize(embeddings.vectors.into_iter().next()?),
+            Err(e) => {
+                warn!("Semantic cache skipped, embedding failed: {}", e);
+                return None;
+            },
+        };
+
+        let slot = SemanticSlot {
+            scope: (model.to_string(), tenant.to_string()),
+            context: context_key(openai_req, user, inputs),
+            embedding,
+        };
+        Some(self.search(config, slot))
+    }
+
+    fn search(&self, config: &SemanticCacheConfig, slot: SemanticSlot) -> SemanticLookup {
+        let mut scopes = self.scopes.lock().unwrap();
+        let scope = scopes.entry(slot.scope.clone()).or_insert_with(|| Scope::new(config));
+        if &scope.config != config {
+            *scope = Scope::new(config);
+        }
+
+        let now = Utc::now().timestamp();
+        for (id, similarity) in scope.index.search(&slot.embedding, SEARCH_DEPTH) {
+            let expired = scope.entries.get(&id).map(|e| now - e.stored_at > config.ttl_secs as i64);
+            match expired {
+                Some(true) | None => scope.remove(id),
+                Some(false) if similarity < config.threshold => break,
+                Some(false) if scope.entries[&id].context != slot.context => {},
+                Some(false) => {
+                    debug!("Semantic cache hit for {} (similarity {:.3})", slot.scope.0, similarity);
+                    let answer = scope.entries[&id].answer.clone();
+                    return SemanticLookup::Hit { answer, similarity };
+                },
+            }
+        }
+
+        SemanticLookup::Miss(slot)
+    }
+
+    fn insert(&self, slot: SemanticSlot, answer: String) {
+        let mut scopes = self.scopes.lock().unwrap();
+        let Some(scope) = scopes.get_mut(&slot.scope) else { return };
+
+        while scope.entries.len() >= scope.config.max_entries.max(1) {
+            let Some(oldest) = scope.order.pop_front() else { break };
+            scope.remove(oldest);
+        }
+        let id = scope.next_id;
+        scope.next_id += 1;
+        scope.index.insert(id, slot.embedding);
+        scope.entries.insert(id, SemanticEntry { answer, context: slot.context, stored_at: Utc::now().timestamp() });
+        scope.order.push_back(id);
+    }
+
+    /// Stores the answer of a successful response under the lookup's embedding.
+    pub(crate) async fn store(self: &Arc<Self>, slot: SemanticSlot, response: HttpResponse) -> HttpResponse {
+        if !response.status().is_success() {
+            return response;
+        }
+        let cache = self.clone();
+        let response = capture_response(response, move |content_type, body| {
+            if let Some(answer) = extract_answer(&content_type, &body) {
+                cache.insert(slot, answer);
+            }
+        }).await;
+        with_cache_status(response, "miss")
+    }
+}
+
+/// The first choice's text from a blocking or streamed chat completion body.
+fn extract_answer(content_type: &str, body: &str) -> Option<String> {
+    if !is_sse(content_type) {
+        let response: Value = serde_json::from_str(body).ok()?;
+        let choice = &response["choices"][0];
+        let content = choice["delta"]["content"].as_str().or(choice["message"]["content"].as_str())?;
+        return Some(content.to_string());
+    }
+
+    if !stream_completed(body) {
+        return None;
+    }
+    let answer: String = body.lines()
+        .filter_map(|line| line.strip_prefix("data: "))
+        .filter_map(|data| serde_json::from_str::<Value>(data).ok())
+        .filter_map(|chunk| chunk["choices"][0]["delta"]["content"].as_str().map(String::from))
+        .collect();
+    Some(answer).filter(|a| !a.is_empty())
+}
+
+/// Builds a chat completion from a cached answer, streamed or not.
+pub(crate) fn hit_response(answer: String, similarity: f32, model: &str, is_streaming: bool) -> HttpResponse {
+    let id = format!("chatcmpl-{}", uuid::Uuid::new_v4());
+    let created = Utc::now().timestamp() as u64;
+    let chunk = |delta: OpenAIDelta, finish_reason: Option<String>| OpenAIResponse {
+        id: id.clone(),
+        object: if is_streaming { "chat.completion.chunk" } else { "chat.completion" }.to_string(),
+        created,
+        model: model.to_string(),
+        choices: vec![OpenAIChoice { index: 0, delta, finish_reason }],
+        usage: None,
+        metadata: None,
+    };
+    let delta = OpenAIDelta {
+        role: Some("assistant".to_string()),
+        content: Some(answer),
+        ..Default::default()
+    };
+
+    let response = if is_streaming {
+        let finish = chunk(OpenAIDelta::default(), Some("stop".to_string()));
+        let content = chunk(delta, None);
+        let events = vec![sse_event(&content), sse_event(&finish), sse_done()];
+        let stream: StreamResponse = Box::pin(futures_util::stream::iter(events.into_iter().map(Ok)));
+        sse_response(stream)
+    } else {
+        HttpResponse::Ok().json(chunk(delta, Some("stop".to_string())))
+    };
+
+    let mut response = with_cache_status(response, "hit");
+    if let Ok(value) = HeaderValue::from_str(&format!("{:.4}", similarity)) {
+        response.headers_mut().insert(HeaderName::from_static(SIMILARITY_HEADER), value);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn request(extra: Value) -> OpenAIRequest {
+        let mut body = json!({"model": "m", "messages": [{"role": "user", "content": "hi"}]});
+        body.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+        serde_json::from_value(body).unwrap()
+    }
+
+    #[test]
+    fn plain_requests_are_eligible() {
+        assert!(eligible(&request(json!({}))));
+        assert!(eligible(&request(json!({"n": 1, "stop": ""}))));
+    }
+
+    #[test]
+    fn stop_sequences_are_not_eligible() {
+        assert!(!eligible(&request(json!({"stop": "END"}))));
+        assert!(!eligible(&request(json!({"stop": ["", "\n"]}))));
+    }
+
+    #[test]
+    fn multi_choice_tools_and_formats_are_not_eligible() {
+        assert!(!eligible(&request(json!({"n": 2}))));
+        assert!(!eligible(&request(json!({"tools": []}))));
+        assert!(!eligible(&request(json!({"response_format": {"type": "json_object"}}))));
+    }
+
+    #[test]
+    fn follow_up_turns_are_not_eligible() {
+        let system = json!({"messages": [{"role": "system", "content": "Be brief"}, {"role": "user", "content": "hi"}]});
+        assert!(eligible(&request(system)));
+        let follow_up = json!({"messages": [
+            {"role": "user", "content": "hi"},
+            {"role": "assistant", "content": "Hello"},
+            {"role": "user", "content": "hi"},
+        ]});
+        assert!(!eligible(&request(follow_up)));
+    }
+
+    fn config() -> SemanticCacheConfig {
+        serde_json::from_value(json!({"embeddings": {"backend": "openai", "base_url": "http://embeddings", "model": "e"}})).unwrap()
+    }
+
+    fn slot(context: &str) -> SemanticSlot {
+        SemanticSlot {
+            scope: ("m".to_string(), "key".to_string()),
+            context: context.to_string(),
+            embedding: normalize(vec![1.0, 0.0]),
+        }
+    }
+
+    #[test]
+    fn answers_are_only_served_under_their_context() {
+        let cache = SemanticCache::default();
+        let config = config();
+        assert!(matches!(cache.search(&config, slot("a")), SemanticLookup::Miss(_)));
+        cache.insert(slot("a"), "Hello".to_string());
+
+        assert!(matches!(cache.search(&config, slot("a")), SemanticLookup::Hit { answer, .. } if answer == "Hello"));
+        assert!(matches!(cache.search(&config, slot("b")), SemanticLookup::Miss(_)));
+    }
+
+    #[test]
+    fn system_prompts_change_the_context() {
+        let inputs = serde_json::Map::new();
+        let ask = |system: &str| request(json!({"messages": [
+            {"role": "system", "content": system},
+            {"role": "user", "content": "hi"},
+        ]}));
+        let base = context_key(&ask("Answer in English"), "u", &inputs);
+        assert_eq!(context_key(&ask("Answer in English"), "u", &inputs), base);
+        assert_ne!(context_key(&ask("Answer in French"), "u", &inputs), base);
+        assert_ne!(context_key(&request(json!({})), "u", &inputs), context_key(&ask(""), "u", &inputs));
+    }
+
+    #[test]
+    fn inputs_change_the_context() {
+        let mut inputs = serde_json::Map::new();
+        let base = context_key(&request(json!({})), "u", &inputs);
+        inputs.insert("language".to_string(), json!("fr"));
+        assert_ne!(context_key(&request(json!({})), "u", &inputs), base);
+    }
+
+    #[test]
+    fn users_change_the_context() {
+        let inputs = serde_json::Map::new();
+        assert_ne!(context_key(&request(json!({})), "u", &inputs), context_key(&request(json!({})), "v", &inputs));
+    }
+
+    #[test]
+    fn answers_are_read_from_blocking_and_streamed_bodies() {
+        let blocking = json!({"choices": [{"message": {"content": "Hello"}}]}).to_string();
+        assert_eq!(extract_answer("application/json", &blocking).as_deref(), Some("Hello"));
+
+        let streamed = "data: {\"choices\":[{\"delta\":{\"content\":\"Hel\"}}]}\n\n\
+                        data: {\"choices\":[{\"delta\":{\"content\":\"lo\"}}]}\n\n\
+                        data: {\"choices\":[{\"delta\":{},\"finish_reason\":\"stop\"}]}\n\n\
+                        data: [DONE]\n\n";
+        assert_eq!(extract_answer("text/event-stream", streamed).as_deref(), Some("Hello"));
+        assert!(extract_answer("text/event-stream", "data: [DONE]\n\n").is_none());
+        assert!(extract_answer("text/event-stream", &streamed.replace("data: [DONE]", "")).is_none());
+        // A truncated answer is not stored
+        let truncated = streamed.replace("\"finish_reason\":\"stop\"", "\"finish_reason\":null");
+        assert!(extract_answer("text/event-stream", &truncated).is_none());
+    }
+
+    #[actix_web::test]
+    async fn hits_carry_the_similarity() {
+        let response = hit_response("Hello".to_string(), 0.75, "m", false);
+        assert_eq!(response.headers().get(SIMILARITY_HEADER).unwrap(), "0.7500");
+        let body = actix_web::body::to_bytes(response.into_body()).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["choices"][0]["delta"]["content"], "Hello");
+    }
+}
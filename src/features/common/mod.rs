@@ -1,2 +1,3 @@
-pub mod types;
 
+pub mod multipart;
+pub mod types;
@@ -0,0 +1,148 @@
+use actix_multipart::Multipart;
+use bytes::{Bytes, BytesMut};
+use futures_util::TryStreamExt;
+use reqwest::StatusCode;
+use std::collections::HashMap;
+
+use crate::features::dify::handlers::openai::chat_completion::ChatCompletionError;
+
+/// The `file` part of a multipart upload.
+pub struct UploadedFile {
+    pub filename: String,
+    /// The part's declared content type, if any.
+    pub content_type: Option<String>,
+    pub data: Bytes,
+}
+
+/// A multipart form: text fields by name and the `file` part.
+pub struct UploadForm {
+    pub fields: HashMap<String, String>,
+    pub file: Option<UploadedFile>,
+}
+
+impl UploadForm {
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields.get(name).map(String::as_str).filter(|v| !v.is_empty())
+    }
+}
+
+/// Combined size of the names and values of a form's text fields.
+const MAX_FIELDS_BYTES: usize = 64 * 1024;
+
+fn too_large(what: &str, max_bytes: usize) -> ChatCompletionError {
+    ChatCompletionError::DifyApiError(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        format!("{} exceeds the maximum size of {} bytes", what, max_bytes),
+    )
+}
+
+/// Reads a multipart form with one `file` part. A `file` larger than
+/// `max_bytes`, or text fields larger than `MAX_FIELDS_BYTES` together, is
+/// rejected with a 413 before more of it is buffered.
+pub(crate) async fn read_upload_form(mut payload: Multipart, max_bytes: usize) -> Result<UploadForm, ChatCompletionError> {
+    let mut form = UploadForm { fields: HashMap::new(), file: None };
+    let mut fields_bytes = 0;
+    let invalid = |e: actix_multipart::MultipartError| {
+        ChatCompletionError::RequestConstructionError(format!("Invalid multipart body: {}", e))
+    };
+
+    while let Some(mut field) = payload.try_next().await.map_err(invalid)? {
+        let Some(name) = field.name().map(str::to_string) else { continue };
+        let is_file = name == "file";
+        if is_file && form.file.is_some() {
+            return Err(ChatCompletionError::RequestConstructionError("Only one 'file' part is allowed".to_string()));
+        }
+        let filename = field.content_disposition().and_then(|cd| cd.get_filename()).map(str::to_string);
+        let content_type = field.content_type().map(|m| m.essence_str().to_string());
+
+        if !is_file {
+            fields_bytes += name.len();
+        }
+        let mut data = BytesMut::new();
+        while let Some(chunk) = field.try_next().await.map_err(invalid)? {
+            if is_file && data.len() + chunk.len() > max_bytes {
+                return Err(too_large("'file'", max_bytes));
+            }
+            if !is_file && fields_bytes + data.len() + chunk.len() > MAX_FIELDS_BYTES {
+                return Err(too_large("The form fields", MAX_FIELDS_BYTES));
+            }
+            data.extend_from_slice(&chunk);
+        }
+
+        if is_file {
+            form.file = Some(UploadedFile {
+                filename: filename.unwrap_or_else(|| "file".to_string()),
+                content_type,
+                data: data.freeze(),
+            });
+        } else {
+            fields_bytes += data.len();
+            form.fields.insert(name, String::from_utf8_lossy(&data).into_owned());
+        }
+    }
+
+    Ok(form)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+    use futures_util::stream;
+
+    fn multipart(body: &str) -> Multipart {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("multipart/form-data; boundary=XX"));
+        let body = Bytes::from(body.replace('\n', "\r\n"));
+        Multipart::new(&headers, stream::iter([Ok::<_, actix_web::error::PayloadError>(body)]))
+    }
+
+    const FORM: &str = "--XX
+Content-Disposition: form-data; name=\"purpose\"
+
+assistants
+--XX
+Content-Disposition: form-data; name=\"file\"; filename=\"notes.txt\"
+Content-Type: text/plain
+
+hello
+--XX--
+";
+
+    #[actix_web::test]
+    async fn fields_and_the_file_are_read() {
+        let form = read_upload_form(multipart(FORM), 1024).await.unwrap();
+        assert_eq!(form.field("purpose"), Some("assistants"));
+        assert_eq!(form.field("missing"), None);
+
+        let file = form.file.unwrap();
+        assert_eq!(file.filename, "notes.txt");
+        assert_eq!(file.content_type.as_deref(), Some("text/plain"));
+        assert_eq!(file.data, "hello");
+    }
+
+    #[actix_web::test]
+    async fn oversized_parts_are_rejected() {
+        let result = read_upload_form(multipart(FORM), 4).await;
+        assert!(matches!(result, Err(ChatCompletionError::DifyApiError(StatusCode::PAYLOAD_TOO_LARGE, _))));
+    }
+
+    #[actix_web::test]
+    async fn a_second_file_is_rejected() {
+        let form = FORM.replace("--XX--", "--XX\nContent-Disposition: form-data; name=\"file\"; filename=\"b.txt\"\n\nmore\n--XX--");
+        let result = read_upload_form(multipart(&form), 1024).await;
+        assert!(matches!(result, Err(ChatCompletionError::RequestConstructionError(_))));
+    }
+
+    #[actix_web::test]
+    async fn fields_count_towards_one_total() {
+        // Each field is small; together they are over the limit
+        let value = "x".repeat(MAX_FIELDS_BYTES / 4);
+        let extra: String = (0..4)
+            .map(|i| format!("--XX\nContent-Disposition: form-data; name=\"extra{}\"\n\n{}\n", i, value))
+            .collect();
+        let result = read_upload_form(multipart(&(extra + FORM)), 1024).await;
+        assert!(matches!(result, Err(ChatCompletionError::DifyApiError(StatusCode::PAYLOAD_TOO_LARGE, message))
+            if message.contains("form fields")));
+    }
+}
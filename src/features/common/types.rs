@@ -10,6 +10,18 @@ pub struct OpenAIMessage {
     pub tool_calls: Option<Vec<ToolCall>>,
 }
 
+impl OpenAIMessage {
+    /// A plain text message.
+    pub fn text(role: &str, text: String) -> Self {
+        OpenAIMessage {
+            role: role.to_string(),
+            content: MessageContent::String(text),
+            function_call: None,
+            tool_calls: None,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 #[serde(untagged)]
 pub enum MessageContent {
@@ -17,10 +29,62 @@ pub enum MessageContent {
     Complex(Vec<ComplexMessageContent>),
 }
 
+impl MessageContent {
+    /// Text of the message, with the text parts of complex content joined by spaces.
+    pub fn to_text(&self) -> String {
+        match self {
+            MessageContent::String(s) => s.clone(),
+            MessageContent::Complex(contents) => {
+                contents.iter()
+                    .filter(|c| c.r#type == "text")
+                    .map(|c| c.text.clone())
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            }
+        }
+    }
+
+    /// Whether the message contains image parts.
+    pub fn has_images(&self) -> bool {
+        match self {
+            MessageContent::String(_) => false,
+            MessageContent::Complex(contents) => contents.iter().any(|c| c.r#type == "image_url"),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct ComplexMessageContent {
     pub r#type: String,
+    #[serde(default)]
     pub text: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_url: Option<ImageUrl>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<FilePart>,
+}
+
+impl ComplexMessageContent {
+    /// A `text` content part.
+    pub fn text(text: String) -> Self {
+        ComplexMessageContent { r#type: "text".to_string(), text, image_url: None, file: None }
+    }
+}
+
+/// A `file` content part referencing an upload from `/v1/files`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct FilePart {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ImageUrl {
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -36,7 +100,7 @@ pub struct ToolCall {
     pub function: FunctionCall,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct OpenAIRequest {
     pub messages: Vec<OpenAIMessage>,
     #[serde(default)]
@@ -53,12 +117,71 @@ pub struct OpenAIRequest {
     pub model: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<StopSequences>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<u32>,
+    // Accepted for compatibility; Dify has no equivalent, see `ParamPolicy`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logit_bias: Option<serde_json::Map<String, serde_json::Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<serde_json::Value>,
+    /// `["text", "audio"]` streams the app's auto-play TTS as `delta.audio`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modalities: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audio: Option<AudioOutput>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Map<String, serde_json::Value>>,
+    /// Proxy extension: fetch Dify's suggested follow-up questions once the
+    /// answer completes and return them in the response `metadata`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suggested_questions: Option<bool>,
+    /// Proxy extension: append a `[n]` marker per cited knowledge base
+    /// segment to the answer, which the annotations then point at.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub citation_markers: Option<bool>,
+    /// Explicit Dify inputs, merged over the configured input mapping.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dify_inputs: Option<serde_json::Map<String, serde_json::Value>>,
+    /// Any other body fields, addressable from `InputSource::Body`.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// The `audio` request parameter. Dify speaks with the app's TTS voice, so
+/// `voice` follows the `unsupported_params` policy.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AudioOutput {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub voice: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+}
+
+impl OpenAIRequest {
+    /// Whether the request asks for audio output.
+    pub fn wants_audio(&self) -> bool {
+        self.modalities.as_ref().is_some_and(|m| m.iter().any(|m| m == "audio"))
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Tool {
     pub r#type: String,
-    pub function: ToolFunction,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub function: Option<ToolFunction>,
+    /// `file_search` tools: the knowledge bases (`datasets` aliases) to search.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vector_store_ids: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_num_results: Option<u32>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -71,25 +194,19 @@ pub struct ToolFunction {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DifyRequest {
     pub inputs: serde_json::Value,
-    pub query: Vec<String>,
+    pub query: String,
     pub response_mode: String,
     pub user: String,
     pub temperature: Option<f32>,
     pub top_p: Option<f32>,
     pub max_tokens: Option<u32>,
     pub tools: Option<Vec<Tool>>,
-}
-
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct DifyResponse {
-    pub event: String,
-    pub task_id: String,
-    pub conversation_id: String,
-    pub message_id: String,
-    pub created_at: u64,
-    pub answer: String,
-    pub tool_calls: Option<Vec<ToolCall>>,
-    pub files: Option<Vec<File>>,
+    /// Dify file references for uploaded files and images (`local_file` or `remote_url`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<serde_json::Value>>,
+    /// Continues an existing Dify conversation, which then supplies the history.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conversation_id: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -107,6 +224,9 @@ pub struct OpenAIResponse {
     pub model: String,
     pub choices: Vec<OpenAIChoice>,
     pub usage: Option<Usage>,
+    /// Proxy extension carrying Dify-specific details (e.g. workflow node progress).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -116,7 +236,7 @@ pub struct OpenAIChoice {
     pub finish_reason: Option<String>,
 }
 
-#[derive(Debug, Serialize, Clone, Deserialize)]
+#[derive(Debug, Serialize, Clone, Deserialize, Default)]
 pub struct OpenAIDelta {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub role: Option<String>,
@@ -126,6 +246,26 @@ pub struct OpenAIDelta {
     pub tool_calls: Option<Vec<ToolCall>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub files: Option<Vec<File>>,
+    /// Set instead of `content` when the answer failed structured output validation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refusal: Option<String>,
+    /// Speech and its transcript when the request asked for audio output.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub audio: Option<AudioDelta>,
+    /// Knowledge base citations from Dify's `retriever_resources`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<Vec<serde_json::Value>>,
+}
+
+#[derive(Debug, Serialize, Clone, Deserialize)]
+pub struct AudioDelta {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// Base64 audio from Dify `tts_message` events.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transcript: Option<String>,
 }
 
 #[derive(Debug, Serialize, Clone, Deserialize)]
@@ -135,14 +275,772 @@ pub struct Usage {
     pub total_tokens: u32,
 }
 
-#[derive(Debug, Serialize, Clone, Deserialize)]
-pub struct DifyEvent {
-    pub event: String,
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum CompletionPrompt {
+    Single(String),
+    Batch(Vec<String>),
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum StopSequences {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl StopSequences {
+    pub fn to_vec(&self) -> Vec<String> {
+        match self {
+            StopSequences::Single(s) => vec![s.clone()],
+            StopSequences::Multiple(v) => v.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct OpenAICompletionRequest {
+    pub model: String,
+    pub prompt: CompletionPrompt,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop: Option<StopSequences>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Map<String, serde_json::Value>>,
+    /// Explicit Dify inputs, merged over the configured input mapping.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dify_inputs: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DifyCompletionRequest {
+    pub inputs: serde_json::Value,
+    pub response_mode: String,
+    pub user: String,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct OpenAICompletionResponse {
+    pub id: String,
+    pub object: String,
+    pub created: u64,
+    pub model: String,
+    pub choices: Vec<CompletionChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct CompletionChoice {
+    pub text: String,
+    pub index: u32,
+    pub logprobs: Option<serde_json::Value>,
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DifyWorkflowRequest {
+    pub inputs: serde_json::Value,
+    pub response_mode: String,
+    pub user: String,
+}
+
+/// Body of the native `/dify/v1/workflows/run` passthrough. `model` selects the
+/// Dify app through the model registry and is not forwarded.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct NativeWorkflowRunRequest {
+    #[serde(default, skip_serializing)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub inputs: serde_json::Value,
+    #[serde(default)]
+    pub response_mode: Option<String>,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files: Option<serde_json::Value>,
+}
+
+/// Typed Dify workflow stream events re-emitted by the native passthrough.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum WorkflowStreamEvent {
+    WorkflowStarted {
+        task_id: String,
+        workflow_run_id: String,
+        data: WorkflowStartedData,
+    },
+    NodeStarted {
+        task_id: String,
+        workflow_run_id: String,
+        data: NodeStartedData,
+    },
+    NodeFinished {
+        task_id: String,
+        workflow_run_id: String,
+        data: NodeFinishedData,
+    },
+    TextChunk {
+        task_id: String,
+        workflow_run_id: String,
+        data: TextChunkData,
+    },
+    WorkflowFinished {
+        task_id: String,
+        workflow_run_id: String,
+        data: WorkflowFinishedData,
+    },
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct WorkflowStartedData {
+    pub id: String,
+    pub workflow_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequence_number: Option<u64>,
+    pub created_at: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct NodeStartedData {
+    pub id: String,
+    pub node_id: String,
+    pub node_type: String,
+    pub title: String,
+    pub index: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub predecessor_node_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inputs: Option<serde_json::Value>,
+    pub created_at: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct NodeFinishedData {
+    pub id: String,
+    pub node_id: String,
+    pub node_type: String,
+    pub title: String,
+    pub index: u32,
+    pub status: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outputs: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub elapsed_time: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub execution_metadata: Option<serde_json::Value>,
+    pub created_at: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TextChunkData {
+    pub text: String,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct WorkflowFinishedData {
+    pub id: String,
+    pub workflow_id: String,
+    pub status: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outputs: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub elapsed_time: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_tokens: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_steps: Option<u64>,
+    pub created_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<u64>,
+}
+
+/// Blocking response of Dify `/workflows/run`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct WorkflowRunResponse {
     pub task_id: String,
-    pub conversation_id: String,
-    pub message_id: String,
+    pub workflow_run_id: String,
+    pub data: WorkflowFinishedData,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum EmbeddingInput {
+    Single(String),
+    Batch(Vec<String>),
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct OpenAIEmbeddingRequest {
+    pub model: String,
+    pub input: EmbeddingInput,
+    /// `float` (default) or `base64`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoding_format: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dimensions: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct OpenAIEmbeddingResponse {
+    pub object: String,
+    pub data: Vec<EmbeddingData>,
+    pub model: String,
+    pub usage: EmbeddingUsage,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct EmbeddingData {
+    pub object: String,
+    pub index: u32,
+    /// A float array, or a base64 string of little-endian `f32`s.
+    pub embedding: serde_json::Value,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct EmbeddingUsage {
+    pub prompt_tokens: u32,
+    pub total_tokens: u32,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct VectorStoreSearchRequest {
+    pub query: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_num_results: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ranking_options: Option<RankingOptions>,
+    /// Attribute filters; Dify has no equivalent, so these are rejected.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filters: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RankingOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score_threshold: Option<f32>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct VectorStoreSearchResponse {
+    pub object: String,
+    pub search_query: String,
+    pub data: Vec<VectorStoreSearchResult>,
+    pub has_more: bool,
+    pub next_page: Option<String>,
+}
+
+/// One retrieved chunk. `file_id` is the Dify document id.
+#[derive(Debug, Serialize, Clone)]
+pub struct VectorStoreSearchResult {
+    pub file_id: String,
+    pub filename: String,
+    pub score: f32,
+    pub attributes: serde_json::Map<String, serde_json::Value>,
+    pub content: Vec<SearchResultContent>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct SearchResultContent {
+    pub r#type: String,
+    pub text: String,
+}
+
+/// An OpenAI file object as returned by `/v1/files`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct FileObject {
+    pub id: String,
+    pub object: String,
+    pub bytes: u64,
+    pub created_at: i64,
+    pub filename: String,
+    pub purpose: String,
+    pub status: String,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct FileListResponse {
+    pub object: String,
+    pub data: Vec<FileObject>,
+    pub has_more: bool,
+    pub first_id: Option<String>,
+    pub last_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct OpenAISpeechRequest {
+    pub model: String,
+    pub input: String,
+    pub voice: String,
+    /// Dify picks the audio format, so anything but `mp3` is unsupported.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speed: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instructions: Option<String>,
+    /// Dify user to synthesize as; defaults to the key's user.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+}
+
+/// Anthropic Messages API request (`POST /v1/messages`).
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AnthropicRequest {
+    pub model: String,
+    pub max_tokens: u32,
+    pub messages: Vec<AnthropicMessage>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system: Option<AnthropicContent>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stop_sequences: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<AnthropicTool>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<serde_json::Value>,
+    /// `user_id` becomes the Dify user; all keys are available to `InputSource::Metadata`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AnthropicMessage {
+    pub role: String,
+    pub content: AnthropicContent,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum AnthropicContent {
+    Text(String),
+    Blocks(Vec<AnthropicContentBlock>),
+}
+
+impl AnthropicContent {
+    /// Text of the content, with text blocks joined by newlines.
+    pub fn to_text(&self) -> String {
+        match self {
+            AnthropicContent::Text(text) => text.clone(),
+            AnthropicContent::Blocks(blocks) => blocks.iter()
+                .filter_map(|block| match block {
+                    AnthropicContentBlock::Text { text } => Some(text.as_str()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AnthropicContentBlock {
+    Text {
+        text: String,
+    },
+    Image {
+        source: AnthropicImageSource,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: serde_json::Value,
+    },
+    ToolResult {
+        tool_use_id: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        content: Option<AnthropicContent>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        is_error: Option<bool>,
+    },
+    /// Block types Dify has no equivalent for (documents, thinking, ...).
+    #[serde(other)]
+    Unsupported,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AnthropicImageSource {
+    Base64 {
+        media_type: String,
+        data: String,
+    },
+    Url {
+        url: String,
+    },
+    #[serde(other)]
+    Unsupported,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AnthropicTool {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    pub input_schema: serde_json::Value,
+}
+
+/// Anthropic Messages API response. Also the `message` of `message_start`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AnthropicResponse {
+    pub id: String,
+    pub r#type: String,
+    pub role: String,
+    pub model: String,
+    pub content: Vec<AnthropicContentBlock>,
+    pub stop_reason: Option<String>,
+    pub stop_sequence: Option<String>,
+    pub usage: AnthropicUsage,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct AnthropicUsage {
+    pub input_tokens: u32,
+    pub output_tokens: u32,
+}
+
+/// Server-sent events of a streamed Anthropic message. The SSE event name is the `type`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AnthropicStreamEvent {
+    MessageStart {
+        message: AnthropicResponse,
+    },
+    ContentBlockStart {
+        index: u32,
+        content_block: AnthropicContentBlock,
+    },
+    ContentBlockDelta {
+        index: u32,
+        delta: AnthropicTextDelta,
+    },
+    ContentBlockStop {
+        index: u32,
+    },
+    MessageDelta {
+        delta: AnthropicMessageDelta,
+        usage: AnthropicUsage,
+    },
+    MessageStop,
+    Error {
+        error: AnthropicErrorBody,
+    },
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AnthropicTextDelta {
+    TextDelta { text: String },
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AnthropicMessageDelta {
+    pub stop_reason: Option<String>,
+    pub stop_sequence: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AnthropicErrorBody {
+    pub r#type: String,
+    pub message: String,
+}
+
+/// OpenAI Responses API request (`POST /v1/responses`).
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ResponsesRequest {
+    pub model: String,
+    pub input: ResponsesInput,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instructions: Option<String>,
+    /// Continues the Dify conversation of an earlier response.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous_response_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_output_tokens: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Map<String, serde_json::Value>>,
+    /// `false` keeps the response out of conversation tracking.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub store: Option<bool>,
+    /// Proxy extension, as on chat completions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub citation_markers: Option<bool>,
+    // Accepted for compatibility; see `ParamPolicy`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<serde_json::Value>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum ResponsesInput {
+    Text(String),
+    Items(Vec<ResponseInputItem>),
+}
+
+/// An input item: a message (the default `type`) or a `function_call_output`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ResponseInputItem {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub r#type: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<ResponseInputContent>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum ResponseInputContent {
+    Text(String),
+    Parts(Vec<ResponseInputPart>),
+}
+
+/// `input_text`, `input_image` or `input_file` content.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ResponseInputPart {
+    pub r#type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_id: Option<String>,
+}
+
+/// OpenAI Responses API response object. The `id` is Dify's `message_id`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ResponseObject {
+    pub id: String,
+    pub object: String,
     pub created_at: u64,
-    pub answer: String,
-    pub tool_calls: Option<Vec<ToolCall>>,
-    pub files: Option<Vec<File>>
-}
\ No newline at end of file
+    pub status: String,
+    pub model: String,
+    pub output: Vec<ResponseOutputItem>,
+    pub instructions: Option<String>,
+    pub previous_response_id: Option<String>,
+    pub usage: Option<ResponseUsage>,
+    pub metadata: serde_json::Map<String, serde_json::Value>,
+    /// Why the response failed; `null` unless `status` is `failed`.
+    #[serde(default)]
+    pub error: Option<ResponseError>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ResponseError {
+    pub code: String,
+    pub message: String,
+}
+
+/// An assistant `message` output item.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ResponseOutputItem {
+    pub r#type: String,
+    pub id: String,
+    pub status: String,
+    pub role: String,
+    pub content: Vec<ResponseOutputText>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ResponseOutputText {
+    pub r#type: String,
+    pub text: String,
+    pub annotations: Vec<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ResponseUsage {
+    pub input_tokens: u32,
+    pub output_tokens: u32,
+    pub total_tokens: u32,
+}
+
+/// Semantic events of a streamed response. The SSE event name is the `type`.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(tag = "type")]
+pub enum ResponseStreamEvent {
+    #[serde(rename = "response.created")]
+    Created { response: ResponseObject },
+    #[serde(rename = "response.in_progress")]
+    InProgress { response: ResponseObject },
+    #[serde(rename = "response.output_item.added")]
+    OutputItemAdded { output_index: u32, item: ResponseOutputItem },
+    #[serde(rename = "response.content_part.added")]
+    ContentPartAdded { item_id: String, output_index: u32, content_index: u32, part: ResponseOutputText },
+    #[serde(rename = "response.output_text.delta")]
+    OutputTextDelta { item_id: String, output_index: u32, content_index: u32, delta: String },
+    #[serde(rename = "response.output_text.annotation.added")]
+    OutputTextAnnotationAdded {
+        item_id: String,
+        output_index: u32,
+        content_index: u32,
+        annotation_index: u32,
+        annotation: serde_json::Value,
+    },
+    #[serde(rename = "response.output_text.done")]
+    OutputTextDone { item_id: String, output_index: u32, content_index: u32, text: String },
+    #[serde(rename = "response.content_part.done")]
+    ContentPartDone { item_id: String, output_index: u32, content_index: u32, part: ResponseOutputText },
+    #[serde(rename = "response.output_item.done")]
+    OutputItemDone { output_index: u32, item: ResponseOutputItem },
+    #[serde(rename = "response.completed")]
+    Completed { response: ResponseObject },
+    #[serde(rename = "response.failed")]
+    Failed { response: ResponseObject },
+}
+
+/// An Assistants API thread, backed by a Dify conversation once it has run.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ThreadObject {
+    pub id: String,
+    pub object: String,
+    pub created_at: i64,
+    pub metadata: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct CreateThreadRequest {
+    #[serde(default)]
+    pub messages: Vec<ThreadMessageRequest>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+/// A message added to a thread. `content` takes the chat completions forms.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ThreadMessageRequest {
+    pub role: String,
+    pub content: MessageContent,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ThreadMessage {
+    pub id: String,
+    pub object: String,
+    pub created_at: i64,
+    pub thread_id: String,
+    pub status: String,
+    pub role: String,
+    pub content: Vec<ThreadContent>,
+    pub assistant_id: Option<String>,
+    pub run_id: Option<String>,
+    pub attachments: Vec<serde_json::Value>,
+    pub metadata: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ThreadContent {
+    Text { text: ThreadText },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ThreadText {
+    pub value: String,
+    pub annotations: Vec<serde_json::Value>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct ThreadMessageList {
+    pub object: String,
+    pub data: Vec<ThreadMessage>,
+    pub first_id: Option<String>,
+    pub last_id: Option<String>,
+    pub has_more: bool,
+}
+
+/// A run of a thread. `assistant_id` names the model alias of the Dify chat app.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct CreateRunRequest {
+    pub assistant_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub instructions: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub additional_instructions: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub additional_messages: Option<Vec<ThreadMessageRequest>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_completion_tokens: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Map<String, serde_json::Value>>,
+    // Accepted for compatibility; see `ParamPolicy`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<serde_json::Value>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<serde_json::Value>,
+}
+
+/// A thread run. Its `id` is the Dify `message_id` of the answer.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RunObject {
+    pub id: String,
+    pub object: String,
+    pub created_at: i64,
+    pub thread_id: String,
+    pub assistant_id: String,
+    pub status: String,
+    pub model: String,
+    pub instructions: Option<String>,
+    pub started_at: Option<i64>,
+    pub completed_at: Option<i64>,
+    pub failed_at: Option<i64>,
+    pub last_error: Option<RunError>,
+    pub usage: Option<Usage>,
+    pub metadata: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RunError {
+    pub code: String,
+    pub message: String,
+}
@@ -0,0 +1,284 @@
+use actix_cors::Cors;
+use actix_web::http::header::HeaderName;
+use actix_web::http::Method;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use super::proxy_config::ConfigError;
+
+/// Rate limit headers matching OpenAI's, expanded from the `x-ratelimit-*` shorthand.
+const RATELIMIT_HEADERS: [&str; 6] = [
+    "x-ratelimit-limit-requests",
+    "x-ratelimit-limit-tokens",
+    "x-ratelimit-remaining-requests",
+    "x-ratelimit-remaining-tokens",
+    "x-ratelimit-reset-requests",
+    "x-ratelimit-reset-tokens",
+];
+
+/// Route scopes that get their own CORS middleware, and so can be overridden.
+pub const CORS_SCOPES: [&str; 3] = ["/v1", "/dify/v1", "/admin"];
+
+/// CORS settings: a default policy plus optional overrides per route scope
+/// (e.g. `"/admin"`).
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct CorsConfig {
+    #[serde(flatten)]
+    pub default: CorsPolicy,
+    #[serde(default)]
+    pub scopes: HashMap<String, CorsPolicy>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(default)]
+pub struct CorsPolicy {
+    /// Exact origins (`https://app.example.com`), wildcard patterns
+    /// (`https://*.example.com`) or `"*"` for any origin.
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    /// Allowed request headers, or `["*"]` for any.
+    pub allowed_headers: Vec<String>,
+    /// Response headers readable by the browser. `x-ratelimit-*` expands to the
+    /// OpenAI rate limit headers.
+    pub exposed_headers: Vec<String>,
+    /// Not allowed together with a `"*"` origin, which would let any site
+    /// make credentialed requests.
+    pub allow_credentials: bool,
+    pub max_age: Option<usize>,
+}
+
+impl Default for CorsPolicy {
+    fn default() -> Self {
+        CorsPolicy {
+            allowed_origins: vec!["*".to_string()],
+            allowed_methods: vec!["GET", "POST", "DELETE", "OPTIONS"]
+                .into_iter().map(String::from).collect(),
+            allowed_headers: vec!["authorization", "content-type", "accept", "openai-organization", "openai-beta", "x-proxy-cache",
+                "x-api-key", "anthropic-version", "anthropic-beta"]
+                .into_iter().map(String::from).collect(),
+            exposed_headers: vec!["x-ratelimit-*", "x-request-id", "x-cache", "x-cache-similarity", "x-ignored-params"]
+                .into_iter().map(String::from).collect(),
+            allow_credentials: false,
+            max_age: Some(3600),
+        }
+    }
+}
+
+impl CorsConfig {
+    /// Policy for a route scope, falling back to the default policy.
+    pub fn policy_for(&self, scope: &str) -> &CorsPolicy {
+        self.scopes.get(scope).unwrap_or(&self.default)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        self.default.validate("default")?;
+        for (scope, policy) in &self.scopes {
+            if !CORS_SCOPES.contains(&scope.as_str()) {
+                return Err(ConfigError::Invalid(format!(
+                    "Unknown CORS scope '{}'; expected one of {}",
+                    scope,
+                    CORS_SCOPES.join(", ")
+                )));
+            }
+            policy.validate(scope)?;
+        }
+        Ok(())
+    }
+}
+
+impl CorsPolicy {
+    fn validate(&self, scope: &str) -> Result<(), ConfigError> {
+        for method in &self.allowed_methods {
+            Method::from_bytes(method.as_bytes())
+                .map_err(|_| ConfigError::Invalid(format!("CORS ({}): invalid method '{}'", scope, method)))?;
+        }
+        for header in self.allowed_headers.iter().filter(|h| h.as_str() != "*") {
+            HeaderName::try_from(header.as_str())
+                .map_err(|_| ConfigError::Invalid(format!("CORS ({}): invalid header '{}'", scope, header)))?;
+        }
+        for header in self.expanded_exposed_headers() {
+            HeaderName::try_from(header.as_str())
+                .map_err(|_| ConfigError::Invalid(format!("CORS ({}): invalid exposed header '{}'", scope, header)))?;
+        }
+        if self.allow_credentials && self.allowed_origins.iter().any(|o| o == "*") {
+            return Err(ConfigError::Invalid(format!(
+                "CORS ({}): allow_credentials cannot be combined with the \"*\" origin; list the allowed origins",
+                scope
+            )));
+        }
+        for origin in self.allowed_origins.iter().filter(|o| o.as_str() != "*") {
+            // A pattern must be a valid origin once its wildcards are filled in
+            if !valid_origin(&origin.replace('*', "x")) {
+                return Err(ConfigError::Invalid(format!("CORS ({}): invalid origin '{}'", scope, origin)));
+            }
+        }
+        Ok(())
+    }
+
+    fn expanded_exposed_headers(&self) -> Vec<String> {
+        self.exposed_headers.iter()
+            .flat_map(|h| {
+                if h.eq_ignore_ascii_case("x-ratelimit-*") {
+                    RATELIMIT_HEADERS.iter().map(|s| s.to_string()).collect()
+                } else {
+                    vec![h.to_lowercase()]
+                }
+            })
+            .collect()
+    }
+
+    /// Builds the actix CORS middleware for this policy. The policy must have
+    /// passed validation.
+    pub fn build(&self) -> Cors {
+        let mut cors = Cors::default();
+
+        let mut patterns = Vec::new();
+        for origin in &self.allowed_origins {
+            if origin == "*" {
+                cors = cors.allow_any_origin();
+            } else if origin.contains('*') {
+                patterns.push(origin.to_lowercase());
+            } else {
+                cors = cors.allowed_origin(origin);
+            }
+        }
+        if !patterns.is_empty() {
+            cors = cors.allowed_origin_fn(move |origin, _req_head| {
+                origin.to_str()
+                    .map(|origin| patterns.iter().any(|p| wildcard_match(p, &origin.to_lowercase())))
+                    .unwrap_or(false)
+            });
+        }
+
+        cors = cors.allowed_methods(self.allowed_methods.iter().map(|m| m.as_str()));
+
+        if self.allowed_headers.iter().any(|h| h == "*") {
+            cors = cors.allow_any_header();
+        } else {
+            cors = cors.allowed_headers(self.allowed_headers.iter().map(|h| h.as_str()));
+        }
+
+        let exposed = self.expanded_exposed_headers();
+        if !exposed.is_empty() {
+            cors = cors.expose_headers(exposed.iter().map(|h| h.as_str()));
+        }
+
+        if self.allow_credentials {
+            cors = cors.supports_credentials();
+        }
+
+        cors.max_age(self.max_age)
+    }
+}
+
+/// Whether `origin` is a serialized origin: `scheme://host[:port]`, without
+/// path, query or credentials.
+fn valid_origin(origin: &str) -> bool {
+    let Some((scheme, authority)) = origin.split_once("://") else { return false };
+    let scheme_ok = scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        && scheme.chars().all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c));
+    // IPv6 hosts are bracketed and contain colons themselves
+    let split = match authority.find(']') {
+        Some(end) if authority.starts_with('[') => Some((&authority[..=end], &authority[end + 1..])),
+        Some(_) => None,
+        None => Some(authority.split_at(authority.find(':').unwrap_or(authority.len()))),
+    };
+    let Some((host, port)) = split else { return false };
+    let host_ok = !host.is_empty()
+        && host.chars().all(|c| c.is_ascii_alphanumeric() || "-.[]:".contains(c));
+    let port_ok = match port.strip_prefix(':') {
+        Some(port) => !port.is_empty() && port.len() <= 5 && port.chars().all(|c| c.is_ascii_digit()),
+        None => port.is_empty(),
+    };
+    scheme_ok && host_ok && port_ok
+}
+
+/// Matches `value` against a pattern where `*` matches any run of characters.
+fn wildcard_match(pattern: &str, value: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if !value.starts_with(first) || value.len() < first.len() + last.len() || !value.ends_with(last) {
+        return false;
+    }
+
+    let mut rest = &value[first.len()..value.len() - last.len()];
+    for part in &parts[1..parts.len() - 1] {
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcard_matches_subdomains() {
+        assert!(wildcard_match("https://*.example.com", "https://app.example.com"));
+        assert!(wildcard_match("https://*.example.com", "https://a.b.example.com"));
+        assert!(!wildcard_match("https://*.example.com", "https://example.com"));
+        assert!(!wildcard_match("https://*.example.com", "https://app.example.com.evil.io"));
+        assert!(!wildcard_match("https://*.example.com", "http://app.example.com"));
+    }
+
+    #[test]
+    fn wildcard_matches_several_stars() {
+        assert!(wildcard_match("https://*.example.com:*", "https://app.example.com:8080"));
+        assert!(wildcard_match("*", "anything"));
+        assert!(!wildcard_match("https://*-dev.*.io", "https://app.example.io"));
+        assert!(wildcard_match("https://*-dev.*.io", "https://app-dev.example.io"));
+    }
+
+    #[test]
+    fn wildcard_does_not_overlap_prefix_and_suffix() {
+        assert!(!wildcard_match("ab*ba", "aba"));
+        assert!(wildcard_match("ab*ba", "abba"));
+    }
+
+    #[test]
+    fn origins_are_validated() {
+        assert!(valid_origin("https://app.example.com"));
+        assert!(valid_origin("http://localhost:3000"));
+        assert!(valid_origin("http://[::1]:8080"));
+        assert!(valid_origin("chrome-extension://abcdef"));
+        assert!(!valid_origin("app.example.com"));
+        assert!(!valid_origin("https://app.example.com/path"));
+        assert!(!valid_origin("https://user@app.example.com"));
+        assert!(!valid_origin("https://app.example.com:port"));
+        assert!(!valid_origin("https://"));
+        assert!(!valid_origin("not an origin"));
+    }
+
+    #[test]
+    fn unknown_scopes_are_rejected() {
+        let mut config = CorsConfig::default();
+        config.scopes.insert("/admin".to_string(), CorsPolicy::default());
+        assert!(config.validate().is_ok());
+        config.scopes.insert("/adminn".to_string(), CorsPolicy::default());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn wildcard_origins_are_validated_as_origins() {
+        let policy = |origin: &str| CorsPolicy { allowed_origins: vec![origin.to_string()], ..CorsPolicy::default() };
+        assert!(policy("https://*.example.com").validate("default").is_ok());
+        assert!(policy("*").validate("default").is_ok());
+        assert!(policy("*.example.com").validate("default").is_err());
+        assert!(policy("https://*.example.com/app").validate("default").is_err());
+    }
+
+    #[test]
+    fn credentials_need_explicit_origins() {
+        let policy = |origin: &str| CorsPolicy {
+            allowed_origins: vec![origin.to_string()],
+            allow_credentials: true,
+            ..CorsPolicy::default()
+        };
+        assert!(policy("*").validate("default").is_err());
+        assert!(policy("https://app.example.com").validate("default").is_ok());
+        assert!(policy("https://*.example.com").validate("default").is_ok());
+    }
+}
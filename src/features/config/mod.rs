@@ -0,0 +1,3 @@
+pub mod cors;
+pub mod proxy_config;
+pub mod reload;
@@ -0,0 +1,345 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::cors::CorsConfig;
+
+/// Proxy configuration loaded from the JSON file pointed to by `PROXY_CONFIG`.
+///
+/// When no file is configured the default (empty) config is used and the proxy
+/// behaves as a plain passthrough: the client's bearer token is forwarded to Dify.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct ProxyConfig {
+    /// Keys accepted on the admin endpoints. Admin routes are disabled when empty.
+    #[serde(default)]
+    pub admin_keys: Vec<String>,
+    /// Proxy API keys handed out to clients, keyed by the bearer token.
+    #[serde(default)]
+    pub keys: HashMap<String, KeyConfig>,
+    /// Model registry: OpenAI model alias -> Dify app.
+    #[serde(default)]
+    pub models: HashMap<String, ModelConfig>,
+    /// Embedding model registry for `/v1/embeddings`: alias -> backend.
+    #[serde(default)]
+    pub embedding_models: HashMap<String, EmbeddingsBackendConfig>,
+    /// Dify knowledge bases searchable as vector stores: alias -> dataset.
+    #[serde(default)]
+    pub datasets: HashMap<String, DatasetConfig>,
+    /// Lets callers use `embedding_models` and `datasets` without proxy keys.
+    /// Their upstream keys are held by the server, so passthrough mode refuses
+    /// them unless this is set.
+    #[serde(default)]
+    pub passthrough_registry: bool,
+    /// CORS policy. Applied when the server starts; changes need a restart.
+    #[serde(default)]
+    pub cors: CorsConfig,
+    /// What to do with OpenAI parameters Dify can't honour. Models can override it.
+    #[serde(default)]
+    pub unsupported_params: ParamPolicy,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct KeyConfig {
+    /// Dify `user` sent on behalf of this key when the request doesn't set one.
+    #[serde(default)]
+    pub user: Option<String>,
+    /// Model aliases this key may use. Empty means every registered model.
+    #[serde(default)]
+    pub models: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ModelConfig {
+    /// Dify app API key (`app-...`) used for requests to this model alias.
+    pub app_key: String,
+    #[serde(default)]
+    pub app_type: AppType,
+    /// Input variable that receives the prompt for completion apps.
+    #[serde(default)]
+    pub prompt_variable: Option<String>,
+    /// Input/output mapping for workflow apps.
+    #[serde(default)]
+    pub workflow: Option<WorkflowConfig>,
+    /// Dify input variables filled from the request, keyed by variable name.
+    #[serde(default)]
+    pub inputs: HashMap<String, InputSource>,
+    /// Overrides the global `unsupported_params` policy for this model.
+    #[serde(default)]
+    pub unsupported_params: Option<ParamPolicy>,
+    /// How `response_format` (JSON mode / structured outputs) is enforced.
+    #[serde(default)]
+    pub structured_output: StructuredOutputConfig,
+    /// Serve cached answers to near-duplicate questions.
+    #[serde(default)]
+    pub semantic_cache: Option<SemanticCacheConfig>,
+    /// Where `file_search` tool results are placed in the Dify request.
+    #[serde(default)]
+    pub file_search: FileSearchConfig,
+    /// OpenAI voice name -> Dify voice for `/v1/audio/speech`. When set, only
+    /// these voices are accepted; when empty the voice is forwarded as is.
+    #[serde(default)]
+    pub voices: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct FileSearchConfig {
+    /// Input variable that receives the retrieved excerpts. When unset they
+    /// are appended to the query; workflow models require it.
+    #[serde(default)]
+    pub context_input: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct StructuredOutputConfig {
+    /// Input variable that receives the format instructions. When unset they
+    /// are appended to the query.
+    #[serde(default)]
+    pub instructions_input: Option<String>,
+    /// Retry once, feeding the validation error back, when the answer doesn't validate.
+    #[serde(default)]
+    pub retry: bool,
+    #[serde(default)]
+    pub on_failure: StructuredOutputFailure,
+}
+
+/// What the client gets when the answer still doesn't validate.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum StructuredOutputFailure {
+    /// A choice with `refusal` set and no `content`.
+    #[default]
+    Refusal,
+    /// A 502 error response.
+    Error,
+}
+
+/// Where a Dify input variable takes its value from.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(tag = "from", rename_all = "snake_case")]
+pub enum InputSource {
+    /// A key of the OpenAI `metadata` object.
+    Metadata { key: String },
+    /// A dot-separated path into the request body, e.g. `dify_inputs.topic`.
+    Body { path: String },
+    /// A request header.
+    Header { name: String },
+    /// A template over the messages, e.g. `"{{system}}\n{{last_user_message}}"`.
+    Template { template: String },
+    /// A constant.
+    Value { value: serde_json::Value },
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct WorkflowConfig {
+    /// Workflow input that receives the text of the last user message.
+    #[serde(default = "default_query_input")]
+    pub query_input: String,
+    /// When the last user message is a JSON object, use its fields as workflow
+    /// inputs instead of `query_input`.
+    #[serde(default)]
+    pub json_inputs: bool,
+    /// Renames JSON message fields to workflow input names (`field -> input`).
+    #[serde(default)]
+    pub input_map: HashMap<String, String>,
+    /// Output variable returned as the answer. When unset, the streamed
+    /// `text_chunk` events are the answer.
+    #[serde(default)]
+    pub output_variable: Option<String>,
+    /// Surface node progress events as chunk `metadata`.
+    #[serde(default)]
+    pub node_events: bool,
+}
+
+impl Default for WorkflowConfig {
+    fn default() -> Self {
+        WorkflowConfig {
+            query_input: default_query_input(),
+            json_inputs: false,
+            input_map: HashMap::new(),
+            output_variable: None,
+            node_events: false,
+        }
+    }
+}
+
+fn default_query_input() -> String {
+    "query".to_string()
+}
+
+/// A Dify knowledge base, queried with a dataset API key that never leaves the proxy.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DatasetConfig {
+    /// Dify dataset id (a UUID).
+    pub dataset_id: String,
+    /// Dify knowledge API key (`dataset-...`).
+    pub api_key: String,
+    /// Dify `retrieval_model` used as the base of every search. When unset the
+    /// dataset's own retrieval settings apply unless the request overrides them.
+    #[serde(default)]
+    pub retrieval_model: Option<serde_json::Value>,
+}
+
+/// Where embeddings come from.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(tag = "backend", rename_all = "snake_case")]
+pub enum EmbeddingsBackendConfig {
+    /// An OpenAI-compatible `/embeddings` API, e.g. `https://api.openai.com/v1`.
+    Openai {
+        base_url: String,
+        #[serde(default)]
+        api_key: Option<String>,
+        /// Upstream model name.
+        model: String,
+    },
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct SemanticCacheConfig {
+    /// Backend used to embed the last user message.
+    pub embeddings: EmbeddingsBackendConfig,
+    /// Minimum cosine similarity for a cached answer to be served.
+    #[serde(default = "default_similarity_threshold")]
+    pub threshold: f32,
+    #[serde(default = "default_semantic_ttl_secs")]
+    pub ttl_secs: u64,
+    /// Entries kept per model alias and tenant; the oldest are evicted first.
+    #[serde(default = "default_semantic_max_entries")]
+    pub max_entries: usize,
+    #[serde(default)]
+    pub index: VectorIndexKind,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum VectorIndexKind {
+    /// Exact search over every entry.
+    #[default]
+    Flat,
+    /// Approximate nearest neighbour graph, for large caches.
+    Hnsw,
+}
+
+fn default_similarity_threshold() -> f32 {
+    0.95
+}
+
+fn default_semantic_ttl_secs() -> u64 {
+    3600
+}
+
+fn default_semantic_max_entries() -> usize {
+    1000
+}
+
+/// The kind of Dify app behind a model alias, which decides the Dify endpoint used.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AppType {
+    /// Chatbot, agent or chatflow apps (`/chat-messages`).
+    #[default]
+    Chat,
+    /// Text generator apps (`/completion-messages`).
+    Completion,
+    /// Workflow apps (`/workflows/run`).
+    Workflow,
+}
+
+/// Handling of request parameters the proxy can't map to Dify
+/// (`presence_penalty`, `seed`, `logit_bias`, ...).
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ParamPolicy {
+    /// Ignore them, log a warning and list them in the `x-ignored-params` response header.
+    #[default]
+    Lenient,
+    /// Reject the request with a 400.
+    Strict,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(String),
+    Parse(String),
+    Invalid(String),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(msg) => write!(f, "Failed to read config: {}", msg),
+            Self::Parse(msg) => write!(f, "Failed to parse config: {}", msg),
+            Self::Invalid(msg) => write!(f, "Invalid config: {}", msg),
+        }
+    }
+}
+
+impl ProxyConfig {
+    /// Reads, parses and validates the config file at `path`.
+    pub fn load(path: &Path) -> Result<ProxyConfig, ConfigError> {
+        let raw = std::fs::read_to_string(path)
+            .map_err(|e| ConfigError::Io(format!("{}: {}", path.display(), e)))?;
+        let config: ProxyConfig = serde_json::from_str(&raw)
+            .map_err(|e| ConfigError::Parse(e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        for (alias, model) in &self.models {
+            if model.app_key.trim().is_empty() {
+                return Err(ConfigError::Invalid(format!("model '{}' has an empty app_key", alias)));
+            }
+            if let Some(semantic_cache) = &model.semantic_cache {
+                if !(semantic_cache.threshold > 0.0 && semantic_cache.threshold <= 1.0) {
+                    return Err(ConfigError::Invalid(format!(
+                        "model '{}' has a semantic_cache threshold outside (0, 1]",
+                        alias
+                    )));
+                }
+            }
+        }
+
+        for (alias, dataset) in &self.datasets {
+            if dataset.dataset_id.trim().is_empty() || dataset.api_key.trim().is_empty() {
+                return Err(ConfigError::Invalid(format!("dataset '{}' needs a dataset_id and an api_key", alias)));
+            }
+            if dataset.retrieval_model.as_ref().is_some_and(|r| !r.is_object()) {
+                return Err(ConfigError::Invalid(format!("dataset '{}' has a retrieval_model that isn't an object", alias)));
+            }
+        }
+
+        for (key, key_config) in &self.keys {
+            if key.trim().is_empty() {
+                return Err(ConfigError::Invalid("empty proxy key".to_string()));
+            }
+            for alias in &key_config.models {
+                if !self.models.contains_key(alias)
+                    && !self.embedding_models.contains_key(alias)
+                    && !self.datasets.contains_key(alias)
+                {
+                    return Err(ConfigError::Invalid(format!("key references unknown model '{}'", alias)));
+                }
+            }
+        }
+
+        if !self.keys.is_empty() && self.models.is_empty() && self.embedding_models.is_empty() && self.datasets.is_empty() {
+            return Err(ConfigError::Invalid("proxy keys are configured but the model registry is empty".to_string()));
+        }
+
+        self.cors.validate()?;
+
+        Ok(())
+    }
+
+    /// Whether requests are authenticated with proxy keys instead of raw Dify app keys.
+    pub fn uses_proxy_keys(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    /// The unsupported-parameter policy for a model alias.
+    pub fn param_policy(&self, model: Option<&str>) -> ParamPolicy {
+        model.and_then(|alias| self.models.get(alias))
+            .and_then(|m| m.unsupported_params)
+            .unwrap_or(self.unsupported_params)
+    }
+}
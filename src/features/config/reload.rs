@@ -0,0 +1,246 @@
+use actix_web::web;
+use chrono::Utc;
+use log::{error, info, warn};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use super::proxy_config::ProxyConfig;
+
+/// Outcome of the most recent reload attempt, exposed on the admin API.
+#[derive(Debug, Serialize, Clone)]
+pub struct ReloadStatus {
+    pub success: bool,
+    pub trigger: String,
+    pub timestamp: i64,
+    pub message: String,
+}
+
+/// Shared, atomically swappable config.
+///
+/// Handlers take a `snapshot()` at the start of a request and keep using it until
+/// the response (including a stream) is finished, so a reload never changes the
+/// config underneath an in-flight request.
+pub struct ConfigHandle {
+    path: Option<PathBuf>,
+    current: RwLock<Arc<ProxyConfig>>,
+    last_reload: RwLock<Option<ReloadStatus>>,
+    last_modified: RwLock<Option<SystemTime>>,
+}
+
+impl ConfigHandle {
+    pub fn new(path: Option<PathBuf>, config: ProxyConfig) -> Self {
+        let last_modified = path.as_deref().and_then(modified_time);
+        ConfigHandle {
+            path,
+            current: RwLock::new(Arc::new(config)),
+            last_reload: RwLock::new(None),
+            last_modified: RwLock::new(last_modified),
+        }
+    }
+
+    pub fn snapshot(&self) -> Arc<ProxyConfig> {
+        self.current.read().unwrap().clone()
+    }
+
+    pub fn last_reload(&self) -> Option<ReloadStatus> {
+        self.last_reload.read().unwrap().clone()
+    }
+
+    /// Re-reads and validates the config file, swapping it in only if it is
+    /// valid. It reads the file, so async callers use `reload_blocking`.
+    pub fn reload(&self, trigger: &str) -> ReloadStatus {
+        let status = match &self.path {
+            None => ReloadStatus {
+                success: false,
+                trigger: trigger.to_string(),
+                timestamp: Utc::now().timestamp(),
+                message: "No config file configured (PROXY_CONFIG is not set)".to_string(),
+            },
+            Some(path) => {
+                // Read before loading, so a write during the load is picked up next time
+                let modified = modified_time(path);
+                match ProxyConfig::load(path) {
+                    Ok(config) => {
+                        let mut message = format!(
+                            "Loaded {} model(s) and {} key(s) from {}",
+                            config.models.len(),
+                            config.keys.len(),
+                            path.display()
+                        );
+                        // The CORS middleware is built once when the server starts
+                        if config.cors != self.snapshot().cors {
+                            warn!("Config reload ({}): CORS changes take effect after a restart", trigger);
+                            message.push_str("; CORS changes take effect after a restart");
+                        }
+                        *self.current.write().unwrap() = Arc::new(config);
+                        *self.last_modified.write().unwrap() = modified;
+                        ReloadStatus {
+                            success: true,
+                            trigger: trigger.to_string(),
+                            timestamp: Utc::now().timestamp(),
+                            message,
+                        }
+                    }
+                    Err(e) => ReloadStatus {
+                        success: false,
+                        trigger: trigger.to_string(),
+                        timestamp: Utc::now().timestamp(),
+                        message: format!("{}; keeping previous config", e),
+                    },
+                }
+            }
+        };
+
+        if status.success {
+            info!("Config reload ({}): {}", status.trigger, status.message);
+        } else {
+            error!("Config reload ({}) failed: {}", status.trigger, status.message);
+        }
+
+        *self.last_reload.write().unwrap() = Some(status.clone());
+        status
+    }
+
+    /// `reload` on the blocking thread pool.
+    pub async fn reload_blocking(self: &Arc<Self>, trigger: &'static str) -> ReloadStatus {
+        let handle = self.clone();
+        web::block(move || handle.reload(trigger)).await.unwrap_or_else(|e| ReloadStatus {
+            success: false,
+            trigger: trigger.to_string(),
+            timestamp: Utc::now().timestamp(),
+            message: format!("Reload did not run: {}; keeping previous config", e),
+        })
+    }
+
+    /// Returns true if the config file changed on disk since the last load.
+    fn file_changed(&self) -> bool {
+        let Some(path) = &self.path else { return false };
+        let modified = modified_time(path);
+        modified.is_some() && modified != *self.last_modified.read().unwrap()
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Spawns the file watcher and, on unix, the SIGHUP handler.
+pub fn spawn_reload_watchers(handle: Arc<ConfigHandle>, poll_interval: Duration) {
+    let watcher = handle.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = tokio::time::interval(poll_interval);
+        loop {
+            interval.tick().await;
+            let changed = watcher.clone();
+            if web::block(move || changed.file_changed()).await.unwrap_or(false) {
+                watcher.reload_blocking("file_change").await;
+            }
+        }
+    });
+
+    #[cfg(unix)]
+    actix_web::rt::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Failed to install SIGHUP handler: {}", e);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            handle.reload_blocking("sighup").await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config_file(contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("proxy-config-{}.json", uuid::Uuid::new_v4().simple()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn a_valid_file_is_swapped_in() {
+        let path = config_file(r#"{"models": {"m": {"app_key": "app-1"}}}"#);
+        let handle = ConfigHandle::new(Some(path.clone()), ProxyConfig::default());
+        let before = handle.snapshot();
+
+        let status = handle.reload("test");
+        assert!(status.success, "{}", status.message);
+        assert!(handle.snapshot().models.contains_key("m"));
+        // Snapshots taken earlier keep the old config
+        assert!(before.models.is_empty());
+        assert_eq!(handle.last_reload().unwrap().trigger, "test");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn an_invalid_file_keeps_the_previous_config() {
+        let path = config_file(r#"{"models": {"m": {"app_key": "app-1"}}}"#);
+        let handle = ConfigHandle::new(Some(path.clone()), ProxyConfig::load(&path).unwrap());
+
+        std::fs::write(&path, r#"{"models": {"m": {"app_key": " "}}}"#).unwrap();
+        assert!(!handle.reload("test").success);
+        assert_eq!(handle.snapshot().models["m"].app_key, "app-1");
+
+        std::fs::write(&path, "not json").unwrap();
+        assert!(!handle.reload("test").success);
+        assert_eq!(handle.snapshot().models["m"].app_key, "app-1");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn failed_loads_are_retried_on_the_next_check() {
+        let path = config_file("not json");
+        let handle = ConfigHandle::new(Some(path.clone()), ProxyConfig::default());
+        std::fs::File::options().write(true).open(&path).unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+        assert!(handle.file_changed());
+
+        assert!(!handle.reload("file_change").success);
+        assert!(handle.file_changed(), "a failed load must not mark the change as seen");
+
+        std::fs::write(&path, r#"{"models": {"m": {"app_key": "app-1"}}}"#).unwrap();
+        assert!(handle.reload("file_change").success);
+        assert!(!handle.file_changed());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn blocking_reloads_swap_in_the_file() {
+        let path = config_file(r#"{"models": {"m": {"app_key": "app-1"}}}"#);
+        let handle = Arc::new(ConfigHandle::new(Some(path.clone()), ProxyConfig::default()));
+        assert!(handle.reload_blocking("admin").await.success);
+        assert!(handle.snapshot().models.contains_key("m"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn cors_changes_are_reported_as_needing_a_restart() {
+        let path = config_file(r#"{"models": {"m": {"app_key": "app-1"}}}"#);
+        let handle = ConfigHandle::new(Some(path.clone()), ProxyConfig::load(&path).unwrap());
+        let status = handle.reload("test");
+        assert!(status.success && !status.message.contains("CORS"), "{}", status.message);
+
+        std::fs::write(&path, r#"{"models": {"m": {"app_key": "app-1"}}, "cors": {"allowed_origins": ["https://app.example.com"]}}"#).unwrap();
+        let status = handle.reload("test");
+        assert!(status.success, "{}", status.message);
+        assert!(status.message.contains("CORS changes take effect after a restart"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn without_a_file_reloads_fail() {
+        let handle = ConfigHandle::new(None, ProxyConfig::default());
+        assert!(!handle.reload("sighup").success);
+        assert!(!handle.file_changed());
+    }
+}
@@ -0,0 +1,177 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::features::app::app_state::AppState;
+use crate::features::auth::resolver::{resolve_target, DifyTarget};
+use crate::features::dify::handlers::openai::chat_completion::{
+    build_client, post_to_dify, read_dify_json, send_to_dify, ChatCompletionError,
+};
+
+/// Orders Dify accepts for `sort_by`.
+const SORT_ORDERS: &[&str] = &["created_at", "-created_at", "updated_at", "-updated_at"];
+
+/// Dify's page size limit for conversations.
+const MAX_LIST_LIMIT: u32 = 100;
+
+/// `model` selects the Dify app; `user` the Dify user, for keys without one.
+#[derive(Debug, Deserialize)]
+pub struct ConversationQuery {
+    pub model: Option<String>,
+    pub user: Option<String>,
+    pub limit: Option<u32>,
+    pub last_id: Option<String>,
+    pub sort_by: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RenameConversationRequest {
+    pub model: Option<String>,
+    pub user: Option<String>,
+    pub name: Option<String>,
+    #[serde(default)]
+    pub auto_generate: bool,
+}
+
+/// Dify conversation ids are UUIDs; anything else is refused before it reaches a URL.
+fn checked_id(id: &str) -> Result<&str, ChatCompletionError> {
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return Err(ChatCompletionError::DifyApiError(
+            reqwest::StatusCode::NOT_FOUND,
+            format!("No conversation found with id '{}'.", id),
+        ));
+    }
+    Ok(id)
+}
+
+/// The Dify user to act as. A proxy key with a `user` always acts as that
+/// user, so its callers can't reach other users' conversations; a different
+/// `user` in the request is refused. Otherwise the request's `user` applies.
+fn dify_user(user: Option<String>, target: &DifyTarget) -> Result<String, ChatCompletionError> {
+    match (&target.default_user, user) {
+        (Some(pinned), Some(user)) if *pinned != user => Err(ChatCompletionError::DifyApiError(
+            reqwest::StatusCode::FORBIDDEN,
+            "This key's conversations belong to its configured user; 'user' can't be changed".to_string(),
+        )),
+        (Some(pinned), _) => Ok(pinned.clone()),
+        (None, user) => Ok(user.unwrap_or_else(|| "default_user".to_string())),
+    }
+}
+
+/// Lists the user's conversations with the app behind `model`, from Dify `/conversations`.
+pub async fn list_conversations(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    query: web::Query<ConversationQuery>,
+) -> Result<HttpResponse, ChatCompletionError> {
+    let query = query.into_inner();
+    let target = resolve_target(&req, &state.config.snapshot(), query.model.as_deref())?;
+    let limit = query.limit.unwrap_or(20);
+    if limit == 0 || limit > MAX_LIST_LIMIT {
+        return Err(ChatCompletionError::RequestConstructionError(format!(
+            "'limit' must be between 1 and {}",
+            MAX_LIST_LIMIT
+        )));
+    }
+    if let Some(sort_by) = query.sort_by.as_deref().filter(|s| !SORT_ORDERS.contains(s)) {
+        return Err(ChatCompletionError::RequestConstructionError(format!(
+            "Invalid sort_by '{}'; expected one of {}",
+            sort_by,
+            SORT_ORDERS.join(", ")
+        )));
+    }
+
+    let limit = limit.to_string();
+    let mut params = vec![("user", dify_user(query.user, &target)?), ("limit", limit)];
+    if let Some(last_id) = query.last_id {
+        params.push(("last_id", last_id));
+    }
+    if let Some(sort_by) = query.sort_by {
+        params.push(("sort_by", sort_by));
+    }
+    let client = build_client()?;
+    let url = format!("{}/v1/conversations", state.dify_api_url);
+    let response = send_to_dify(client.get(&url).query(&params), &target.api_key).await?;
+    Ok(HttpResponse::Ok().json(read_dify_json(response).await?))
+}
+
+/// Renames a conversation, or has Dify generate a name with `auto_generate`.
+pub async fn rename_conversation(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    body: web::Json<RenameConversationRequest>,
+) -> Result<HttpResponse, ChatCompletionError> {
+    let body = body.into_inner();
+    let target = resolve_target(&req, &state.config.snapshot(), body.model.as_deref())?;
+    if body.name.as_deref().unwrap_or_default().trim().is_empty() && !body.auto_generate {
+        return Err(ChatCompletionError::RequestConstructionError(
+            "'name' is required unless 'auto_generate' is true".to_string(),
+        ));
+    }
+
+    let client = build_client()?;
+    let url = format!("{}/v1/conversations/{}/name", state.dify_api_url, checked_id(&path)?);
+    let payload = json!({
+        "name": body.name,
+        "auto_generate": body.auto_generate,
+        "user": dify_user(body.user, &target)?,
+    });
+    let response = post_to_dify(&client, &url, &target.api_key, &payload).await?;
+    Ok(HttpResponse::Ok().json(read_dify_json(response).await?))
+}
+
+/// Deletes a conversation and its messages in Dify.
+pub async fn delete_conversation(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<ConversationQuery>,
+) -> Result<HttpResponse, ChatCompletionError> {
+    let query = query.into_inner();
+    let target = resolve_target(&req, &state.config.snapshot(), query.model.as_deref())?;
+    let id = checked_id(&path)?;
+
+    let client = build_client()?;
+    let url = format!("{}/v1/conversations/{}", state.dify_api_url, id);
+    let request = client.delete(&url).json(&json!({ "user": dify_user(query.user, &target)? }));
+    send_to_dify(request, &target.api_key).await?;
+    Ok(HttpResponse::Ok().json(json!({
+        "id": id,
+        "object": "conversation.deleted",
+        "deleted": true,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(user: Option<&str>) -> DifyTarget {
+        DifyTarget { api_key: "app-key".to_string(), model_config: None, default_user: user.map(String::from) }
+    }
+
+    #[test]
+    fn a_key_with_a_user_is_pinned_to_it() {
+        let pinned = target(Some("alice"));
+        assert_eq!(dify_user(None, &pinned).unwrap(), "alice");
+        assert_eq!(dify_user(Some("alice".to_string()), &pinned).unwrap(), "alice");
+        assert!(matches!(
+            dify_user(Some("bob".to_string()), &pinned),
+            Err(ChatCompletionError::DifyApiError(reqwest::StatusCode::FORBIDDEN, _))
+        ));
+    }
+
+    #[test]
+    fn a_key_without_a_user_takes_the_requested_one() {
+        assert_eq!(dify_user(Some("bob".to_string()), &target(None)).unwrap(), "bob");
+        assert_eq!(dify_user(None, &target(None)).unwrap(), "default_user");
+    }
+
+    #[test]
+    fn conversation_ids_must_be_uuid_like() {
+        assert!(checked_id("3f1c0a2e-0000-4000-8000-000000000000").is_ok());
+        assert!(checked_id("").is_err());
+        assert!(checked_id("../apps").is_err());
+    }
+}
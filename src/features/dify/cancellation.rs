@@ -0,0 +1,227 @@
+use actix_web::HttpRequest;
+use futures_util::{future, StreamExt};
+use log::{info, warn};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::features::app::app_state::AppState;
+use crate::features::auth::resolver::extract_api_key;
+use crate::features::dify::handlers::openai::chat_completion::{
+    build_client, post_to_dify, ChatCompletionError, DifyEventStream,
+};
+
+/// Which Dify stop endpoint a task belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskKind {
+    Chat,
+    Completion,
+    Workflow,
+}
+
+impl TaskKind {
+    fn stop_path(&self, task_id: &str) -> String {
+        match self {
+            TaskKind::Chat => format!("/v1/chat-messages/{}/stop", task_id),
+            TaskKind::Completion => format!("/v1/completion-messages/{}/stop", task_id),
+            TaskKind::Workflow => format!("/v1/workflows/tasks/{}/stop", task_id),
+        }
+    }
+}
+
+/// A running Dify generation that can be stopped.
+#[derive(Debug, Clone)]
+pub struct StreamTask {
+    pub task_id: String,
+    pub kind: TaskKind,
+    pub api_key: String,
+    pub user: String,
+    /// Bearer token of the client that started the stream.
+    pub owner: String,
+}
+
+/// Streams in flight, keyed by the completion id the client sees. An id can
+/// cover several Dify tasks (e.g. `n > 1`).
+#[derive(Default)]
+pub struct ActiveStreams {
+    tasks: Mutex<HashMap<String, Vec<StreamTask>>>,
+}
+
+impl ActiveStreams {
+    fn register(&self, completion_id: &str, task: StreamTask) {
+        self.tasks.lock().unwrap()
+            .entry(completion_id.to_string())
+            .or_default()
+            .push(task);
+    }
+
+    fn unregister(&self, completion_id: &str, task_id: &str) {
+        let mut tasks = self.tasks.lock().unwrap();
+        if let Some(entries) = tasks.get_mut(completion_id) {
+            entries.retain(|t| t.task_id != task_id);
+            if entries.is_empty() {
+                tasks.remove(completion_id);
+            }
+        }
+    }
+
+    /// Removes and returns the tasks of a completion started by `owner`.
+    pub fn take(&self, completion_id: &str, owner: &str) -> Option<Vec<StreamTask>> {
+        let mut tasks = self.tasks.lock().unwrap();
+        if tasks.get(completion_id)?.iter().any(|t| t.owner != owner) {
+            return None;
+        }
+        tasks.remove(completion_id)
+    }
+}
+
+/// Everything needed to stop the Dify task behind a stream.
+#[derive(Clone)]
+pub struct CancelContext {
+    pub registry: Arc<ActiveStreams>,
+    pub dify_api_url: String,
+    pub api_key: String,
+    pub user: String,
+    pub owner: String,
+    pub kind: TaskKind,
+    /// Id the client sees, when it isn't Dify's `message_id`/`workflow_run_id`.
+    pub completion_id: Option<String>,
+}
+
+impl CancelContext {
+    pub fn new(state: &AppState, req: &HttpRequest, api_key: &str, user: &str, kind: TaskKind) -> Self {
+        CancelContext {
+            registry: state.streams.clone(),
+            dify_api_url: state.dify_api_url.clone(),
+            api_key: api_key.to_string(),
+            user: user.to_string(),
+            owner: extract_api_key(req).unwrap_or_default(),
+            kind,
+            completion_id: None,
+        }
+    }
+}
+
+/// Tracks one stream's task and stops it if the stream is dropped early.
+struct StreamGuard {
+    ctx: CancelContext,
+    task: Option<(String, StreamTask)>,
+    finished: bool,
+}
+
+impl StreamGuard {
+    fn observe(&mut self, event: &Value) {
+        if self.task.is_none() {
+            if let Some(task_id) = event["task_id"].as_str() {
+                let completion_id = self.ctx.completion_id.clone()
+                    .or_else(|| event["message_id"].as_str().map(String::from))
+                    .or_else(|| event["workflow_run_id"].as_str().map(String::from))
+                    .unwrap_or_else(|| task_id.to_string());
+                let task = StreamTask {
+                    task_id: task_id.to_string(),
+                    kind: self.ctx.kind,
+                    api_key: self.ctx.api_key.clone(),
+                    user: self.ctx.user.clone(),
+                    owner: self.ctx.owner.clone(),
+                };
+                self.ctx.registry.register(&completion_id, task.clone());
+                self.task = Some((completion_id, task));
+            }
+        }
+
+        if matches!(event["event"].as_str(), Some("message_end") | Some("workflow_finished") | Some("error")) {
+            self.finished = true;
+        }
+    }
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        let Some((completion_id, task)) = self.task.take() else { return };
+        self.ctx.registry.unregister(&completion_id, &task.task_id);
+
+        if !self.finished {
+            info!("Client disconnected from {}, stopping Dify task {}", completion_id, task.task_id);
+            let dify_api_url = self.ctx.dify_api_url.clone();
+            actix_web::rt::spawn(async move {
+                if let Err(e) = stop_task(&dify_api_url, &task).await {
+                    warn!("Failed to stop Dify task {}: {}", task.task_id, e);
+                }
+            });
+        }
+    }
+}
+
+/// Wraps a Dify event stream so that dropping it before the final event (the
+/// client went away) stops the generation in Dify.
+pub(crate) fn cancel_on_disconnect(events: DifyEventStream, ctx: CancelContext) -> DifyEventStream {
+    let guard = StreamGuard { ctx, task: None, finished: false };
+    Box::pin(events.scan(guard, |guard, event| {
+        if let Ok(event) = &event {
+            guard.observe(event);
+        }
+        future::ready(Some(event))
+    }))
+}
+
+/// Calls Dify's stop endpoint for a task.
+pub(crate) async fn stop_task(dify_api_url: &str, task: &StreamTask) -> Result<(), ChatCompletionError> {
+    let client = build_client()?;
+    let url = format!("{}{}", dify_api_url, task.kind.stop_path(&task.task_id));
+    post_to_dify(&client, &url, &task.api_key, &json!({ "user": task.user })).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::stream;
+
+    fn context(registry: &Arc<ActiveStreams>, completion_id: Option<&str>) -> CancelContext {
+        CancelContext {
+            registry: registry.clone(),
+            dify_api_url: "http://dify".to_string(),
+            api_key: "app-key".to_string(),
+            user: "u".to_string(),
+            owner: "key-a".to_string(),
+            kind: TaskKind::Chat,
+            completion_id: completion_id.map(String::from),
+        }
+    }
+
+    fn events(kinds: &[&str]) -> DifyEventStream {
+        let events: Vec<_> = kinds.iter()
+            .map(|kind| Ok(json!({"event": kind, "task_id": "t1", "message_id": "m1"})))
+            .collect();
+        Box::pin(stream::iter(events))
+    }
+
+    #[actix_web::test]
+    async fn tasks_are_registered_under_the_client_id_while_streaming() {
+        let registry = Arc::new(ActiveStreams::default());
+        let mut stream = cancel_on_disconnect(events(&["message", "message_end"]), context(&registry, Some("chatcmpl-1")));
+
+        stream.next().await.unwrap().unwrap();
+        assert!(registry.take("chatcmpl-1", "key-b").is_none());
+        let tasks = registry.take("chatcmpl-1", "key-a").unwrap();
+        assert_eq!(tasks[0].task_id, "t1");
+        assert_eq!(tasks[0].kind.stop_path("t1"), "/v1/chat-messages/t1/stop");
+        // Finishing the stream doesn't stop the task
+        stream.collect::<Vec<_>>().await;
+    }
+
+    #[actix_web::test]
+    async fn finished_streams_are_unregistered() {
+        let registry = Arc::new(ActiveStreams::default());
+        let stream = cancel_on_disconnect(events(&["message", "message_end"]), context(&registry, None));
+
+        assert_eq!(stream.collect::<Vec<_>>().await.len(), 2);
+        assert!(registry.take("m1", "key-a").is_none());
+    }
+
+    #[test]
+    fn stop_paths_follow_the_app_type() {
+        assert_eq!(TaskKind::Completion.stop_path("t"), "/v1/completion-messages/t/stop");
+        assert_eq!(TaskKind::Workflow.stop_path("t"), "/v1/workflows/tasks/t/stop");
+    }
+}
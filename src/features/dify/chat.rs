@@ -0,0 +1,439 @@
+//! Dify chat apps behind every chat front-end.
+//!
+//! A front-end translates its request into an `OpenAIRequest`, prepares a
+//! `DifyChat` from it, and renders the resulting `Answer` or `AnswerEvent`s in
+//! its own wire format. Target resolution, inputs, parameter validation, files,
+//! stop sequences, citation markers, speech and task cancellation are handled
+//! here once.
+
+use actix_web::HttpRequest;
+use futures_util::{future, stream, Stream, StreamExt};
+use reqwest::Client;
+use serde_json::Value;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use crate::features::app::app_state::AppState;
+use crate::features::auth::resolver::{extract_api_key, resolve_target, DifyTarget};
+use crate::features::common::types::{DifyRequest, File, OpenAIRequest, ToolCall, Usage};
+use crate::features::config::proxy_config::{AppType, ProxyConfig};
+use crate::features::dify::citations::Citations;
+use crate::features::dify::conversations::{MessageTracker, TrackedMessage};
+use crate::features::dify::cancellation::{cancel_on_disconnect, CancelContext, TaskKind};
+use crate::features::dify::handlers::openai::chat_completion::{
+    construct_dify_request, dify_event_stream, dify_stream_error, extract_usage, post_to_dify, read_dify_json,
+    ChatCompletionError, DifyEventStream,
+};
+use crate::features::dify::handlers::openai::inputs::build_inputs;
+use crate::features::dify::handlers::openai::stop::StopFilter;
+use crate::features::dify::parameters::validate_request;
+use crate::features::files::dify::message_files;
+
+/// A chat message ready to be sent to a Dify chat app. It can be sent more
+/// than once, e.g. once per choice.
+#[derive(Clone)]
+pub struct DifyChat {
+    pub target: DifyTarget,
+    pub request: DifyRequest,
+    url: String,
+    /// Stop sequences the answer is cut at. Taken from the request.
+    pub stop: Vec<String>,
+    /// Whether `[n]` citation markers are appended. Taken from the request.
+    pub markers: bool,
+    /// Whether Dify's text-to-speech events are passed on. Off by default.
+    pub speech: bool,
+    /// Proxy key the answer is for.
+    owner: String,
+    /// Where finished answers are recorded, so feedback on them can be checked.
+    messages: Arc<MessageTracker>,
+}
+
+/// A complete answer from a blocking call.
+#[derive(Debug, Clone)]
+pub struct Answer {
+    pub message_id: String,
+    pub conversation_id: String,
+    pub created: u64,
+    pub text: String,
+    /// The stop sequence the text was cut at, if any.
+    pub stop: Option<String>,
+    pub usage: Option<Usage>,
+    /// Knowledge base segments the answer drew on. With markers, `text` ends with them.
+    pub citations: Citations,
+}
+
+/// One step of a streamed answer.
+#[derive(Debug, Clone)]
+pub enum AnswerEvent {
+    /// First event, once Dify has assigned the message.
+    Started { message_id: String, conversation_id: String, created: u64 },
+    Text(String),
+    /// Tool calls or files attached to a Dify message event.
+    Attachments { tool_calls: Option<Vec<ToolCall>>, files: Option<Vec<File>> },
+    /// A base64 chunk of the answer's speech, when `speech` is on.
+    Audio(String),
+    /// Last event of a successful answer. With speech, it follows the last audio. With citation markers, they were
+    /// the last text.
+    Finished { usage: Option<Usage>, stop: Option<String>, citations: Citations },
+    /// Dify or transport error; the stream ends after it.
+    Error(String),
+}
+
+pub type AnswerStream = Pin<Box<dyn Stream<Item = AnswerEvent> + Send>>;
+
+impl DifyChat {
+    /// Resolves the chat app for the request and builds the Dify request. Only
+    /// chat apps are supported.
+    pub(crate) async fn prepare(
+        state: &AppState,
+        req: &HttpRequest,
+        config: &ProxyConfig,
+        client: &Client,
+        openai_req: &OpenAIRequest,
+    ) -> Result<Self, ChatCompletionError> {
+        let target = resolve_target(req, config, openai_req.model.as_deref())?;
+        if target.model_config.as_ref().is_some_and(|m| m.app_type != AppType::Chat) {
+            return Err(ChatCompletionError::RequestConstructionError(format!(
+                "The model '{}' is not a chat app",
+                openai_req.model.as_deref().unwrap_or_default()
+            )));
+        }
+
+        let input_mapping = target.model_config.as_ref().map(|m| m.inputs.clone()).unwrap_or_default();
+        let inputs = build_inputs(req, openai_req, &input_mapping)?;
+        let has_images = openai_req.messages.iter().any(|m| m.content.has_images());
+        validate_request(&state.parameters, client, &state.dify_api_url, &target.api_key, &inputs, has_images).await?;
+        Self::new(state, req, client, target, openai_req, inputs).await
+    }
+
+    /// Builds the Dify request for a target and inputs the caller has already
+    /// resolved and validated.
+    pub(crate) async fn new(
+        state: &AppState,
+        req: &HttpRequest,
+        client: &Client,
+        target: DifyTarget,
+        openai_req: &OpenAIRequest,
+        inputs: serde_json::Map<String, Value>,
+    ) -> Result<Self, ChatCompletionError> {
+        let user = openai_req.user.clone().or(target.default_user.clone());
+        let mut request = construct_dify_request(openai_req, user, inputs).await?;
+        let owner = extract_api_key(req).unwrap_or_default();
+        request.files = message_files(
+            client, &state.dify_api_url, &state.files, &owner, &target.api_key, &request.user, openai_req,
+        ).await?;
+
+        Ok(DifyChat {
+            target,
+            request,
+            url: format!("{}/v1/chat-messages", state.dify_api_url),
+            stop: openai_req.stop.as_ref().map(|s| s.to_vec()).unwrap_or_default(),
+            markers: openai_req.citation_markers == Some(true),
+            speech: false,
+            owner,
+            messages: state.messages.clone(),
+        })
+    }
+
+    /// Who gets the answer.
+    fn recipient(&self) -> TrackedMessage {
+        TrackedMessage {
+            owner: self.owner.clone(),
+            api_key: self.target.api_key.clone(),
+            user: self.request.user.clone(),
+        }
+    }
+
+    /// Sends the message in blocking mode.
+    pub(crate) async fn answer(&self, client: &Client) -> Result<Answer, ChatCompletionError> {
+        let mut request = self.request.clone();
+        request.response_mode = "blocking".to_string();
+        let response = post_to_dify(client, &self.url, &self.target.api_key, &request).await?;
+        let json = read_dify_json(response).await?;
+
+        let field = |name: &str| {
+            json[name].as_str().map(String::from).ok_or_else(|| {
+                ChatCompletionError::JsonSerializationError(format!("Missing '{}' field", name))
+            })
+        };
+        let mut filter = StopFilter::new(self.stop.clone());
+        let mut text = filter.push(&field("answer")?);
+        text.push_str(&filter.finish());
+        let citations = Citations::new(&json["metadata"], self.markers);
+        text.push_str(&citations.markers());
+
+        let message_id = field("message_id")?;
+        self.messages.record(&message_id, self.recipient());
+        Ok(Answer {
+            message_id,
+            conversation_id: json["conversation_id"].as_str().unwrap_or_default().to_string(),
+            created: json["created_at"].as_u64().unwrap_or_default(),
+            text,
+            stop: filter.matched().map(String::from),
+            usage: extract_usage(&json["metadata"]),
+            citations,
+        })
+    }
+
+    /// Sends the message in streaming mode. Dropping the stream stops the Dify task.
+    pub(crate) async fn stream(
+        &self,
+        client: &Client,
+        state: &AppState,
+        req: &HttpRequest,
+    ) -> Result<AnswerStream, ChatCompletionError> {
+        let cancel = CancelContext::new(state, req, &self.target.api_key, &self.request.user, TaskKind::Chat);
+        self.stream_with(client, cancel).await
+    }
+
+    /// Like `stream`, with the caller's cancellation context, e.g. one that
+    /// registers the task under a completion id shared by several choices.
+    pub(crate) async fn stream_with(&self, client: &Client, cancel: CancelContext) -> Result<AnswerStream, ChatCompletionError> {
+        let mut request = self.request.clone();
+        request.response_mode = "streaming".to_string();
+        let response = post_to_dify(client, &self.url, &self.target.api_key, &request).await?;
+        let events = cancel_on_disconnect(dify_event_stream(response), cancel);
+        let state = AnswerState {
+            filter: StopFilter::new(self.stop.clone()),
+            markers: self.markers,
+            speech: self.speech,
+            started: false,
+            pending_finish: None,
+            done: false,
+        };
+        let (messages, recipient) = (self.messages.clone(), self.recipient());
+        Ok(Box::pin(answer_events(events, state).scan(None, move |started, event| {
+            match &event {
+                AnswerEvent::Started { message_id, .. } => *started = Some(message_id.clone()),
+                AnswerEvent::Finished { .. } => {
+                    if let Some(message_id) = started {
+                        messages.record(message_id, recipient.clone());
+                    }
+                },
+                _ => {},
+            }
+            future::ready(Some(event))
+        })))
+    }
+}
+
+struct AnswerState {
+    filter: StopFilter,
+    markers: bool,
+    speech: bool,
+    started: bool,
+    /// With speech, the `Finished` event held back until the audio ends.
+    pending_finish: Option<AnswerEvent>,
+    done: bool,
+}
+
+/// Turns Dify chat events into answer events, applying stop sequences.
+fn answer_events(events: DifyEventStream, state: AnswerState) -> AnswerStream {
+    Box::pin(events
+        .map(Some)
+        .chain(stream::once(future::ready(None)))
+        .scan(state, |state, event| {
+            if state.done {
+                return future::ready(None);
+            }
+            let out = match event {
+                Some(Ok(event)) => process_event(event, state),
+                Some(Err(e)) => {
+                    state.done = true;
+                    vec![AnswerEvent::Error(e.to_string())]
+                },
+                // A stream that ends without `tts_message_end` still finishes; one that ends before
+                // `message_end` fails, after the text the stop filter held back
+                None => {
+                    state.done = true;
+                    match state.pending_finish.take() {
+                        Some(finished) => vec![finished],
+                        None => {
+                            let rest = state.filter.finish();
+                            let mut out: Vec<_> = (!rest.is_empty()).then_some(AnswerEvent::Text(rest)).into_iter().collect();
+                            out.push(AnswerEvent::Error("Dify closed the stream before the answer finished".to_string()));
+                            out
+                        },
+                    }
+                },
+            };
+            future::ready(Some(stream::iter(out)))
+        })
+        .flatten())
+}
+
+fn process_event(event: Value, state: &mut AnswerState) -> Vec<AnswerEvent> {
+    let mut out = Vec::new();
+    let kind = event["event"].as_str().unwrap_or_default();
+    let answering = matches!(kind, "message" | "agent_message" | "message_end") || (state.speech && kind == "tts_message");
+    if !state.started && answering {
+        state.started = true;
+        out.push(AnswerEvent::Started {
+            message_id: event["message_id"].as_str().unwrap_or_default().to_string(),
+            conversation_id: event["conversation_id"].as_str().unwrap_or_default().to_string(),
+            created: event["created_at"].as_u64().unwrap_or_default(),
+        });
+    }
+
+    match kind {
+        "message" | "agent_message" => {
+            let text = state.filter.push(event["answer"].as_str().unwrap_or_default());
+            if !text.is_empty() {
+                out.push(AnswerEvent::Text(text));
+            }
+            let tool_calls = serde_json::from_value(event["tool_calls"].clone()).ok().flatten();
+            let files = serde_json::from_value(event["files"].clone()).ok().flatten();
+            if tool_calls.is_some() || files.is_some() {
+                out.push(AnswerEvent::Attachments { tool_calls, files });
+            }
+            if state.filter.stopped() {
+                // Ending the stream here drops the Dify stream, which stops the task
+                state.done = true;
+                out.push(AnswerEvent::Finished {
+                    usage: None,
+                    stop: state.filter.matched().map(String::from),
+                    citations: Citations::default(),
+                });
+            }
+        },
+        "message_end" => {
+            let citations = Citations::new(&event["metadata"], state.markers);
+            let rest = state.filter.finish() + &citations.markers();
+            if !rest.is_empty() {
+                out.push(AnswerEvent::Text(rest));
+            }
+            let finished = AnswerEvent::Finished {
+                usage: extract_usage(&event["metadata"]),
+                stop: None,
+                citations,
+            };
+            if state.speech {
+                // Speech keeps streaming after the text; finish once it ends
+                state.pending_finish = Some(finished);
+            } else {
+                state.done = true;
+                out.push(finished);
+            }
+        },
+        "tts_message" if state.speech => {
+            if let Some(audio) = event["audio"].as_str().filter(|a| !a.is_empty()) {
+                out.push(AnswerEvent::Audio(audio.to_string()));
+            }
+        },
+        "tts_message_end" if state.speech => {
+            state.done = true;
+            out.extend(state.pending_finish.take());
+        },
+        "error" => {
+            state.done = true;
+            out.push(AnswerEvent::Error(dify_stream_error(&event)));
+        },
+        _ => {},
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn state(stop: &[&str], speech: bool) -> AnswerState {
+        AnswerState {
+            filter: StopFilter::new(stop.iter().map(|s| s.to_string()).collect()),
+            markers: true,
+            speech,
+            started: false,
+            pending_finish: None,
+            done: false,
+        }
+    }
+
+    fn message(answer: &str) -> Value {
+        json!({"event": "message", "message_id": "m1", "conversation_id": "c1", "created_at": 7, "answer": answer})
+    }
+
+    fn message_end() -> Value {
+        json!({
+            "event": "message_end",
+            "message_id": "m1",
+            "metadata": {
+                "usage": {"prompt_tokens": 1, "completion_tokens": 2, "total_tokens": 3},
+                "retriever_resources": [{"position": 1, "document_id": "d1", "document_name": "a.pdf"}],
+            },
+        })
+    }
+
+    async fn run(events: Vec<Value>, state: AnswerState) -> Vec<AnswerEvent> {
+        let events: DifyEventStream = Box::pin(stream::iter(events.into_iter().map(Ok)));
+        answer_events(events, state).collect().await
+    }
+
+    fn text(events: &[AnswerEvent]) -> String {
+        events.iter().filter_map(|e| match e { AnswerEvent::Text(t) => Some(t.as_str()), _ => None }).collect()
+    }
+
+    #[actix_web::test]
+    async fn answers_start_stream_text_and_finish_with_markers() {
+        let events = run(vec![message("Hello "), message("world"), message_end()], state(&[], false)).await;
+        assert!(matches!(&events[0], AnswerEvent::Started { message_id, conversation_id, created: 7 }
+            if message_id == "m1" && conversation_id == "c1"));
+        assert_eq!(text(&events), "Hello world [1]");
+        match events.last().unwrap() {
+            AnswerEvent::Finished { usage, stop, citations } => {
+                assert_eq!(usage.as_ref().map(|u| u.total_tokens), Some(3));
+                assert!(stop.is_none());
+                assert_eq!(citations.items.len(), 1);
+            },
+            other => panic!("unexpected last event: {:?}", other),
+        }
+    }
+
+    #[actix_web::test]
+    async fn stop_sequences_end_the_answer_early() {
+        let events = run(vec![message("Hello ST"), message("OP more"), message_end()], state(&["STOP"], false)).await;
+        assert_eq!(text(&events), "Hello ");
+        assert!(matches!(events.last(), Some(AnswerEvent::Finished { stop: Some(stop), .. }) if stop == "STOP"));
+    }
+
+    #[actix_web::test]
+    async fn speech_holds_the_finish_until_the_audio_ends() {
+        let tts = |audio: &str| json!({"event": "tts_message", "message_id": "m1", "audio": audio});
+        let events = run(
+            vec![message("Hi"), message_end(), tts("QUJD"), tts(""), json!({"event": "tts_message_end"}), message("late")],
+            state(&[], true),
+        ).await;
+        let kinds: Vec<&str> = events.iter().map(|e| match e {
+            AnswerEvent::Started { .. } => "started",
+            AnswerEvent::Text(_) => "text",
+            AnswerEvent::Audio(_) => "audio",
+            AnswerEvent::Finished { .. } => "finished",
+            _ => "other",
+        }).collect();
+        assert_eq!(kinds, ["started", "text", "text", "audio", "finished"]);
+
+        // Without `tts_message_end` the held finish still comes when Dify's stream ends
+        let events = run(vec![message("Hi"), message_end()], state(&[], true)).await;
+        assert!(matches!(events.last(), Some(AnswerEvent::Finished { .. })));
+    }
+
+    #[actix_web::test]
+    async fn streams_closed_before_the_answer_finishes_fail() {
+        let events = run(vec![message("Hello ST")], state(&["STOP"], false)).await;
+        assert_eq!(text(&events), "Hello ST");
+        assert!(matches!(events.last(),
+            Some(AnswerEvent::Error(message)) if message == "Dify closed the stream before the answer finished"));
+        assert!(!events.iter().any(|e| matches!(e, AnswerEvent::Finished { .. })));
+
+        let events = run(Vec::new(), state(&[], false)).await;
+        assert!(matches!(events.as_slice(), [AnswerEvent::Error(_)]));
+    }
+
+    #[actix_web::test]
+    async fn dify_errors_end_the_stream() {
+        let error = json!({"event": "error", "message": "quota exceeded"});
+        let events = run(vec![message("Hi"), error, message("ignored")], state(&[], false)).await;
+        assert!(matches!(events.last(), Some(AnswerEvent::Error(message)) if message == "quota exceeded"));
+        assert_eq!(text(&events), "Hi");
+    }
+}
@@ -0,0 +1,229 @@
+//! Dify retriever resources as OpenAI annotations.
+//!
+//! Dify reports the knowledge base segments an answer drew on as
+//! `retriever_resources`, but not where in the answer they were used. Each
+//! front-end's annotations therefore sit at the end of the text, or, with
+//! inline markers, on a `[n]` marker per resource appended to it.
+//!
+//! A `file_citation`'s `file_id` is the Dify `document_id` of the knowledge
+//! base document, not a `/v1/files` id: Dify's API doesn't expose dataset
+//! documents as files, so `GET /v1/files/{id}` answers 404 for it.
+
+use serde_json::{json, Value};
+
+/// A knowledge base segment the answer drew on.
+#[derive(Debug, Clone)]
+pub struct Citation {
+    /// Dify's 1-based position, used as the marker number.
+    pub position: u64,
+    pub dataset_id: String,
+    pub dataset_name: String,
+    /// Dify knowledge base document; reported as `file_id`.
+    pub document_id: String,
+    pub document_name: String,
+    pub segment_id: String,
+    pub score: Option<f64>,
+    pub content: String,
+    /// Source URL, for documents crawled from a website.
+    pub url: Option<String>,
+}
+
+/// Annotation shapes of the front-ends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnotationStyle {
+    /// Chat completions message `annotations`.
+    Chat,
+    /// Responses `output_text` annotations.
+    Responses,
+    /// Assistants thread message text annotations.
+    Assistants,
+}
+
+/// The citations of one answer.
+#[derive(Debug, Clone, Default)]
+pub struct Citations {
+    pub items: Vec<Citation>,
+    /// Whether `[n]` markers are appended to the answer.
+    pub marked: bool,
+}
+
+impl Citations {
+    /// Reads `retriever_resources` from a `message_end` event's or blocking
+    /// response's `metadata`, or from a `/messages` history entry.
+    pub fn new(source: &Value, marked: bool) -> Self {
+        let items = source["retriever_resources"].as_array()
+            .map(|resources| resources.iter().enumerate().map(|(i, r)| citation(i, r)).collect())
+            .unwrap_or_default();
+        Citations { items, marked }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Text to append to the answer: ` [1][2]` when marked, otherwise empty.
+    pub fn markers(&self) -> String {
+        if !self.marked || self.items.is_empty() {
+            return String::new();
+        }
+        let markers: String = self.items.iter().map(|c| format!("[{}]", c.position)).collect();
+        format!(" {}", markers)
+    }
+
+    /// Annotations for an answer of `text_len` characters, which ends with
+    /// `markers()`.
+    pub fn annotations(&self, text_len: usize, style: AnnotationStyle) -> Vec<Value> {
+        let mut start = text_len.saturating_sub(self.markers().chars().count()) + usize::from(self.marked);
+        self.items.iter()
+            .map(|citation| {
+                let (start_index, end_index) = match self.marked {
+                    true => (start, start + marker_len(citation)),
+                    false => (text_len, text_len),
+                };
+                start = end_index;
+                annotation(citation, start_index, end_index, style)
+            })
+            .collect()
+    }
+}
+
+fn citation(index: usize, resource: &Value) -> Citation {
+    let field = |name: &str| resource[name].as_str().unwrap_or_default().to_string();
+    let document_name = field("document_name");
+    let url = ["url", "source_url"].iter()
+        .find_map(|key| resource["doc_metadata"][key].as_str())
+        .map(String::from)
+        .or_else(|| Some(document_name.clone()).filter(|n| n.starts_with("http://") || n.starts_with("https://")));
+    Citation {
+        position: resource["position"].as_u64().unwrap_or(index as u64 + 1),
+        dataset_id: field("dataset_id"),
+        dataset_name: field("dataset_name"),
+        document_id: field("document_id"),
+        document_name,
+        segment_id: field("segment_id"),
+        score: resource["score"].as_f64(),
+        content: field("content"),
+        url,
+    }
+}
+
+fn marker_len(citation: &Citation) -> usize {
+    citation.position.to_string().len() + 2
+}
+
+fn annotation(citation: &Citation, start_index: usize, end_index: usize, style: AnnotationStyle) -> Value {
+    match (style, &citation.url) {
+        (AnnotationStyle::Chat, Some(url)) => json!({
+            "type": "url_citation",
+            "url_citation": {
+                "start_index": start_index,
+                "end_index": end_index,
+                "url": url,
+                "title": citation.document_name,
+            },
+        }),
+        (AnnotationStyle::Chat, None) => json!({
+            "type": "file_citation",
+            "file_citation": {
+                "start_index": start_index,
+                "end_index": end_index,
+                "file_id": citation.document_id,
+                "filename": citation.document_name,
+                "dataset_id": citation.dataset_id,
+                "dataset_name": citation.dataset_name,
+                "segment_id": citation.segment_id,
+                "score": citation.score,
+                "quote": citation.content,
+            },
+        }),
+        (AnnotationStyle::Responses, Some(url)) => json!({
+            "type": "url_citation",
+            "start_index": start_index,
+            "end_index": end_index,
+            "url": url,
+            "title": citation.document_name,
+        }),
+        (AnnotationStyle::Responses, None) => json!({
+            "type": "file_citation",
+            "index": start_index,
+            "file_id": citation.document_id,
+            "filename": citation.document_name,
+        }),
+        (AnnotationStyle::Assistants, _) => json!({
+            "type": "file_citation",
+            "text": if end_index > start_index { format!("[{}]", citation.position) } else { String::new() },
+            "start_index": start_index,
+            "end_index": end_index,
+            "file_citation": {
+                "file_id": citation.document_id,
+                "quote": citation.content,
+            },
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn citations(positions: &[u64], marked: bool) -> Citations {
+        let resources: Vec<Value> = positions.iter()
+            .map(|p| json!({"position": p, "document_id": format!("doc{}", p), "document_name": "a.pdf", "content": "quote"}))
+            .collect();
+        Citations::new(&json!({"retriever_resources": resources}), marked)
+    }
+
+    /// The text each annotation spans in `text`.
+    fn spans(text: &str, annotations: &[Value]) -> Vec<String> {
+        annotations.iter()
+            .map(|a| {
+                let index = |name: &str| a[name].as_u64().or(a["file_citation"][name].as_u64()).unwrap() as usize;
+                text.chars().skip(index("start_index")).take(index("end_index") - index("start_index")).collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn marked_annotations_span_their_markers() {
+        let citations = citations(&[1, 12], true);
+        let text = format!("Héllo wörld{}", citations.markers());
+        assert_eq!(text, "Héllo wörld [1][12]");
+
+        let len = text.chars().count();
+        assert_eq!(spans(&text, &citations.annotations(len, AnnotationStyle::Chat)), ["[1]", "[12]"]);
+        let annotations = citations.annotations(len, AnnotationStyle::Assistants);
+        assert_eq!(spans(&text, &annotations), ["[1]", "[12]"]);
+        assert_eq!(annotations[1]["text"], "[12]");
+    }
+
+    #[test]
+    fn unmarked_annotations_sit_at_the_end() {
+        let citations = citations(&[1, 2], false);
+        assert_eq!(citations.markers(), "");
+
+        let annotations = citations.annotations(5, AnnotationStyle::Chat);
+        for annotation in &annotations {
+            assert_eq!(annotation["file_citation"]["start_index"], 5);
+            assert_eq!(annotation["file_citation"]["end_index"], 5);
+        }
+        assert_eq!(citations.annotations(5, AnnotationStyle::Assistants)[0]["text"], "");
+        assert_eq!(citations.annotations(5, AnnotationStyle::Responses)[1]["index"], 5);
+    }
+
+    #[test]
+    fn file_ids_are_dify_document_ids() {
+        let annotations = citations(&[3], false).annotations(0, AnnotationStyle::Responses);
+        assert_eq!(annotations[0]["file_id"], "doc3");
+    }
+
+    #[test]
+    fn website_documents_become_url_citations() {
+        let source = json!({"retriever_resources": [
+            {"document_name": "https://example.com/faq"},
+            {"document_name": "faq", "doc_metadata": {"source_url": "https://example.com/a"}},
+        ]});
+        let annotations = Citations::new(&source, false).annotations(0, AnnotationStyle::Chat);
+        assert_eq!(annotations[0]["url_citation"]["url"], "https://example.com/faq");
+        assert_eq!(annotations[1]["url_citation"]["url"], "https://example.com/a");
+    }
+}
@@ -0,0 +1,119 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// An entry only the proxy key that created it may use.
+pub trait Owned {
+    fn owner(&self) -> &str;
+}
+
+/// The Dify conversation behind a response id.
+#[derive(Debug, Clone)]
+pub struct TrackedConversation {
+    /// Proxy key that created the response; only it may continue the conversation.
+    pub owner: String,
+    /// Dify app key the conversation lives in.
+    pub api_key: String,
+    /// Dify user the conversation belongs to.
+    pub user: String,
+    pub conversation_id: String,
+}
+
+impl Owned for TrackedConversation {
+    fn owner(&self) -> &str {
+        &self.owner
+    }
+}
+
+/// Who a Dify answer (by `message_id`) was given to, for feedback.
+#[derive(Debug, Clone)]
+pub struct TrackedMessage {
+    /// Proxy key the answer was given to; only it may rate the answer.
+    pub owner: String,
+    /// Dify app key of the app that answered.
+    pub api_key: String,
+    /// Dify user the message belongs to.
+    pub user: String,
+}
+
+impl Owned for TrackedMessage {
+    fn owner(&self) -> &str {
+        &self.owner
+    }
+}
+
+/// Maps ids the proxy handed out to what they refer to in Dify. Kept in
+/// memory; the oldest entries are dropped beyond `capacity`.
+pub struct Tracker<T> {
+    entries: Mutex<(HashMap<String, T>, VecDeque<String>)>,
+    capacity: usize,
+}
+
+/// Response ids, so a later request can continue the conversation by id
+/// (`previous_response_id`).
+pub type ConversationTracker = Tracker<TrackedConversation>;
+
+/// Answer ids, so feedback goes to the app and user that got the answer.
+pub type MessageTracker = Tracker<TrackedMessage>;
+
+impl<T: Owned + Clone> Tracker<T> {
+    pub fn new(capacity: usize) -> Self {
+        Tracker {
+            entries: Mutex::new((HashMap::new(), VecDeque::new())),
+            capacity: capacity.max(1),
+        }
+    }
+
+    pub fn record(&self, id: &str, entry: T) {
+        let mut entries = self.entries.lock().unwrap();
+        let (map, order) = &mut *entries;
+        if map.insert(id.to_string(), entry).is_none() {
+            order.push_back(id.to_string());
+        }
+        while order.len() > self.capacity {
+            if let Some(oldest) = order.pop_front() {
+                map.remove(&oldest);
+            }
+        }
+    }
+
+    /// The entry for `id`, if it was created by `owner`.
+    pub fn get(&self, id: &str, owner: &str) -> Option<T> {
+        let entries = self.entries.lock().unwrap();
+        entries.0.get(id)
+            .filter(|entry| entry.owner() == owner)
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(owner: &str) -> TrackedMessage {
+        TrackedMessage { owner: owner.to_string(), api_key: "app-key".to_string(), user: "u".to_string() }
+    }
+
+    #[test]
+    fn entries_are_scoped_to_their_owner() {
+        let tracker = MessageTracker::new(10);
+        tracker.record("m1", message("key-a"));
+
+        assert_eq!(tracker.get("m1", "key-a").unwrap().api_key, "app-key");
+        assert!(tracker.get("m1", "key-b").is_none());
+        assert!(tracker.get("m2", "key-a").is_none());
+    }
+
+    #[test]
+    fn the_oldest_entries_are_dropped_beyond_capacity() {
+        let tracker = MessageTracker::new(2);
+        for id in ["m1", "m2", "m3"] {
+            tracker.record(id, message("key"));
+        }
+        // Recording an id again doesn't count twice
+        tracker.record("m3", message("key"));
+
+        assert!(tracker.get("m1", "key").is_none());
+        assert!(tracker.get("m2", "key").is_some());
+        assert!(tracker.get("m3", "key").is_some());
+    }
+}
@@ -0,0 +1,74 @@
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+
+use crate::features::common::types::AnthropicErrorBody;
+use crate::features::dify::handlers::openai::chat_completion::ChatCompletionError;
+
+/// A `ChatCompletionError` rendered in Anthropic's error format.
+#[derive(Debug)]
+pub struct AnthropicError(pub ChatCompletionError);
+
+impl From<ChatCompletionError> for AnthropicError {
+    fn from(error: ChatCompletionError) -> Self {
+        AnthropicError(error)
+    }
+}
+
+impl std::fmt::Display for AnthropicError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl ResponseError for AnthropicError {
+    fn status_code(&self) -> StatusCode {
+        self.0.status_code()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(serde_json::json!({
+            "type": "error",
+            "error": error_body(self.status_code(), self.0.client_message()),
+        }))
+    }
+}
+
+/// Anthropic's error object, typed by HTTP status.
+pub(crate) fn error_body(status: StatusCode, message: String) -> AnthropicErrorBody {
+    let kind = match status.as_u16() {
+        400 => "invalid_request_error",
+        401 => "authentication_error",
+        403 => "permission_error",
+        404 => "not_found_error",
+        413 => "request_too_large",
+        429 => "rate_limit_error",
+        529 => "overloaded_error",
+        _ => "api_error",
+    };
+    AnthropicErrorBody { r#type: kind.to_string(), message }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_types_follow_the_status() {
+        assert_eq!(error_body(StatusCode::BAD_REQUEST, String::new()).r#type, "invalid_request_error");
+        assert_eq!(error_body(StatusCode::UNAUTHORIZED, String::new()).r#type, "authentication_error");
+        assert_eq!(error_body(StatusCode::NOT_FOUND, String::new()).r#type, "not_found_error");
+        assert_eq!(error_body(StatusCode::BAD_GATEWAY, String::new()).r#type, "api_error");
+    }
+
+    #[actix_web::test]
+    async fn errors_render_in_anthropic_format() {
+        let error = AnthropicError::from(ChatCompletionError::ModelNotFound("m".to_string()));
+        let response = error.error_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let body = actix_web::body::to_bytes(response.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["type"], "error");
+        assert_eq!(body["error"]["type"], "not_found_error");
+    }
+}
@@ -0,0 +1,195 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use futures_util::{stream, StreamExt};
+
+use crate::features::app::app_state::AppState;
+use crate::features::common::types::{
+    AnthropicContentBlock, AnthropicMessageDelta, AnthropicRequest, AnthropicResponse, AnthropicStreamEvent,
+    AnthropicTextDelta, AnthropicUsage, Usage,
+};
+use crate::features::dify::chat::{Answer, AnswerEvent, AnswerStream, DifyChat};
+use crate::features::dify::handlers::openai::chat_completion::{
+    build_client, sse_response, sse_typed_event, with_ignored_params, StreamResponse,
+};
+use crate::features::dify::handlers::openai::params::{check_params, unsupported_params};
+use super::error::{error_body, AnthropicError};
+use super::translate::{to_openai_request, unsupported_anthropic_params};
+
+/// `POST /v1/messages`: the Anthropic Messages API over a Dify chat app.
+pub async fn messages(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: web::Json<AnthropicRequest>,
+) -> Result<HttpResponse, AnthropicError> {
+    let config = state.config.snapshot();
+    let openai_req = to_openai_request(&body)?;
+    let ignored = check_params(
+        unsupported_params(&openai_req, &unsupported_anthropic_params(&body)),
+        config.param_policy(Some(&body.model)),
+    )?;
+
+    let client = build_client()?;
+    let chat = DifyChat::prepare(&state, &req, &config, &client, &openai_req).await?;
+    let response = if body.stream.unwrap_or(false) {
+        let events = chat.stream(&client, &state, &req).await?;
+        sse_response(anthropic_stream(events, body.model.clone()))
+    } else {
+        let answer = chat.answer(&client).await?;
+        HttpResponse::Ok().json(anthropic_response(answer, &body.model))
+    };
+    Ok(with_ignored_params(response, &ignored))
+}
+
+fn anthropic_usage(usage: Option<&Usage>) -> AnthropicUsage {
+    usage
+        .map(|u| AnthropicUsage { input_tokens: u.prompt_tokens, output_tokens: u.completion_tokens })
+        .unwrap_or_default()
+}
+
+fn stop_reason(stop: Option<&str>) -> String {
+    match stop {
+        Some(_) => "stop_sequence",
+        None => "end_turn",
+    }
+    .to_string()
+}
+
+fn anthropic_response(answer: Answer, model: &str) -> AnthropicResponse {
+    AnthropicResponse {
+        id: answer.message_id,
+        r#type: "message".to_string(),
+        role: "assistant".to_string(),
+        model: model.to_string(),
+        content: vec![AnthropicContentBlock::Text { text: answer.text }],
+        stop_reason: Some(stop_reason(answer.stop.as_deref())),
+        stop_sequence: answer.stop,
+        usage: anthropic_usage(answer.usage.as_ref()),
+    }
+}
+
+/// Renders answer events as Anthropic stream events. The answer is a single
+/// text block at index 0.
+fn anthropic_stream(events: AnswerStream, model: String) -> StreamResponse {
+    Box::pin(events
+        .map(move |event| {
+            let out = match event {
+                AnswerEvent::Started { message_id, .. } => vec![
+                    AnthropicStreamEvent::MessageStart {
+                        message: AnthropicResponse {
+                            id: message_id,
+                            r#type: "message".to_string(),
+                            role: "assistant".to_string(),
+                            model: model.clone(),
+                            content: Vec::new(),
+                            stop_reason: None,
+                            stop_sequence: None,
+                            usage: AnthropicUsage::default(),
+                        },
+                    },
+                    AnthropicStreamEvent::ContentBlockStart {
+                        index: 0,
+                        content_block: AnthropicContentBlock::Text { text: String::new() },
+                    },
+                ],
+                AnswerEvent::Text(text) => vec![AnthropicStreamEvent::ContentBlockDelta {
+                    index: 0,
+                    delta: AnthropicTextDelta::TextDelta { text },
+                }],
+                AnswerEvent::Finished { usage, stop, .. } => vec![
+                    AnthropicStreamEvent::ContentBlockStop { index: 0 },
+                    AnthropicStreamEvent::MessageDelta {
+                        delta: AnthropicMessageDelta {
+                            stop_reason: Some(stop_reason(stop.as_deref())),
+                            stop_sequence: stop,
+                        },
+                        usage: anthropic_usage(usage.as_ref()),
+                    },
+                    AnthropicStreamEvent::MessageStop,
+                ],
+                AnswerEvent::Attachments { .. } | AnswerEvent::Audio(_) => Vec::new(),
+                AnswerEvent::Error(message) => vec![AnthropicStreamEvent::Error {
+                    error: error_body(actix_web::http::StatusCode::INTERNAL_SERVER_ERROR, message),
+                }],
+            };
+            stream::iter(out.iter().map(sse_typed_event).collect::<Vec<_>>())
+        })
+        .flatten()
+        .map(Ok))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    use crate::features::dify::citations::Citations;
+
+    fn usage() -> Usage {
+        Usage { prompt_tokens: 5, completion_tokens: 7, total_tokens: 12 }
+    }
+
+    /// The `event:` names and data of a rendered stream.
+    async fn rendered(events: Vec<AnswerEvent>) -> Vec<(String, Value)> {
+        let chunks: Vec<_> = anthropic_stream(Box::pin(stream::iter(events)), "m".to_string()).collect().await;
+        chunks.into_iter()
+            .map(|chunk| {
+                let chunk = String::from_utf8(chunk.unwrap().to_vec()).unwrap();
+                let (event, data) = chunk.trim_end().split_once('\n').unwrap();
+                (event.trim_start_matches("event: ").to_string(), serde_json::from_str(data.trim_start_matches("data: ")).unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn blocking_answers_become_messages() {
+        let answer = Answer {
+            message_id: "m1".to_string(),
+            conversation_id: "c1".to_string(),
+            created: 7,
+            text: "Hello".to_string(),
+            stop: Some("STOP".to_string()),
+            usage: Some(usage()),
+            citations: Citations::default(),
+        };
+
+        let response = serde_json::to_value(anthropic_response(answer, "m")).unwrap();
+        assert_eq!(response["id"], "m1");
+        assert_eq!(response["content"], json!([{"type": "text", "text": "Hello"}]));
+        assert_eq!(response["stop_reason"], "stop_sequence");
+        assert_eq!(response["stop_sequence"], "STOP");
+        assert_eq!(response["usage"], json!({"input_tokens": 5, "output_tokens": 7}));
+    }
+
+    #[actix_web::test]
+    async fn answers_stream_as_one_text_block() {
+        let events = rendered(vec![
+            AnswerEvent::Started { message_id: "m1".to_string(), conversation_id: "c1".to_string(), created: 7 },
+            AnswerEvent::Text("Hel".to_string()),
+            AnswerEvent::Audio("UklG".to_string()),
+            AnswerEvent::Text("lo".to_string()),
+            AnswerEvent::Finished { usage: Some(usage()), stop: None, citations: Citations::default() },
+        ]).await;
+
+        let names: Vec<&str> = events.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, [
+            "message_start",
+            "content_block_start",
+            "content_block_delta",
+            "content_block_delta",
+            "content_block_stop",
+            "message_delta",
+            "message_stop",
+        ]);
+        assert_eq!(events[0].1["message"]["id"], "m1");
+        assert_eq!(events[3].1["delta"], json!({"type": "text_delta", "text": "lo"}));
+        assert_eq!(events[5].1["delta"]["stop_reason"], "end_turn");
+        assert_eq!(events[5].1["usage"]["output_tokens"], 7);
+    }
+
+    #[actix_web::test]
+    async fn errors_end_the_stream_with_an_error_event() {
+        let events = rendered(vec![AnswerEvent::Error("boom".to_string())]).await;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].0, "error");
+        assert_eq!(events[0].1["error"], json!({"type": "api_error", "message": "boom"}));
+    }
+}
@@ -0,0 +1,176 @@
+use crate::features::common::types::{
+    AnthropicContent, AnthropicContentBlock, AnthropicImageSource, AnthropicRequest, ComplexMessageContent,
+    FunctionCall, ImageUrl, MessageContent, OpenAIMessage, OpenAIRequest, StopSequences, ToolCall,
+};
+use crate::features::dify::handlers::openai::chat_completion::ChatCompletionError;
+
+/// Anthropic parameters Dify has no equivalent for. Dify chat apps use their
+/// configured tools, so request `tools` are not sent.
+pub(crate) fn unsupported_anthropic_params(anthropic_req: &AnthropicRequest) -> Vec<&'static str> {
+    let mut params = Vec::new();
+    if anthropic_req.tools.as_ref().is_some_and(|t| !t.is_empty()) {
+        params.push("tools");
+    }
+    if anthropic_req.top_k.is_some() {
+        params.push("top_k");
+    }
+    if anthropic_req.tool_choice.as_ref().is_some_and(|c| c["type"] != "auto") {
+        params.push("tool_choice");
+    }
+    params
+}
+
+/// Translates an Anthropic Messages request into the OpenAI request the Dify
+/// chat layer works from.
+///
+/// The system prompt becomes the first message, images become `image_url`
+/// parts, `tool_use` blocks become tool calls and `tool_result` blocks become
+/// `tool` messages ahead of the rest of their message.
+pub(crate) fn to_openai_request(anthropic_req: &AnthropicRequest) -> Result<OpenAIRequest, ChatCompletionError> {
+    let mut messages = Vec::new();
+    if let Some(system) = &anthropic_req.system {
+        messages.push(OpenAIMessage::text("system", system.to_text()));
+    }
+
+    for (index, message) in anthropic_req.messages.iter().enumerate() {
+        let blocks = match &message.content {
+            AnthropicContent::Text(text) => {
+                messages.push(OpenAIMessage::text(&message.role, text.clone()));
+                continue;
+            },
+            AnthropicContent::Blocks(blocks) => blocks,
+        };
+
+        let mut parts = Vec::new();
+        let mut tool_calls = Vec::new();
+        for block in blocks {
+            match block {
+                AnthropicContentBlock::Text { text } => parts.push(ComplexMessageContent::text(text.clone())),
+                AnthropicContentBlock::Image { source } => parts.push(ComplexMessageContent {
+                    r#type: "image_url".to_string(),
+                    text: String::new(),
+                    image_url: Some(ImageUrl { url: image_url(source, index)?, detail: None }),
+                    file: None,
+                }),
+                AnthropicContentBlock::ToolUse { id, name, input } => tool_calls.push(ToolCall {
+                    id: id.clone(),
+                    r#type: "function".to_string(),
+                    function: FunctionCall { name: name.clone(), arguments: input.to_string() },
+                }),
+                AnthropicContentBlock::ToolResult { content, .. } => {
+                    let text = content.as_ref().map(|c| c.to_text()).unwrap_or_default();
+                    messages.push(OpenAIMessage::text("tool", text));
+                },
+                AnthropicContentBlock::Unsupported => {
+                    return Err(ChatCompletionError::RequestConstructionError(format!(
+                        "messages.{}: unsupported content block type",
+                        index
+                    )));
+                },
+            }
+        }
+
+        if !parts.is_empty() || !tool_calls.is_empty() {
+            messages.push(OpenAIMessage {
+                role: message.role.clone(),
+                content: MessageContent::Complex(parts),
+                function_call: None,
+                tool_calls: (!tool_calls.is_empty()).then_some(tool_calls),
+            });
+        }
+    }
+
+    Ok(OpenAIRequest {
+        messages,
+        stream: anthropic_req.stream,
+        temperature: anthropic_req.temperature,
+        top_p: anthropic_req.top_p,
+        max_tokens: Some(anthropic_req.max_tokens),
+        model: Some(anthropic_req.model.clone()),
+        user: anthropic_req.metadata.as_ref()
+            .and_then(|m| m.get("user_id"))
+            .and_then(|u| u.as_str())
+            .map(String::from),
+        stop: anthropic_req.stop_sequences.clone().map(StopSequences::Multiple),
+        metadata: anthropic_req.metadata.clone(),
+        ..Default::default()
+    })
+}
+
+fn image_url(source: &AnthropicImageSource, index: usize) -> Result<String, ChatCompletionError> {
+    match source {
+        AnthropicImageSource::Base64 { media_type, data } => Ok(format!("data:{};base64,{}", media_type, data)),
+        AnthropicImageSource::Url { url } => Ok(url.clone()),
+        AnthropicImageSource::Unsupported => Err(ChatCompletionError::RequestConstructionError(format!(
+            "messages.{}: unsupported image source type",
+            index
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::features::dify::handlers::openai::chat_completion::construct_dify_request;
+    use serde_json::json;
+
+    fn request(body: serde_json::Value) -> AnthropicRequest {
+        let mut base = json!({"model": "claude", "max_tokens": 256, "messages": []});
+        base.as_object_mut().unwrap().extend(body.as_object().unwrap().clone());
+        serde_json::from_value(base).unwrap()
+    }
+
+    #[test]
+    fn tools_and_top_k_are_reported() {
+        let req = request(json!({
+            "tools": [{"name": "lookup", "input_schema": {"type": "object"}}],
+            "tool_choice": {"type": "any"},
+            "top_k": 5,
+        }));
+        assert_eq!(unsupported_anthropic_params(&req), vec!["tools", "top_k", "tool_choice"]);
+        assert!(to_openai_request(&req).unwrap().tools.is_none());
+
+        let req = request(json!({"tools": [], "tool_choice": {"type": "auto"}}));
+        assert!(unsupported_anthropic_params(&req).is_empty());
+    }
+
+    #[test]
+    fn tool_blocks_become_openai_messages() {
+        let req = request(json!({"messages": [
+            {"role": "assistant", "content": [
+                {"type": "text", "text": "Looking it up"},
+                {"type": "tool_use", "id": "tu_1", "name": "lookup", "input": {"q": "x"}},
+            ]},
+            {"role": "user", "content": [
+                {"type": "tool_result", "tool_use_id": "tu_1", "content": "found"},
+                {"type": "text", "text": "Thanks"},
+            ]},
+        ]}));
+        let messages = to_openai_request(&req).unwrap().messages;
+        let roles: Vec<&str> = messages.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, ["assistant", "tool", "user"]);
+        let call = &messages[0].tool_calls.as_ref().unwrap()[0];
+        assert_eq!((call.id.as_str(), call.function.arguments.as_str()), ("tu_1", r#"{"q":"x"}"#));
+        assert_eq!(messages[1].content.to_text(), "found");
+    }
+
+    #[test]
+    fn unsupported_blocks_are_rejected() {
+        let req = request(json!({"messages": [{"role": "user", "content": [{"type": "document"}]}]}));
+        assert!(to_openai_request(&req).is_err());
+    }
+
+    #[actix_web::test]
+    async fn system_prompt_is_a_separate_paragraph_of_the_query() {
+        let req = request(json!({
+            "system": "Be brief.",
+            "messages": [
+                {"role": "user", "content": "Hi"},
+                {"role": "assistant", "content": "Hello"},
+                {"role": "user", "content": [{"type": "text", "text": "Bye"}]},
+            ],
+        }));
+        let dify = construct_dify_request(&to_openai_request(&req).unwrap(), None, Default::default()).await.unwrap();
+        assert_eq!(dify.query, "Be brief.\n\nHi\n\nHello\n\nBye");
+    }
+}
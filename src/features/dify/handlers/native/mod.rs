@@ -0,0 +1 @@
+pub mod workflow_run;
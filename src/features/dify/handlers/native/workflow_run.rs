@@ -0,0 +1,166 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use bytes::Bytes;
+use futures_util::StreamExt;
+use log::{debug, info, warn};
+use serde_json::{json, Value};
+
+use crate::features::app::app_state::AppState;
+use crate::features::auth::resolver::resolve_target;
+use crate::features::common::types::{NativeWorkflowRunRequest, WorkflowRunResponse, WorkflowStreamEvent};
+use crate::features::config::proxy_config::AppType;
+use crate::features::dify::cancellation::{cancel_on_disconnect, CancelContext, TaskKind};
+use crate::features::dify::handlers::openai::chat_completion::{
+    build_client, dify_event_stream, post_to_dify, read_dify_json, sse_event, sse_response,
+    ChatCompletionError, StreamResponse,
+};
+
+const TYPED_EVENTS: [&str; 5] = [
+    "workflow_started",
+    "node_started",
+    "node_finished",
+    "text_chunk",
+    "workflow_finished",
+];
+
+/// Validates one Dify workflow event and re-frames it for the client.
+///
+/// Known events must match their typed shape and are then forwarded as Dify
+/// sent them, including fields the types don't declare; Dify `error` events
+/// are passed through; other events (pings, iteration progress, ...) are
+/// dropped.
+fn process_native_event(event: Value, model: &str) -> Bytes {
+    let name = event["event"].as_str().unwrap_or_default().to_string();
+
+    if name == "error" {
+        return sse_event(&event);
+    }
+    if !TYPED_EVENTS.contains(&name.as_str()) {
+        debug!("Dropping untyped workflow event '{}'", name);
+        return Bytes::new();
+    }
+
+    match serde_json::from_value::<WorkflowStreamEvent>(event.clone()) {
+        Ok(typed) => {
+            if let WorkflowStreamEvent::WorkflowFinished { workflow_run_id, data, .. } = &typed {
+                info!("Workflow run {} for '{}' finished: {}", workflow_run_id, model, data.status);
+            }
+            sse_event(&event)
+        },
+        Err(e) => {
+            warn!("Invalid '{}' workflow event from Dify: {}", name, e);
+            sse_event(&json!({
+                "event": "error",
+                "status": 502,
+                "code": "invalid_upstream_event",
+                "message": format!("Invalid '{}' event from Dify: {}", name, e),
+            }))
+        },
+    }
+}
+
+/// Native Dify workflow run, authenticated through the proxy's key system.
+pub async fn workflow_run(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: web::Json<NativeWorkflowRunRequest>,
+) -> Result<HttpResponse, ChatCompletionError> {
+    let config = state.config.snapshot();
+    let target = resolve_target(&req, &config, body.model.as_deref())?;
+    let model = body.model.clone().unwrap_or_default();
+
+    if let Some(model_config) = &target.model_config {
+        if model_config.app_type != AppType::Workflow {
+            return Err(ChatCompletionError::RequestConstructionError(format!(
+                "The model '{}' is not a workflow app",
+                model
+            )));
+        }
+    }
+
+    let mut request = body.into_inner();
+    let is_streaming = request.response_mode.as_deref() == Some("streaming");
+    request.response_mode = Some(if is_streaming { "streaming" } else { "blocking" }.to_string());
+    request.user = request.user.or(target.default_user.clone()).or(Some("default_user".to_string()));
+
+    info!("Native workflow run for '{}' ({})", model, request.response_mode.as_deref().unwrap_or_default());
+
+    let client = build_client()?;
+    let url = format!("{}/v1/workflows/run", state.dify_api_url);
+    let response = post_to_dify(&client, &url, &target.api_key, &request).await?;
+
+    if is_streaming {
+        let user = request.user.clone().unwrap_or_default();
+        let cancel = CancelContext::new(&state, &req, &target.api_key, &user, TaskKind::Workflow);
+        let stream: StreamResponse = Box::pin(cancel_on_disconnect(dify_event_stream(response), cancel)
+            .map(move |event| event
+                .map(|event| process_native_event(event, &model))
+                .map_err(actix_web::error::ErrorInternalServerError)));
+        return Ok(sse_response(stream));
+    }
+
+    // Validated against the typed shape, returned as Dify sent it
+    let raw_json = read_dify_json(response).await?;
+    let run: WorkflowRunResponse = serde_json::from_value(raw_json.clone())
+        .map_err(|e| ChatCompletionError::JsonSerializationError(format!("Invalid workflow response from Dify: {}", e)))?;
+    info!("Workflow run {} for '{}' finished: {}", run.workflow_run_id, model, run.data.status);
+
+    Ok(HttpResponse::Ok().json(raw_json))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(bytes: Bytes) -> Value {
+        let text = String::from_utf8(bytes.to_vec()).unwrap();
+        serde_json::from_str(text.trim().trim_start_matches("data: ")).unwrap()
+    }
+
+    #[test]
+    fn typed_events_pass_through() {
+        let event = json!({"event": "text_chunk", "task_id": "t1", "workflow_run_id": "w1", "data": {"text": "Hi"}});
+        let out = data(process_native_event(event, "m"));
+        assert_eq!(out["event"], "text_chunk");
+        assert_eq!(out["data"]["text"], "Hi");
+    }
+
+    #[test]
+    fn undeclared_fields_are_forwarded() {
+        let event = json!({
+            "event": "node_finished",
+            "task_id": "t1",
+            "workflow_run_id": "w1",
+            "data": {
+                "id": "n1",
+                "node_id": "llm",
+                "node_type": "llm",
+                "title": "LLM",
+                "index": 1,
+                "status": "succeeded",
+                "inputs": {"query": "Hi"},
+                "process_data": {"prompts": []},
+                "execution_metadata": {"total_tokens": 3},
+                "files": [],
+                "created_at": 7,
+            },
+        });
+        let out = data(process_native_event(event.clone(), "m"));
+        assert_ne!(out["event"], "error", "{}", out);
+        assert_eq!(out, event);
+    }
+
+    #[test]
+    fn malformed_events_become_errors() {
+        let event = json!({"event": "text_chunk", "task_id": "t1"});
+        let out = data(process_native_event(event, "m"));
+        assert_eq!(out["event"], "error");
+        assert_eq!(out["code"], "invalid_upstream_event");
+    }
+
+    #[test]
+    fn dify_errors_pass_and_untyped_events_drop() {
+        let error = json!({"event": "error", "message": "boom"});
+        assert_eq!(data(process_native_event(error.clone(), "m")), error);
+        assert!(process_native_event(json!({"event": "ping"}), "m").is_empty());
+    }
+}
@@ -0,0 +1,37 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use log::warn;
+use serde_json::json;
+
+use crate::features::app::app_state::AppState;
+use crate::features::auth::resolver::extract_api_key;
+use crate::features::dify::cancellation::stop_task;
+use super::chat_completion::ChatCompletionError;
+
+/// Stops an in-flight streamed completion. Only the key that started the
+/// stream can cancel it; unknown or finished ids return 404.
+pub async fn cancel_completion(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ChatCompletionError> {
+    let owner = extract_api_key(&req).ok_or(ChatCompletionError::InvalidApiKey)?;
+    let completion_id = path.into_inner();
+
+    let tasks = state.streams.take(&completion_id, &owner)
+        .ok_or_else(|| ChatCompletionError::DifyApiError(
+            reqwest::StatusCode::NOT_FOUND,
+            format!("No active completion with id '{}'", completion_id),
+        ))?;
+
+    for task in &tasks {
+        if let Err(e) = stop_task(&state.dify_api_url, task).await {
+            warn!("Failed to stop Dify task {}: {}", task.task_id, e);
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(json!({
+        "id": completion_id,
+        "object": "chat.completion.cancel",
+        "cancelled": true,
+    })))
+}
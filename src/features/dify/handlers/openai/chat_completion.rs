@@ -1,19 +1,37 @@
 use actix_web::{web, HttpRequest, HttpResponse};
 use reqwest::{Client, StatusCode};
+use serde::Serialize;
 use serde_json::json;
-use lazy_static::lazy_static;
-use std::env;
 use std::time::Duration;
-use futures_util::{StreamExt, Stream};
+use futures_util::{future, stream, StreamExt, Stream};
 use bytes::Bytes;
 use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 use log::debug;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use uuid::Uuid;
 
-use crate::features::common::types::{OpenAIRequest, DifyRequest, OpenAIResponse, OpenAIChoice, OpenAIDelta, DifyEvent};
+use crate::features::app::app_state::AppState;
+use crate::features::auth::resolver::{extract_api_key, resolve_target, DifyTarget};
+use crate::features::cache::response::ResponseCache;
+use crate::features::cache::semantic::{hit_response, SemanticLookup, SemanticSlot};
+use crate::features::config::proxy_config::AppType;
+use crate::features::common::types::{OpenAIRequest, DifyRequest, OpenAIResponse, OpenAIChoice, OpenAIDelta, Usage, AudioDelta};
+use crate::features::dify::cancellation::{CancelContext, TaskKind};
+use crate::features::dify::chat::{AnswerEvent, DifyChat};
+use crate::features::dify::parameters::validate_request;
+use super::file_search::apply_file_search;
+use super::inputs::build_inputs;
+use super::params::{check_params, unsupported_params, IGNORED_PARAMS_HEADER, MAX_CHOICES};
+use super::structured::{output_format, structured_chat_completion};
+use super::suggested::{suggested_questions, suggestions_metadata};
+use crate::features::dify::citations::AnnotationStyle;
+use super::workflow::{workflow_chat_completion, workflow_request_inputs};
 
 #[derive(Debug)]
 pub enum ChatCompletionError {
     InvalidApiKey,
+    ModelNotFound(String),
     RequestConstructionError(String),
     DifyApiError(StatusCode, String),
     JsonSerializationError(String),
@@ -23,6 +41,7 @@ impl actix_web::ResponseError for ChatCompletionError {
     fn status_code(&self) -> actix_web::http::StatusCode {
         match self {
             ChatCompletionError::InvalidApiKey => actix_web::http::StatusCode::UNAUTHORIZED,
+            ChatCompletionError::ModelNotFound(_) => actix_web::http::StatusCode::NOT_FOUND,
             ChatCompletionError::RequestConstructionError(_) => actix_web::http::StatusCode::BAD_REQUEST,
             ChatCompletionError::DifyApiError(status, _) => {
                 reqwest_to_actix_status(*status)
@@ -32,14 +51,23 @@ impl actix_web::ResponseError for ChatCompletionError {
     }
 
     fn error_response(&self) -> HttpResponse {
-        let message = match self {
-            ChatCompletionError::InvalidApiKey => "Missing or invalid Authorization header",
-            ChatCompletionError::RequestConstructionError(msg) => msg,
-            ChatCompletionError::DifyApiError(_, msg) => msg,
-            ChatCompletionError::JsonSerializationError(msg) => msg,
-        };
         HttpResponse::build(self.status_code())
-            .json(create_error_response(message))
+            .json(create_error_response(&self.client_message()))
+    }
+}
+
+impl ChatCompletionError {
+    /// The message shown to API clients, whatever the front-end's error format.
+    pub(crate) fn client_message(&self) -> String {
+        match self {
+            ChatCompletionError::InvalidApiKey => "Missing or invalid Authorization header".to_string(),
+            ChatCompletionError::ModelNotFound(model) => {
+                format!("The model '{}' does not exist or you do not have access to it", model)
+            },
+            ChatCompletionError::RequestConstructionError(msg) => msg.clone(),
+            ChatCompletionError::DifyApiError(_, msg) => msg.clone(),
+            ChatCompletionError::JsonSerializationError(msg) => msg.clone(),
+        }
     }
 }
 
@@ -47,6 +75,7 @@ impl std::fmt::Display for ChatCompletionError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::InvalidApiKey => write!(f, "Missing or invalid Authorization header"),
+            Self::ModelNotFound(model) => write!(f, "Model not found: {}", model),
             Self::RequestConstructionError(msg) => write!(f, "Request construction error: {}", msg),
             Self::DifyApiError(status, msg) => write!(f, "Dify API error ({}): {}", status, msg),
             Self::JsonSerializationError(msg) => write!(f, "JSON serialization error: {}", msg),
@@ -59,7 +88,7 @@ fn reqwest_to_actix_status(status: StatusCode) -> actix_web::http::StatusCode {
         .unwrap_or(actix_web::http::StatusCode::BAD_GATEWAY)
 }
 
-fn create_error_response(message: &str) -> serde_json::Value {
+pub(crate) fn create_error_response(message: &str) -> serde_json::Value {
     json!({
         "error": {
             "message": message,
@@ -68,40 +97,20 @@ fn create_error_response(message: &str) -> serde_json::Value {
     })
 }
 
-fn extract_api_key(req: &HttpRequest) -> Option<String> {
-    req.headers()
-        .get("Authorization")
-        .and_then(|auth_header| auth_header.to_str().ok())
-        .and_then(|auth_str| {
-            if auth_str.starts_with("Bearer ") {
-                Some(auth_str[7..].to_string())
-            } else {
-                None
-            }
-        })
-}
-
-async fn construct_dify_request(
+pub(crate) async fn construct_dify_request(
     openai_req: &OpenAIRequest,
     user: Option<String>,
+    inputs: serde_json::Map<String, serde_json::Value>,
 ) -> Result<DifyRequest, ChatCompletionError> {
-    // Convert messages to query format
+    // Dify takes one query string; messages are separated by a blank line
     let query = openai_req.messages.iter()
-        .map(|msg| {
-            match &msg.content {
-                crate::features::common::types::MessageContent::String(s) => s.clone(),
-                crate::features::common::types::MessageContent::Complex(contents) => {
-                    contents.iter()
-                        .map(|c| c.text.clone())
-                        .collect::<Vec<String>>()
-                        .join(" ")
-                }
-            }
-        })
-        .collect();
+        .map(|msg| msg.content.to_text())
+        .filter(|text| !text.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n\n");
 
     let dify_request = DifyRequest {
-        inputs: json!({}),
+        inputs: serde_json::Value::Object(inputs),
         query,
         response_mode: if openai_req.stream.unwrap_or(false) {
             "streaming".to_string()
@@ -113,115 +122,262 @@ async fn construct_dify_request(
         top_p: openai_req.top_p,
         max_tokens: openai_req.max_tokens,
         tools: openai_req.tools.clone(),
+        files: None,
+        conversation_id: None,
     };
 
     Ok(dify_request)
 }
 
-fn transform_dify_to_openai_stream(dify_event: DifyEvent) -> OpenAIResponse {
-    OpenAIResponse {
-        id: dify_event.message_id,
-        object: "chat.completion.chunk".to_string(),
-        created: dify_event.created_at,
-        model: "gpt-3.5-turbo".to_string(), // Default model
-        choices: vec![OpenAIChoice {
-            index: 0,
-            delta: OpenAIDelta {
-                role: None,
-                content: Some(dify_event.answer),
-                tool_calls: dify_event.tool_calls,
-                files: dify_event.files,
-            },
-            finish_reason: None,
-        }],
-        usage: None,
+/// Reads token usage from the `metadata` of a Dify blocking response or `message_end` event.
+pub(crate) fn extract_usage(metadata: &serde_json::Value) -> Option<Usage> {
+    let usage = metadata.get("usage")?;
+    Some(Usage {
+        prompt_tokens: usage["prompt_tokens"].as_u64().unwrap_or(0) as u32,
+        completion_tokens: usage["completion_tokens"].as_u64().unwrap_or(0) as u32,
+        total_tokens: usage["total_tokens"].as_u64().unwrap_or(0) as u32,
+    })
+}
+
+/// Adds one call's token usage to a running total.
+pub(crate) fn add_usage(total: &mut Option<Usage>, usage: Usage) {
+    let total = total.get_or_insert(Usage { prompt_tokens: 0, completion_tokens: 0, total_tokens: 0 });
+    total.prompt_tokens += usage.prompt_tokens;
+    total.completion_tokens += usage.completion_tokens;
+    total.total_tokens += usage.total_tokens;
+}
+
+pub(crate) type StreamResponse = Pin<Box<dyn Stream<Item = Result<Bytes, actix_web::Error>> + Send>>;
+
+pub(crate) type DifyEventStream = Pin<Box<dyn Stream<Item = Result<serde_json::Value, reqwest::Error>> + Send>>;
+
+/// Parses a Dify SSE response into its JSON events.
+///
+/// Lines are buffered across network chunks, so an event split over two reads is
+/// still delivered whole.
+pub(crate) fn dify_event_stream(response: reqwest::Response) -> DifyEventStream {
+    Box::pin(response
+        .bytes_stream()
+        .scan(Vec::new(), |buffer: &mut Vec<u8>, chunk_result| {
+            let events = match chunk_result {
+                Ok(chunk) => {
+                    buffer.extend_from_slice(&chunk);
+                    drain_sse_events(buffer).into_iter().map(Ok).collect()
+                },
+                Err(e) => vec![Err(e)],
+            };
+            future::ready(Some(stream::iter(events)))
+        })
+        .flatten())
+}
+
+fn drain_sse_events(buffer: &mut Vec<u8>) -> Vec<serde_json::Value> {
+    let mut events = Vec::new();
+    while let Some(pos) = buffer.iter().position(|b| *b == b'\n') {
+        let line: Vec<u8> = buffer.drain(..=pos).collect();
+        let line = String::from_utf8_lossy(&line);
+        if let Some(data) = line.trim().strip_prefix("data:") {
+            match serde_json::from_str(data.trim()) {
+                Ok(event) => events.push(event),
+                Err(e) => debug!("Skipping unparseable Dify event ({}): {}", e, data),
+            }
+        }
     }
+    events
 }
 
-fn transform_dify_to_openai_blocking(dify_response: serde_json::Value) -> Result<OpenAIResponse, ChatCompletionError> {
-    // Log the raw response for debugging
-    debug!("Raw Dify response: {:?}", dify_response);
+/// Frames a value as an SSE `data:` event.
+pub(crate) fn sse_event<T: Serialize>(value: &T) -> Bytes {
+    match serde_json::to_string(value) {
+        Ok(json) => Bytes::from(format!("data: {}\n\n", json)),
+        Err(_) => Bytes::new(),
+    }
+}
 
-    let answer = dify_response["answer"]
-        .as_str()
-        .ok_or_else(|| ChatCompletionError::JsonSerializationError("Missing 'answer' field".to_string()))?
-        .to_string();
+/// Frames a value as a named SSE event.
+pub(crate) fn sse_named_event<T: Serialize>(name: &str, value: &T) -> Bytes {
+    match serde_json::to_string(value) {
+        Ok(json) => Bytes::from(format!("event: {}\ndata: {}\n\n", name, json)),
+        Err(_) => Bytes::new(),
+    }
+}
 
-    let message_id = dify_response["message_id"]
-        .as_str()
-        .ok_or_else(|| ChatCompletionError::JsonSerializationError("Missing 'message_id' field".to_string()))?
-        .to_string();
+/// Frames a value as an SSE event named after its `type` field, as the
+/// Anthropic and Responses streams do.
+pub(crate) fn sse_typed_event<T: Serialize>(value: &T) -> Bytes {
+    match serde_json::to_value(value) {
+        Ok(json) => sse_named_event(json["type"].as_str().unwrap_or_default(), &json),
+        Err(_) => Bytes::new(),
+    }
+}
 
-    let created_at = dify_response["created_at"]
-        .as_u64()
-        .ok_or_else(|| ChatCompletionError::JsonSerializationError("Missing or invalid 'created_at' field".to_string()))?;
+pub(crate) fn sse_done() -> Bytes {
+    Bytes::from_static(b"data: [DONE]\n\n")
+}
 
-    Ok(OpenAIResponse {
-        id: message_id,
-        object: "chat.completion".to_string(),
-        created: created_at,
-        model: "gpt-3.5-turbo".to_string(),
-        choices: vec![OpenAIChoice {
-            index: 0,
-            delta: OpenAIDelta {
-                role: Some("assistant".to_string()),
-                content: Some(answer),
-                tool_calls: None, // We'll add tool calls support if needed
-                files: None,
-            },
-            finish_reason: Some("stop".to_string()),
-        }],
-        usage: None,
-    })
+/// Wraps an SSE body stream in a response with the usual streaming headers.
+pub(crate) fn sse_response(stream: StreamResponse) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header(("Content-Type", "text/event-stream"))
+        .insert_header(("Cache-Control", "no-cache"))
+        .insert_header(("Connection", "keep-alive"))
+        .streaming(stream.filter(|chunk| future::ready(!matches!(chunk, Ok(bytes) if bytes.is_empty()))))
+}
+
+/// Message text of a Dify `error` stream event.
+pub(crate) fn dify_stream_error(event: &serde_json::Value) -> String {
+    event["message"].as_str().unwrap_or("Unknown Dify error").to_string()
 }
 
-type StreamResponse = Pin<Box<dyn Stream<Item = Result<Bytes, actix_web::Error>> + Send>>;
+/// Per-choice state of a streamed chat completion.
+struct ChoiceState {
+    index: u32,
+    /// Completion id shared by all choices when `n > 1`; otherwise Dify's `message_id` is used.
+    id: Option<String>,
+    created: u64,
+    model: String,
+    /// Audio item id when the request asked for audio. Text then goes to
+    /// `delta.audio.transcript`.
+    audio_id: Option<String>,
+    /// Receives the `message_id` once the answer is complete, when suggested
+    /// questions were requested.
+    answered: Option<Arc<Mutex<Option<String>>>>,
+    message_id: String,
+    /// Characters of answer text sent so far, for annotation indices.
+    text_len: usize,
+}
 
-fn process_chunk(chunk: Bytes) -> Result<Bytes, actix_web::Error> {
-    let chunk_str = String::from_utf8_lossy(&chunk);
-    let events: Vec<&str> = chunk_str.trim().split('\n').collect();
-    
-    let mut transformed_chunks = Vec::new();
-    for event in events {
-        if event.starts_with("data: ") {
-            let data = &event[6..];
-            if data == "[DONE]" {
-                transformed_chunks.push(b"data: [DONE]\n\n".to_vec());
-                continue;
-            }
+impl ChoiceState {
+    fn chunk(&self, delta: OpenAIDelta, finish_reason: Option<&str>, usage: Option<Usage>) -> Bytes {
+        sse_event(&OpenAIResponse {
+            id: self.id.clone().unwrap_or_default(),
+            object: "chat.completion.chunk".to_string(),
+            created: self.created,
+            model: self.model.clone(),
+            choices: vec![OpenAIChoice {
+                index: self.index,
+                delta,
+                finish_reason: finish_reason.map(String::from),
+            }],
+            usage,
+            metadata: None,
+        })
+    }
 
-            if let Ok(dify_event) = serde_json::from_str::<DifyEvent>(data) {
-                let openai_response = transform_dify_to_openai_stream(dify_event);
-                if let Ok(json) = serde_json::to_string(&openai_response) {
-                    transformed_chunks.push(format!("data: {}\n\n", json).into_bytes());
+    fn render(&mut self, event: AnswerEvent) -> Bytes {
+        match event {
+            AnswerEvent::Started { message_id, created, .. } => {
+                self.id.get_or_insert_with(|| message_id.clone());
+                self.message_id = message_id;
+                self.created = created;
+                Bytes::new()
+            },
+            AnswerEvent::Text(text) => {
+                self.text_len += text.chars().count();
+                let delta = match &self.audio_id {
+                    Some(audio_id) => OpenAIDelta {
+                        audio: Some(AudioDelta { id: Some(audio_id.clone()), data: None, transcript: Some(text) }),
+                        ..Default::default()
+                    },
+                    None => OpenAIDelta { content: Some(text), ..Default::default() },
+                };
+                self.chunk(delta, None, None)
+            },
+            AnswerEvent::Attachments { tool_calls, files } => {
+                self.chunk(OpenAIDelta { tool_calls, files, ..Default::default() }, None, None)
+            },
+            AnswerEvent::Audio(data) => {
+                let audio = AudioDelta { id: self.audio_id.clone(), data: Some(data), transcript: None };
+                self.chunk(OpenAIDelta { audio: Some(audio), ..Default::default() }, None, None)
+            },
+            AnswerEvent::Finished { usage, citations, .. } => {
+                if let Some(answered) = &self.answered {
+                    *answered.lock().unwrap() = Some(self.message_id.clone());
                 }
-            }
+                let annotations = (!citations.is_empty())
+                    .then(|| citations.annotations(self.text_len, AnnotationStyle::Chat));
+                self.chunk(OpenAIDelta { annotations, ..Default::default() }, Some("stop"), usage)
+            },
+            AnswerEvent::Error(message) => sse_event(&create_error_response(&message)),
         }
     }
+}
+
+/// Streams a chat completion with one Dify answer per choice, merged into one
+/// chunk stream. With `suggest`, a last chunk carries the suggested questions
+/// in `metadata`.
+#[allow(clippy::too_many_arguments)]
+async fn stream_chat_completion(
+    state: &AppState,
+    req: &HttpRequest,
+    client: &Client,
+    chat: &DifyChat,
+    n: usize,
+    model: String,
+    suggest: bool,
+) -> Result<HttpResponse, ChatCompletionError> {
+    let id = (n > 1).then(|| format!("chatcmpl-{}", Uuid::new_v4()));
+    let mut cancel = CancelContext::new(state, req, &chat.target.api_key, &chat.request.user, TaskKind::Chat);
+    cancel.completion_id = id.clone();
+    let answers = future::try_join_all((0..n).map(|_| chat.stream_with(client, cancel.clone()))).await?;
+
+    let answered = suggest.then(|| Arc::new(Mutex::new(None)));
+    let suggestions = suggestions_chunk(answered.clone(), model.clone(), cancel);
+    let choices = answers.into_iter().enumerate().map(|(index, events)| {
+        let mut choice = ChoiceState {
+            index: index as u32,
+            id: id.clone(),
+            created: 0,
+            model: model.clone(),
+            audio_id: chat.speech.then(|| format!("audio_{}", Uuid::new_v4().simple())),
+            answered: answered.clone(),
+            message_id: String::new(),
+            text_len: 0,
+        };
+        events.map(move |event| choice.render(event))
+    });
 
-    Ok(Bytes::from(transformed_chunks.concat()))
+    Ok(sse_response(Box::pin(stream::select_all(choices)
+        .chain(stream::once(suggestions))
+        .chain(stream::once(future::ready(sse_done())))
+        .map(Ok))))
 }
 
-fn handle_streaming_response(response: reqwest::Response) -> StreamResponse {
-    Box::pin(response
-        .bytes_stream()
-        .map(|chunk_result| {
-            chunk_result
-                .map_err(|e| actix_web::error::ErrorInternalServerError(e))
-                .and_then(process_chunk)
-        }))
+/// The chunk with the suggested questions for a finished answer; empty when
+/// none were requested or the answer didn't finish.
+async fn suggestions_chunk(answered: Option<Arc<Mutex<Option<String>>>>, model: String, cancel: CancelContext) -> Bytes {
+    let Some(message_id) = answered.and_then(|answered| answered.lock().unwrap().take()) else {
+        return Bytes::new();
+    };
+    let Ok(client) = build_client() else { return Bytes::new() };
+    match suggested_questions(&client, &cancel.dify_api_url, &cancel.api_key, &message_id, &cancel.user).await {
+        Some(questions) => sse_event(&OpenAIResponse {
+            id: message_id,
+            object: "chat.completion.chunk".to_string(),
+            created: chrono::Utc::now().timestamp() as u64,
+            model,
+            choices: Vec::new(),
+            usage: None,
+            metadata: Some(suggestions_metadata(questions)),
+        }),
+        None => Bytes::new(),
+    }
 }
 
-async fn send_request_to_dify(
-    client: &Client,
-    url: &str,
+pub(crate) fn build_client() -> Result<Client, ChatCompletionError> {
+    Client::builder()
+        .timeout(Duration::from_secs(30))
+        .build()
+        .map_err(|e| ChatCompletionError::RequestConstructionError(e.to_string()))
+}
+
+/// Sends a request to Dify with the app key and turns non-2xx responses into `DifyApiError`.
+pub(crate) async fn send_to_dify(
+    request: reqwest::RequestBuilder,
     api_key: &str,
-    body: &DifyRequest,
-    is_streaming: bool,
-) -> Result<HttpResponse, ChatCompletionError> {
-    let response = client.post(url)
+) -> Result<reqwest::Response, ChatCompletionError> {
+    let response = request
         .header("Authorization", format!("Bearer {}", api_key))
-        .json(body)
         .send()
         .await
         .map_err(|e| ChatCompletionError::DifyApiError(
@@ -230,50 +386,403 @@ async fn send_request_to_dify(
         ))?;
 
     let status = response.status();
-    
+
     if !status.is_success() {
         let error_text = response.text().await
             .unwrap_or_else(|_| "Unknown error".to_string());
         return Err(ChatCompletionError::DifyApiError(status, error_text));
     }
 
-    if is_streaming {
-        Ok(HttpResponse::Ok()
-            .insert_header(("Content-Type", "text/event-stream"))
-            .insert_header(("Cache-Control", "no-cache"))
-            .insert_header(("Connection", "keep-alive"))
-            .streaming(handle_streaming_response(response)))
-    } else {
-        // First get the raw JSON value to inspect it
-        let raw_json = response.json::<serde_json::Value>().await
-            .map_err(|e| ChatCompletionError::JsonSerializationError(format!("Failed to parse JSON: {}", e)))?;
-        
-        // Transform the raw JSON into OpenAI format
-        let openai_response = transform_dify_to_openai_blocking(raw_json)?;
-        Ok(HttpResponse::Ok().json(openai_response))
+    Ok(response)
+}
+
+/// POSTs a JSON body to Dify and turns non-2xx responses into `DifyApiError`.
+pub(crate) async fn post_to_dify<T: Serialize>(
+    client: &Client,
+    url: &str,
+    api_key: &str,
+    body: &T,
+) -> Result<reqwest::Response, ChatCompletionError> {
+    send_to_dify(client.post(url).json(body), api_key).await
+}
+
+/// GETs a Dify endpoint and turns non-2xx responses into `DifyApiError`.
+pub(crate) async fn get_from_dify(
+    client: &Client,
+    url: &str,
+    api_key: &str,
+) -> Result<reqwest::Response, ChatCompletionError> {
+    send_to_dify(client.get(url), api_key).await
+}
+
+/// Reads a blocking Dify response body as JSON.
+pub(crate) async fn read_dify_json(response: reqwest::Response) -> Result<serde_json::Value, ChatCompletionError> {
+    response.json::<serde_json::Value>().await
+        .map_err(|e| ChatCompletionError::JsonSerializationError(format!("Failed to parse JSON: {}", e)))
+}
+
+/// Asks Dify once per choice and combines the answers.
+async fn blocking_chat_completion(
+    state: &AppState,
+    client: &Client,
+    chat: &DifyChat,
+    n: usize,
+    model: String,
+    suggest: bool,
+) -> Result<HttpResponse, ChatCompletionError> {
+    let answers = future::try_join_all((0..n).map(|_| chat.answer(client))).await?;
+
+    let mut usage: Option<Usage> = None;
+    let mut choices = Vec::with_capacity(answers.len());
+    for (index, answer) in answers.iter().enumerate() {
+        if let Some(answer_usage) = answer.usage.clone() {
+            add_usage(&mut usage, answer_usage);
+        }
+        let annotations = (!answer.citations.is_empty())
+            .then(|| answer.citations.annotations(answer.text.chars().count(), AnnotationStyle::Chat));
+        choices.push(OpenAIChoice {
+            index: index as u32,
+            delta: OpenAIDelta {
+                role: Some("assistant".to_string()),
+                content: Some(answer.text.clone()),
+                annotations,
+                ..Default::default()
+            },
+            finish_reason: Some("stop".to_string()),
+        });
+    }
+
+    let (mut id, created) = answers.first().map(|a| (a.message_id.clone(), a.created)).unwrap_or_default();
+    if n > 1 {
+        id = format!("chatcmpl-{}", Uuid::new_v4());
     }
+    let metadata = match suggest {
+        true => suggested_questions(client, &state.dify_api_url, &chat.target.api_key, &id, &chat.request.user).await
+            .map(suggestions_metadata),
+        false => None,
+    };
+
+    Ok(HttpResponse::Ok().json(OpenAIResponse {
+        id,
+        object: "chat.completion".to_string(),
+        created,
+        model,
+        choices,
+        usage,
+        metadata,
+    }))
 }
 
 pub async fn chat_completion(
     req: HttpRequest,
+    state: web::Data<AppState>,
     body: web::Json<OpenAIRequest>,
 ) -> Result<HttpResponse, ChatCompletionError> {
-    lazy_static! {
-        static ref DIFY_URL: String = env::var("DIFY_URL").expect("DIFY_URL must be set");
+    // Pin the config for the lifetime of this request so a reload can't affect it
+    let config = state.config.snapshot();
+    let target = resolve_target(&req, &config, body.model.as_deref())?;
+
+    let user = body.user.clone().or(target.default_user.clone());
+    let model = body.model.clone().unwrap_or_else(|| "gpt-3.5-turbo".to_string());
+    let input_mapping = target.model_config.as_ref().map(|m| m.inputs.clone()).unwrap_or_default();
+    let inputs = build_inputs(&req, &body, &input_mapping)?;
+    let policy = config.param_policy(body.model.as_deref());
+
+    let n = body.n.unwrap_or(1).max(1) as usize;
+    if n > MAX_CHOICES {
+        return Err(ChatCompletionError::RequestConstructionError(format!(
+            "At most {} choices (n) are supported per request",
+            MAX_CHOICES
+        )));
     }
+    let stop = body.stop.as_ref().map(|s| s.to_vec()).unwrap_or_default();
 
-    let api_key = extract_api_key(&req)
-        .ok_or(ChatCompletionError::InvalidApiKey)?;
+    let client = build_client()?;
+    // The caches see the request as sent, before file search rewrites it
+    let request = body.into_inner();
+    let mut body = request.clone();
+    let mut inputs = inputs;
+    apply_file_search(&client, &state.dify_api_url, &req, &config, target.model_config.as_ref(), &mut body, &mut inputs).await?;
 
-    let client = Client::builder()
-        .timeout(Duration::from_secs(30))
-        .build()
-        .map_err(|e| ChatCompletionError::RequestConstructionError(e.to_string()))?;
+    if let Some(model_config) = &target.model_config {
+        match model_config.app_type {
+            AppType::Workflow => {
+                // Workflow outputs aren't fanned out or truncated by the proxy
+                let mut extra = Vec::new();
+                if n > 1 {
+                    extra.push("n");
+                }
+                if !stop.is_empty() {
+                    extra.push("stop");
+                }
+                if output_format(body.response_format.as_ref())?.is_some() {
+                    extra.push("response_format");
+                }
+                if body.wants_audio() {
+                    extra.push("modalities");
+                }
+                if body.suggested_questions == Some(true) {
+                    extra.push("suggested_questions");
+                }
+                if body.citation_markers == Some(true) {
+                    extra.push("citation_markers");
+                }
+                let ignored = check_params(unsupported_params(&body, &extra), policy)?;
 
-    let is_streaming = body.stream.unwrap_or(false);
-    let dify_request = construct_dify_request(&body, body.user.clone()).await?;
+                let workflow = model_config.workflow.clone().unwrap_or_default();
+                let user = user.unwrap_or_else(|| "default_user".to_string());
+                let inputs = workflow_request_inputs(&state, &client, &target.api_key, &body, inputs, &workflow).await?;
+                let slots = match cached(&state, &req, &client, &target, &request, &model, &user, &inputs).await {
+                    Cached::Hit(hit) => return Ok(with_ignored_params(hit, &ignored)),
+                    Cached::Miss(slots) => slots,
+                };
+                let cancel = CancelContext::new(&state, &req, &target.api_key, &user, TaskKind::Workflow);
+                let response = workflow_chat_completion(&state, &client, &target.api_key, &body, inputs, workflow, user, model, cancel).await?;
+                return Ok(store_in_cache(&state, slots, with_ignored_params(response, &ignored)).await);
+            },
+            AppType::Completion => {
+                return Err(ChatCompletionError::RequestConstructionError(format!(
+                    "The model '{}' is a completion app; use /v1/completions instead",
+                    model
+                )));
+            },
+            AppType::Chat => {},
+        }
+    }
+
+    // Dify only streams speech, and structured outputs are answered in blocking mode
+    let format = output_format(body.response_format.as_ref())?;
+    let audio = body.wants_audio() && body.stream.unwrap_or(false) && format.is_none();
+    let mut extra = Vec::new();
+    if body.wants_audio() && !audio {
+        extra.push("modalities");
+    }
+    if body.audio.as_ref().and_then(|a| a.format.as_deref()).is_some_and(|f| f != "mp3") {
+        extra.push("audio.format");
+    }
+    // Dify speaks with the app's TTS voice
+    if body.audio.as_ref().is_some_and(|a| a.voice.is_some()) {
+        extra.push("audio.voice");
+    }
+    // Suggestions follow a single Dify message
+    let suggest = body.suggested_questions == Some(true) && n == 1 && format.is_none();
+    if body.suggested_questions == Some(true) && !suggest {
+        extra.push("suggested_questions");
+    }
+    // Structured answers are validated whole, so they can't be cut at a stop sequence
+    if format.is_some() && !stop.is_empty() {
+        extra.push("stop");
+    }
+    // Markers would break structured output
+    let markers = body.citation_markers == Some(true) && format.is_none();
+    if body.citation_markers == Some(true) && !markers {
+        extra.push("citation_markers");
+    }
+    let ignored = check_params(unsupported_params(&body, &extra), policy)?;
 
-    let final_url = format!("{}/v1/chat-messages", DIFY_URL.as_str());
+    let has_images = body.messages.iter().any(|m| m.content.has_images());
+    validate_request(&state.parameters, &client, &state.dify_api_url, &target.api_key, &inputs, has_images).await?;
+    let dify_user = user.clone().unwrap_or_else(|| "default_user".to_string());
+    let slots = match cached(&state, &req, &client, &target, &request, &model, &dify_user, &inputs).await {
+        Cached::Hit(hit) => return Ok(with_ignored_params(hit, &ignored)),
+        Cached::Miss(slots) => slots,
+    };
+    let mut chat = DifyChat::new(&state, &req, &client, target, &body, inputs).await?;
+    if format.is_some() {
+        chat.stop.clear();
+    }
+    chat.markers = markers;
+    chat.speech = audio;
+
+    let response = match format {
+        Some(format) => {
+            let structured = chat.target.model_config.as_ref().map(|m| m.structured_output.clone()).unwrap_or_default();
+            structured_chat_completion(&client, &chat, format, &structured, n, model).await?
+        },
+        None if body.stream.unwrap_or(false) => {
+            stream_chat_completion(&state, &req, &client, &chat, n, model, suggest).await?
+        },
+        None => blocking_chat_completion(&state, &client, &chat, n, model, suggest).await?,
+    };
+    Ok(store_in_cache(&state, slots, with_ignored_params(response, &ignored)).await)
+}
+
+/// Where a response goes after a cache miss.
+struct CacheSlots {
+    exact: Option<(Arc<ResponseCache>, String)>,
+    semantic: Option<SemanticSlot>,
+}
+
+enum Cached {
+    Hit(HttpResponse),
+    Miss(CacheSlots),
+}
 
-    send_request_to_dify(&client, &final_url, &api_key, &dify_request, is_streaming).await
+/// Looks the request up in the exact and semantic caches. Only called once the
+/// request has passed the parameter policy and app validation, so a hit can't
+/// answer a request that would otherwise be rejected.
+#[allow(clippy::too_many_arguments)]
+async fn cached(
+    state: &AppState,
+    req: &HttpRequest,
+    client: &Client,
+    target: &DifyTarget,
+    request: &OpenAIRequest,
+    model: &str,
+    user: &str,
+    inputs: &serde_json::Map<String, serde_json::Value>,
+) -> Cached {
+    let exact = state.response_cache.clone()
+        .and_then(|cache| cache.key(req, request, &target.api_key, user, inputs).map(|key| (cache, key)));
+    if let Some((cache, key)) = &exact {
+        if let Some(hit) = cache.lookup(key).await {
+            return Cached::Hit(hit);
+        }
+    }
+
+    let semantic = match target.model_config.as_ref().and_then(|m| m.semantic_cache.as_ref()) {
+        Some(semantic_config) => {
+            let tenant = extract_api_key(req).unwrap_or_default();
+            state.semantic_cache.lookup(client, semantic_config, model, &tenant, request, user, inputs).await
+        },
+        None => None,
+    };
+    let semantic = match semantic {
+        Some(SemanticLookup::Hit { answer, similarity }) => {
+            return Cached::Hit(hit_response(answer, similarity, model, request.stream.unwrap_or(false)));
+        },
+        Some(SemanticLookup::Miss(slot)) => Some(slot),
+        None => None,
+    };
+    Cached::Miss(CacheSlots { exact, semantic })
+}
+
+/// Stores the response in the exact and semantic caches the request was eligible for.
+async fn store_in_cache(state: &AppState, slots: CacheSlots, mut response: HttpResponse) -> HttpResponse {
+    if let Some((cache, key)) = slots.exact {
+        response = cache.store(key, response).await;
+    }
+    if let Some(slot) = slots.semantic {
+        response = state.semantic_cache.store(slot, response).await;
+    }
+    response
+}
+
+/// Lists parameters ignored under the lenient policy in a response header.
+pub(crate) fn with_ignored_params(mut response: HttpResponse, ignored: &[&str]) -> HttpResponse {
+    if !ignored.is_empty() {
+        if let Ok(value) = HeaderValue::from_str(&ignored.join(", ")) {
+            response.headers_mut().insert(HeaderName::from_static(IGNORED_PARAMS_HEADER), value);
+        }
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    use crate::features::dify::cancellation::ActiveStreams;
+    use crate::features::dify::citations::Citations;
+    use super::super::suggested::tests::fake_dify;
+
+    fn choice(id: Option<&str>, audio_id: Option<&str>) -> ChoiceState {
+        ChoiceState {
+            index: 1,
+            id: id.map(String::from),
+            created: 0,
+            model: "m".to_string(),
+            audio_id: audio_id.map(String::from),
+            answered: Some(Arc::new(Mutex::new(None))),
+            message_id: String::new(),
+            text_len: 0,
+        }
+    }
+
+    fn started() -> AnswerEvent {
+        AnswerEvent::Started { message_id: "m1".to_string(), conversation_id: "c1".to_string(), created: 7 }
+    }
+
+    fn data(chunk: Bytes) -> Value {
+        let chunk = String::from_utf8(chunk.to_vec()).unwrap();
+        serde_json::from_str(chunk.trim().trim_start_matches("data: ")).unwrap()
+    }
+
+    #[test]
+    fn choices_take_the_dify_message_id_unless_shared() {
+        let mut state = choice(None, None);
+        assert!(state.render(started()).is_empty());
+        let chunk = data(state.render(AnswerEvent::Text("Hi".to_string())));
+        assert_eq!(chunk["id"], "m1");
+        assert_eq!(chunk["created"], 7);
+        assert_eq!(chunk["choices"][0]["index"], 1);
+        assert_eq!(chunk["choices"][0]["delta"]["content"], "Hi");
+
+        let mut shared = choice(Some("chatcmpl-1"), None);
+        shared.render(started());
+        assert_eq!(data(shared.render(AnswerEvent::Text("Hi".to_string())))["id"], "chatcmpl-1");
+    }
+
+    #[test]
+    fn audio_requests_stream_a_transcript() {
+        let mut state = choice(None, Some("audio_1"));
+        state.render(started());
+        let text = data(state.render(AnswerEvent::Text("Hi".to_string())));
+        assert_eq!(text["choices"][0]["delta"]["audio"], serde_json::json!({"id": "audio_1", "transcript": "Hi"}));
+        let audio = data(state.render(AnswerEvent::Audio("UklG".to_string())));
+        assert_eq!(audio["choices"][0]["delta"]["audio"], serde_json::json!({"id": "audio_1", "data": "UklG"}));
+    }
+
+    #[test]
+    fn the_finish_chunk_carries_usage_and_annotations() {
+        let mut state = choice(None, None);
+        state.render(started());
+        state.render(AnswerEvent::Text("Héllo".to_string()));
+        let citations = Citations::new(&serde_json::json!({"retriever_resources": [{"document_id": "d1"}]}), false);
+        let usage = Usage { prompt_tokens: 1, completion_tokens: 2, total_tokens: 3 };
+
+        let finish = data(state.render(AnswerEvent::Finished { usage: Some(usage), stop: None, citations }));
+        assert_eq!(finish["choices"][0]["finish_reason"], "stop");
+        assert_eq!(finish["usage"]["total_tokens"], 3);
+        assert_eq!(finish["choices"][0]["delta"]["annotations"][0]["file_citation"]["start_index"], 5);
+        assert_eq!(state.answered.unwrap().lock().unwrap().as_deref(), Some("m1"));
+    }
+
+    fn cancel_context(dify_api_url: &str) -> CancelContext {
+        CancelContext {
+            registry: Arc::new(ActiveStreams::default()),
+            dify_api_url: dify_api_url.to_string(),
+            api_key: "app-key".to_string(),
+            user: "u".to_string(),
+            owner: "key".to_string(),
+            kind: TaskKind::Chat,
+            completion_id: None,
+        }
+    }
+
+    #[actix_web::test]
+    async fn the_last_chunk_carries_the_suggestions() {
+        let (url, _) = fake_dify(200, serde_json::json!({"result": "success", "data": ["Why?"]})).await;
+        let answered = Some(Arc::new(Mutex::new(Some("m1".to_string()))));
+
+        let chunk = data(suggestions_chunk(answered, "m".to_string(), cancel_context(&url)).await);
+        assert_eq!(chunk["id"], "m1");
+        assert_eq!(chunk["object"], "chat.completion.chunk");
+        assert_eq!(chunk["model"], "m");
+        assert_eq!(chunk["choices"], serde_json::json!([]));
+        assert_eq!(chunk["metadata"], serde_json::json!({"suggested_questions": ["Why?"]}));
+    }
+
+    #[actix_web::test]
+    async fn no_suggestion_chunk_without_a_finished_answer_or_questions() {
+        let context = cancel_context("http://127.0.0.1:9");
+        assert!(suggestions_chunk(None, "m".to_string(), context.clone()).await.is_empty());
+        let unanswered = Some(Arc::new(Mutex::new(None)));
+        assert!(suggestions_chunk(unanswered, "m".to_string(), context).await.is_empty());
+
+        let (url, _) = fake_dify(500, serde_json::json!({"message": "boom"})).await;
+        let answered = Some(Arc::new(Mutex::new(Some("m1".to_string()))));
+        assert!(suggestions_chunk(answered, "m".to_string(), cancel_context(&url)).await.is_empty());
+    }
 }
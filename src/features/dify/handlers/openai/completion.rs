@@ -0,0 +1,324 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use bytes::Bytes;
+use chrono::Utc;
+use futures_util::{future, stream, Stream, StreamExt};
+use std::pin::Pin;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::features::app::app_state::AppState;
+use crate::features::auth::resolver::resolve_target;
+use crate::features::common::types::{
+    CompletionChoice, CompletionPrompt, DifyCompletionRequest, OpenAICompletionRequest,
+    OpenAICompletionResponse, OpenAIMessage, OpenAIRequest, Usage,
+};
+use crate::features::config::proxy_config::AppType;
+use crate::features::dify::cancellation::{cancel_on_disconnect, CancelContext, TaskKind};
+use super::chat_completion::{
+    build_client, create_error_response, dify_event_stream, dify_stream_error, extract_usage,
+    post_to_dify, read_dify_json, sse_done, sse_event, sse_response, with_ignored_params, ChatCompletionError,
+    DifyEventStream, StreamResponse,
+};
+use crate::features::dify::parameters::validate_request;
+use super::inputs::build_inputs;
+use super::params::{check_params, unsupported_params, MAX_CHOICES};
+use super::stop::{truncate_at_stop, StopFilter};
+
+fn completion_chunk(id: &str, model: &str, index: u32, text: String, finish_reason: Option<String>) -> OpenAICompletionResponse {
+    OpenAICompletionResponse {
+        id: id.to_string(),
+        object: "text_completion".to_string(),
+        created: Utc::now().timestamp() as u64,
+        model: model.to_string(),
+        choices: vec![CompletionChoice {
+            text,
+            index,
+            logprobs: None,
+            finish_reason,
+        }],
+        usage: None,
+    }
+}
+
+type ChoiceStream = Pin<Box<dyn Stream<Item = Bytes> + Send>>;
+
+/// Translates one Dify completion stream into `text_completion` chunks for choice `index`.
+fn handle_choice_stream(
+    response: reqwest::Response,
+    id: String,
+    model: String,
+    index: u32,
+    stop: Vec<String>,
+    cancel: CancelContext,
+) -> ChoiceStream {
+    choice_chunks(cancel_on_disconnect(dify_event_stream(response), cancel), id, model, index, stop)
+}
+
+/// The chunks of one choice. It finishes at `message_end` or at a stop
+/// sequence, and fails on an error or when Dify closes the stream first.
+/// Ending early drops the Dify stream, which stops the task.
+fn choice_chunks(events: DifyEventStream, id: String, model: String, index: u32, stop: Vec<String>) -> ChoiceStream {
+    let chunk = move |text: String, finish_reason: Option<&str>| {
+        sse_event(&completion_chunk(&id, &model, index, text, finish_reason.map(String::from)))
+    };
+    Box::pin(events
+        .map(Some)
+        .chain(stream::once(future::ready(None)))
+        .scan((StopFilter::new(stop), false), move |(filter, done), event| {
+            if *done {
+                return future::ready(None);
+            }
+            let error = |message: &str| sse_event(&create_error_response(message));
+            let bytes = match event {
+                Some(Ok(event)) => match event["event"].as_str() {
+                    Some("message") => {
+                        let text = filter.push(event["answer"].as_str().unwrap_or_default());
+                        let mut chunks = Vec::new();
+                        if !text.is_empty() {
+                            chunks.push(chunk(text, None));
+                        }
+                        if filter.stopped() {
+                            *done = true;
+                            chunks.push(chunk(String::new(), Some("stop")));
+                        }
+                        Bytes::from(chunks.concat())
+                    },
+                    Some("message_end") => {
+                        *done = true;
+                        chunk(filter.finish(), Some("stop"))
+                    },
+                    Some("error") => {
+                        *done = true;
+                        error(&dify_stream_error(&event))
+                    },
+                    _ => Bytes::new(),
+                },
+                Some(Err(e)) => {
+                    *done = true;
+                    error(&e.to_string())
+                },
+                None => {
+                    *done = true;
+                    error("Dify closed the stream before the completion finished")
+                },
+            };
+            future::ready(Some(bytes))
+        }))
+}
+
+/// A prompt as a chat request, so the model's input mapping applies to it as
+/// it does to chat completions (`{{last_user_message}}` is the prompt).
+fn as_chat_request(body: &OpenAICompletionRequest, prompt: &str) -> OpenAIRequest {
+    OpenAIRequest {
+        messages: vec![OpenAIMessage::text("user", prompt.to_string())],
+        model: Some(body.model.clone()),
+        max_tokens: body.max_tokens,
+        temperature: body.temperature,
+        top_p: body.top_p,
+        user: body.user.clone(),
+        metadata: body.metadata.clone(),
+        dify_inputs: body.dify_inputs.clone(),
+        ..Default::default()
+    }
+}
+
+pub async fn completion(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: web::Json<OpenAICompletionRequest>,
+) -> Result<HttpResponse, ChatCompletionError> {
+    let config = state.config.snapshot();
+    let target = resolve_target(&req, &config, Some(&body.model))?;
+
+    if let Some(model_config) = &target.model_config {
+        if model_config.app_type != AppType::Completion {
+            return Err(ChatCompletionError::RequestConstructionError(format!(
+                "The model '{}' is not a completion app; use /v1/chat/completions instead",
+                body.model
+            )));
+        }
+    }
+
+    let prompts = match &body.prompt {
+        CompletionPrompt::Single(prompt) => vec![prompt.clone()],
+        CompletionPrompt::Batch(prompts) => prompts.clone(),
+    };
+    let n = body.n.unwrap_or(1).max(1) as usize;
+    if prompts.is_empty() {
+        return Err(ChatCompletionError::RequestConstructionError("'prompt' must not be empty".to_string()));
+    }
+    if prompts.len() * n > MAX_CHOICES {
+        return Err(ChatCompletionError::RequestConstructionError(format!(
+            "At most {} choices (prompts x n) are supported per request",
+            MAX_CHOICES
+        )));
+    }
+
+    // Sampling parameters are checked as they are for chat completions
+    let ignored = check_params(
+        unsupported_params(&as_chat_request(&body, ""), &[]),
+        config.param_policy(Some(&body.model)),
+    )?;
+
+    let is_streaming = body.stream.unwrap_or(false);
+    let stop = body.stop.as_ref().map(|s| s.to_vec()).unwrap_or_default();
+    let prompt_variable = target.model_config.as_ref()
+        .and_then(|m| m.prompt_variable.clone())
+        .unwrap_or_else(|| "query".to_string());
+    let user = body.user.clone()
+        .or(target.default_user.clone())
+        .unwrap_or_else(|| "default_user".to_string());
+
+    let client = build_client()?;
+    let url = format!("{}/v1/completion-messages", state.dify_api_url);
+    let input_mapping = target.model_config.as_ref().map(|m| m.inputs.clone()).unwrap_or_default();
+
+    let mut prompt_inputs = Vec::with_capacity(prompts.len());
+    for prompt in &prompts {
+        let mut inputs = build_inputs(&req, &as_chat_request(&body, prompt), &input_mapping)?;
+        inputs.insert(prompt_variable.clone(), json!(prompt));
+        validate_request(&state.parameters, &client, &state.dify_api_url, &target.api_key, &inputs, false).await?;
+        prompt_inputs.push(serde_json::Value::Object(inputs));
+    }
+
+    // OpenAI orders choices prompt-major: all `n` choices for prompt 0 come first
+    let requests: Vec<DifyCompletionRequest> = prompt_inputs.iter()
+        .flat_map(|inputs| (0..n).map(move |_| inputs))
+        .map(|inputs| DifyCompletionRequest {
+            inputs: inputs.clone(),
+            response_mode: if is_streaming { "streaming" } else { "blocking" }.to_string(),
+            user: user.clone(),
+        })
+        .collect();
+
+    let responses = future::try_join_all(
+        requests.iter().map(|r| post_to_dify(&client, &url, &target.api_key, r))
+    ).await?;
+
+    let id = format!("cmpl-{}", Uuid::new_v4());
+
+    if is_streaming {
+        let mut cancel = CancelContext::new(&state, &req, &target.api_key, &user, TaskKind::Completion);
+        cancel.completion_id = Some(id.clone());
+        let choices = responses.into_iter()
+            .enumerate()
+            .map(|(index, response)| handle_choice_stream(response, id.clone(), body.model.clone(), index as u32, stop.clone(), cancel.clone()));
+        let merged: StreamResponse = Box::pin(stream::select_all(choices)
+            .chain(stream::once(future::ready(sse_done())))
+            .map(Ok));
+        return Ok(with_ignored_params(sse_response(merged), &ignored));
+    }
+
+    let mut choices = Vec::with_capacity(responses.len());
+    let mut usage = Usage { prompt_tokens: 0, completion_tokens: 0, total_tokens: 0 };
+    for (index, response) in responses.into_iter().enumerate() {
+        let raw_json = read_dify_json(response).await?;
+        let answer = raw_json["answer"]
+            .as_str()
+            .ok_or_else(|| ChatCompletionError::JsonSerializationError("Missing 'answer' field".to_string()))?;
+        let (text, _) = truncate_at_stop(answer, &stop);
+
+        if let Some(choice_usage) = extract_usage(&raw_json["metadata"]) {
+            usage.prompt_tokens += choice_usage.prompt_tokens;
+            usage.completion_tokens += choice_usage.completion_tokens;
+            usage.total_tokens += choice_usage.total_tokens;
+        }
+
+        choices.push(CompletionChoice {
+            text,
+            index: index as u32,
+            logprobs: None,
+            finish_reason: Some("stop".to_string()),
+        });
+    }
+
+    let response = HttpResponse::Ok().json(OpenAICompletionResponse {
+        id,
+        object: "text_completion".to_string(),
+        created: Utc::now().timestamp() as u64,
+        model: body.model.clone(),
+        choices,
+        usage: Some(usage),
+    });
+    Ok(with_ignored_params(response, &ignored))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::features::config::proxy_config::ParamPolicy;
+
+    fn body(extra: serde_json::Value) -> OpenAICompletionRequest {
+        let mut body = json!({"model": "m", "prompt": "Once upon a time", "user": "u", "metadata": {"topic": "t"}});
+        body.as_object_mut().unwrap().extend(extra.as_object().unwrap().clone());
+        serde_json::from_value(body).unwrap()
+    }
+
+    #[test]
+    fn prompts_become_single_user_messages() {
+        let openai_req = as_chat_request(&body(json!({})), "Once upon a time");
+        assert_eq!(openai_req.messages.len(), 1);
+        assert_eq!(openai_req.messages[0].role, "user");
+        assert_eq!(openai_req.messages[0].content.to_text(), "Once upon a time");
+        assert_eq!(openai_req.user.as_deref(), Some("u"));
+        assert_eq!(openai_req.metadata.unwrap()["topic"], "t");
+    }
+
+    #[test]
+    fn sampling_params_are_accepted_as_for_chat() {
+        let openai_req = as_chat_request(&body(json!({"max_tokens": 5, "temperature": 0.2, "top_p": 1.0})), "");
+        assert_eq!((openai_req.max_tokens, openai_req.temperature, openai_req.top_p), (Some(5), Some(0.2), Some(1.0)));
+        assert!(check_params(unsupported_params(&openai_req, &[]), ParamPolicy::Strict).unwrap().is_empty());
+    }
+
+    async fn run(events: Vec<serde_json::Value>, stop: &[&str]) -> Vec<serde_json::Value> {
+        let events: DifyEventStream = Box::pin(stream::iter(events.into_iter().map(Ok)));
+        let stop = stop.iter().map(|s| s.to_string()).collect();
+        let chunks: Vec<Bytes> = choice_chunks(events, "cmpl-1".to_string(), "m".to_string(), 0, stop).collect().await;
+        chunks.iter()
+            .flat_map(|chunk| String::from_utf8_lossy(chunk).lines().map(String::from).collect::<Vec<_>>())
+            .filter_map(|line| line.strip_prefix("data: ").map(|data| serde_json::from_str(data).unwrap()))
+            .collect()
+    }
+
+    fn message(answer: &str) -> serde_json::Value {
+        json!({"event": "message", "answer": answer})
+    }
+
+    #[actix_web::test]
+    async fn choices_finish_at_message_end() {
+        let chunks = run(vec![message("Once "), message("more"), json!({"event": "message_end"})], &[]).await;
+        let text: String = chunks.iter().filter_map(|c| c["choices"][0]["text"].as_str()).collect();
+        assert_eq!(text, "Once more");
+        assert_eq!(chunks.last().unwrap()["choices"][0]["finish_reason"], "stop");
+    }
+
+    #[actix_web::test]
+    async fn stop_sequences_end_the_choice() {
+        let chunks = run(vec![message("Hello ST"), message("OP more"), message("ignored"), json!({"event": "message_end"})], &["STOP"]).await;
+        let text: String = chunks.iter().filter_map(|c| c["choices"][0]["text"].as_str()).collect();
+        assert_eq!(text, "Hello ");
+        assert_eq!(chunks.last().unwrap()["choices"][0]["finish_reason"], "stop");
+        assert_eq!(chunks.iter().filter(|c| !c["choices"][0]["finish_reason"].is_null()).count(), 1);
+    }
+
+    #[actix_web::test]
+    async fn streams_closed_before_message_end_fail() {
+        let chunks = run(vec![message("Once")], &[]).await;
+        assert_eq!(chunks[0]["choices"][0]["text"], "Once");
+        assert_eq!(chunks[1]["error"]["message"], "Dify closed the stream before the completion finished");
+        assert_eq!(chunks.len(), 2);
+
+        let chunks = run(vec![json!({"event": "error", "message": "quota exceeded"}), message("late")], &[]).await;
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0]["error"]["message"], "quota exceeded");
+    }
+
+    #[test]
+    fn chunks_are_text_completions() {
+        let chunk = completion_chunk("cmpl-1", "m", 2, "Hi".to_string(), Some("stop".to_string()));
+        assert_eq!(chunk.object, "text_completion");
+        assert_eq!((chunk.choices[0].index, chunk.choices[0].text.as_str()), (2, "Hi"));
+        assert_eq!(chunk.choices[0].finish_reason.as_deref(), Some("stop"));
+    }
+}
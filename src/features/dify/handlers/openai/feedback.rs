@@ -0,0 +1,56 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::features::app::app_state::AppState;
+use crate::features::auth::resolver::authenticate;
+use super::chat_completion::{build_client, post_to_dify, ChatCompletionError};
+
+/// Feedback on a completion. `rating` is `like`, `dislike`, or `null` to
+/// withdraw earlier feedback.
+#[derive(Debug, Deserialize)]
+pub struct FeedbackRequest {
+    pub rating: Option<String>,
+    #[serde(default)]
+    pub content: Option<String>,
+}
+
+/// Forwards a rating to Dify `/messages/{message_id}/feedbacks`. The
+/// completion id is Dify's `message_id`; the app and user are those the
+/// answer was given to, so only the key that got it can rate it. Completions
+/// with `n > 1`, whose id is the proxy's own, can't be rated.
+pub async fn completion_feedback(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    body: web::Json<FeedbackRequest>,
+) -> Result<HttpResponse, ChatCompletionError> {
+    let body = body.into_inner();
+    let owner = authenticate(&req, &state.config.snapshot())?;
+    let completion_id = path.into_inner();
+    let answer = state.messages.get(&completion_id, &owner).ok_or_else(|| {
+        ChatCompletionError::DifyApiError(
+            reqwest::StatusCode::NOT_FOUND,
+            format!("No completion found with id '{}'", completion_id),
+        )
+    })?;
+    if body.rating.as_deref().is_some_and(|r| r != "like" && r != "dislike") {
+        return Err(ChatCompletionError::RequestConstructionError(
+            "'rating' must be 'like', 'dislike' or null".to_string(),
+        ));
+    }
+
+    let url = format!("{}/v1/messages/{}/feedbacks", state.dify_api_url, completion_id);
+    let payload = json!({
+        "rating": body.rating,
+        "user": answer.user,
+        "content": body.content,
+    });
+    post_to_dify(&build_client()?, &url, &answer.api_key, &payload).await?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "id": completion_id,
+        "object": "chat.completion.feedback",
+        "rating": body.rating,
+    })))
+}
@@ -0,0 +1,157 @@
+use actix_web::HttpRequest;
+use futures_util::future::try_join_all;
+use reqwest::Client;
+use serde_json::{Map, Value};
+
+use crate::features::auth::resolver::resolve_dataset;
+use crate::features::common::types::{MessageContent, OpenAIMessage, OpenAIRequest, Tool, VectorStoreSearchResult};
+use crate::features::config::proxy_config::{AppType, ModelConfig, ProxyConfig};
+use crate::features::knowledge::dataset::retrieve;
+use super::chat_completion::ChatCompletionError;
+
+/// Excerpts injected when a `file_search` tool doesn't set `max_num_results`.
+const DEFAULT_FILE_SEARCH_RESULTS: u32 = 5;
+
+/// Runs the request's `file_search` tools against the configured knowledge
+/// bases with the last user message and hands the excerpts to Dify. The tools
+/// themselves are removed from the request.
+pub(crate) async fn apply_file_search(
+    client: &Client,
+    dify_api_url: &str,
+    req: &HttpRequest,
+    config: &ProxyConfig,
+    model_config: Option<&ModelConfig>,
+    openai_req: &mut OpenAIRequest,
+    inputs: &mut Map<String, Value>,
+) -> Result<(), ChatCompletionError> {
+    let Some(tools) = openai_req.tools.take() else { return Ok(()) };
+    let (searches, tools): (Vec<Tool>, Vec<Tool>) = tools.into_iter().partition(|t| t.r#type == "file_search");
+    openai_req.tools = Some(tools).filter(|t| !t.is_empty());
+    if searches.is_empty() {
+        return Ok(());
+    }
+
+    let file_search = model_config.map(|m| m.file_search.clone()).unwrap_or_default();
+    if file_search.context_input.is_none() && model_config.is_some_and(|m| m.app_type == AppType::Workflow) {
+        return Err(ChatCompletionError::RequestConstructionError(
+            "file_search on a workflow model needs file_search.context_input in the model config".to_string(),
+        ));
+    }
+    let Some(last_user) = openai_req.messages.iter().rposition(|m| m.role == "user") else { return Ok(()) };
+    let query = openai_req.messages[last_user].content.to_text();
+
+    let mut searches_to_run = Vec::new();
+    let mut limit = 0;
+    for tool in &searches {
+        let ids = tool.vector_store_ids.as_deref().unwrap_or_default();
+        if ids.is_empty() {
+            return Err(ChatCompletionError::RequestConstructionError(
+                "file_search tools need at least one vector_store_id".to_string(),
+            ));
+        }
+        limit = limit.max(tool.max_num_results.unwrap_or(DEFAULT_FILE_SEARCH_RESULTS));
+        for id in ids {
+            let dataset = resolve_dataset(req, config, id)?;
+            searches_to_run.push((dataset, tool.max_num_results));
+        }
+    }
+
+    let found = try_join_all(searches_to_run.iter().map(|(dataset, max_num_results)| {
+        retrieve(client, dify_api_url, dataset, &query, *max_num_results, None)
+    })).await?;
+    let mut results: Vec<VectorStoreSearchResult> = found.into_iter().flatten().collect();
+    results.sort_by(|a, b| b.score.total_cmp(&a.score));
+    results.truncate(limit as usize);
+    if results.is_empty() {
+        return Ok(());
+    }
+
+    let context = render_context(&results);
+    match file_search.context_input {
+        Some(variable) => {
+            inputs.insert(variable, Value::String(context));
+        },
+        None => openai_req.messages.insert(last_user, OpenAIMessage {
+            role: "system".to_string(),
+            content: MessageContent::String(context),
+            function_call: None,
+            tool_calls: None,
+        }),
+    }
+    Ok(())
+}
+
+/// Numbered excerpts with their source documents.
+fn render_context(results: &[VectorStoreSearchResult]) -> String {
+    let excerpts: Vec<String> = results.iter()
+        .enumerate()
+        .map(|(i, result)| {
+            let text: Vec<&str> = result.content.iter().map(|c| c.text.as_str()).collect();
+            format!("[{}] {}\n{}", i + 1, result.filename, text.join("\n"))
+        })
+        .collect();
+    format!(
+        "Use the following excerpts from the knowledge base where they are relevant.\n\n{}",
+        excerpts.join("\n\n")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use serde_json::json;
+
+    use crate::features::common::types::SearchResultContent;
+
+    fn request(tools: Value) -> OpenAIRequest {
+        serde_json::from_value(json!({
+            "model": "m",
+            "messages": [{"role": "user", "content": "What is Reveles?"}],
+            "tools": tools,
+        })).unwrap()
+    }
+
+    async fn apply(openai_req: &mut OpenAIRequest, model_config: Option<&ModelConfig>) -> Result<(), ChatCompletionError> {
+        let req = TestRequest::default().insert_header(("Authorization", "Bearer app-key")).to_http_request();
+        let config = ProxyConfig::default();
+        apply_file_search(&Client::new(), "http://dify", &req, &config, model_config, openai_req, &mut Map::new()).await
+    }
+
+    #[actix_web::test]
+    async fn other_tools_are_left_alone() {
+        let function = json!({"type": "function", "function": {"name": "f", "description": "", "parameters": {}}});
+        let mut openai_req = request(json!([function]));
+        apply(&mut openai_req, None).await.unwrap();
+        assert_eq!(openai_req.tools.unwrap().len(), 1);
+        assert_eq!(openai_req.messages.len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn searches_need_a_vector_store() {
+        let mut openai_req = request(json!([{"type": "file_search"}]));
+        assert!(apply(&mut openai_req, None).await.is_err());
+        assert!(openai_req.tools.is_none());
+    }
+
+    #[actix_web::test]
+    async fn workflows_need_a_context_input() {
+        let model_config: ModelConfig = serde_json::from_value(json!({"app_key": "app-1", "app_type": "workflow"})).unwrap();
+        let mut openai_req = request(json!([{"type": "file_search", "vector_store_ids": ["kb"]}]));
+        assert!(apply(&mut openai_req, Some(&model_config)).await.is_err());
+    }
+
+    #[test]
+    fn excerpts_are_numbered_with_their_sources() {
+        let result = |filename: &str, text: &str| VectorStoreSearchResult {
+            file_id: String::new(),
+            filename: filename.to_string(),
+            score: 1.0,
+            attributes: Map::new(),
+            content: vec![SearchResultContent { r#type: "text".to_string(), text: text.to_string() }],
+        };
+
+        let context = render_context(&[result("a.md", "One"), result("b.md", "Two")]);
+        assert!(context.ends_with("\n\n[1] a.md\nOne\n\n[2] b.md\nTwo"));
+    }
+}
@@ -0,0 +1,171 @@
+use actix_web::HttpRequest;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+use crate::features::common::types::OpenAIRequest;
+use crate::features::config::proxy_config::InputSource;
+use super::chat_completion::ChatCompletionError;
+
+/// Builds Dify `inputs` from the model's input mapping, then applies the
+/// request's explicit `dify_inputs` on top. Sources that resolve to nothing
+/// are left out so Dify defaults (and required-input checks) still apply.
+pub(crate) fn build_inputs(
+    req: &HttpRequest,
+    openai_req: &OpenAIRequest,
+    mapping: &HashMap<String, InputSource>,
+) -> Result<Map<String, Value>, ChatCompletionError> {
+    let body = serde_json::to_value(openai_req)
+        .map_err(|e| ChatCompletionError::JsonSerializationError(e.to_string()))?;
+
+    let mut inputs = Map::new();
+    for (variable, source) in mapping {
+        let value = match source {
+            InputSource::Metadata { key } => openai_req.metadata.as_ref().and_then(|m| m.get(key).cloned()),
+            InputSource::Body { path } => lookup_path(&body, path).cloned(),
+            InputSource::Header { name } => req.headers()
+                .get(name.as_str())
+                .and_then(|h| h.to_str().ok())
+                .map(|h| Value::String(h.to_string())),
+            InputSource::Template { template } => Some(Value::String(render_template(template, openai_req))),
+            InputSource::Value { value } => Some(value.clone()),
+        };
+        if let Some(value) = value.filter(|v| !v.is_null()) {
+            inputs.insert(variable.clone(), value);
+        }
+    }
+
+    if let Some(explicit) = &openai_req.dify_inputs {
+        inputs.extend(explicit.clone());
+    }
+
+    Ok(inputs)
+}
+
+fn lookup_path<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(value, |current, segment| current.get(segment))
+}
+
+/// Renders `{{...}}` placeholders over the conversation.
+///
+/// Supported: `last_user_message`, `system`, `history` (all messages before
+/// the last one as `role: content` lines), `message_count` and `metadata.KEY`.
+/// Unknown placeholders render as empty strings.
+pub(crate) fn render_template(template: &str, openai_req: &OpenAIRequest) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        match after.find("}}") {
+            Some(end) => {
+                out.push_str(&resolve_placeholder(after[..end].trim(), openai_req));
+                rest = &after[end + 2..];
+            },
+            None => {
+                out.push_str(&rest[start..]);
+                rest = "";
+            },
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn resolve_placeholder(expr: &str, openai_req: &OpenAIRequest) -> String {
+    let messages = &openai_req.messages;
+    match expr {
+        "last_user_message" => messages.iter()
+            .rev()
+            .find(|m| m.role == "user")
+            .map(|m| m.content.to_text())
+            .unwrap_or_default(),
+        "system" => messages.iter()
+            .filter(|m| m.role == "system")
+            .map(|m| m.content.to_text())
+            .collect::<Vec<_>>()
+            .join("\n"),
+        "history" => messages.iter()
+            .take(messages.len().saturating_sub(1))
+            .map(|m| format!("{}: {}", m.role, m.content.to_text()))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        "message_count" => messages.len().to_string(),
+        _ => match expr.strip_prefix("metadata.") {
+            Some(key) => openai_req.metadata.as_ref()
+                .and_then(|m| m.get(key))
+                .map(|v| v.as_str().map(String::from).unwrap_or_else(|| v.to_string()))
+                .unwrap_or_default(),
+            None => String::new(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use serde_json::json;
+
+    use crate::features::common::types::OpenAIMessage;
+
+    fn request() -> OpenAIRequest {
+        OpenAIRequest {
+            messages: vec![
+                OpenAIMessage::text("system", "Be brief.".to_string()),
+                OpenAIMessage::text("user", "Hi".to_string()),
+                OpenAIMessage::text("assistant", "Hello".to_string()),
+                OpenAIMessage::text("user", "Bye".to_string()),
+            ],
+            model: Some("m".to_string()),
+            metadata: serde_json::from_value(json!({"topic": "rust", "count": 3})).unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn templates_render_over_the_conversation() {
+        let rendered = render_template(
+            "{{system}}|{{ last_user_message }}|{{message_count}}|{{metadata.topic}}|{{metadata.count}}|{{nope}}",
+            &request(),
+        );
+        assert_eq!(rendered, "Be brief.|Bye|4|rust|3|");
+        assert_eq!(render_template("{{history}}", &request()), "system: Be brief.\nuser: Hi\nassistant: Hello");
+        assert_eq!(render_template("open {{system", &request()), "open {{system");
+    }
+
+    #[test]
+    fn inputs_come_from_every_source() {
+        let mapping: HashMap<String, InputSource> = serde_json::from_value(json!({
+            "topic": {"from": "metadata", "key": "topic"},
+            "model": {"from": "body", "path": "model"},
+            "tenant": {"from": "header", "name": "x-tenant"},
+            "prompt": {"from": "template", "template": "{{last_user_message}}"},
+            "fixed": {"from": "value", "value": 1},
+            "missing": {"from": "metadata", "key": "missing"},
+        })).unwrap();
+        let req = TestRequest::default().insert_header(("x-tenant", "acme")).to_http_request();
+
+        let inputs = build_inputs(&req, &request(), &mapping).unwrap();
+        assert_eq!(Value::Object(inputs), json!({
+            "topic": "rust",
+            "model": "m",
+            "tenant": "acme",
+            "prompt": "Bye",
+            "fixed": 1,
+        }));
+    }
+
+    #[test]
+    fn explicit_inputs_win() {
+        let mapping: HashMap<String, InputSource> =
+            serde_json::from_value(json!({"topic": {"from": "metadata", "key": "topic"}})).unwrap();
+        let openai_req = OpenAIRequest {
+            dify_inputs: serde_json::from_value(json!({"topic": "go"})).unwrap(),
+            ..request()
+        };
+
+        let inputs = build_inputs(&TestRequest::default().to_http_request(), &openai_req, &mapping).unwrap();
+        assert_eq!(inputs["topic"], "go");
+    }
+}
@@ -0,0 +1,419 @@
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+/// Most subschemas `check` lets apply to one value. Shared `$ref`s under
+/// `allOf`/`anyOf`/`oneOf` can multiply to far more than the schema's size.
+const MAX_EXPANSION: usize = 1_000;
+
+/// Most `validate` steps for one answer, in case an instance is large enough
+/// to make even a checked schema expensive.
+const MAX_STEPS: usize = 1_000_000;
+
+/// Validates `value` against a JSON Schema.
+///
+/// Covers the subset used by OpenAI structured outputs: `type`, `enum`,
+/// `const`, `properties`, `required`, `additionalProperties`, `items`,
+/// `anyOf`/`oneOf`/`allOf`, local `$ref`s (`#/$defs/...`), and the length and
+/// range keywords. `pattern` and `format` are not checked. The error names the
+/// first failing location, e.g. `$.items[2].price: expected number`.
+pub(crate) fn validate(schema: &Value, value: &Value) -> Result<(), String> {
+    let mut steps = MAX_STEPS;
+    validate_at(schema, schema, value, "$", &mut Vec::new(), &mut steps)
+}
+
+/// Checks a client-supplied schema before it is used: every `$ref` must
+/// resolve, and no chain of `$ref`s and `allOf`/`anyOf`/`oneOf` may lead back
+/// to itself without descending into a property or item. Such a loop would
+/// never finish validating. Recursion through `properties` or `items` (trees,
+/// linked lists) is fine. Nor may those chains apply more than
+/// `MAX_EXPANSION` subschemas to one value.
+pub(crate) fn check(schema: &Value) -> Result<(), String> {
+    let mut states = HashMap::new();
+    let mut pending = vec![schema];
+    while let Some(node) = pending.pop() {
+        match node {
+            Value::Object(object) => {
+                check_loops(schema, node, &mut states)?;
+                pending.extend(object.values());
+            },
+            Value::Array(items) => pending.extend(items),
+            _ => {},
+        }
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Visit {
+    InProgress,
+    /// How many subschemas validating a value against this one applies.
+    Done(usize),
+}
+
+/// Depth-first search along the edges that validate the same value again.
+/// Returns how many subschemas `schema` applies to a value.
+fn check_loops<'a>(
+    root: &'a Value,
+    schema: &'a Value,
+    states: &mut HashMap<*const Value, Visit>,
+) -> Result<usize, String> {
+    let key = schema as *const Value;
+    match states.get(&key) {
+        Some(Visit::Done(expansion)) => return Ok(*expansion),
+        Some(Visit::InProgress) => return Err("$ref cycle that never reaches a value".to_string()),
+        None => {},
+    }
+    let Value::Object(object) = schema else { return Ok(1) };
+    states.insert(key, Visit::InProgress);
+
+    let mut expansion = 1usize;
+    if let Some(reference) = object.get("$ref").and_then(Value::as_str) {
+        let target = resolve_ref(root, reference)
+            .ok_or_else(|| format!("unresolvable $ref '{}'", reference))?;
+        expansion += check_loops(root, target, states)
+            .map_err(|e| format!("{} via '{}'", e, reference))?;
+    }
+    for keyword in ["allOf", "anyOf", "oneOf"] {
+        for sub in object.get(keyword).and_then(Value::as_array).into_iter().flatten() {
+            expansion = expansion.saturating_add(check_loops(root, sub, states)?);
+        }
+    }
+    if expansion > MAX_EXPANSION {
+        return Err(format!("$refs under allOf/anyOf/oneOf apply more than {} subschemas to one value", MAX_EXPANSION));
+    }
+
+    states.insert(key, Visit::Done(expansion));
+    Ok(expansion)
+}
+
+/// `refs` holds the `$ref`s followed at the current value; it starts empty for
+/// every property and item, so only loops that never descend are cut off.
+/// `steps` is what is left of the `MAX_STEPS` budget.
+fn validate_at<'a>(
+    root: &'a Value,
+    schema: &'a Value,
+    value: &Value,
+    path: &str,
+    refs: &mut Vec<&'a str>,
+    steps: &mut usize,
+) -> Result<(), String> {
+    if *steps == 0 {
+        return Err(format!("{}: the schema takes too many steps to validate", path));
+    }
+    *steps -= 1;
+    let schema = match schema {
+        Value::Bool(true) => return Ok(()),
+        Value::Bool(false) => return Err(format!("{}: no value is allowed here", path)),
+        Value::Object(schema) => schema,
+        _ => return Ok(()),
+    };
+
+    if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+        if refs.contains(&reference) {
+            return Err(format!("{}: $ref '{}' refers back to itself", path, reference));
+        }
+        let target = resolve_ref(root, reference)
+            .ok_or_else(|| format!("{}: unresolvable $ref '{}'", path, reference))?;
+        refs.push(reference);
+        let result = validate_at(root, target, value, path, refs, steps);
+        refs.pop();
+        result?;
+    }
+
+    if let Some(types) = schema.get("type") {
+        let matches = match types {
+            Value::String(t) => type_matches(t, value),
+            Value::Array(ts) => ts.iter().filter_map(Value::as_str).any(|t| type_matches(t, value)),
+            _ => true,
+        };
+        if !matches {
+            return Err(format!("{}: expected {}", path, describe_type(types)));
+        }
+    }
+
+    if let Some(options) = schema.get("enum").and_then(Value::as_array) {
+        if !options.contains(value) {
+            return Err(format!("{}: must be one of {}", path, Value::Array(options.clone())));
+        }
+    }
+    if let Some(expected) = schema.get("const") {
+        if expected != value {
+            return Err(format!("{}: must be {}", path, expected));
+        }
+    }
+
+    if let Some(all) = schema.get("allOf").and_then(Value::as_array) {
+        for sub in all {
+            validate_at(root, sub, value, path, refs, steps)?;
+        }
+    }
+    if let Some(any) = schema.get("anyOf").and_then(Value::as_array) {
+        if !any.iter().any(|sub| validate_at(root, sub, value, path, refs, steps).is_ok()) {
+            return Err(format!("{}: does not match any allowed schema", path));
+        }
+    }
+    if let Some(one) = schema.get("oneOf").and_then(Value::as_array) {
+        let matching = one.iter().filter(|sub| validate_at(root, sub, value, path, refs, steps).is_ok()).count();
+        if matching != 1 {
+            return Err(format!("{}: must match exactly one schema, matched {}", path, matching));
+        }
+    }
+
+    match value {
+        Value::Object(object) => {
+            for field in schema.get("required").and_then(Value::as_array).into_iter().flatten() {
+                if let Some(field) = field.as_str() {
+                    if !object.contains_key(field) {
+                        return Err(format!("{}: missing required property '{}'", path, field));
+                    }
+                }
+            }
+            let properties = schema.get("properties").and_then(Value::as_object);
+            for (key, item) in object {
+                let item_path = format!("{}.{}", path, key);
+                match properties.and_then(|p| p.get(key)) {
+                    Some(sub) => validate_at(root, sub, item, &item_path, &mut Vec::new(), steps)?,
+                    None => match schema.get("additionalProperties") {
+                        Some(Value::Bool(false)) => {
+                            return Err(format!("{}: unexpected property '{}'", path, key));
+                        },
+                        Some(sub) => validate_at(root, sub, item, &item_path, &mut Vec::new(), steps)?,
+                        None => {},
+                    },
+                }
+            }
+        },
+        Value::Array(items) => {
+            check_bound(schema, "minItems", items.len() as f64, path, |len, min| len >= min, "at least {} items")?;
+            check_bound(schema, "maxItems", items.len() as f64, path, |len, max| len <= max, "at most {} items")?;
+            if let Some(sub) = schema.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    validate_at(root, sub, item, &format!("{}[{}]", path, i), &mut Vec::new(), steps)?;
+                }
+            }
+        },
+        Value::String(s) => {
+            let len = s.chars().count() as f64;
+            check_bound(schema, "minLength", len, path, |len, min| len >= min, "at least {} characters")?;
+            check_bound(schema, "maxLength", len, path, |len, max| len <= max, "at most {} characters")?;
+        },
+        Value::Number(n) => {
+            let n = n.as_f64().unwrap_or_default();
+            check_bound(schema, "minimum", n, path, |n, min| n >= min, ">= {}")?;
+            check_bound(schema, "maximum", n, path, |n, max| n <= max, "<= {}")?;
+            check_bound(schema, "exclusiveMinimum", n, path, |n, min| n > min, "> {}")?;
+            check_bound(schema, "exclusiveMaximum", n, path, |n, max| n < max, "< {}")?;
+        },
+        _ => {},
+    }
+
+    Ok(())
+}
+
+fn check_bound(
+    schema: &serde_json::Map<String, Value>,
+    keyword: &str,
+    actual: f64,
+    path: &str,
+    ok: impl Fn(f64, f64) -> bool,
+    expected: &str,
+) -> Result<(), String> {
+    match schema.get(keyword).and_then(Value::as_f64) {
+        Some(bound) if !ok(actual, bound) => {
+            Err(format!("{}: must be {}", path, expected.replace("{}", &bound.to_string())))
+        },
+        _ => Ok(()),
+    }
+}
+
+fn type_matches(expected: &str, value: &Value) -> bool {
+    match expected {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        "number" => value.is_number(),
+        "integer" => value.as_i64().is_some()
+            || value.as_u64().is_some()
+            || value.as_f64().is_some_and(|f| f.fract() == 0.0),
+        _ => true,
+    }
+}
+
+fn describe_type(types: &Value) -> String {
+    match types {
+        Value::Array(ts) => ts.iter().filter_map(Value::as_str).collect::<Vec<_>>().join(" or "),
+        other => other.as_str().unwrap_or("a valid value").to_string(),
+    }
+}
+
+/// Resolves a local JSON pointer reference such as `#/$defs/Item`.
+fn resolve_ref<'a>(root: &'a Value, reference: &str) -> Option<&'a Value> {
+    let pointer = reference.strip_prefix('#')?;
+    root.pointer(pointer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn person() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "name": {"type": "string", "minLength": 1},
+                "age": {"type": "integer", "minimum": 0},
+                "tags": {"type": "array", "items": {"type": "string"}, "maxItems": 2},
+                "role": {"enum": ["admin", "user"]},
+            },
+            "required": ["name", "age"],
+            "additionalProperties": false,
+        })
+    }
+
+    #[test]
+    fn accepts_a_matching_object() {
+        assert_eq!(validate(&person(), &json!({"name": "a", "age": 3, "tags": ["x"], "role": "user"})), Ok(()));
+    }
+
+    #[test]
+    fn reports_the_failing_location() {
+        let schema = person();
+        assert_eq!(validate(&schema, &json!({"name": "a"})), Err("$: missing required property 'age'".to_string()));
+        assert_eq!(validate(&schema, &json!({"name": "a", "age": "3"})), Err("$.age: expected integer".to_string()));
+        assert_eq!(validate(&schema, &json!({"name": "a", "age": 3, "tags": ["x", 1]})), Err("$.tags[1]: expected string".to_string()));
+        assert_eq!(validate(&schema, &json!({"name": "a", "age": 3, "extra": 1})), Err("$: unexpected property 'extra'".to_string()));
+    }
+
+    #[test]
+    fn checks_bounds() {
+        let schema = person();
+        assert!(validate(&schema, &json!({"name": "", "age": 3})).is_err());
+        assert!(validate(&schema, &json!({"name": "a", "age": -1})).is_err());
+        assert!(validate(&schema, &json!({"name": "a", "age": 3, "tags": ["x", "y", "z"]})).is_err());
+        let exclusive = json!({"type": "number", "exclusiveMinimum": 0, "exclusiveMaximum": 1});
+        assert!(validate(&exclusive, &json!(0.5)).is_ok());
+        assert!(validate(&exclusive, &json!(0)).is_err());
+        assert!(validate(&exclusive, &json!(1)).is_err());
+    }
+
+    #[test]
+    fn string_length_counts_characters() {
+        let schema = json!({"type": "string", "maxLength": 2});
+        assert!(validate(&schema, &json!("éé")).is_ok());
+        assert!(validate(&schema, &json!("ééé")).is_err());
+    }
+
+    #[test]
+    fn integers_accept_whole_floats() {
+        let schema = json!({"type": "integer"});
+        assert!(validate(&schema, &json!(2.0)).is_ok());
+        assert!(validate(&schema, &json!(2.5)).is_err());
+    }
+
+    #[test]
+    fn checks_enum_and_const() {
+        assert!(validate(&person(), &json!({"name": "a", "age": 3, "role": "root"})).is_err());
+        assert!(validate(&json!({"const": 1}), &json!(1)).is_ok());
+        assert!(validate(&json!({"const": 1}), &json!(2)).is_err());
+    }
+
+    #[test]
+    fn combines_schemas() {
+        let any = json!({"anyOf": [{"type": "string"}, {"type": "null"}]});
+        assert!(validate(&any, &json!(null)).is_ok());
+        assert!(validate(&any, &json!(1)).is_err());
+        let one = json!({"oneOf": [{"type": "number"}, {"type": "integer"}]});
+        assert!(validate(&one, &json!(1.5)).is_ok());
+        assert_eq!(validate(&one, &json!(1)), Err("$: must match exactly one schema, matched 2".to_string()));
+        let all = json!({"allOf": [{"type": "string"}, {"minLength": 2}]});
+        assert!(validate(&all, &json!("a")).is_err());
+    }
+
+    #[test]
+    fn resolves_local_refs() {
+        let schema = json!({
+            "type": "array",
+            "items": {"$ref": "#/$defs/item"},
+            "$defs": {"item": {"type": "object", "required": ["id"]}},
+        });
+        assert!(validate(&schema, &json!([{"id": 1}])).is_ok());
+        assert_eq!(validate(&schema, &json!([{"id": 1}, {}])), Err("$[1]: missing required property 'id'".to_string()));
+        assert!(validate(&json!({"$ref": "#/$defs/missing"}), &json!(1)).is_err());
+    }
+
+    #[test]
+    fn rejects_ref_cycles() {
+        let own = json!({"$ref": "#"});
+        assert!(check(&own).is_err());
+        assert_eq!(validate(&own, &json!(1)), Err("$: $ref '#' refers back to itself".to_string()));
+
+        let mutual = json!({
+            "$ref": "#/$defs/a",
+            "$defs": {
+                "a": {"anyOf": [{"$ref": "#/$defs/b"}]},
+                "b": {"allOf": [{"$ref": "#/$defs/a"}]},
+            },
+        });
+        assert!(check(&mutual).is_err());
+        assert!(validate(&mutual, &json!(1)).is_err());
+        assert!(check(&json!({"$ref": "#/$defs/missing"})).is_err());
+    }
+
+    #[test]
+    fn rejects_schemas_that_expand_exponentially() {
+        // Each level refers to the next one twice, so a value meets 2^n subschemas
+        let mut defs = serde_json::Map::new();
+        for level in 0..20 {
+            let next = json!({"$ref": format!("#/$defs/l{}", level + 1)});
+            defs.insert(format!("l{}", level), json!({"anyOf": [next.clone(), next]}));
+        }
+        defs.insert("l20".to_string(), json!({"type": "string"}));
+        let schema = json!({"$ref": "#/$defs/l0", "$defs": defs.clone()});
+        let error = check(&schema).unwrap_err();
+        assert!(error.contains("more than 1000 subschemas"), "{}", error);
+
+        // The same sharing a few levels deep is fine
+        let shallow_defs: serde_json::Map<_, _> = defs.into_iter()
+            .filter(|(name, _)| name[1..].parse::<u32>().unwrap() >= 17)
+            .collect();
+        let shallow = json!({"$ref": "#/$defs/l17", "$defs": shallow_defs});
+        assert_eq!(check(&shallow), Ok(()));
+        assert!(validate(&shallow, &json!("a")).is_ok());
+    }
+
+    #[test]
+    fn validation_stops_at_the_step_budget() {
+        let schema = json!({"anyOf": [{"type": "string"}, {"type": "number"}]});
+        let mut steps = 2;
+        let result = validate_at(&schema, &schema, &json!(1), "$", &mut Vec::new(), &mut steps);
+        assert!(result.is_err());
+        assert_eq!(steps, 0);
+    }
+
+    #[test]
+    fn allows_recursion_through_values() {
+        let tree = json!({
+            "$ref": "#/$defs/node",
+            "$defs": {"node": {
+                "type": "object",
+                "properties": {"children": {"type": "array", "items": {"$ref": "#/$defs/node"}}},
+            }},
+        });
+        assert_eq!(check(&tree), Ok(()));
+        assert!(validate(&tree, &json!({"children": [{"children": []}]})).is_ok());
+        assert!(validate(&tree, &json!({"children": [{"children": 1}]})).is_err());
+    }
+
+    #[test]
+    fn boolean_schemas() {
+        assert!(validate(&json!(true), &json!({"any": "thing"})).is_ok());
+        let schema = json!({"type": "object", "additionalProperties": {"type": "number"}});
+        assert!(validate(&schema, &json!({"a": 1})).is_ok());
+        assert!(validate(&schema, &json!({"a": "1"})).is_err());
+        assert!(validate(&json!({"properties": {"a": false}}), &json!({"a": 1})).is_err());
+    }
+}
@@ -1,3 +1,14 @@
+pub mod cancel;
 pub mod chat_completion;
-pub mod transform;
+pub mod completion;
+pub mod feedback;
+pub mod file_search;
+pub mod inputs;
+pub mod json_schema;
+pub mod params;
+pub mod responses;
+pub mod stop;
+pub mod structured;
+pub mod suggested;
+pub mod workflow;
 //pub mod image;
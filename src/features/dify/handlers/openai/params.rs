@@ -0,0 +1,82 @@
+use log::warn;
+
+use crate::features::common::types::OpenAIRequest;
+use crate::features::config::proxy_config::ParamPolicy;
+use super::chat_completion::ChatCompletionError;
+
+/// Response header listing the parameters a lenient request ignored.
+pub(crate) const IGNORED_PARAMS_HEADER: &str = "x-ignored-params";
+
+/// Upper bound on choices per request (`n`, or `prompts x n` for completions),
+/// since every choice is a separate Dify call.
+pub(crate) const MAX_CHOICES: usize = 16;
+
+/// Request parameters that are set to something Dify can't honour. Neutral
+/// values (zero penalties) don't count.
+pub(crate) fn unsupported_params(openai_req: &OpenAIRequest, extra: &[&'static str]) -> Vec<&'static str> {
+    let mut params = Vec::new();
+    if openai_req.presence_penalty.is_some_and(|p| p != 0.0) {
+        params.push("presence_penalty");
+    }
+    if openai_req.frequency_penalty.is_some_and(|p| p != 0.0) {
+        params.push("frequency_penalty");
+    }
+    if openai_req.seed.is_some() {
+        params.push("seed");
+    }
+    if openai_req.logit_bias.as_ref().is_some_and(|b| !b.is_empty()) {
+        params.push("logit_bias");
+    }
+    params.extend_from_slice(extra);
+    params
+}
+
+/// Applies the policy to the unsupported parameters of a request. Returns the
+/// parameters that are being ignored.
+pub(crate) fn check_params(
+    params: Vec<&'static str>,
+    policy: ParamPolicy,
+) -> Result<Vec<&'static str>, ChatCompletionError> {
+    if params.is_empty() {
+        return Ok(params);
+    }
+    match policy {
+        ParamPolicy::Strict => Err(ChatCompletionError::RequestConstructionError(format!(
+            "Unsupported parameter(s) for this model: {}",
+            params.join(", ")
+        ))),
+        ParamPolicy::Lenient => {
+            warn!("Ignoring unsupported parameter(s): {}", params.join(", "));
+            Ok(params)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn neutral_values_are_supported() {
+        let openai_req = OpenAIRequest { presence_penalty: Some(0.0), frequency_penalty: Some(0.0), ..Default::default() };
+        assert!(unsupported_params(&openai_req, &[]).is_empty());
+    }
+
+    #[test]
+    fn unsupported_values_are_listed_with_the_extra_ones() {
+        let openai_req = OpenAIRequest {
+            presence_penalty: Some(0.5),
+            frequency_penalty: Some(-1.0),
+            seed: Some(7),
+            ..Default::default()
+        };
+        assert_eq!(unsupported_params(&openai_req, &["tools"]), ["presence_penalty", "frequency_penalty", "seed", "tools"]);
+    }
+
+    #[test]
+    fn the_policy_rejects_or_ignores() {
+        assert!(check_params(vec!["seed"], ParamPolicy::Strict).is_err());
+        assert_eq!(check_params(vec!["seed"], ParamPolicy::Lenient).unwrap(), ["seed"]);
+        assert!(check_params(Vec::new(), ParamPolicy::Strict).unwrap().is_empty());
+    }
+}
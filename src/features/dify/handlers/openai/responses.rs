@@ -0,0 +1,557 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use bytes::Bytes;
+use futures_util::{future, stream, StreamExt};
+use reqwest::StatusCode;
+use chrono::Utc;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::features::app::app_state::AppState;
+use crate::features::auth::resolver::extract_api_key;
+use crate::features::common::types::{
+    ComplexMessageContent, FilePart, ImageUrl, MessageContent, OpenAIMessage, OpenAIRequest, ResponseError,
+    ResponseInputContent, ResponseInputPart, ResponseObject, ResponseOutputItem, ResponseOutputText, ResponseStreamEvent, ResponseUsage,
+    ResponsesInput, ResponsesRequest, Usage,
+};
+use crate::features::dify::chat::{AnswerEvent, AnswerStream, DifyChat};
+use crate::features::dify::citations::{AnnotationStyle, Citations};
+use crate::features::dify::conversations::{ConversationTracker, TrackedConversation};
+use super::chat_completion::{
+    build_client, sse_response, sse_typed_event, with_ignored_params, ChatCompletionError, StreamResponse,
+};
+use super::params::{check_params, unsupported_params};
+
+/// `POST /v1/responses`: the OpenAI Responses API over a Dify chat app.
+///
+/// Responses are tracked by id, so `previous_response_id` continues the Dify
+/// conversation of an earlier response instead of resending the history. The
+/// tracking is in memory only: after a restart earlier ids are not found.
+pub async fn create_response(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: web::Json<ResponsesRequest>,
+) -> Result<HttpResponse, ChatCompletionError> {
+    let config = state.config.snapshot();
+    let owner = extract_api_key(&req).unwrap_or_default();
+    let mut openai_req = to_openai_request(&body)?;
+    let ignored = check_params(
+        unsupported_params(&openai_req, &unsupported_responses_params(&body)),
+        config.param_policy(Some(&body.model)),
+    )?;
+
+    // Dify conversations belong to one user, so the earlier response's user is kept
+    let previous = match &body.previous_response_id {
+        Some(id) => {
+            let conversation = state.conversations.get(id, &owner).ok_or_else(|| {
+                ChatCompletionError::DifyApiError(
+                    StatusCode::NOT_FOUND,
+                    format!("Previous response with id '{}' not found.", id),
+                )
+            })?;
+            openai_req.user = Some(conversation.user.clone());
+            Some(conversation)
+        },
+        None => None,
+    };
+
+    let client = build_client()?;
+    let mut chat = DifyChat::prepare(&state, &req, &config, &client, &openai_req).await?;
+    if let Some(previous) = previous {
+        if previous.api_key != chat.target.api_key {
+            return Err(ChatCompletionError::RequestConstructionError(format!(
+                "Previous response '{}' belongs to a different model",
+                body.previous_response_id.as_deref().unwrap_or_default()
+            )));
+        }
+        chat.request.conversation_id = Some(previous.conversation_id);
+    }
+
+    let tracking = body.store.unwrap_or(true).then(|| Tracking {
+        conversations: state.conversations.clone(),
+        owner,
+        api_key: chat.target.api_key.clone(),
+        user: chat.request.user.clone(),
+    });
+    let template = ResponseTemplate {
+        model: body.model.clone(),
+        instructions: body.instructions.clone(),
+        previous_response_id: body.previous_response_id.clone(),
+        metadata: body.metadata.clone().unwrap_or_default(),
+    };
+
+    let response = if body.stream.unwrap_or(false) {
+        let events = chat.stream(&client, &state, &req).await?;
+        sse_response(response_stream(events, template, tracking))
+    } else {
+        let answer = chat.answer(&client).await?;
+        if let Some(tracking) = &tracking {
+            tracking.record(&answer.message_id, &answer.conversation_id);
+        }
+        HttpResponse::Ok().json(template.object(
+            &answer.message_id,
+            answer.created,
+            "completed",
+            Some(annotated_text(answer.text, &answer.citations)),
+            answer.usage.as_ref(),
+        ))
+    };
+    Ok(with_ignored_params(response, &ignored))
+}
+
+/// Responses parameters Dify has no equivalent for.
+fn unsupported_responses_params(body: &ResponsesRequest) -> Vec<&'static str> {
+    let mut params = Vec::new();
+    if body.tools.as_ref().is_some_and(|t| !t.is_empty()) {
+        params.push("tools");
+    }
+    if body.tool_choice.is_some() {
+        params.push("tool_choice");
+    }
+    if body.reasoning.is_some() {
+        params.push("reasoning");
+    }
+    if body.text.as_ref().is_some_and(|t| t["format"]["type"].as_str().is_some_and(|f| f != "text")) {
+        params.push("text.format");
+    }
+    params
+}
+
+/// Translates a Responses request into the OpenAI request the Dify chat layer
+/// works from. `instructions` become a system message and `developer`
+/// messages count as system messages.
+fn to_openai_request(body: &ResponsesRequest) -> Result<OpenAIRequest, ChatCompletionError> {
+    let mut messages = Vec::new();
+    if let Some(instructions) = &body.instructions {
+        messages.push(OpenAIMessage::text("system", instructions.clone()));
+    }
+
+    match &body.input {
+        ResponsesInput::Text(text) => messages.push(OpenAIMessage::text("user", text.clone())),
+        ResponsesInput::Items(items) => {
+            for (index, item) in items.iter().enumerate() {
+                match item.r#type.as_deref().unwrap_or("message") {
+                    "message" => {
+                        let role = match item.role.as_deref().unwrap_or("user") {
+                            "developer" => "system",
+                            role => role,
+                        };
+                        let content = match &item.content {
+                            Some(ResponseInputContent::Text(text)) => MessageContent::String(text.clone()),
+                            Some(ResponseInputContent::Parts(parts)) => MessageContent::Complex(
+                                parts.iter()
+                                    .enumerate()
+                                    .map(|(part_index, part)| input_part(part, index, part_index))
+                                    .collect::<Result<_, _>>()?,
+                            ),
+                            None => MessageContent::String(String::new()),
+                        };
+                        messages.push(OpenAIMessage { role: role.to_string(), content, function_call: None, tool_calls: None });
+                    },
+                    "function_call_output" => {
+                        messages.push(OpenAIMessage::text("tool", item.output.clone().unwrap_or_default()));
+                    },
+                    other => {
+                        return Err(ChatCompletionError::RequestConstructionError(format!(
+                            "input[{}]: unsupported item type '{}'",
+                            index, other
+                        )));
+                    },
+                }
+            }
+        },
+    }
+
+    Ok(OpenAIRequest {
+        messages,
+        stream: body.stream,
+        temperature: body.temperature,
+        top_p: body.top_p,
+        max_tokens: body.max_output_tokens,
+        model: Some(body.model.clone()),
+        user: body.user.clone(),
+        metadata: body.metadata.clone(),
+        citation_markers: body.citation_markers,
+        ..Default::default()
+    })
+}
+
+fn input_part(
+    part: &ResponseInputPart,
+    index: usize,
+    part_index: usize,
+) -> Result<ComplexMessageContent, ChatCompletionError> {
+    let missing = |field: &str| ChatCompletionError::RequestConstructionError(format!(
+        "input[{}].content[{}]: missing '{}'",
+        index, part_index, field
+    ));
+    match part.r#type.as_str() {
+        "input_text" | "output_text" => Ok(ComplexMessageContent::text(part.text.clone().ok_or_else(|| missing("text"))?)),
+        "input_image" => Ok(ComplexMessageContent {
+            r#type: "image_url".to_string(),
+            text: String::new(),
+            image_url: Some(ImageUrl { url: part.image_url.clone().ok_or_else(|| missing("image_url"))?, detail: None }),
+            file: None,
+        }),
+        "input_file" => Ok(ComplexMessageContent {
+            r#type: "file".to_string(),
+            text: String::new(),
+            image_url: None,
+            file: Some(FilePart { file_id: Some(part.file_id.clone().ok_or_else(|| missing("file_id"))?), filename: None }),
+        }),
+        other => Err(ChatCompletionError::RequestConstructionError(format!(
+            "input[{}].content[{}]: unsupported content type '{}'",
+            index, part_index, other
+        ))),
+    }
+}
+
+/// Records which Dify conversation a response belongs to.
+struct Tracking {
+    conversations: Arc<ConversationTracker>,
+    owner: String,
+    api_key: String,
+    user: String,
+}
+
+impl Tracking {
+    fn record(&self, response_id: &str, conversation_id: &str) {
+        if conversation_id.is_empty() {
+            return;
+        }
+        self.conversations.record(response_id, TrackedConversation {
+            owner: self.owner.clone(),
+            api_key: self.api_key.clone(),
+            user: self.user.clone(),
+            conversation_id: conversation_id.to_string(),
+        });
+    }
+}
+
+/// The request fields echoed on every response object.
+struct ResponseTemplate {
+    model: String,
+    instructions: Option<String>,
+    previous_response_id: Option<String>,
+    metadata: serde_json::Map<String, serde_json::Value>,
+}
+
+impl ResponseTemplate {
+    /// A response object; `text` is the assistant message, if there is one yet.
+    fn object(
+        &self,
+        id: &str,
+        created: u64,
+        status: &str,
+        text: Option<ResponseOutputText>,
+        usage: Option<&Usage>,
+    ) -> ResponseObject {
+        ResponseObject {
+            id: id.to_string(),
+            object: "response".to_string(),
+            created_at: created,
+            status: status.to_string(),
+            model: self.model.clone(),
+            output: text.map(|text| output_message(id, "completed", Some(text))).into_iter().collect(),
+            instructions: self.instructions.clone(),
+            previous_response_id: self.previous_response_id.clone(),
+            usage: usage.map(|u| ResponseUsage {
+                input_tokens: u.prompt_tokens,
+                output_tokens: u.completion_tokens,
+                total_tokens: u.total_tokens,
+            }),
+            metadata: self.metadata.clone(),
+            error: None,
+        }
+    }
+
+    /// A failed response object carrying the error.
+    fn failed(&self, id: &str, created: u64, message: String) -> ResponseObject {
+        ResponseObject {
+            error: Some(ResponseError { code: "server_error".to_string(), message }),
+            ..self.object(id, created, "failed", None, None)
+        }
+    }
+}
+
+fn output_text(text: String) -> ResponseOutputText {
+    ResponseOutputText { r#type: "output_text".to_string(), text, annotations: Vec::new() }
+}
+
+/// The answer text with its citations as annotations.
+fn annotated_text(text: String, citations: &Citations) -> ResponseOutputText {
+    let annotations = citations.annotations(text.chars().count(), AnnotationStyle::Responses);
+    ResponseOutputText { annotations, ..output_text(text) }
+}
+
+fn output_message(id: &str, status: &str, text: Option<ResponseOutputText>) -> ResponseOutputItem {
+    ResponseOutputItem {
+        r#type: "message".to_string(),
+        id: id.to_string(),
+        status: status.to_string(),
+        role: "assistant".to_string(),
+        content: text.into_iter().collect(),
+    }
+}
+
+struct StreamState {
+    template: ResponseTemplate,
+    tracking: Option<Tracking>,
+    id: String,
+    created: u64,
+    text: String,
+    sequence_number: u64,
+    /// Whether the response completed or failed.
+    done: bool,
+}
+
+impl StreamState {
+    /// Fails the response. A failure before Dify assigned the message still
+    /// gets a response to fail.
+    fn fail(&mut self, message: String) -> Vec<ResponseStreamEvent> {
+        self.done = true;
+        let mut events = Vec::new();
+        if self.id.is_empty() {
+            self.id = format!("resp_{}", Uuid::new_v4().simple());
+            self.created = Utc::now().timestamp() as u64;
+            events.push(ResponseStreamEvent::Created {
+                response: self.template.object(&self.id, self.created, "in_progress", None, None),
+            });
+        }
+        events.push(ResponseStreamEvent::Failed {
+            response: self.template.failed(&self.id, self.created, message),
+        });
+        events
+    }
+
+    fn frame(&mut self, events: Vec<ResponseStreamEvent>) -> Vec<Bytes> {
+        events.iter()
+            .filter_map(|event| serde_json::to_value(event).ok())
+            .map(|mut json| {
+                json["sequence_number"] = self.sequence_number.into();
+                self.sequence_number += 1;
+                sse_typed_event(&json)
+            })
+            .collect()
+    }
+}
+
+/// Renders answer events as Responses semantic events. The answer is one
+/// message output item with one `output_text` part. A stream that ends
+/// before the answer finishes fails the response.
+fn response_stream(events: AnswerStream, template: ResponseTemplate, tracking: Option<Tracking>) -> StreamResponse {
+    let state = StreamState {
+        template,
+        tracking,
+        id: String::new(),
+        created: 0,
+        text: String::new(),
+        sequence_number: 0,
+        done: false,
+    };
+    Box::pin(events
+        .map(Some)
+        .chain(stream::once(future::ready(None)))
+        .scan(state, |state, event| {
+            let Some(event) = event else {
+                let events = if state.done {
+                    Vec::new()
+                } else {
+                    state.fail("The Dify stream ended before the answer finished".to_string())
+                };
+                return future::ready(Some(stream::iter(state.frame(events))));
+            };
+            let events = match event {
+                AnswerEvent::Started { message_id, conversation_id, created } => {
+                    if let Some(tracking) = &state.tracking {
+                        tracking.record(&message_id, &conversation_id);
+                    }
+                    state.id = message_id;
+                    state.created = created;
+                    let response = state.template.object(&state.id, created, "in_progress", None, None);
+                    vec![
+                        ResponseStreamEvent::Created { response: response.clone() },
+                        ResponseStreamEvent::InProgress { response },
+                        ResponseStreamEvent::OutputItemAdded {
+                            output_index: 0,
+                            item: output_message(&state.id, "in_progress", None),
+                        },
+                        ResponseStreamEvent::ContentPartAdded {
+                            item_id: state.id.clone(),
+                            output_index: 0,
+                            content_index: 0,
+                            part: output_text(String::new()),
+                        },
+                    ]
+                },
+                AnswerEvent::Text(delta) => {
+                    state.text.push_str(&delta);
+                    vec![ResponseStreamEvent::OutputTextDelta {
+                        item_id: state.id.clone(),
+                        output_index: 0,
+                        content_index: 0,
+                        delta,
+                    }]
+                },
+                AnswerEvent::Finished { usage, citations, .. } => {
+                    state.done = true;
+                    let text = annotated_text(state.text.clone(), &citations);
+                    let mut events: Vec<_> = text.annotations.iter().enumerate()
+                        .map(|(index, annotation)| ResponseStreamEvent::OutputTextAnnotationAdded {
+                            item_id: state.id.clone(),
+                            output_index: 0,
+                            content_index: 0,
+                            annotation_index: index as u32,
+                            annotation: annotation.clone(),
+                        })
+                        .collect();
+                    events.extend([
+                        ResponseStreamEvent::OutputTextDone {
+                            item_id: state.id.clone(),
+                            output_index: 0,
+                            content_index: 0,
+                            text: state.text.clone(),
+                        },
+                        ResponseStreamEvent::ContentPartDone {
+                            item_id: state.id.clone(),
+                            output_index: 0,
+                            content_index: 0,
+                            part: text.clone(),
+                        },
+                        ResponseStreamEvent::OutputItemDone {
+                            output_index: 0,
+                            item: output_message(&state.id, "completed", Some(text.clone())),
+                        },
+                        ResponseStreamEvent::Completed {
+                            response: state.template.object(
+                                &state.id,
+                                state.created,
+                                "completed",
+                                Some(text),
+                                usage.as_ref(),
+                            ),
+                        },
+                    ]);
+                    events
+                },
+                AnswerEvent::Attachments { .. } | AnswerEvent::Audio(_) => Vec::new(),
+                AnswerEvent::Error(message) => state.fail(message),
+            };
+            future::ready(Some(stream::iter(state.frame(events))))
+        })
+        .flatten()
+        .map(Ok))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    fn template() -> ResponseTemplate {
+        ResponseTemplate { model: "m".to_string(), instructions: None, previous_response_id: None, metadata: Default::default() }
+    }
+
+    /// The `data` of every SSE event in a response stream.
+    async fn run(events: Vec<AnswerEvent>) -> Vec<Value> {
+        let events: AnswerStream = Box::pin(stream::iter(events));
+        response_stream(events, template(), None)
+            .map(|chunk| {
+                let chunk = String::from_utf8(chunk.unwrap().to_vec()).unwrap();
+                let data = chunk.lines().find_map(|line| line.strip_prefix("data: ")).unwrap().to_string();
+                serde_json::from_str(&data).unwrap()
+            })
+            .collect()
+            .await
+    }
+
+    fn types(events: &[Value]) -> Vec<&str> {
+        events.iter().map(|e| e["type"].as_str().unwrap()).collect()
+    }
+
+    #[actix_web::test]
+    async fn dify_errors_fail_the_response() {
+        let events = run(vec![
+            AnswerEvent::Started { message_id: "m1".to_string(), conversation_id: "c1".to_string(), created: 7 },
+            AnswerEvent::Text("Hi".to_string()),
+            AnswerEvent::Error("quota exceeded".to_string()),
+        ]).await;
+        let failed = events.last().unwrap();
+        assert_eq!(failed["type"], "response.failed");
+        assert_eq!(failed["response"]["id"], "m1");
+        assert_eq!(failed["response"]["status"], "failed");
+        assert_eq!(failed["response"]["error"]["message"], "quota exceeded");
+        let sequence: Vec<u64> = events.iter().map(|e| e["sequence_number"].as_u64().unwrap()).collect();
+        assert_eq!(sequence, (0..events.len() as u64).collect::<Vec<_>>());
+    }
+
+    #[actix_web::test]
+    async fn errors_before_the_answer_starts_still_create_a_response() {
+        let events = run(vec![AnswerEvent::Error("bad gateway".to_string())]).await;
+        assert_eq!(types(&events), ["response.created", "response.failed"]);
+        assert_eq!(events[0]["response"]["id"], events[1]["response"]["id"]);
+        assert!(events[1]["response"]["id"].as_str().unwrap().starts_with("resp_"));
+    }
+
+    #[actix_web::test]
+    async fn streams_ending_early_fail_the_response() {
+        let events = run(vec![
+            AnswerEvent::Started { message_id: "m1".to_string(), conversation_id: "c1".to_string(), created: 7 },
+            AnswerEvent::Text("Hi".to_string()),
+        ]).await;
+        let failed = events.last().unwrap();
+        assert_eq!(failed["type"], "response.failed");
+        assert_eq!(failed["response"]["id"], "m1");
+        assert_eq!(types(&events).iter().filter(|t| t.starts_with("response.failed")).count(), 1);
+
+        let events = run(Vec::new()).await;
+        assert_eq!(types(&events), ["response.created", "response.failed"]);
+    }
+
+    #[actix_web::test]
+    async fn answers_complete_with_the_full_text() {
+        let events = run(vec![
+            AnswerEvent::Started { message_id: "m1".to_string(), conversation_id: "c1".to_string(), created: 7 },
+            AnswerEvent::Text("Hello ".to_string()),
+            AnswerEvent::Text("world".to_string()),
+            AnswerEvent::Finished { usage: None, stop: None, citations: Citations::default() },
+        ]).await;
+        let completed = events.last().unwrap();
+        assert_eq!(completed["type"], "response.completed");
+        assert_eq!(completed["response"]["output"][0]["content"][0]["text"], "Hello world");
+        assert!(completed["response"]["error"].is_null());
+        assert_eq!(types(&events).iter().filter(|t| **t == "response.failed").count(), 0);
+    }
+
+    #[test]
+    fn input_items_become_openai_messages() {
+        let body: ResponsesRequest = serde_json::from_value(json!({
+            "model": "m",
+            "instructions": "Be brief.",
+            "input": [
+                {"role": "developer", "content": "Use French."},
+                {"role": "user", "content": [
+                    {"type": "input_text", "text": "What is this?"},
+                    {"type": "input_image", "image_url": "https://example.com/a.png"},
+                    {"type": "input_file", "file_id": "file-abc"},
+                ]},
+                {"type": "function_call_output", "call_id": "c", "output": "42"},
+            ],
+        })).unwrap();
+        let messages = to_openai_request(&body).unwrap().messages;
+        let roles: Vec<&str> = messages.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, ["system", "system", "user", "tool"]);
+        assert!(messages[2].content.has_images());
+        assert_eq!(messages[3].content.to_text(), "42");
+    }
+
+    #[test]
+    fn unsupported_input_is_rejected() {
+        let body: ResponsesRequest = serde_json::from_value(json!({
+            "model": "m",
+            "input": [{"role": "user", "content": [{"type": "input_audio"}]}],
+        })).unwrap();
+        assert!(to_openai_request(&body).is_err());
+        let body: ResponsesRequest = serde_json::from_value(json!({"model": "m", "input": [{"type": "reasoning"}]})).unwrap();
+        assert!(to_openai_request(&body).is_err());
+    }
+}
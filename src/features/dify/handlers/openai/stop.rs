@@ -0,0 +1,161 @@
+/// Enforces OpenAI `stop` sequences on streamed text.
+///
+/// Dify has no notion of stop sequences, so the proxy truncates the output
+/// itself. Text that could be the start of a stop sequence is held back until
+/// the next chunk shows whether it really is one, so a sequence split across
+/// two chunks is still caught.
+#[derive(Debug, Clone)]
+pub struct StopFilter {
+    stops: Vec<String>,
+    pending: String,
+    stopped: bool,
+    matched: Option<String>,
+}
+
+impl StopFilter {
+    pub fn new(stops: Vec<String>) -> Self {
+        StopFilter {
+            stops: stops.into_iter().filter(|s| !s.is_empty()).collect(),
+            pending: String::new(),
+            stopped: false,
+            matched: None,
+        }
+    }
+
+    /// Feeds the next chunk and returns the text that is safe to emit.
+    pub fn push(&mut self, chunk: &str) -> String {
+        if self.stopped {
+            return String::new();
+        }
+        if self.stops.is_empty() {
+            return chunk.to_string();
+        }
+
+        self.pending.push_str(chunk);
+        if let Some((idx, stop)) = find_stop(&self.pending, &self.stops) {
+            self.stopped = true;
+            self.matched = Some(stop.to_string());
+            let out = self.pending[..idx].to_string();
+            self.pending.clear();
+            return out;
+        }
+
+        let hold = self.partial_match_len();
+        let emit_to = self.pending.len() - hold;
+        let out = self.pending[..emit_to].to_string();
+        self.pending.drain(..emit_to);
+        out
+    }
+
+    /// Whether a stop sequence has been hit.
+    pub fn stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// The stop sequence that was hit, if any.
+    pub fn matched(&self) -> Option<&str> {
+        self.matched.as_deref()
+    }
+
+    /// Returns any held-back text at the end of the stream.
+    pub fn finish(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+
+    /// Length of the longest suffix of `pending` that is a proper prefix of a stop sequence.
+    fn partial_match_len(&self) -> usize {
+        self.pending
+            .char_indices()
+            .map(|(i, _)| &self.pending[i..])
+            .find(|suffix| self.stops.iter().any(|stop| stop.len() > suffix.len() && stop.starts_with(suffix)))
+            .map(|suffix| suffix.len())
+            .unwrap_or(0)
+    }
+}
+
+/// Truncates complete text at the first stop sequence. Returns the text and
+/// whether a stop sequence was found.
+pub fn truncate_at_stop(text: &str, stops: &[String]) -> (String, bool) {
+    let stops: Vec<String> = stops.iter().filter(|s| !s.is_empty()).cloned().collect();
+    match find_stop(text, &stops) {
+        Some((idx, _)) => (text[..idx].to_string(), true),
+        None => (text.to_string(), false),
+    }
+}
+
+/// Position of the earliest stop sequence in `text`, and the sequence.
+fn find_stop<'a>(text: &str, stops: &'a [String]) -> Option<(usize, &'a str)> {
+    stops.iter()
+        .filter_map(|stop| text.find(stop.as_str()).map(|idx| (idx, stop.as_str())))
+        .min_by_key(|(idx, _)| *idx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(stops: &[&str], chunks: &[&str]) -> (String, Option<String>) {
+        let mut filter = StopFilter::new(stops.iter().map(|s| s.to_string()).collect());
+        let mut out: String = chunks.iter().map(|chunk| filter.push(chunk)).collect();
+        out.push_str(&filter.finish());
+        (out, filter.matched().map(String::from))
+    }
+
+    #[test]
+    fn passes_text_through_without_stops() {
+        assert_eq!(run(&[], &["Hello ", "world"]), ("Hello world".to_string(), None));
+        assert_eq!(run(&[""], &["Hello"]), ("Hello".to_string(), None));
+    }
+
+    #[test]
+    fn stops_within_a_chunk() {
+        assert_eq!(run(&["END"], &["one END two"]), ("one ".to_string(), Some("END".to_string())));
+    }
+
+    #[test]
+    fn stops_across_chunks() {
+        assert_eq!(run(&["STOP"], &["Hello ST", "OP world"]), ("Hello ".to_string(), Some("STOP".to_string())));
+        assert_eq!(run(&["STOP"], &["a S", "T", "O", "P b"]), ("a ".to_string(), Some("STOP".to_string())));
+    }
+
+    #[test]
+    fn holds_back_only_possible_prefixes() {
+        let mut filter = StopFilter::new(vec!["STOP".to_string()]);
+        assert_eq!(filter.push("Hello ST"), "Hello ");
+        assert_eq!(filter.push("ART"), "START");
+        assert!(!filter.stopped());
+    }
+
+    #[test]
+    fn releases_a_false_prefix_on_finish() {
+        assert_eq!(run(&["STOP"], &["Hello ST"]), ("Hello ST".to_string(), None));
+    }
+
+    #[test]
+    fn earliest_of_several_sequences_wins() {
+        assert_eq!(run(&["world", "lo"], &["Hello world"]), ("Hel".to_string(), Some("lo".to_string())));
+        assert_eq!(run(&["\n\n", "###"], &["a\n", "\nb ### c"]), ("a".to_string(), Some("\n\n".to_string())));
+    }
+
+    #[test]
+    fn ignores_text_after_the_stop() {
+        let mut filter = StopFilter::new(vec!["X".to_string()]);
+        assert_eq!(filter.push("aXb"), "a");
+        assert!(filter.stopped());
+        assert_eq!(filter.push("more"), "");
+        assert_eq!(filter.finish(), "");
+    }
+
+    #[test]
+    fn handles_multibyte_text() {
+        assert_eq!(run(&["。"], &["你好", "世界。再见"]), ("你好世界".to_string(), Some("。".to_string())));
+        assert_eq!(run(&["éé"], &["caf", "é", "é!"]), ("caf".to_string(), Some("éé".to_string())));
+    }
+
+    #[test]
+    fn truncates_complete_text() {
+        let stops = vec!["STOP".to_string(), String::new()];
+        assert_eq!(truncate_at_stop("Hello STOP here", &stops), ("Hello ".to_string(), true));
+        assert_eq!(truncate_at_stop("Hello", &stops), ("Hello".to_string(), false));
+    }
+}
@@ -0,0 +1,307 @@
+use actix_web::HttpResponse;
+use bytes::Bytes;
+use futures_util::{future, stream};
+use log::warn;
+use reqwest::{Client, StatusCode};
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::features::common::types::{DifyRequest, OpenAIChoice, OpenAIDelta, OpenAIResponse, Usage};
+use crate::features::config::proxy_config::{StructuredOutputConfig, StructuredOutputFailure};
+use crate::features::dify::chat::DifyChat;
+use super::chat_completion::{add_usage, sse_done, sse_event, sse_response, ChatCompletionError, StreamResponse};
+use super::json_schema;
+
+/// A `response_format` the proxy enforces.
+#[derive(Debug, Clone)]
+pub(crate) enum OutputFormat {
+    JsonObject,
+    JsonSchema { name: String, schema: Value },
+}
+
+/// Reads the request's `response_format`. `text` (or none) needs no enforcement.
+pub(crate) fn output_format(response_format: Option<&Value>) -> Result<Option<OutputFormat>, ChatCompletionError> {
+    let Some(response_format) = response_format else { return Ok(None) };
+    match response_format["type"].as_str() {
+        None | Some("text") => Ok(None),
+        Some("json_object") => Ok(Some(OutputFormat::JsonObject)),
+        Some("json_schema") => {
+            let json_schema = &response_format["json_schema"];
+            let schema = json_schema.get("schema")
+                .filter(|s| s.is_object())
+                .ok_or_else(|| ChatCompletionError::RequestConstructionError(
+                    "'response_format.json_schema.schema' must be a JSON Schema object".to_string(),
+                ))?;
+            json_schema::check(schema).map_err(|e| ChatCompletionError::RequestConstructionError(
+                format!("Invalid 'response_format.json_schema.schema': {}", e),
+            ))?;
+            Ok(Some(OutputFormat::JsonSchema {
+                name: json_schema["name"].as_str().unwrap_or("response").to_string(),
+                schema: schema.clone(),
+            }))
+        },
+        Some(other) => Err(ChatCompletionError::RequestConstructionError(format!(
+            "Unsupported response_format type '{}'",
+            other
+        ))),
+    }
+}
+
+impl OutputFormat {
+    fn instructions(&self) -> String {
+        match self {
+            OutputFormat::JsonObject => {
+                "Respond only with a valid JSON object, without any other text or code fences.".to_string()
+            },
+            OutputFormat::JsonSchema { name, schema } => format!(
+                "Respond only with JSON that conforms to the JSON Schema \"{}\" below, without any other text or code fences.\n{}",
+                name, schema
+            ),
+        }
+    }
+
+    /// Parses and validates an answer, returning it as compact JSON.
+    fn check(&self, answer: &str) -> Result<String, String> {
+        let value: Value = serde_json::from_str(extract_json(answer))
+            .map_err(|e| format!("answer is not valid JSON: {}", e))?;
+        match self {
+            OutputFormat::JsonObject if !value.is_object() => {
+                return Err("answer is not a JSON object".to_string());
+            },
+            OutputFormat::JsonSchema { schema, .. } => json_schema::validate(schema, &value)?,
+            _ => {},
+        }
+        Ok(value.to_string())
+    }
+}
+
+/// Strips surrounding whitespace and a Markdown code fence, which models add
+/// even when told not to.
+fn extract_json(answer: &str) -> &str {
+    let trimmed = answer.trim();
+    match trimmed.strip_prefix("```") {
+        Some(fenced) => {
+            let body = fenced.split_once('\n').map(|(_, body)| body).unwrap_or(fenced);
+            body.strip_suffix("```").unwrap_or(body).trim()
+        },
+        None => trimmed,
+    }
+}
+
+/// Puts format instructions into the configured input variable, or appends them to the query.
+fn with_instructions(request: &DifyRequest, config: &StructuredOutputConfig, instructions: String) -> DifyRequest {
+    let mut request = request.clone();
+    match (&config.instructions_input, request.inputs.as_object_mut()) {
+        (Some(variable), Some(inputs)) => {
+            inputs.insert(variable.clone(), Value::String(instructions));
+        },
+        _ => {
+            request.query.push_str("\n\n");
+            request.query.push_str(&instructions);
+        },
+    }
+    request
+}
+
+struct StructuredAnswer {
+    id: String,
+    created: u64,
+    result: Result<String, String>,
+    usage: Option<Usage>,
+}
+
+/// Runs one choice: asks Dify, validates, and optionally retries once with the error fed back.
+async fn run_choice(
+    client: &Client,
+    chat: &DifyChat,
+    format: &OutputFormat,
+    config: &StructuredOutputConfig,
+) -> Result<StructuredAnswer, ChatCompletionError> {
+    let mut instructions = format.instructions();
+    let mut usage = None;
+    let attempts = if config.retry { 2 } else { 1 };
+
+    let mut attempt = 1;
+    loop {
+        let mut attempt_chat = chat.clone();
+        attempt_chat.request = with_instructions(&chat.request, config, instructions.clone());
+        let answer = attempt_chat.answer(client).await?;
+        if let Some(attempt_usage) = answer.usage {
+            add_usage(&mut usage, attempt_usage);
+        }
+
+        let content = answer.text;
+        let result = format.check(&content);
+        if let Err(e) = &result {
+            warn!("Structured output attempt {}/{} failed validation: {}", attempt, attempts, e);
+            if attempt < attempts {
+                instructions = format!(
+                    "{}\nYour previous answer was rejected ({}). Previous answer:\n{}",
+                    format.instructions(), e, content
+                );
+                attempt += 1;
+                continue;
+            }
+        }
+        return Ok(StructuredAnswer {
+            id: answer.message_id,
+            created: answer.created,
+            result,
+            usage,
+        });
+    }
+}
+
+/// Chat completion with an enforced `response_format`. Answers are generated in
+/// blocking mode so they can be validated before anything reaches the client;
+/// streaming requests get the validated answer replayed as chunks.
+pub(crate) async fn structured_chat_completion(
+    client: &Client,
+    chat: &DifyChat,
+    format: OutputFormat,
+    config: &StructuredOutputConfig,
+    n: usize,
+    model: String,
+) -> Result<HttpResponse, ChatCompletionError> {
+    let is_streaming = chat.request.response_mode == "streaming";
+    let answers = future::try_join_all((0..n).map(|_| run_choice(client, chat, &format, config))).await?;
+
+    let mut choices = Vec::with_capacity(answers.len());
+    let mut usage = None;
+    for (index, answer) in answers.iter().enumerate() {
+        if let Some(answer_usage) = answer.usage.clone() {
+            add_usage(&mut usage, answer_usage);
+        }
+        let (content, refusal) = match &answer.result {
+            Ok(json) => (Some(json.clone()), None),
+            Err(e) if config.on_failure == StructuredOutputFailure::Error => {
+                return Err(ChatCompletionError::DifyApiError(
+                    StatusCode::BAD_GATEWAY,
+                    format!("The model's answer did not match the requested response_format: {}", e),
+                ));
+            },
+            Err(e) => (None, Some(format!("The answer did not match the requested response_format: {}", e))),
+        };
+        choices.push(OpenAIChoice {
+            index: index as u32,
+            delta: OpenAIDelta {
+                role: Some("assistant".to_string()),
+                content,
+                refusal,
+                ..Default::default()
+            },
+            finish_reason: Some("stop".to_string()),
+        });
+    }
+
+    let (mut id, created) = answers.first().map(|a| (a.id.clone(), a.created)).unwrap_or_default();
+    if n > 1 {
+        id = format!("chatcmpl-{}", Uuid::new_v4());
+    }
+    let response = OpenAIResponse {
+        id,
+        object: "chat.completion".to_string(),
+        created,
+        model,
+        choices,
+        usage,
+        metadata: None,
+    };
+
+    if !is_streaming {
+        return Ok(HttpResponse::Ok().json(response));
+    }
+    Ok(sse_response(replay_as_stream(response)))
+}
+
+/// Emits a complete response as chat completion chunks: one content chunk and
+/// one finish chunk per choice, usage on the last one.
+fn replay_as_stream(response: OpenAIResponse) -> StreamResponse {
+    let mut events: Vec<Bytes> = Vec::new();
+    let last = response.choices.len().saturating_sub(1);
+    for (i, choice) in response.choices.iter().enumerate() {
+        let mut content_chunk = response.clone();
+        content_chunk.object = "chat.completion.chunk".to_string();
+        content_chunk.usage = None;
+        content_chunk.choices = vec![OpenAIChoice { finish_reason: None, ..choice.clone() }];
+        events.push(sse_event(&content_chunk));
+
+        let mut finish_chunk = content_chunk.clone();
+        let mut finished = choice.clone();
+        finished.delta.role = None;
+        finished.delta.content = None;
+        finished.delta.refusal = None;
+        finish_chunk.choices = vec![finished];
+        if i == last {
+            finish_chunk.usage = response.usage.clone();
+        }
+        events.push(sse_event(&finish_chunk));
+    }
+    events.push(sse_done());
+    Box::pin(stream::iter(events.into_iter().map(Ok)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn schema_format() -> OutputFormat {
+        output_format(Some(&json!({
+            "type": "json_schema",
+            "json_schema": {"name": "person", "schema": {"type": "object", "required": ["name"]}},
+        }))).unwrap().unwrap()
+    }
+
+    #[test]
+    fn response_formats_are_parsed() {
+        assert!(output_format(None).unwrap().is_none());
+        assert!(output_format(Some(&json!({"type": "text"}))).unwrap().is_none());
+        assert!(matches!(output_format(Some(&json!({"type": "json_object"}))).unwrap(), Some(OutputFormat::JsonObject)));
+        assert!(matches!(schema_format(), OutputFormat::JsonSchema { name, .. } if name == "person"));
+        assert!(output_format(Some(&json!({"type": "json_schema", "json_schema": {"name": "x"}}))).is_err());
+        assert!(output_format(Some(&json!({"type": "xml"}))).is_err());
+        let looping = output_format(Some(&json!({"type": "json_schema", "json_schema": {"schema": {"$ref": "#"}}})));
+        assert!(matches!(looping, Err(ChatCompletionError::RequestConstructionError(e)) if e.starts_with("Invalid 'response_format.json_schema.schema'")));
+    }
+
+    #[test]
+    fn code_fences_are_stripped() {
+        assert_eq!(extract_json(" {\"a\": 1} "), "{\"a\": 1}");
+        assert_eq!(extract_json("```json\n{\"a\": 1}\n```"), "{\"a\": 1}");
+        assert_eq!(extract_json("```\n[1]\n```\n"), "[1]");
+    }
+
+    #[test]
+    fn answers_are_checked_against_the_format() {
+        assert_eq!(OutputFormat::JsonObject.check("```json\n{\"a\": 1}\n```").unwrap(), "{\"a\":1}");
+        assert!(OutputFormat::JsonObject.check("[1]").is_err());
+        assert!(OutputFormat::JsonObject.check("Sure! {\"a\": 1}").is_err());
+        assert!(schema_format().check("{\"name\": \"a\"}").is_ok());
+        assert!(schema_format().check("{\"age\": 3}").is_err());
+    }
+
+    #[test]
+    fn instructions_go_to_the_configured_input_or_the_query() {
+        let request = DifyRequest {
+            inputs: json!({"topic": "t"}),
+            query: "Hi".to_string(),
+            response_mode: "blocking".to_string(),
+            user: "u".to_string(),
+            temperature: None,
+            top_p: None,
+            max_tokens: None,
+            tools: None,
+            files: None,
+            conversation_id: None,
+        };
+
+        let appended = with_instructions(&request, &StructuredOutputConfig::default(), "JSON only".to_string());
+        assert_eq!(appended.query, "Hi\n\nJSON only");
+
+        let config = StructuredOutputConfig { instructions_input: Some("format".to_string()), ..Default::default() };
+        let input = with_instructions(&request, &config, "JSON only".to_string());
+        assert_eq!(input.query, "Hi");
+        assert_eq!(input.inputs, json!({"topic": "t", "format": "JSON only"}));
+    }
+}
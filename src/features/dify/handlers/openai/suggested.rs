@@ -0,0 +1,96 @@
+use log::warn;
+use reqwest::Client;
+use serde_json::json;
+
+use super::chat_completion::{read_dify_json, send_to_dify};
+
+/// Follow-up questions Dify suggests after an answer (`/messages/{id}/suggested`).
+/// A failure, e.g. the app having suggestions turned off, only costs the
+/// suggestions, not the answer.
+pub(crate) async fn suggested_questions(
+    client: &Client,
+    dify_api_url: &str,
+    api_key: &str,
+    message_id: &str,
+    user: &str,
+) -> Option<Vec<String>> {
+    let url = format!("{}/v1/messages/{}/suggested", dify_api_url, message_id);
+    let result = match send_to_dify(client.get(&url).query(&[("user", user)]), api_key).await {
+        Ok(response) => read_dify_json(response).await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(json) => json["data"].as_array()
+            .map(|questions| questions.iter().filter_map(|q| q.as_str().map(String::from)).collect()),
+        Err(e) => {
+            warn!("Failed to fetch suggested questions for {}: {}", message_id, e);
+            None
+        },
+    }
+}
+
+/// The response `metadata` extension carrying suggested questions.
+pub(crate) fn suggestions_metadata(questions: Vec<String>) -> serde_json::Value {
+    json!({ "suggested_questions": questions })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    /// A Dify stand-in that answers one request with `status` and a JSON
+    /// `body`. The handle yields the request head it received.
+    pub(crate) async fn fake_dify(status: u16, body: serde_json::Value) -> (String, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut head = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+                let read = socket.read(&mut buf).await.unwrap();
+                if read == 0 {
+                    break;
+                }
+                head.extend_from_slice(&buf[..read]);
+            }
+            let body = body.to_string();
+            let response = format!(
+                "HTTP/1.1 {} X\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                status, body.len(), body
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8_lossy(&head).into_owned()
+        });
+        (url, handle)
+    }
+
+    #[actix_web::test]
+    async fn questions_are_fetched_for_the_message_and_user() {
+        let (url, request) = fake_dify(200, json!({"result": "success", "data": ["Why?", "How?"]})).await;
+        let questions = suggested_questions(&Client::new(), &url, "app-key", "m1", "alice").await;
+        assert_eq!(questions, Some(vec!["Why?".to_string(), "How?".to_string()]));
+
+        let request = request.await.unwrap();
+        assert!(request.starts_with("GET /v1/messages/m1/suggested?user=alice "), "{}", request);
+        assert!(request.to_lowercase().contains("authorization: bearer app-key"));
+    }
+
+    #[actix_web::test]
+    async fn upstream_failures_only_cost_the_suggestions() {
+        let (url, _) = fake_dify(400, json!({"code": "not_support", "message": "Suggested questions is disabled."})).await;
+        assert_eq!(suggested_questions(&Client::new(), &url, "app-key", "m1", "u").await, None);
+
+        // Nothing listens on a closed listener's port
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        assert_eq!(suggested_questions(&Client::new(), &format!("http://{}", closed), "app-key", "m1", "u").await, None);
+    }
+
+    #[test]
+    fn questions_go_in_the_metadata_extension() {
+        assert_eq!(suggestions_metadata(vec!["Why?".to_string()]), json!({"suggested_questions": ["Why?"]}));
+    }
+}
@@ -0,0 +1,452 @@
+use actix_web::HttpResponse;
+use bytes::Bytes;
+use chrono::Utc;
+use futures_util::{future, stream, StreamExt};
+use reqwest::{Client, StatusCode};
+use serde_json::{json, Map, Value};
+
+use crate::features::common::types::{
+    DifyWorkflowRequest, OpenAIChoice, OpenAIDelta, OpenAIRequest, OpenAIResponse, Usage,
+};
+use crate::features::config::proxy_config::WorkflowConfig;
+use crate::features::app::app_state::AppState;
+use crate::features::dify::cancellation::{cancel_on_disconnect, CancelContext};
+use crate::features::dify::parameters::validate_request;
+use super::chat_completion::{
+    create_error_response, dify_event_stream, dify_stream_error, post_to_dify,
+    read_dify_json, sse_done, sse_event, sse_response, ChatCompletionError, DifyEventStream, StreamResponse,
+};
+
+/// Builds workflow `inputs` from the last user message.
+///
+/// A JSON object message is spread into inputs (renamed through `input_map`)
+/// when `json_inputs` is enabled; anything else goes into `query_input`.
+pub(crate) fn workflow_inputs(openai_req: &OpenAIRequest, workflow: &WorkflowConfig) -> Result<Value, ChatCompletionError> {
+    let last_user = openai_req.messages.iter()
+        .rev()
+        .find(|m| m.role == "user")
+        .ok_or_else(|| ChatCompletionError::RequestConstructionError("Workflow models need a user message".to_string()))?;
+    let text = last_user.content.to_text();
+
+    if workflow.json_inputs {
+        if let Ok(Value::Object(fields)) = serde_json::from_str::<Value>(&text) {
+            let inputs: Map<String, Value> = fields.into_iter()
+                .map(|(field, value)| {
+                    let name = workflow.input_map.get(&field).cloned().unwrap_or(field);
+                    (name, value)
+                })
+                .collect();
+            return Ok(Value::Object(inputs));
+        }
+    }
+
+    Ok(json!({ workflow.query_input.as_str(): text }))
+}
+
+/// Renders a workflow output value as answer text.
+fn output_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
+
+/// Picks the answer from workflow `outputs`: the configured variable, the only
+/// output if there is exactly one, or the whole object as JSON.
+fn select_output(outputs: &Value, workflow: &WorkflowConfig) -> String {
+    if let Some(variable) = &workflow.output_variable {
+        return output_text(&outputs[variable.as_str()]);
+    }
+    match outputs.as_object() {
+        Some(map) if map.len() == 1 => output_text(map.values().next().unwrap()),
+        Some(_) => outputs.to_string(),
+        None => String::new(),
+    }
+}
+
+/// Prompt/completion token split summed from the LLM nodes' `node_finished`
+/// outputs. Dify reports only a total for the workflow run itself, and
+/// blocking runs carry no node events.
+#[derive(Debug, Default)]
+struct NodeTokens {
+    prompt: u32,
+    completion: u32,
+    seen: bool,
+}
+
+impl NodeTokens {
+    fn add(&mut self, event: &Value) {
+        let usage = &event["data"]["outputs"]["usage"];
+        if let (Some(prompt), Some(completion)) = (usage["prompt_tokens"].as_u64(), usage["completion_tokens"].as_u64()) {
+            self.prompt += prompt as u32;
+            self.completion += completion as u32;
+            self.seen = true;
+        }
+    }
+}
+
+/// Usage with the real split, if the LLM nodes reported it.
+fn workflow_usage(data: &Value, tokens: &NodeTokens) -> Option<Usage> {
+    tokens.seen.then(|| Usage {
+        prompt_tokens: tokens.prompt,
+        completion_tokens: tokens.completion,
+        total_tokens: data["total_tokens"].as_u64().map(|t| t as u32).unwrap_or(tokens.prompt + tokens.completion),
+    })
+}
+
+/// Serializes a workflow response. Without a known split, `usage` carries only
+/// the run's `total_tokens` rather than inventing prompt and completion counts.
+fn with_total_tokens(response: &OpenAIResponse, data: &Value) -> Value {
+    let mut json = serde_json::to_value(response).unwrap_or_default();
+    if response.usage.is_none() {
+        if let Some(total) = data["total_tokens"].as_u64() {
+            json["usage"] = json!({ "total_tokens": total });
+        }
+    }
+    json
+}
+
+fn node_metadata(event: &Value) -> Value {
+    let data = &event["data"];
+    json!({
+        "event": event["event"],
+        "node_id": data["node_id"],
+        "node_type": data["node_type"],
+        "title": data["title"],
+        "index": data["index"],
+        "status": data["status"],
+        "elapsed_time": data["elapsed_time"],
+    })
+}
+
+fn workflow_chunk(
+    id: &str,
+    model: &str,
+    content: Option<String>,
+    finish_reason: Option<String>,
+    usage: Option<Usage>,
+    metadata: Option<Value>,
+) -> OpenAIResponse {
+    OpenAIResponse {
+        id: id.to_string(),
+        object: "chat.completion.chunk".to_string(),
+        created: Utc::now().timestamp() as u64,
+        model: model.to_string(),
+        choices: vec![OpenAIChoice {
+            index: 0,
+            delta: OpenAIDelta { content, ..Default::default() },
+            finish_reason,
+        }],
+        usage,
+        metadata,
+    }
+}
+
+/// State of a streamed workflow run.
+#[derive(Debug, Default)]
+struct WorkflowStream {
+    tokens: NodeTokens,
+    /// Whether the run finished or failed.
+    done: bool,
+}
+
+/// An error chunk that ends the stream.
+fn workflow_error(state: &mut WorkflowStream, message: &str) -> Bytes {
+    state.done = true;
+    Bytes::from([sse_event(&create_error_response(message)), sse_done()].concat())
+}
+
+fn process_workflow_event(event: Value, model: &str, workflow: &WorkflowConfig, state: &mut WorkflowStream) -> Bytes {
+    let id = event["workflow_run_id"].as_str().unwrap_or_default().to_string();
+    if event["event"].as_str() == Some("node_finished") {
+        state.tokens.add(&event);
+    }
+    match event["event"].as_str() {
+        Some("text_chunk") if workflow.output_variable.is_none() => {
+            let text = event["data"]["text"].as_str().unwrap_or_default().to_string();
+            sse_event(&workflow_chunk(&id, model, Some(text), None, None, None))
+        },
+        Some("node_started") | Some("node_finished") if workflow.node_events => {
+            sse_event(&workflow_chunk(&id, model, None, None, None, Some(node_metadata(&event))))
+        },
+        Some("workflow_finished") => {
+            let data = &event["data"];
+            if data["status"].as_str() != Some("succeeded") {
+                return workflow_error(state, data["error"].as_str().unwrap_or("Workflow run failed"));
+            }
+            state.done = true;
+            let mut chunks = Vec::new();
+            if workflow.output_variable.is_some() {
+                let answer = select_output(&data["outputs"], workflow);
+                chunks.push(sse_event(&workflow_chunk(&id, model, Some(answer), None, None, None)));
+            }
+            let finish = workflow_chunk(&id, model, None, Some("stop".to_string()), workflow_usage(data, &state.tokens), None);
+            chunks.push(sse_event(&with_total_tokens(&finish, data)));
+            chunks.push(sse_done());
+            Bytes::from(chunks.concat())
+        },
+        Some("error") => workflow_error(state, &dify_stream_error(&event)),
+        _ => Bytes::new(),
+    }
+}
+
+/// Renders workflow events as chat completion chunks. A stream that ends
+/// before `workflow_finished` ends with an error.
+fn workflow_stream(events: DifyEventStream, model: String, workflow: WorkflowConfig) -> StreamResponse {
+    Box::pin(events
+        .map(Some)
+        .chain(stream::once(future::ready(None)))
+        .scan(WorkflowStream::default(), move |state, event| {
+            if state.done {
+                return future::ready(None);
+            }
+            let chunk = match event {
+                Some(Ok(event)) => process_workflow_event(event, &model, &workflow, state),
+                Some(Err(e)) => workflow_error(state, &e.to_string()),
+                None => workflow_error(state, "Dify closed the stream before the workflow finished"),
+            };
+            future::ready(Some(chunk))
+        })
+        .map(Ok))
+}
+
+/// The workflow inputs for a chat completion request: those derived from the
+/// last user message plus the mapped inputs, validated against the app.
+pub(crate) async fn workflow_request_inputs(
+    state: &AppState,
+    client: &Client,
+    api_key: &str,
+    openai_req: &OpenAIRequest,
+    mapped_inputs: Map<String, Value>,
+    workflow: &WorkflowConfig,
+) -> Result<Map<String, Value>, ChatCompletionError> {
+    let mut inputs = match workflow_inputs(openai_req, workflow)? {
+        Value::Object(inputs) => inputs,
+        _ => Map::new(),
+    };
+    inputs.extend(mapped_inputs);
+    let has_images = openai_req.messages.iter().any(|m| m.content.has_images());
+    validate_request(&state.parameters, client, &state.dify_api_url, api_key, &inputs, has_images).await?;
+    Ok(inputs)
+}
+
+/// Runs a Dify workflow app for a chat completion request, with inputs from
+/// `workflow_request_inputs`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn workflow_chat_completion(
+    state: &AppState,
+    client: &Client,
+    api_key: &str,
+    openai_req: &OpenAIRequest,
+    inputs: Map<String, Value>,
+    workflow: WorkflowConfig,
+    user: String,
+    model: String,
+    cancel: CancelContext,
+) -> Result<HttpResponse, ChatCompletionError> {
+    let is_streaming = openai_req.stream.unwrap_or(false);
+    let request = DifyWorkflowRequest {
+        inputs: Value::Object(inputs),
+        response_mode: if is_streaming { "streaming" } else { "blocking" }.to_string(),
+        user,
+    };
+    let url = format!("{}/v1/workflows/run", state.dify_api_url);
+    let response = post_to_dify(client, &url, api_key, &request).await?;
+
+    if is_streaming {
+        let events = cancel_on_disconnect(dify_event_stream(response), cancel);
+        return Ok(sse_response(workflow_stream(events, model, workflow)));
+    }
+
+    let raw_json = read_dify_json(response).await?;
+    let data = &raw_json["data"];
+    if data["status"].as_str() != Some("succeeded") {
+        let message = data["error"].as_str().unwrap_or("Workflow run failed").to_string();
+        return Err(ChatCompletionError::DifyApiError(StatusCode::BAD_GATEWAY, message));
+    }
+
+    let metadata = workflow.node_events.then(|| json!({
+        "workflow_run_id": raw_json["workflow_run_id"],
+        "status": data["status"],
+        "elapsed_time": data["elapsed_time"],
+        "outputs": data["outputs"],
+    }));
+
+    let response = OpenAIResponse {
+        id: raw_json["workflow_run_id"].as_str().unwrap_or_default().to_string(),
+        object: "chat.completion".to_string(),
+        created: data["created_at"].as_u64().unwrap_or_else(|| Utc::now().timestamp() as u64),
+        model,
+        choices: vec![OpenAIChoice {
+            index: 0,
+            delta: OpenAIDelta {
+                role: Some("assistant".to_string()),
+                content: Some(select_output(&data["outputs"], &workflow)),
+                ..Default::default()
+            },
+            finish_reason: Some("stop".to_string()),
+        }],
+        usage: workflow_usage(data, &NodeTokens::default()),
+        metadata,
+    };
+    Ok(HttpResponse::Ok().json(with_total_tokens(&response, data)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(messages: &[(&str, &str)]) -> OpenAIRequest {
+        let messages: Vec<Value> = messages.iter().map(|(role, text)| json!({"role": role, "content": text})).collect();
+        serde_json::from_value(json!({"messages": messages})).unwrap()
+    }
+
+    fn config(value: Value) -> WorkflowConfig {
+        serde_json::from_value(value).unwrap()
+    }
+
+    /// The JSON payloads of a rendered chunk, with `[DONE]` as a string.
+    fn payloads(bytes: &Bytes) -> Vec<Value> {
+        String::from_utf8_lossy(bytes).lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .map(|data| serde_json::from_str(data).unwrap_or_else(|_| json!(data)))
+            .collect()
+    }
+
+    async fn run(events: Vec<Value>, workflow: WorkflowConfig) -> Vec<Value> {
+        let events: DifyEventStream = Box::pin(stream::iter(events.into_iter().map(Ok)));
+        let chunks: Vec<_> = workflow_stream(events, "wf".to_string(), workflow).collect().await;
+        chunks.iter().flat_map(|chunk| payloads(chunk.as_ref().unwrap())).collect()
+    }
+
+    #[test]
+    fn the_last_user_message_becomes_the_query_input() {
+        let req = request(&[("user", "first"), ("assistant", "a"), ("user", "{\"city\": \"Oslo\"}")]);
+        assert_eq!(workflow_inputs(&req, &config(json!({"query_input": "q"}))).unwrap(), json!({"q": "{\"city\": \"Oslo\"}"}));
+
+        let error = workflow_inputs(&request(&[("system", "s")]), &WorkflowConfig::default()).unwrap_err();
+        assert!(matches!(error, ChatCompletionError::RequestConstructionError(_)));
+    }
+
+    #[test]
+    fn json_messages_are_spread_through_the_input_map() {
+        let workflow = config(json!({"json_inputs": true, "input_map": {"city": "location"}}));
+        let req = request(&[("user", "{\"city\": \"Oslo\", \"days\": 3}")]);
+        // Mapped fields are renamed, others keep their name
+        assert_eq!(workflow_inputs(&req, &workflow).unwrap(), json!({"location": "Oslo", "days": 3}));
+        // Text that isn't a JSON object still goes into the query input
+        assert_eq!(workflow_inputs(&request(&[("user", "[1]")]), &workflow).unwrap(), json!({"query": "[1]"}));
+    }
+
+    #[test]
+    fn outputs_are_selected_by_variable_or_fallback() {
+        let outputs = json!({"answer": "Hi", "score": 0.5, "tags": ["a"]});
+        assert_eq!(select_output(&outputs, &config(json!({"output_variable": "answer"}))), "Hi");
+        assert_eq!(select_output(&outputs, &config(json!({"output_variable": "score"}))), "0.5");
+        assert_eq!(select_output(&outputs, &config(json!({"output_variable": "tags"}))), "[\"a\"]");
+        assert_eq!(select_output(&outputs, &config(json!({"output_variable": "missing"}))), "");
+
+        let default = WorkflowConfig::default();
+        assert_eq!(select_output(&json!({"only": {"a": 1}}), &default), "{\"a\":1}");
+        assert_eq!(select_output(&outputs, &default), outputs.to_string());
+        assert_eq!(select_output(&json!(null), &default), "");
+    }
+
+    fn finished(outputs: Value) -> Value {
+        json!({
+            "event": "workflow_finished",
+            "workflow_run_id": "run",
+            "data": {"status": "succeeded", "outputs": outputs, "total_tokens": 9},
+        })
+    }
+
+    #[actix_web::test]
+    async fn text_chunks_stream_and_the_run_finishes() {
+        let node = json!({"event": "node_finished", "workflow_run_id": "run", "data": {
+            "node_id": "llm", "node_type": "llm", "title": "LLM", "index": 1, "status": "succeeded",
+            "outputs": {"usage": {"prompt_tokens": 4, "completion_tokens": 5}},
+        }});
+        let chunks = run(vec![
+            json!({"event": "workflow_started", "workflow_run_id": "run"}),
+            json!({"event": "text_chunk", "workflow_run_id": "run", "data": {"text": "Hel"}}),
+            node,
+            json!({"event": "text_chunk", "workflow_run_id": "run", "data": {"text": "lo"}}),
+            finished(json!({"text": "Hello"})),
+        ], config(json!({"node_events": true}))).await;
+
+        assert_eq!(chunks[0]["choices"][0]["delta"]["content"], "Hel");
+        assert_eq!(chunks[1]["metadata"]["node_id"], "llm");
+        assert_eq!(chunks[1]["metadata"]["event"], "node_finished");
+        assert_eq!(chunks[2]["choices"][0]["delta"]["content"], "lo");
+        assert_eq!(chunks[3]["choices"][0]["finish_reason"], "stop");
+        assert_eq!(chunks[3]["usage"]["prompt_tokens"], 4);
+        assert_eq!(chunks[4], "[DONE]");
+        assert_eq!(chunks.len(), 5);
+    }
+
+    #[actix_web::test]
+    async fn output_variables_arrive_with_the_finish() {
+        let chunks = run(vec![
+            json!({"event": "text_chunk", "workflow_run_id": "run", "data": {"text": "draft"}}),
+            finished(json!({"answer": "Final", "other": 1})),
+        ], config(json!({"output_variable": "answer"}))).await;
+
+        assert_eq!(chunks[0]["choices"][0]["delta"]["content"], "Final");
+        assert_eq!(chunks[1]["choices"][0]["finish_reason"], "stop");
+        assert_eq!(chunks[1]["usage"], json!({"total_tokens": 9}));
+        assert_eq!(chunks.len(), 3);
+    }
+
+    #[actix_web::test]
+    async fn failures_end_the_stream_with_an_error() {
+        let failed = json!({"event": "workflow_finished", "data": {"status": "failed", "error": "node crashed"}});
+        let chunks = run(vec![failed, finished(json!({}))], WorkflowConfig::default()).await;
+        assert_eq!(chunks, [json!({"error": {"message": "node crashed", "type": "invalid_request_error"}}), json!("[DONE]")]);
+
+        let error = json!({"event": "error", "message": "quota exceeded"});
+        let chunks = run(vec![error], WorkflowConfig::default()).await;
+        assert_eq!(chunks[0]["error"]["message"], "quota exceeded");
+        assert_eq!(chunks[1], "[DONE]");
+    }
+
+    #[actix_web::test]
+    async fn streams_closed_before_the_run_finishes_fail() {
+        let chunks = run(vec![
+            json!({"event": "text_chunk", "workflow_run_id": "run", "data": {"text": "Hel"}}),
+        ], WorkflowConfig::default()).await;
+        assert_eq!(chunks[0]["choices"][0]["delta"]["content"], "Hel");
+        assert_eq!(chunks[1]["error"]["message"], "Dify closed the stream before the workflow finished");
+        assert_eq!(chunks[2], "[DONE]");
+        assert!(chunks.iter().all(|c| c["choices"][0]["finish_reason"].is_null()));
+    }
+
+    fn finish(usage: Option<Usage>) -> OpenAIResponse {
+        workflow_chunk("run", "wf", None, Some("stop".to_string()), usage, None)
+    }
+
+    #[test]
+    fn total_only_without_node_usage() {
+        let data = json!({"total_tokens": 42});
+        let usage = workflow_usage(&data, &NodeTokens::default());
+        assert!(usage.is_none());
+        assert_eq!(with_total_tokens(&finish(usage), &data)["usage"], json!({"total_tokens": 42}));
+    }
+
+    #[test]
+    fn split_summed_from_llm_nodes() {
+        let mut tokens = NodeTokens::default();
+        tokens.add(&json!({"data": {"outputs": {"usage": {"prompt_tokens": 10, "completion_tokens": 5}}}}));
+        tokens.add(&json!({"data": {"outputs": {"text": "no usage here"}}}));
+        tokens.add(&json!({"data": {"outputs": {"usage": {"prompt_tokens": 3, "completion_tokens": 2}}}}));
+        let data = json!({"total_tokens": 20});
+        let usage = workflow_usage(&data, &tokens).unwrap();
+        assert_eq!((usage.prompt_tokens, usage.completion_tokens, usage.total_tokens), (13, 7, 20));
+        assert_eq!(with_total_tokens(&finish(Some(usage)), &data)["usage"]["prompt_tokens"], 13);
+    }
+
+    #[test]
+    fn no_usage_without_total() {
+        assert!(with_total_tokens(&finish(None), &json!({}))["usage"].is_null());
+    }
+}
@@ -1 +1,6 @@
-pub mod handlers;
\ No newline at end of file
+pub mod cancellation;
+pub mod chat;
+pub mod citations;
+pub mod conversations;
+pub mod handlers;
+pub mod parameters;
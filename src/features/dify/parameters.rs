@@ -0,0 +1,245 @@
+use chrono::Utc;
+use log::{info, warn};
+use reqwest::Client;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::features::dify::handlers::openai::chat_completion::{get_from_dify, read_dify_json, ChatCompletionError};
+
+/// Fetches an app's parameters (`GET /parameters`): its user input form, file
+/// upload settings and feature flags.
+pub(crate) async fn fetch_parameters(
+    client: &Client,
+    dify_api_url: &str,
+    api_key: &str,
+) -> Result<Value, ChatCompletionError> {
+    let url = format!("{}/v1/parameters", dify_api_url);
+    let response = get_from_dify(client, &url, api_key).await?;
+    read_dify_json(response).await
+}
+
+struct CachedParameters {
+    parameters: Arc<Value>,
+    fetched_at: Instant,
+    fetched_at_unix: i64,
+}
+
+/// Summary of a cache entry for the admin API. The app key is masked.
+#[derive(Debug, Serialize)]
+pub struct CachedParametersInfo {
+    pub app_key: String,
+    pub fetched_at: i64,
+    pub parameters: Value,
+}
+
+/// App parameters cached per Dify app key.
+///
+/// Entries are fetched on first use (or warmed at startup) and refetched once
+/// older than the TTL.
+pub struct ParametersCache {
+    ttl: Duration,
+    entries: RwLock<HashMap<String, CachedParameters>>,
+}
+
+impl ParametersCache {
+    pub fn new(ttl: Duration) -> Self {
+        ParametersCache {
+            ttl,
+            entries: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Cached parameters for `api_key`, fetching them if missing or stale.
+    pub(crate) async fn get(
+        &self,
+        client: &Client,
+        dify_api_url: &str,
+        api_key: &str,
+    ) -> Result<Arc<Value>, ChatCompletionError> {
+        if let Some(entry) = self.entries.read().unwrap().get(api_key) {
+            if entry.fetched_at.elapsed() < self.ttl {
+                return Ok(entry.parameters.clone());
+            }
+        }
+        self.refresh(client, dify_api_url, api_key).await
+    }
+
+    /// Fetches parameters for `api_key` and replaces the cached entry.
+    pub(crate) async fn refresh(
+        &self,
+        client: &Client,
+        dify_api_url: &str,
+        api_key: &str,
+    ) -> Result<Arc<Value>, ChatCompletionError> {
+        let parameters = Arc::new(fetch_parameters(client, dify_api_url, api_key).await?);
+        self.entries.write().unwrap().insert(api_key.to_string(), CachedParameters {
+            parameters: parameters.clone(),
+            fetched_at: Instant::now(),
+            fetched_at_unix: Utc::now().timestamp(),
+        });
+        Ok(parameters)
+    }
+
+    pub fn clear(&self) {
+        self.entries.write().unwrap().clear();
+    }
+
+    pub fn cached(&self, api_key: &str) -> Option<CachedParametersInfo> {
+        self.entries.read().unwrap().get(api_key).map(|entry| CachedParametersInfo {
+            app_key: mask_key(api_key),
+            fetched_at: entry.fetched_at_unix,
+            parameters: entry.parameters.as_ref().clone(),
+        })
+    }
+
+    /// Fetches parameters for every app key up front. Failures are logged and
+    /// left to be retried on first use.
+    pub(crate) async fn warm(&self, client: &Client, dify_api_url: &str, api_keys: Vec<String>) {
+        for api_key in api_keys {
+            match self.refresh(client, dify_api_url, &api_key).await {
+                Ok(_) => info!("Cached app parameters for {}", mask_key(&api_key)),
+                Err(e) => warn!("Could not fetch app parameters for {}: {}", mask_key(&api_key), e),
+            }
+        }
+    }
+}
+
+fn mask_key(api_key: &str) -> String {
+    let visible: String = api_key.chars().rev().take(4).collect::<Vec<_>>().into_iter().rev().collect();
+    format!("...{}", visible)
+}
+
+/// Input variables of the app's `user_input_form`, as `(variable, label, required)`.
+///
+/// Each form entry is an object keyed by its control type (`text-input`,
+/// `paragraph`, `select`, ...).
+pub(crate) fn form_variables(parameters: &Value) -> Vec<(String, String, bool)> {
+    parameters["user_input_form"]
+        .as_array()
+        .map(|form| form.iter()
+            .filter_map(|entry| entry.as_object()?.values().next())
+            .filter_map(|control| {
+                let variable = control["variable"].as_str()?.to_string();
+                let label = control["label"].as_str().unwrap_or(&variable).to_string();
+                Some((variable, label, control["required"].as_bool().unwrap_or(false)))
+            })
+            .collect())
+        .unwrap_or_default()
+}
+
+/// Rejects the request if a required input variable is missing or empty.
+pub(crate) fn check_required_inputs(parameters: &Value, inputs: &Map<String, Value>) -> Result<(), ChatCompletionError> {
+    for (variable, label, required) in form_variables(parameters) {
+        let missing = match inputs.get(&variable) {
+            None | Some(Value::Null) => true,
+            Some(Value::String(s)) => s.trim().is_empty(),
+            Some(_) => false,
+        };
+        if required && missing {
+            return Err(ChatCompletionError::RequestConstructionError(format!(
+                "Missing required Dify input '{}' ({})",
+                variable, label
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Whether the app accepts image uploads, in either the legacy
+/// (`file_upload.image.enabled`) or current (`allowed_file_types`) format.
+pub(crate) fn supports_images(parameters: &Value) -> bool {
+    let file_upload = &parameters["file_upload"];
+    let legacy = file_upload["image"]["enabled"].as_bool().unwrap_or(false);
+    let current = file_upload["enabled"].as_bool().unwrap_or(false)
+        && file_upload["allowed_file_types"]
+            .as_array()
+            .map(|types| types.iter().any(|t| t.as_str() == Some("image")))
+            .unwrap_or(false);
+    legacy || current
+}
+
+/// Checks a request against the app's cached parameters: required inputs
+/// must be present and image parts need an app with image upload enabled.
+/// If the parameters can't be fetched the request is let through and Dify
+/// has the final say.
+pub(crate) async fn validate_request(
+    cache: &ParametersCache,
+    client: &Client,
+    dify_api_url: &str,
+    api_key: &str,
+    inputs: &Map<String, Value>,
+    has_images: bool,
+) -> Result<(), ChatCompletionError> {
+    let parameters = match cache.get(client, dify_api_url, api_key).await {
+        Ok(parameters) => parameters,
+        Err(e) => {
+            warn!("Skipping request validation, could not fetch app parameters: {}", e);
+            return Ok(());
+        },
+    };
+
+    check_required_inputs(&parameters, inputs)?;
+
+    if has_images && !supports_images(&parameters) {
+        return Err(ChatCompletionError::RequestConstructionError(
+            "Image inputs are not supported by this model".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn parameters() -> Value {
+        json!({
+            "user_input_form": [
+                {"text-input": {"label": "Topic", "variable": "topic", "required": true}},
+                {"select": {"variable": "tone", "required": false, "options": ["a", "b"]}},
+            ],
+            "file_upload": {"enabled": true, "allowed_file_types": ["image", "document"]},
+        })
+    }
+
+    fn inputs(value: Value) -> Map<String, Value> {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn form_variables_are_read_from_any_control() {
+        assert_eq!(form_variables(&parameters()), [
+            ("topic".to_string(), "Topic".to_string(), true),
+            ("tone".to_string(), "tone".to_string(), false),
+        ]);
+        assert!(form_variables(&json!({})).is_empty());
+    }
+
+    #[test]
+    fn required_inputs_must_be_present_and_non_empty() {
+        assert!(check_required_inputs(&parameters(), &inputs(json!({"topic": "rust"}))).is_ok());
+        assert!(check_required_inputs(&parameters(), &inputs(json!({"topic": 0}))).is_ok());
+        assert!(check_required_inputs(&parameters(), &inputs(json!({}))).is_err());
+        assert!(check_required_inputs(&parameters(), &inputs(json!({"topic": " "}))).is_err());
+        assert!(check_required_inputs(&parameters(), &inputs(json!({"topic": null}))).is_err());
+    }
+
+    #[test]
+    fn image_support_reads_both_formats() {
+        assert!(supports_images(&parameters()));
+        assert!(supports_images(&json!({"file_upload": {"image": {"enabled": true}}})));
+        assert!(!supports_images(&json!({"file_upload": {"enabled": true, "allowed_file_types": ["document"]}})));
+        assert!(!supports_images(&json!({"file_upload": {"enabled": false, "allowed_file_types": ["image"]}})));
+    }
+
+    #[test]
+    fn keys_are_masked_to_their_last_characters() {
+        assert_eq!(mask_key("app-123456"), "...3456");
+        assert_eq!(mask_key("ab"), "...ab");
+    }
+}
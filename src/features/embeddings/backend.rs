@@ -0,0 +1,174 @@
+use log::warn;
+use reqwest::{Client, StatusCode};
+use serde_json::{json, Value};
+
+use crate::features::config::proxy_config::EmbeddingsBackendConfig;
+use crate::features::dify::handlers::openai::chat_completion::ChatCompletionError;
+
+/// Embeddings for a batch of inputs, in input order.
+#[derive(Debug, Clone)]
+pub struct Embeddings {
+    pub vectors: Vec<Vec<f32>>,
+    pub prompt_tokens: u32,
+}
+
+/// Embeds `input` with the configured backend. The backend is the server's,
+/// so its failures are reported in the proxy's own terms: rejected input as a
+/// 400, rate limits as a 429 and anything else as a 502.
+pub(crate) async fn embed(
+    client: &Client,
+    backend: &EmbeddingsBackendConfig,
+    input: Vec<String>,
+    dimensions: Option<u32>,
+) -> Result<Embeddings, ChatCompletionError> {
+    let expected = input.len();
+    let embeddings = match backend {
+        EmbeddingsBackendConfig::Openai { base_url, api_key, model } => {
+            embed_openai(client, base_url, api_key.as_deref(), model, input, dimensions).await?
+        },
+    };
+    if embeddings.vectors.len() != expected || embeddings.vectors.iter().any(Vec::is_empty) {
+        return Err(ChatCompletionError::DifyApiError(StatusCode::BAD_GATEWAY, format!(
+            "The embeddings backend returned {} vector(s) for {} input(s)",
+            embeddings.vectors.iter().filter(|v| !v.is_empty()).count(),
+            expected
+        )));
+    }
+    Ok(embeddings)
+}
+
+/// Turns a failed backend response into a client-facing error. The raw body
+/// is logged, not passed on; only the message of rejected input is.
+fn upstream_error(status: StatusCode, body: &str) -> ChatCompletionError {
+    warn!("Embeddings backend returned {}: {}", status, body);
+    let message = serde_json::from_str::<Value>(body).ok()
+        .and_then(|json| json["error"]["message"].as_str().or(json["message"].as_str()).map(String::from));
+    match status {
+        StatusCode::BAD_REQUEST | StatusCode::PAYLOAD_TOO_LARGE | StatusCode::UNPROCESSABLE_ENTITY => {
+            ChatCompletionError::RequestConstructionError(format!(
+                "The embeddings backend rejected the input: {}",
+                message.unwrap_or_else(|| "no reason given".to_string())
+            ))
+        },
+        StatusCode::TOO_MANY_REQUESTS => ChatCompletionError::DifyApiError(
+            status,
+            "The embeddings backend is rate limited; retry later".to_string(),
+        ),
+        _ => ChatCompletionError::DifyApiError(
+            StatusCode::BAD_GATEWAY,
+            format!("The embeddings backend failed ({})", status.as_u16()),
+        ),
+    }
+}
+
+async fn embed_openai(
+    client: &Client,
+    base_url: &str,
+    api_key: Option<&str>,
+    model: &str,
+    input: Vec<String>,
+    dimensions: Option<u32>,
+) -> Result<Embeddings, ChatCompletionError> {
+    let mut body = json!({
+        "model": model,
+        "input": input,
+        "encoding_format": "float",
+    });
+    if let Some(dimensions) = dimensions {
+        body["dimensions"] = json!(dimensions);
+    }
+
+    let mut request = client.post(format!("{}/embeddings", base_url.trim_end_matches('/'))).json(&body);
+    if let Some(api_key) = api_key {
+        request = request.header("Authorization", format!("Bearer {}", api_key));
+    }
+    let response = request.send().await.map_err(|e| {
+        warn!("Embeddings backend unreachable: {}", e);
+        ChatCompletionError::DifyApiError(StatusCode::BAD_GATEWAY, "The embeddings backend is unreachable".to_string())
+    })?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let error_text = response.text().await.unwrap_or_default();
+        return Err(upstream_error(status, &error_text));
+    }
+
+    let invalid = |reason: &str| {
+        warn!("Invalid embeddings response: {}", reason);
+        ChatCompletionError::DifyApiError(StatusCode::BAD_GATEWAY, "The embeddings backend returned an invalid response".to_string())
+    };
+    let raw: Value = response.json().await.map_err(|e| invalid(&e.to_string()))?;
+    let mut data: Vec<(u64, Vec<f32>)> = raw["data"]
+        .as_array()
+        .ok_or_else(|| invalid("missing 'data'"))?
+        .iter()
+        .enumerate()
+        .map(|(i, item)| {
+            let vector = item["embedding"]
+                .as_array()
+                .map(|values| values.iter().filter_map(Value::as_f64).map(|v| v as f32).collect())
+                .unwrap_or_default();
+            (item["index"].as_u64().unwrap_or(i as u64), vector)
+        })
+        .collect();
+    data.sort_by_key(|(index, _)| *index);
+
+    Ok(Embeddings {
+        vectors: data.into_iter().map(|(_, vector)| vector).collect(),
+        prompt_tokens: raw["usage"]["prompt_tokens"].as_u64().unwrap_or(0) as u32,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::features::dify::handlers::openai::suggested::tests::fake_dify;
+
+    fn backend(base_url: &str) -> EmbeddingsBackendConfig {
+        EmbeddingsBackendConfig::Openai { base_url: base_url.to_string(), api_key: Some("sk".to_string()), model: "e".to_string() }
+    }
+
+    fn status(error: &ChatCompletionError) -> u16 {
+        actix_web::ResponseError::status_code(error).as_u16()
+    }
+
+    #[actix_web::test]
+    async fn vectors_come_back_in_input_order() {
+        let (url, _) = fake_dify(200, json!({
+            "data": [{"index": 1, "embedding": [0.0, 1.0]}, {"index": 0, "embedding": [1.0, 0.0]}],
+            "usage": {"prompt_tokens": 4},
+        })).await;
+        let embeddings = embed(&Client::new(), &backend(&url), vec!["a".into(), "b".into()], None).await.unwrap();
+        assert_eq!(embeddings.vectors, [vec![1.0, 0.0], vec![0.0, 1.0]]);
+        assert_eq!(embeddings.prompt_tokens, 4);
+    }
+
+    #[actix_web::test]
+    async fn a_vector_per_input_is_required() {
+        let (url, _) = fake_dify(200, json!({"data": [{"index": 0, "embedding": [1.0]}]})).await;
+        let error = embed(&Client::new(), &backend(&url), vec!["a".into(), "b".into()], None).await.unwrap_err();
+        assert_eq!(status(&error), 502);
+        assert_eq!(error.client_message(), "The embeddings backend returned 1 vector(s) for 2 input(s)");
+    }
+
+    #[actix_web::test]
+    async fn backend_errors_are_not_passed_through() {
+        let (url, _) = fake_dify(401, json!({"error": {"message": "Incorrect API key provided: sk-live-123"}})).await;
+        let error = embed(&Client::new(), &backend(&url), vec!["a".into()], None).await.unwrap_err();
+        assert_eq!(status(&error), 502);
+        assert!(!error.client_message().contains("sk-live"));
+    }
+
+    #[test]
+    fn rejected_input_and_rate_limits_keep_their_meaning() {
+        let body = json!({"error": {"message": "input too long"}}).to_string();
+        let rejected = upstream_error(StatusCode::BAD_REQUEST, &body);
+        assert_eq!(status(&rejected), 400);
+        assert_eq!(rejected.client_message(), "The embeddings backend rejected the input: input too long");
+
+        assert_eq!(status(&upstream_error(StatusCode::TOO_MANY_REQUESTS, "")), 429);
+        let failed = upstream_error(StatusCode::INTERNAL_SERVER_ERROR, "<html>stack trace</html>");
+        assert_eq!(status(&failed), 502);
+        assert_eq!(failed.client_message(), "The embeddings backend failed (500)");
+    }
+}
@@ -0,0 +1,105 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use base64::Engine;
+use serde_json::Value;
+
+use crate::features::app::app_state::AppState;
+use crate::features::auth::resolver::resolve_embeddings;
+use crate::features::cache::index::normalize;
+use crate::features::common::types::{
+    EmbeddingData, EmbeddingInput, EmbeddingUsage, OpenAIEmbeddingRequest, OpenAIEmbeddingResponse,
+};
+use crate::features::dify::handlers::openai::chat_completion::{build_client, ChatCompletionError};
+use super::backend::embed;
+
+/// Upper bound on inputs per request, matching OpenAI's limit.
+const MAX_INPUTS: usize = 2048;
+
+fn encode(vector: Vec<f32>, base64: bool) -> Value {
+    if base64 {
+        let bytes: Vec<u8> = vector.iter().flat_map(|v| v.to_le_bytes()).collect();
+        Value::String(base64::engine::general_purpose::STANDARD.encode(bytes))
+    } else {
+        Value::from(vector)
+    }
+}
+
+pub async fn embeddings(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: web::Json<OpenAIEmbeddingRequest>,
+) -> Result<HttpResponse, ChatCompletionError> {
+    let config = state.config.snapshot();
+    let backend = resolve_embeddings(&req, &config, &body.model)?;
+
+    let inputs = match &body.input {
+        EmbeddingInput::Single(input) => vec![input.clone()],
+        EmbeddingInput::Batch(inputs) => inputs.clone(),
+    };
+    if inputs.is_empty() || inputs.len() > MAX_INPUTS {
+        return Err(ChatCompletionError::RequestConstructionError(format!(
+            "'input' must contain between 1 and {} items",
+            MAX_INPUTS
+        )));
+    }
+    if inputs.iter().any(|input| input.is_empty()) {
+        return Err(ChatCompletionError::RequestConstructionError("'input' must not contain empty strings".to_string()));
+    }
+    let base64 = match body.encoding_format.as_deref() {
+        None | Some("float") => false,
+        Some("base64") => true,
+        Some(other) => {
+            return Err(ChatCompletionError::RequestConstructionError(format!(
+                "Unsupported encoding_format '{}'",
+                other
+            )));
+        },
+    };
+    if body.dimensions == Some(0) {
+        return Err(ChatCompletionError::RequestConstructionError("'dimensions' must be positive".to_string()));
+    }
+
+    let client = build_client()?;
+    let result = embed(&client, &backend, inputs, body.dimensions).await?;
+
+    let data = result.vectors.into_iter()
+        .enumerate()
+        .map(|(index, mut vector)| {
+            // Backends that ignore `dimensions` are shortened the way OpenAI does it
+            if let Some(dimensions) = body.dimensions {
+                if vector.len() > dimensions as usize {
+                    vector.truncate(dimensions as usize);
+                    vector = normalize(vector);
+                }
+            }
+            EmbeddingData {
+                object: "embedding".to_string(),
+                index: index as u32,
+                embedding: encode(vector, base64),
+            }
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(OpenAIEmbeddingResponse {
+        object: "list".to_string(),
+        data,
+        model: body.model.clone(),
+        usage: EmbeddingUsage {
+            prompt_tokens: result.prompt_tokens,
+            total_tokens: result.prompt_tokens,
+        },
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vectors_encode_as_floats_or_little_endian_base64() {
+        assert_eq!(encode(vec![0.5, -1.0], false), serde_json::json!([0.5, -1.0]));
+
+        let encoded = encode(vec![1.0], true);
+        let bytes = base64::engine::general_purpose::STANDARD.decode(encoded.as_str().unwrap()).unwrap();
+        assert_eq!(bytes, 1.0f32.to_le_bytes());
+    }
+}
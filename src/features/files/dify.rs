@@ -0,0 +1,260 @@
+use base64::Engine;
+use reqwest::multipart::{Form, Part};
+use reqwest::Client;
+use serde_json::{json, Value};
+
+use crate::features::common::types::{MessageContent, OpenAIRequest};
+use crate::features::dify::handlers::openai::chat_completion::{read_dify_json, send_to_dify, ChatCompletionError};
+use super::store::{hash_key, FileStore};
+
+/// Dify's file type for a MIME type.
+fn dify_file_type(content_type: &str) -> &'static str {
+    match content_type.split('/').next() {
+        Some("image") => "image",
+        Some("audio") => "audio",
+        Some("video") => "video",
+        _ => "document",
+    }
+}
+
+/// Uploads a file to Dify `/files/upload` for the app with `api_key`,
+/// returning Dify's `upload_file_id`.
+pub(crate) async fn upload_to_dify(
+    client: &Client,
+    dify_api_url: &str,
+    api_key: &str,
+    user: &str,
+    filename: &str,
+    content_type: &str,
+    data: Vec<u8>,
+) -> Result<String, ChatCompletionError> {
+    let part = Part::bytes(data)
+        .file_name(filename.to_string())
+        .mime_str(content_type)
+        .map_err(|e| ChatCompletionError::RequestConstructionError(e.to_string()))?;
+    let form = Form::new()
+        .part("file", part)
+        .text("user", user.to_string());
+
+    let url = format!("{}/v1/files/upload", dify_api_url);
+    let response = read_dify_json(send_to_dify(client.post(url).multipart(form), api_key).await?).await?;
+    response["id"].as_str()
+        .map(str::to_string)
+        .ok_or_else(|| ChatCompletionError::JsonSerializationError("Dify file upload returned no id".to_string()))
+}
+
+/// A file a message refers to.
+enum MessageFile<'a> {
+    /// A `file` part: an upload from `/v1/files`.
+    Stored(&'a str),
+    /// An `image_url` part: a web URL or a `data:` URL.
+    Image(&'a str),
+}
+
+/// Dify `files` entries for the `file` and `image_url` content parts of the
+/// request's messages. Stored files are uploaded to the app on first use, and
+/// later requests reuse the recorded `upload_file_id`. Web images are passed
+/// to Dify by URL; `data:` images are uploaded with each request.
+pub(crate) async fn message_files(
+    client: &Client,
+    dify_api_url: &str,
+    store: &FileStore,
+    owner: &str,
+    app_key: &str,
+    user: &str,
+    openai_req: &OpenAIRequest,
+) -> Result<Option<Vec<Value>>, ChatCompletionError> {
+    let mut referenced: Vec<MessageFile> = Vec::new();
+    for message in &openai_req.messages {
+        if let MessageContent::Complex(parts) = &message.content {
+            for part in parts {
+                match (part.r#type.as_str(), &part.file, &part.image_url) {
+                    ("file", Some(file), _) => {
+                        if let Some(id) = file.file_id.as_deref() {
+                            if !referenced.iter().any(|f| matches!(f, MessageFile::Stored(other) if *other == id)) {
+                                referenced.push(MessageFile::Stored(id));
+                            }
+                        }
+                    },
+                    ("image_url", _, Some(image)) => referenced.push(MessageFile::Image(&image.url)),
+                    ("image_url", _, None) => {
+                        return Err(ChatCompletionError::RequestConstructionError(
+                            "image_url content parts need an 'image_url.url'".to_string(),
+                        ));
+                    },
+                    _ => {},
+                }
+            }
+        }
+    }
+    if referenced.is_empty() {
+        return Ok(None);
+    }
+
+    let app = hash_key(app_key);
+    let mut files = Vec::new();
+    for (index, file) in referenced.into_iter().enumerate() {
+        let id = match file {
+            MessageFile::Stored(id) => id,
+            MessageFile::Image(url) => {
+                files.push(image_file(client, dify_api_url, app_key, user, url, index).await?);
+                continue;
+            },
+        };
+        let file = store.get(owner, id).await
+            .ok_or_else(|| ChatCompletionError::RequestConstructionError(format!("No such File object: {}", id)))?;
+
+        let upload_file_id = match file.dify_uploads.get(&app) {
+            Some(upload_file_id) => upload_file_id.clone(),
+            None => {
+                let data = store.content(&file).await
+                    .map_err(|e| ChatCompletionError::JsonSerializationError(format!("Failed to read {}: {}", id, e)))?;
+                let upload_file_id = upload_to_dify(
+                    client, dify_api_url, app_key, user, &file.object.filename, &file.content_type, data,
+                ).await?;
+                store.set_dify_upload(id, app_key, upload_file_id.clone()).await;
+                upload_file_id
+            },
+        };
+
+        files.push(json!({
+            "type": dify_file_type(&file.content_type),
+            "transfer_method": "local_file",
+            "upload_file_id": upload_file_id,
+        }));
+    }
+    Ok(Some(files))
+}
+
+/// The Dify `files` entry for an image URL.
+async fn image_file(
+    client: &Client,
+    dify_api_url: &str,
+    app_key: &str,
+    user: &str,
+    url: &str,
+    index: usize,
+) -> Result<Value, ChatCompletionError> {
+    if url.starts_with("https://") || url.starts_with("http://") {
+        return Ok(json!({"type": "image", "transfer_method": "remote_url", "url": url}));
+    }
+    let (content_type, data) = parse_data_url(url).ok_or_else(|| ChatCompletionError::RequestConstructionError(
+        "image_url must be an http(s) URL or a base64 'data:image/...' URL".to_string(),
+    ))?;
+    let extension = content_type.strip_prefix("image/").unwrap_or("bin");
+    let filename = format!("image-{}.{}", index, extension);
+    let upload_file_id = upload_to_dify(client, dify_api_url, app_key, user, &filename, content_type, data).await?;
+    Ok(json!({"type": "image", "transfer_method": "local_file", "upload_file_id": upload_file_id}))
+}
+
+/// Splits a base64 `data:image/...` URL into its MIME type and bytes.
+fn parse_data_url(url: &str) -> Option<(&str, Vec<u8>)> {
+    let (header, data) = url.strip_prefix("data:")?.split_once(',')?;
+    let content_type = header.strip_suffix(";base64")?;
+    if !content_type.starts_with("image/") {
+        return None;
+    }
+    let data = base64::engine::general_purpose::STANDARD.decode(data.trim()).ok()?;
+    Some((content_type, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::features::dify::handlers::openai::chat_completion::construct_dify_request;
+    use std::path::PathBuf;
+
+    fn request(file_ids: &[&str]) -> OpenAIRequest {
+        let parts: Vec<Value> = file_ids.iter().map(|id| json!({"type": "file", "file": {"file_id": id}})).collect();
+        serde_json::from_value(json!({"model": "m", "messages": [{"role": "user", "content": parts}]})).unwrap()
+    }
+
+    fn store() -> (FileStore, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("file-dify-{}", uuid::Uuid::new_v4().simple()));
+        (FileStore::new(dir.clone(), 1024, 100, 1024 * 100).unwrap(), dir)
+    }
+
+    #[test]
+    fn file_types_follow_the_mime_type() {
+        assert_eq!(dify_file_type("image/png"), "image");
+        assert_eq!(dify_file_type("audio/mpeg"), "audio");
+        assert_eq!(dify_file_type("video/mp4"), "video");
+        assert_eq!(dify_file_type("application/pdf"), "document");
+    }
+
+    #[actix_web::test]
+    async fn recorded_uploads_are_reused() {
+        let (store, dir) = store();
+        let file = store.create("key", "a.png".to_string(), "assistants".to_string(), "image/png".to_string(), b"png")
+            .await
+            .unwrap();
+        store.set_dify_upload(&file.object.id, "app-key", "upload-1".to_string()).await;
+
+        let id = file.object.id.as_str();
+        let files = message_files(&Client::new(), "http://dify", &store, "key", "app-key", "u", &request(&[id, id]))
+            .await
+            .unwrap();
+        assert_eq!(files, Some(vec![json!({
+            "type": "image",
+            "transfer_method": "local_file",
+            "upload_file_id": "upload-1",
+        })]));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn data_urls_are_decoded() {
+        assert_eq!(parse_data_url("data:image/png;base64,cG5n"), Some(("image/png", b"png".to_vec())));
+        assert_eq!(parse_data_url("data:text/plain;base64,cG5n"), None);
+        assert_eq!(parse_data_url("data:image/png,png"), None);
+        assert_eq!(parse_data_url("data:image/png;base64,!!"), None);
+    }
+
+    #[actix_web::test]
+    async fn web_images_are_sent_by_url() {
+        let (store, dir) = store();
+        let openai_req: OpenAIRequest = serde_json::from_value(json!({"model": "m", "messages": [{"role": "user", "content": [
+            {"type": "text", "text": "What is this?"},
+            {"type": "image_url", "image_url": {"url": "https://example.com/a.png"}},
+        ]}]})).unwrap();
+        let mut request = construct_dify_request(&openai_req, None, Default::default()).await.unwrap();
+        request.files = message_files(&Client::new(), "http://dify", &store, "key", "app-key", "u", &openai_req)
+            .await
+            .unwrap();
+
+        let body = serde_json::to_value(&request).unwrap();
+        assert_eq!(body["query"], "What is this?");
+        assert_eq!(body["files"], json!([{
+            "type": "image",
+            "transfer_method": "remote_url",
+            "url": "https://example.com/a.png",
+        }]));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn other_image_urls_are_rejected() {
+        let (store, dir) = store();
+        for url in ["ftp://example.com/a.png", "data:text/plain;base64,cG5n"] {
+            let openai_req: OpenAIRequest = serde_json::from_value(json!({"model": "m", "messages": [{"role": "user", "content": [
+                {"type": "image_url", "image_url": {"url": url}},
+            ]}]})).unwrap();
+            let result = message_files(&Client::new(), "http://dify", &store, "key", "app-key", "u", &openai_req).await;
+            assert!(matches!(result, Err(ChatCompletionError::RequestConstructionError(_))), "{}", url);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn other_owners_files_are_not_found() {
+        let (store, dir) = store();
+        let file = store.create("key-a", "a.txt".to_string(), "assistants".to_string(), "text/plain".to_string(), b"a")
+            .await
+            .unwrap();
+
+        let result = message_files(&Client::new(), "http://dify", &store, "key-b", "app-key", "u", &request(&[&file.object.id])).await;
+        assert!(result.is_err());
+        assert_eq!(message_files(&Client::new(), "http://dify", &store, "key-b", "app-key", "u", &request(&[])).await.unwrap(), None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
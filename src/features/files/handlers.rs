@@ -0,0 +1,153 @@
+use actix_multipart::Multipart;
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::{web, HttpRequest, HttpResponse};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::features::app::app_state::AppState;
+use crate::features::auth::resolver::{authenticate, authenticate_storage};
+use crate::features::common::multipart::read_upload_form;
+use crate::features::common::types::{FileListResponse, FileObject};
+use crate::features::dify::handlers::openai::chat_completion::{build_client, ChatCompletionError};
+use super::store::StoredFile;
+
+/// Purposes accepted on upload, as in OpenAI's API.
+const PURPOSES: &[&str] = &["assistants", "batch", "fine-tune", "vision", "user_data", "evals"];
+
+/// Upper bound on `limit` when listing files.
+const MAX_LIST_LIMIT: usize = 10_000;
+
+fn not_found(id: &str) -> ChatCompletionError {
+    ChatCompletionError::DifyApiError(StatusCode::NOT_FOUND, format!("No such File object: {}", id))
+}
+
+fn storage_error(e: std::io::Error) -> ChatCompletionError {
+    if e.kind() == std::io::ErrorKind::QuotaExceeded {
+        return ChatCompletionError::DifyApiError(StatusCode::FORBIDDEN, e.to_string());
+    }
+    ChatCompletionError::JsonSerializationError(format!("File storage failed: {}", e))
+}
+
+/// The caller's file `id`.
+async fn owned_file(req: &HttpRequest, state: &AppState, id: &str) -> Result<(String, StoredFile), ChatCompletionError> {
+    let owner = authenticate(req, &state.config.snapshot())?;
+    let file = state.files.get(&owner, id).await.ok_or_else(|| not_found(id))?;
+    Ok((owner, file))
+}
+
+pub async fn upload_file(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    payload: Multipart,
+) -> Result<HttpResponse, ChatCompletionError> {
+    let owner = authenticate_storage(&req, &state, &build_client()?).await?;
+    let form = read_upload_form(payload, state.files.max_bytes()).await?;
+
+    let purpose = form.field("purpose")
+        .ok_or_else(|| ChatCompletionError::RequestConstructionError("'purpose' is required".to_string()))?
+        .to_string();
+    if !PURPOSES.contains(&purpose.as_str()) {
+        return Err(ChatCompletionError::RequestConstructionError(format!(
+            "Invalid purpose '{}'; expected one of {}",
+            purpose,
+            PURPOSES.join(", ")
+        )));
+    }
+    let file = form.file
+        .ok_or_else(|| ChatCompletionError::RequestConstructionError("'file' is required".to_string()))?;
+
+    // Trust a specific declared type, otherwise go by the extension
+    let content_type = file.content_type
+        .filter(|ct| ct != "application/octet-stream")
+        .unwrap_or_else(|| mime_guess::from_path(&file.filename).first_or_octet_stream().essence_str().to_string());
+
+    let stored = state.files.create(&owner, file.filename, purpose, content_type, &file.data)
+        .await
+        .map_err(storage_error)?;
+    Ok(HttpResponse::Ok().json(stored.object))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FileListQuery {
+    pub purpose: Option<String>,
+    pub limit: Option<usize>,
+    /// `asc` or `desc` (default) by creation time.
+    pub order: Option<String>,
+    /// Cursor: list files after this id.
+    pub after: Option<String>,
+}
+
+pub async fn list_files(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    query: web::Query<FileListQuery>,
+) -> Result<HttpResponse, ChatCompletionError> {
+    let owner = authenticate(&req, &state.config.snapshot())?;
+
+    let mut files: Vec<FileObject> = state.files.list(&owner).await
+        .into_iter()
+        .map(|file| file.object)
+        .filter(|file| query.purpose.is_none() || query.purpose.as_ref() == Some(&file.purpose))
+        .collect();
+    match query.order.as_deref() {
+        None | Some("desc") => files.reverse(),
+        Some("asc") => {},
+        Some(other) => {
+            return Err(ChatCompletionError::RequestConstructionError(format!("Invalid order '{}'", other)));
+        },
+    }
+    if let Some(after) = &query.after {
+        let position = files.iter().position(|f| &f.id == after).ok_or_else(|| not_found(after))?;
+        files.drain(..=position);
+    }
+
+    let limit = query.limit.unwrap_or(MAX_LIST_LIMIT).clamp(1, MAX_LIST_LIMIT);
+    let has_more = files.len() > limit;
+    files.truncate(limit);
+
+    Ok(HttpResponse::Ok().json(FileListResponse {
+        object: "list".to_string(),
+        first_id: files.first().map(|f| f.id.clone()),
+        last_id: files.last().map(|f| f.id.clone()),
+        data: files,
+        has_more,
+    }))
+}
+
+pub async fn retrieve_file(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ChatCompletionError> {
+    let (_, file) = owned_file(&req, &state, &path).await?;
+    Ok(HttpResponse::Ok().json(file.object))
+}
+
+pub async fn file_content(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ChatCompletionError> {
+    let (_, file) = owned_file(&req, &state, &path).await?;
+    let data = state.files.content(&file).await.map_err(storage_error)?;
+    Ok(HttpResponse::Ok()
+        .insert_header((CONTENT_TYPE, file.content_type))
+        .body(data))
+}
+
+pub async fn delete_file(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ChatCompletionError> {
+    let owner = authenticate(&req, &state.config.snapshot())?;
+    if !state.files.delete(&owner, &path).await {
+        return Err(not_found(&path));
+    }
+    Ok(HttpResponse::Ok().json(json!({
+        "id": path.as_str(),
+        "object": "file",
+        "deleted": true,
+    })))
+}
@@ -0,0 +1,3 @@
+pub mod dify;
+pub mod handlers;
+pub mod store;
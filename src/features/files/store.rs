@@ -0,0 +1,284 @@
+use chrono::Utc;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use tokio::fs;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::features::common::types::FileObject;
+
+/// A stored file's metadata.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredFile {
+    #[serde(flatten)]
+    pub object: FileObject,
+    /// Hash of the bearer token that uploaded the file; only that token sees it.
+    pub owner: String,
+    pub content_type: String,
+    /// Dify `upload_file_id` per app (hashed app key), so each app gets one upload.
+    #[serde(default)]
+    pub dify_uploads: HashMap<String, String>,
+}
+
+/// Files uploaded through `/v1/files`, kept on local disk: `{id}` holds the
+/// content and `{id}.json` the metadata. The metadata is also indexed in
+/// memory, so lookups don't touch the disk.
+pub struct FileStore {
+    dir: PathBuf,
+    max_bytes: usize,
+    /// Most files one owner may keep.
+    max_owner_files: usize,
+    /// Most bytes one owner may keep.
+    max_owner_bytes: u64,
+    /// Held across metadata writes, so updates reach the disk in order.
+    index: Mutex<Index>,
+}
+
+#[derive(Default)]
+struct Index {
+    files: HashMap<String, StoredFile>,
+    /// File ids per owner hash, oldest first.
+    by_owner: HashMap<String, Vec<String>>,
+}
+
+impl Index {
+    fn insert(&mut self, file: StoredFile) {
+        self.by_owner.entry(file.owner.clone()).or_default().push(file.object.id.clone());
+        self.files.insert(file.object.id.clone(), file);
+    }
+
+    fn remove(&mut self, id: &str) {
+        let Some(file) = self.files.remove(id) else { return };
+        if let Some(ids) = self.by_owner.get_mut(&file.owner) {
+            ids.retain(|other| other != id);
+            if ids.is_empty() {
+                self.by_owner.remove(&file.owner);
+            }
+        }
+    }
+
+    fn owned(&self, owner: &str, id: &str) -> Option<&StoredFile> {
+        self.files.get(id).filter(|file| file.owner == hash_key(owner))
+    }
+
+    /// How many files, and bytes, the owner with hash `owner` keeps.
+    fn usage(&self, owner: &str) -> (usize, u64) {
+        let ids = self.by_owner.get(owner).map(Vec::as_slice).unwrap_or_default();
+        (ids.len(), ids.iter().filter_map(|id| self.files.get(id)).map(|file| file.object.bytes).sum())
+    }
+}
+
+/// Hex SHA-1 of a secret, so keys aren't written to disk.
+pub(crate) fn hash_key(key: &str) -> String {
+    Sha1::digest(key.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Whether `id` looks like one of our ids, which keeps it safe to use as a file name.
+fn valid_id(id: &str) -> bool {
+    id.strip_prefix("file-").is_some_and(|rest| !rest.is_empty() && rest.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+impl FileStore {
+    /// Opens the store in `dir`, indexing the files already there. Uploads
+    /// are limited to `max_bytes` each, and each owner to `max_owner_files`
+    /// files and `max_owner_bytes` bytes.
+    pub fn new(dir: PathBuf, max_bytes: usize, max_owner_files: usize, max_owner_bytes: u64) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        let mut files: Vec<StoredFile> = std::fs::read_dir(&dir)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().to_str()?.strip_suffix(".json").map(str::to_string))
+            .filter(|id| valid_id(id))
+            .filter_map(|id| {
+                let raw = std::fs::read_to_string(dir.join(format!("{}.json", id))).ok()?;
+                serde_json::from_str(&raw).ok()
+            })
+            .collect();
+        files.sort_by(|a, b| (a.object.created_at, &a.object.id).cmp(&(b.object.created_at, &b.object.id)));
+
+        let mut index = Index::default();
+        files.into_iter().for_each(|file| index.insert(file));
+        Ok(FileStore { dir, max_bytes, max_owner_files, max_owner_bytes, index: Mutex::new(index) })
+    }
+
+    /// Largest accepted upload.
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    fn content_path(&self, id: &str) -> PathBuf {
+        self.dir.join(id)
+    }
+
+    fn metadata_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    async fn write_metadata(&self, file: &StoredFile) -> std::io::Result<()> {
+        let raw = serde_json::to_vec(file).map_err(std::io::Error::other)?;
+        fs::write(self.metadata_path(&file.object.id), raw).await
+    }
+
+    /// Stores a file for `owner`. Fails with `QuotaExceeded` if it would take
+    /// the owner over their limits.
+    pub async fn create(
+        &self,
+        owner: &str,
+        filename: String,
+        purpose: String,
+        content_type: String,
+        data: &[u8],
+    ) -> std::io::Result<StoredFile> {
+        let file = StoredFile {
+            object: FileObject {
+                id: format!("file-{}", Uuid::new_v4().simple()),
+                object: "file".to_string(),
+                bytes: data.len() as u64,
+                created_at: Utc::now().timestamp(),
+                filename,
+                purpose,
+                status: "processed".to_string(),
+            },
+            owner: hash_key(owner),
+            content_type,
+            dify_uploads: HashMap::new(),
+        };
+
+        // The quota is checked and the file written under the lock, so
+        // concurrent uploads can't overshoot it together
+        let mut index = self.index.lock().await;
+        let (files, bytes) = index.usage(&file.owner);
+        if files >= self.max_owner_files || bytes + file.object.bytes > self.max_owner_bytes {
+            return Err(std::io::Error::new(std::io::ErrorKind::QuotaExceeded, format!(
+                "File quota exceeded: at most {} files and {} bytes per key; delete files to upload more",
+                self.max_owner_files, self.max_owner_bytes
+            )));
+        }
+        fs::write(self.content_path(&file.object.id), data).await?;
+        if let Err(e) = self.write_metadata(&file).await {
+            let _ = fs::remove_file(self.content_path(&file.object.id)).await;
+            return Err(e);
+        }
+        index.insert(file.clone());
+        Ok(file)
+    }
+
+    /// The file `id` if `owner` uploaded it.
+    pub async fn get(&self, owner: &str, id: &str) -> Option<StoredFile> {
+        self.index.lock().await.owned(owner, id).cloned()
+    }
+
+    /// `owner`'s files, oldest first.
+    pub async fn list(&self, owner: &str) -> Vec<StoredFile> {
+        let index = self.index.lock().await;
+        index.by_owner.get(&hash_key(owner))
+            .map(|ids| ids.iter().filter_map(|id| index.files.get(id).cloned()).collect())
+            .unwrap_or_default()
+    }
+
+    pub async fn content(&self, file: &StoredFile) -> std::io::Result<Vec<u8>> {
+        fs::read(self.content_path(&file.object.id)).await
+    }
+
+    /// Deletes `owner`'s file `id`, returning whether it existed.
+    pub async fn delete(&self, owner: &str, id: &str) -> bool {
+        let mut index = self.index.lock().await;
+        if index.owned(owner, id).is_none() {
+            return false;
+        }
+        index.remove(id);
+        let _ = fs::remove_file(self.content_path(id)).await;
+        let _ = fs::remove_file(self.metadata_path(id)).await;
+        true
+    }
+
+    /// Records the Dify upload of `id` for the app with `app_key`.
+    pub async fn set_dify_upload(&self, id: &str, app_key: &str, upload_file_id: String) {
+        let mut index = self.index.lock().await;
+        let Some(file) = index.files.get_mut(id) else { return };
+        file.dify_uploads.insert(hash_key(app_key), upload_file_id);
+        let file = file.clone();
+        if let Err(e) = self.write_metadata(&file).await {
+            warn!("Failed to record Dify upload of {}: {}", id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> (FileStore, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("file-store-{}", Uuid::new_v4().simple()));
+        (FileStore::new(dir.clone(), 1024, 3, 1024).unwrap(), dir)
+    }
+
+    async fn upload(store: &FileStore, owner: &str, name: &str) -> StoredFile {
+        store.create(owner, name.to_string(), "assistants".to_string(), "text/plain".to_string(), name.as_bytes())
+            .await
+            .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn files_are_scoped_to_their_owner() {
+        let (store, dir) = store();
+        let a = upload(&store, "key-a", "a.txt").await;
+        let b = upload(&store, "key-b", "b.txt").await;
+
+        assert!(store.get("key-a", &a.object.id).await.is_some());
+        assert!(store.get("key-a", &b.object.id).await.is_none());
+        assert_eq!(store.list("key-b").await.iter().map(|f| f.object.id.clone()).collect::<Vec<_>>(), vec![b.object.id.clone()]);
+        assert!(!store.delete("key-a", &b.object.id).await);
+        assert!(store.delete("key-b", &b.object.id).await);
+        assert!(store.list("key-b").await.is_empty());
+        assert!(!dir.join(&b.object.id).exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn owners_keep_to_their_quota() {
+        let (store, dir) = store();
+        for name in ["1", "2", "3"] {
+            upload(&store, "key", name).await;
+        }
+        async fn create(store: &FileStore, owner: &str, data: &[u8]) -> std::io::Result<StoredFile> {
+            store.create(owner, "f".to_string(), "assistants".to_string(), "text/plain".to_string(), data).await
+        }
+        let error = create(&store, "key", b"4").await.unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::QuotaExceeded);
+        // Other owners have their own quota, in files and in bytes
+        create(&store, "other", &[0; 1000]).await.unwrap();
+        assert_eq!(create(&store, "other", &[0; 100]).await.unwrap_err().kind(), std::io::ErrorKind::QuotaExceeded);
+
+        let first = store.list("key").await.remove(0);
+        assert!(store.delete("key", &first.object.id).await);
+        create(&store, "key", b"4").await.unwrap();
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn index_is_rebuilt_from_disk() {
+        let (store, dir) = store();
+        let first = upload(&store, "key", "first.txt").await;
+        let second = upload(&store, "key", "second.txt").await;
+        store.set_dify_upload(&first.object.id, "app-key", "upload-1".to_string()).await;
+        std::fs::write(dir.join("notes.json"), "{}").unwrap();
+        drop(store);
+
+        let reopened = FileStore::new(dir.clone(), 1024, 3, 1024).unwrap();
+        let ids: Vec<String> = reopened.list("key").await.into_iter().map(|f| f.object.id).collect();
+        let mut expected = [&first.object, &second.object];
+        expected.sort_by_key(|f| (f.created_at, f.id.clone()));
+        assert_eq!(ids, expected.iter().map(|f| f.id.clone()).collect::<Vec<_>>());
+
+        let file = reopened.get("key", &first.object.id).await.unwrap();
+        assert_eq!(file.dify_uploads.get(&hash_key("app-key")).map(String::as_str), Some("upload-1"));
+        assert_eq!(reopened.content(&file).await.unwrap(), b"first.txt");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,138 @@
+use reqwest::Client;
+use serde_json::{json, Map, Value};
+
+use crate::features::common::types::{SearchResultContent, VectorStoreSearchResult};
+use crate::features::config::proxy_config::DatasetConfig;
+use crate::features::dify::handlers::openai::chat_completion::{post_to_dify, read_dify_json, ChatCompletionError};
+
+/// `top_k` sent when the proxy builds the `retrieval_model` itself.
+const DEFAULT_TOP_K: u32 = 4;
+
+/// Searches a Dify knowledge base through `/datasets/{id}/retrieve`, best match first.
+pub(crate) async fn retrieve(
+    client: &Client,
+    dify_api_url: &str,
+    dataset: &DatasetConfig,
+    query: &str,
+    top_k: Option<u32>,
+    score_threshold: Option<f32>,
+) -> Result<Vec<VectorStoreSearchResult>, ChatCompletionError> {
+    let mut body = json!({ "query": query });
+    if let Some(retrieval_model) = retrieval_model(dataset, top_k, score_threshold) {
+        body["retrieval_model"] = retrieval_model;
+    }
+
+    let url = format!("{}/v1/datasets/{}/retrieve", dify_api_url, dataset.dataset_id);
+    let response = read_dify_json(post_to_dify(client, &url, &dataset.api_key, &body).await?).await?;
+
+    let mut results: Vec<VectorStoreSearchResult> = response["records"]
+        .as_array()
+        .map(|records| records.iter().map(search_result).collect())
+        .unwrap_or_default();
+    results.sort_by(|a, b| b.score.total_cmp(&a.score));
+    if let Some(top_k) = top_k {
+        results.truncate(top_k as usize);
+    }
+    Ok(results)
+}
+
+/// The configured `retrieval_model` with the request's overrides applied, or
+/// `None` to leave the dataset's own settings in place.
+fn retrieval_model(dataset: &DatasetConfig, top_k: Option<u32>, score_threshold: Option<f32>) -> Option<Value> {
+    if dataset.retrieval_model.is_none() && top_k.is_none() && score_threshold.is_none() {
+        return None;
+    }
+
+    let mut retrieval_model = dataset.retrieval_model.clone().unwrap_or_else(|| json!({
+        "search_method": "semantic_search",
+        "reranking_enable": false,
+        "score_threshold_enabled": false,
+    }));
+    match top_k {
+        Some(top_k) => retrieval_model["top_k"] = json!(top_k),
+        None if retrieval_model.get("top_k").is_none() => retrieval_model["top_k"] = json!(DEFAULT_TOP_K),
+        None => {},
+    }
+    if let Some(score_threshold) = score_threshold {
+        retrieval_model["score_threshold_enabled"] = json!(true);
+        retrieval_model["score_threshold"] = json!(score_threshold);
+    }
+    Some(retrieval_model)
+}
+
+/// Maps a Dify retrieval record (a document segment) to a search result.
+fn search_result(record: &Value) -> VectorStoreSearchResult {
+    let segment = &record["segment"];
+    let document = &segment["document"];
+
+    let mut attributes = Map::new();
+    attributes.insert("segment_id".to_string(), segment["id"].clone());
+    attributes.insert("position".to_string(), segment["position"].clone());
+
+    // Q&A segments keep the answer next to the question
+    let content = [&segment["content"], &segment["answer"]]
+        .into_iter()
+        .filter_map(Value::as_str)
+        .filter(|text| !text.is_empty())
+        .map(|text| SearchResultContent { r#type: "text".to_string(), text: text.to_string() })
+        .collect();
+
+    VectorStoreSearchResult {
+        file_id: segment["document_id"].as_str().or(document["id"].as_str()).unwrap_or_default().to_string(),
+        filename: document["name"].as_str().unwrap_or_default().to_string(),
+        score: record["score"].as_f64().unwrap_or(0.0) as f32,
+        attributes,
+        content,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dataset(retrieval_model: Option<Value>) -> DatasetConfig {
+        DatasetConfig { dataset_id: "ds".to_string(), api_key: "dataset-key".to_string(), retrieval_model }
+    }
+
+    #[test]
+    fn dataset_settings_apply_without_overrides() {
+        assert!(retrieval_model(&dataset(None), None, None).is_none());
+    }
+
+    #[test]
+    fn overrides_apply_on_top_of_the_configured_model() {
+        let model = retrieval_model(&dataset(None), Some(8), None).unwrap();
+        assert_eq!(model["search_method"], "semantic_search");
+        assert_eq!(model["top_k"], 8);
+
+        let configured = dataset(Some(json!({"search_method": "hybrid_search", "top_k": 2})));
+        assert_eq!(retrieval_model(&configured, None, None).unwrap()["top_k"], 2);
+        let model = retrieval_model(&configured, None, Some(0.5)).unwrap();
+        assert_eq!(model["search_method"], "hybrid_search");
+        assert_eq!(model["score_threshold_enabled"], true);
+        assert_eq!(model["score_threshold"], 0.5);
+
+        assert_eq!(retrieval_model(&dataset(Some(json!({}))), None, None).unwrap()["top_k"], DEFAULT_TOP_K);
+    }
+
+    #[test]
+    fn records_become_search_results() {
+        let record = json!({
+            "score": 0.8,
+            "segment": {
+                "id": "seg1",
+                "position": 3,
+                "document_id": "doc1",
+                "content": "Question?",
+                "answer": "Answer.",
+                "document": {"id": "doc1", "name": "faq.md"},
+            },
+        });
+
+        let result = search_result(&record);
+        assert_eq!((result.file_id.as_str(), result.filename.as_str()), ("doc1", "faq.md"));
+        assert_eq!(result.score, 0.8);
+        assert_eq!(result.attributes["segment_id"], "seg1");
+        assert_eq!(result.content.iter().map(|c| c.text.as_str()).collect::<Vec<_>>(), ["Question?", "Answer."]);
+    }
+}
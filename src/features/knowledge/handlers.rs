@@ -0,0 +1,51 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+
+use crate::features::app::app_state::AppState;
+use crate::features::auth::resolver::resolve_dataset;
+use crate::features::common::types::{VectorStoreSearchRequest, VectorStoreSearchResponse};
+use crate::features::dify::handlers::openai::chat_completion::{build_client, ChatCompletionError};
+use super::dataset::retrieve;
+
+/// Upper bound on `max_num_results`, matching OpenAI's limit.
+const MAX_RESULTS: u32 = 50;
+
+/// Searches the knowledge base registered as `vector_store_id`.
+pub async fn search_vector_store(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    body: web::Json<VectorStoreSearchRequest>,
+) -> Result<HttpResponse, ChatCompletionError> {
+    let config = state.config.snapshot();
+    let dataset = resolve_dataset(&req, &config, &path)?;
+
+    if body.query.trim().is_empty() {
+        return Err(ChatCompletionError::RequestConstructionError("'query' must not be empty".to_string()));
+    }
+    if body.max_num_results.is_some_and(|max| max == 0 || max > MAX_RESULTS) {
+        return Err(ChatCompletionError::RequestConstructionError(format!(
+            "'max_num_results' must be between 1 and {}",
+            MAX_RESULTS
+        )));
+    }
+    if body.filters.is_some() {
+        return Err(ChatCompletionError::RequestConstructionError("'filters' are not supported".to_string()));
+    }
+    let score_threshold = body.ranking_options.as_ref().and_then(|r| r.score_threshold);
+    if score_threshold.is_some_and(|t| !(0.0..=1.0).contains(&t)) {
+        return Err(ChatCompletionError::RequestConstructionError(
+            "'ranking_options.score_threshold' must be between 0 and 1".to_string(),
+        ));
+    }
+
+    let client = build_client()?;
+    let data = retrieve(&client, &state.dify_api_url, &dataset, &body.query, body.max_num_results, score_threshold).await?;
+
+    Ok(HttpResponse::Ok().json(VectorStoreSearchResponse {
+        object: "vector_store.search_results.page".to_string(),
+        search_query: body.query.clone(),
+        data,
+        has_more: false,
+        next_page: None,
+    }))
+}
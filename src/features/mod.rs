@@ -1,3 +1,13 @@
+pub mod conversations;
 pub mod dify;
+pub mod embeddings;
+pub mod files;
+pub mod knowledge;
+pub mod threads;
 pub mod app;
-pub mod common;
\ No newline at end of file
+pub mod audio;
+pub mod cache;
+pub mod common;
+pub mod config;
+pub mod auth;
+pub mod admin;
@@ -0,0 +1,411 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::Utc;
+use reqwest::{Client, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::features::app::app_state::AppState;
+use crate::features::auth::resolver::{authenticate, authenticate_storage, resolve_target};
+use crate::features::common::types::{
+    CreateThreadRequest, MessageContent, ThreadContent, ThreadMessage, ThreadMessageList, ThreadMessageRequest,
+    ThreadText,
+};
+use crate::features::dify::citations::{AnnotationStyle, Citations};
+use crate::features::dify::handlers::openai::chat_completion::{
+    build_client, read_dify_json, send_to_dify, ChatCompletionError,
+};
+use super::store::StoredThread;
+
+/// Upper bound on `limit` when listing messages.
+const MAX_LIST_LIMIT: usize = 100;
+
+pub(crate) fn not_found(id: &str) -> ChatCompletionError {
+    ChatCompletionError::DifyApiError(StatusCode::NOT_FOUND, format!("No thread found with id '{}'.", id))
+}
+
+pub(crate) fn storage_error(e: std::io::Error) -> ChatCompletionError {
+    if e.kind() == std::io::ErrorKind::QuotaExceeded {
+        return ChatCompletionError::DifyApiError(StatusCode::FORBIDDEN, e.to_string());
+    }
+    ChatCompletionError::JsonSerializationError(format!("Thread storage failed: {}", e))
+}
+
+pub(crate) fn text_content(value: String) -> ThreadContent {
+    ThreadContent::Text { text: ThreadText { value, annotations: Vec::new() } }
+}
+
+/// Assistant text with its knowledge base citations as annotations.
+pub(crate) fn cited_content(value: String, citations: &Citations) -> ThreadContent {
+    let annotations = citations.annotations(value.chars().count(), AnnotationStyle::Assistants);
+    ThreadContent::Text { text: ThreadText { value, annotations } }
+}
+
+/// A thread message not yet sent to Dify.
+pub(crate) fn new_message(thread_id: &str, request: &ThreadMessageRequest) -> Result<ThreadMessage, ChatCompletionError> {
+    if request.role != "user" && request.role != "assistant" {
+        return Err(ChatCompletionError::RequestConstructionError(format!(
+            "Invalid role '{}'; expected 'user' or 'assistant'",
+            request.role
+        )));
+    }
+    let content = match &request.content {
+        MessageContent::String(text) => vec![text_content(text.clone())],
+        MessageContent::Complex(parts) => parts.iter()
+            .map(|part| match (part.r#type.as_str(), &part.image_url) {
+                ("text", _) => Ok(text_content(part.text.clone())),
+                ("image_url", Some(image_url)) => Ok(ThreadContent::ImageUrl { image_url: image_url.clone() }),
+                (other, _) => Err(ChatCompletionError::RequestConstructionError(format!(
+                    "Unsupported message content type '{}'",
+                    other
+                ))),
+            })
+            .collect::<Result<_, _>>()?,
+    };
+    Ok(ThreadMessage {
+        id: format!("msg_{}", Uuid::new_v4().simple()),
+        object: "thread.message".to_string(),
+        created_at: Utc::now().timestamp(),
+        thread_id: thread_id.to_string(),
+        status: "completed".to_string(),
+        role: request.role.clone(),
+        content,
+        assistant_id: None,
+        run_id: None,
+        attachments: Vec::new(),
+        metadata: request.metadata.clone().unwrap_or_default(),
+    })
+}
+
+/// The caller's thread `id`.
+async fn owned_thread(req: &HttpRequest, state: &AppState, id: &str) -> Result<(String, StoredThread), ChatCompletionError> {
+    let owner = authenticate(req, &state.config.snapshot())?;
+    let thread = state.threads.get(&owner, id).await.ok_or_else(|| not_found(id))?;
+    Ok((owner, thread))
+}
+
+pub async fn create_thread(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: Option<web::Json<CreateThreadRequest>>,
+) -> Result<HttpResponse, ChatCompletionError> {
+    let owner = authenticate_storage(&req, &state, &build_client()?).await?;
+    let body = body.map(|b| b.into_inner()).unwrap_or_default();
+
+    // Validate before the thread exists, then stamp the messages with its id
+    let messages = body.messages.iter()
+        .map(|m| new_message("", m))
+        .collect::<Result<Vec<_>, _>>()?;
+    let thread = state.threads
+        .create(&owner, body.metadata.unwrap_or_default(), |id| {
+            messages.into_iter().map(|m| ThreadMessage { thread_id: id.to_string(), ..m }).collect()
+        })
+        .await
+        .map_err(storage_error)?;
+    Ok(HttpResponse::Ok().json(thread.object))
+}
+
+pub async fn retrieve_thread(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ChatCompletionError> {
+    let (_, thread) = owned_thread(&req, &state, &path).await?;
+    Ok(HttpResponse::Ok().json(thread.object))
+}
+
+/// Adds a message to the thread. It is sent to Dify with the next run.
+pub async fn create_message(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    body: web::Json<ThreadMessageRequest>,
+) -> Result<HttpResponse, ChatCompletionError> {
+    let thread_id = path.into_inner();
+    let owner = authenticate_storage(&req, &state, &build_client()?).await?;
+    let message = new_message(&thread_id, &body)?;
+    state.threads
+        .add_message(&owner, &thread_id, message.clone())
+        .await
+        .map_err(storage_error)?
+        .ok_or_else(|| not_found(&thread_id))?;
+    Ok(HttpResponse::Ok().json(message))
+}
+
+/// Deletes the thread. Its Dify conversation is kept.
+pub async fn delete_thread(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, ChatCompletionError> {
+    let owner = authenticate(&req, &state.config.snapshot())?;
+    if !state.threads.delete(&owner, &path).await.map_err(storage_error)? {
+        return Err(not_found(&path));
+    }
+    Ok(HttpResponse::Ok().json(json!({
+        "id": path.as_str(),
+        "object": "thread.deleted",
+        "deleted": true,
+    })))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListMessagesQuery {
+    pub limit: Option<usize>,
+    pub order: Option<String>,
+    pub after: Option<String>,
+    pub before: Option<String>,
+    pub run_id: Option<String>,
+}
+
+impl ListMessagesQuery {
+    /// Picks the page out of `messages`, the thread's newest messages read so
+    /// far (newest first; `complete` once there are no older ones). Returns
+    /// `None` while the page depends on messages not read yet.
+    fn page(
+        &self,
+        messages: &[ThreadMessage],
+        limit: usize,
+        complete: bool,
+    ) -> Result<Option<(Vec<ThreadMessage>, bool)>, ChatCompletionError> {
+        let asc = self.order.as_deref() == Some("asc");
+        // The cursors bound the page from the newer and from the older side
+        let (newer_than, older_than) = if asc { (&self.after, &self.before) } else { (&self.before, &self.after) };
+        let locate = |cursor: &Option<String>| match cursor {
+            None => Ok(Some(None)),
+            Some(id) => match messages.iter().position(|m| &m.id == id) {
+                Some(i) => Ok(Some(Some(i))),
+                None if complete => Err(ChatCompletionError::DifyApiError(
+                    StatusCode::NOT_FOUND,
+                    format!("No message found with id '{}'.", id),
+                )),
+                None => Ok(None),
+            },
+        };
+        let (Some(end), Some(start)) = (locate(newer_than)?, locate(older_than)?) else { return Ok(None) };
+        let start = start.map_or(0, |i| i + 1);
+        let end_known = end.is_some() || complete;
+        let end = end.unwrap_or(messages.len()).max(start);
+        let mut page: Vec<ThreadMessage> = messages[start..end].iter()
+            .filter(|m| self.run_id.is_none() || m.run_id == self.run_id)
+            .cloned()
+            .collect();
+
+        // The page is the `limit` messages next to `before`, or else from
+        // `after` (or the first) on. Unless those sit at the newest end of the
+        // range, the range must be read to its older end first.
+        let from_newest = asc == self.before.is_some();
+        if !(end_known || (from_newest && page.len() > limit)) {
+            return Ok(None);
+        }
+        if asc {
+            page.reverse();
+        }
+        let has_more = page.len() > limit;
+        if self.before.is_some() {
+            page.drain(..page.len().saturating_sub(limit));
+        } else {
+            page.truncate(limit);
+        }
+        Ok(Some((page, has_more)))
+    }
+}
+
+/// Lists the thread's messages: the Dify conversation history from
+/// `/messages`, followed by messages added since the last run. The history is
+/// read newest first, only as far back as the page needs; ascending pages
+/// without an `after` cursor start at the oldest message and so read it all.
+pub async fn list_messages(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<ListMessagesQuery>,
+) -> Result<HttpResponse, ChatCompletionError> {
+    let (_, thread) = owned_thread(&req, &state, &path).await?;
+    let limit = query.limit.unwrap_or(20).clamp(1, MAX_LIST_LIMIT);
+
+    let mut history = match (&thread.assistant_id, &thread.conversation_id) {
+        (Some(assistant_id), Some(conversation_id)) => Some(History {
+            client: build_client()?,
+            url: format!("{}/v1/messages", state.dify_api_url),
+            api_key: resolve_target(&req, &state.config.snapshot(), Some(assistant_id))?.api_key,
+            conversation_id: conversation_id.clone(),
+            user: thread.user.clone().unwrap_or_else(|| "default_user".to_string()),
+            first_id: None,
+        }),
+        _ => None,
+    };
+    let mut messages: Vec<ThreadMessage> = thread.pending.iter().rev().cloned().collect();
+    let (messages, has_more) = loop {
+        if let Some(page) = query.page(&messages, limit, history.is_none())? {
+            break page;
+        }
+        if let Some(pages) = &mut history {
+            let (older, more) = pages.next(&thread, limit + 1).await?;
+            messages.extend(older);
+            if !more {
+                history = None;
+            }
+        }
+    };
+
+    Ok(HttpResponse::Ok().json(ThreadMessageList {
+        object: "list".to_string(),
+        first_id: messages.first().map(|m| m.id.clone()),
+        last_id: messages.last().map(|m| m.id.clone()),
+        data: messages,
+        has_more,
+    }))
+}
+
+/// Reads a Dify conversation backwards from its newest message, a page of
+/// `/messages` at a time.
+struct History {
+    client: Client,
+    url: String,
+    api_key: String,
+    conversation_id: String,
+    user: String,
+    /// Oldest message read so far; Dify pages back from it.
+    first_id: Option<String>,
+}
+
+impl History {
+    /// The next (older) page as thread messages, newest first, and whether
+    /// there are older ones still.
+    async fn next(&mut self, thread: &StoredThread, limit: usize) -> Result<(Vec<ThreadMessage>, bool), ChatCompletionError> {
+        let limit = limit.min(MAX_LIST_LIMIT).to_string();
+        let mut params = vec![
+            ("conversation_id", self.conversation_id.as_str()),
+            ("user", self.user.as_str()),
+            ("limit", limit.as_str()),
+        ];
+        if let Some(first_id) = &self.first_id {
+            params.push(("first_id", first_id));
+        }
+        let page = read_dify_json(send_to_dify(self.client.get(&self.url).query(&params), &self.api_key).await?).await?;
+        let data = page["data"].as_array().cloned().unwrap_or_default();
+        self.first_id = data.first().and_then(|m| m["id"].as_str()).map(String::from);
+        let messages = data.iter().rev().flat_map(|m| dify_message(thread, m).into_iter().rev()).collect();
+        Ok((messages, page["has_more"].as_bool().unwrap_or(false) && self.first_id.is_some()))
+    }
+}
+
+/// A Dify message as the user message (`query`) and assistant message (`answer`)
+/// it holds. The assistant message and its run share the Dify `message_id`.
+fn dify_message(thread: &StoredThread, message: &Value) -> Vec<ThreadMessage> {
+    let id = message["id"].as_str().unwrap_or_default();
+    let created_at = message["created_at"].as_i64().unwrap_or_default();
+    let build = |id: String, role: &str, content: ThreadContent, run_id: Option<String>| ThreadMessage {
+        id,
+        object: "thread.message".to_string(),
+        created_at,
+        thread_id: thread.object.id.clone(),
+        status: "completed".to_string(),
+        role: role.to_string(),
+        content: vec![content],
+        assistant_id: run_id.as_ref().and(thread.assistant_id.clone()),
+        run_id,
+        attachments: Vec::new(),
+        metadata: serde_json::Map::new(),
+    };
+
+    let mut messages = Vec::new();
+    if let Some(query) = message["query"].as_str().filter(|q| !q.is_empty()) {
+        messages.push(build(format!("{}-query", id), "user", text_content(query.to_string()), None));
+    }
+    let answer = message["answer"].as_str().unwrap_or_default().to_string();
+    let content = cited_content(answer, &Citations::new(message, false));
+    messages.push(build(id.to_string(), "assistant", content, Some(id.to_string())));
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Messages `m9` (newest) down to `m0`.
+    fn newest_first() -> Vec<ThreadMessage> {
+        (0..10).rev()
+            .map(|i| {
+                let request = ThreadMessageRequest {
+                    role: "user".to_string(),
+                    content: MessageContent::String(i.to_string()),
+                    metadata: None,
+                };
+                ThreadMessage { id: format!("m{}", i), ..new_message("thread_1", &request).unwrap() }
+            })
+            .collect()
+    }
+
+    fn query(order: &str, after: Option<&str>, before: Option<&str>) -> ListMessagesQuery {
+        ListMessagesQuery {
+            limit: None,
+            order: Some(order.to_string()),
+            after: after.map(String::from),
+            before: before.map(String::from),
+            run_id: None,
+        }
+    }
+
+    fn ids(page: Option<(Vec<ThreadMessage>, bool)>) -> Option<(Vec<String>, bool)> {
+        page.map(|(messages, has_more)| (messages.into_iter().map(|m| m.id).collect(), has_more))
+    }
+
+    #[test]
+    fn descending_pages_need_only_the_newest_messages() {
+        let messages = newest_first();
+        let page = query("desc", None, None).page(&messages[..4], 3, false).unwrap();
+        assert_eq!(ids(page), Some((vec!["m9".into(), "m8".into(), "m7".into()], true)));
+
+        let page = query("desc", Some("m7"), None).page(&messages[..4], 3, false).unwrap();
+        assert!(page.is_none());
+        let page = query("desc", Some("m7"), None).page(&messages, 3, true).unwrap();
+        assert_eq!(ids(page), Some((vec!["m6".into(), "m5".into(), "m4".into()], true)));
+    }
+
+    #[test]
+    fn before_takes_the_messages_next_to_it() {
+        let messages = newest_first();
+        let page = query("desc", None, Some("m3")).page(&messages[..7], 2, false).unwrap();
+        assert_eq!(ids(page), Some((vec!["m5".into(), "m4".into()], true)));
+
+        let page = query("asc", None, Some("m6")).page(&messages[..7], 2, false).unwrap();
+        assert_eq!(ids(page), Some((vec!["m4".into(), "m5".into()], true)));
+    }
+
+    #[test]
+    fn ascending_pages_start_at_the_oldest_message() {
+        let messages = newest_first();
+        assert!(query("asc", None, None).page(&messages, 3, false).unwrap().is_none());
+        let page = query("asc", None, None).page(&messages, 3, true).unwrap();
+        assert_eq!(ids(page), Some((vec!["m0".into(), "m1".into(), "m2".into()], true)));
+
+        let page = query("asc", Some("m7"), None).page(&messages[..3], 3, false).unwrap();
+        assert_eq!(ids(page), Some((vec!["m8".into(), "m9".into()], false)));
+    }
+
+    #[test]
+    fn unknown_cursors_are_rejected_once_everything_is_read() {
+        let messages = newest_first();
+        assert!(query("desc", Some("gone"), None).page(&messages, 3, false).unwrap().is_none());
+        assert!(query("desc", Some("gone"), None).page(&messages, 3, true).is_err());
+        assert!(query("desc", None, Some("gone")).page(&messages, 3, true).is_err());
+    }
+
+    #[actix_web::test]
+    async fn dify_messages_split_into_query_and_answer() {
+        let dir = std::env::temp_dir().join(format!("thread-handlers-{}", Uuid::new_v4().simple()));
+        let store = super::super::store::ThreadStore::new(dir.clone(), 100, 100).unwrap();
+        let thread = store.create("key", serde_json::Map::new(), |_| Vec::new()).await.unwrap();
+        let message = serde_json::json!({"id": "d1", "query": "hi", "answer": "hello", "created_at": 5});
+
+        let messages = dify_message(&thread, &message);
+        assert_eq!(messages.iter().map(|m| (m.id.as_str(), m.role.as_str())).collect::<Vec<_>>(), [
+            ("d1-query", "user"),
+            ("d1", "assistant"),
+        ]);
+        assert_eq!(messages[1].run_id.as_deref(), Some("d1"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,439 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use bytes::Bytes;
+use chrono::Utc;
+use futures_util::{future, stream, StreamExt};
+use log::warn;
+use serde_json::json;
+use std::sync::Arc;
+
+use crate::features::app::app_state::AppState;
+use crate::features::auth::resolver::authenticate;
+use crate::features::config::proxy_config::ProxyConfig;
+use crate::features::common::types::{
+    ComplexMessageContent, CreateRunRequest, MessageContent, OpenAIMessage, OpenAIRequest, RunError, RunObject,
+    ThreadContent, ThreadMessage, Usage,
+};
+use crate::features::dify::chat::{AnswerEvent, AnswerStream, DifyChat};
+use crate::features::dify::citations::Citations;
+use crate::features::dify::handlers::openai::chat_completion::{
+    build_client, sse_named_event, sse_response, with_ignored_params, ChatCompletionError, StreamResponse,
+};
+use crate::features::dify::handlers::openai::params::{check_params, unsupported_params};
+use super::handlers::{cited_content, new_message, not_found, storage_error};
+use super::store::{StoredThread, ThreadStore};
+
+/// Runs the thread: sends the messages added since the last run to its Dify
+/// conversation (starting one on the first run). `assistant_id` names the
+/// model alias of a chat app. Without `stream` the run completes before the
+/// response is returned.
+pub async fn create_run(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    body: web::Json<CreateRunRequest>,
+) -> Result<HttpResponse, ChatCompletionError> {
+    let config = state.config.snapshot();
+    let owner = authenticate(&req, &config)?;
+    let thread_id = path.into_inner();
+    let thread = state.threads.get(&owner, &thread_id).await.ok_or_else(|| not_found(&thread_id))?;
+
+    if let Some(assistant_id) = thread.assistant_id.as_ref().filter(|a| **a != body.assistant_id) {
+        return Err(ChatCompletionError::RequestConstructionError(format!(
+            "Thread '{}' belongs to assistant '{}'",
+            thread_id, assistant_id
+        )));
+    }
+
+    let additional = body.additional_messages.iter().flatten()
+        .map(|m| new_message(&thread_id, m))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Claim the pending messages under the store's lock so a concurrent run
+    // can't send them too; they go back if this run never reaches Dify.
+    let mut claimed = Vec::new();
+    let thread = state.threads
+        .update(&owner, &thread_id, |thread| claimed = std::mem::take(&mut thread.pending))
+        .await
+        .map_err(storage_error)?
+        .ok_or_else(|| not_found(&thread_id))?;
+    let mut run = RunContext {
+        threads: state.threads.clone(),
+        owner,
+        thread_id,
+        assistant_id: body.assistant_id.clone(),
+        instructions: body.instructions.clone(),
+        metadata: body.metadata.clone().unwrap_or_default(),
+        user: String::new(),
+        claimed,
+        created_at: Utc::now().timestamp(),
+    };
+    let response = send_run(&req, &state, &config, &body, &thread, &mut run, additional).await;
+    if response.is_err() {
+        run.release().await;
+    }
+    response
+}
+
+/// Sends the run's messages to Dify and answers with the run.
+async fn send_run(
+    req: &HttpRequest,
+    state: &AppState,
+    config: &ProxyConfig,
+    body: &CreateRunRequest,
+    thread: &StoredThread,
+    run: &mut RunContext,
+    additional: Vec<ThreadMessage>,
+) -> Result<HttpResponse, ChatCompletionError> {
+    let new_messages: Vec<&ThreadMessage> = run.claimed.iter().chain(additional.iter()).collect();
+    if !new_messages.iter().any(|m| m.role == "user") {
+        return Err(ChatCompletionError::RequestConstructionError(format!(
+            "Thread '{}' has no new user message to run",
+            run.thread_id
+        )));
+    }
+
+    let mut messages = Vec::new();
+    for instructions in [&body.instructions, &body.additional_instructions].into_iter().flatten() {
+        messages.push(OpenAIMessage::text("system", instructions.clone()));
+    }
+    messages.extend(new_messages.into_iter().map(to_openai_message));
+    let openai_req = OpenAIRequest {
+        messages,
+        stream: body.stream,
+        temperature: body.temperature,
+        top_p: body.top_p,
+        max_tokens: body.max_completion_tokens,
+        model: Some(body.assistant_id.clone()),
+        user: thread.user.clone(),
+        metadata: body.metadata.clone(),
+        ..Default::default()
+    };
+
+    let mut extra = Vec::new();
+    if body.tools.as_ref().is_some_and(|t| !t.is_empty()) {
+        extra.push("tools");
+    }
+    if body.tool_choice.is_some() {
+        extra.push("tool_choice");
+    }
+    let ignored = check_params(unsupported_params(&openai_req, &extra), config.param_policy(Some(&body.assistant_id)))?;
+
+    let client = build_client()?;
+    let mut chat = DifyChat::prepare(state, req, config, &client, &openai_req).await?;
+    chat.request.conversation_id = thread.conversation_id.clone();
+    run.user = chat.request.user.clone();
+
+    let response = if body.stream.unwrap_or(false) {
+        let events = chat.stream(&client, state, req).await?;
+        sse_response(run_stream(events, run.clone()))
+    } else {
+        let answer = chat.answer(&client).await?;
+        run.started(&answer.message_id, &answer.conversation_id).await;
+        let completed = run.finished(&answer.message_id, Ok(answer.usage)).await;
+        HttpResponse::Ok().json(completed)
+    };
+    Ok(with_ignored_params(response, &ignored))
+}
+
+pub async fn retrieve_run(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, ChatCompletionError> {
+    let (thread_id, run_id) = path.into_inner();
+    let owner = authenticate(&req, &state.config.snapshot())?;
+    let thread = state.threads.get(&owner, &thread_id).await.ok_or_else(|| not_found(&thread_id))?;
+    let run = thread.runs.into_iter().find(|run| run.id == run_id).ok_or_else(|| {
+        ChatCompletionError::DifyApiError(reqwest::StatusCode::NOT_FOUND, format!("No run found with id '{}'.", run_id))
+    })?;
+    Ok(HttpResponse::Ok().json(run))
+}
+
+fn to_openai_message(message: &ThreadMessage) -> OpenAIMessage {
+    let parts = message.content.iter()
+        .map(|content| match content {
+            ThreadContent::Text { text } => ComplexMessageContent::text(text.value.clone()),
+            ThreadContent::ImageUrl { image_url } => ComplexMessageContent {
+                r#type: "image_url".to_string(),
+                text: String::new(),
+                image_url: Some(image_url.clone()),
+                file: None,
+            },
+        })
+        .collect();
+    OpenAIMessage {
+        role: message.role.clone(),
+        content: MessageContent::Complex(parts),
+        function_call: None,
+        tool_calls: None,
+    }
+}
+
+/// A run in progress, and how to record it on the thread.
+#[derive(Clone)]
+struct RunContext {
+    threads: Arc<ThreadStore>,
+    owner: String,
+    thread_id: String,
+    assistant_id: String,
+    instructions: Option<String>,
+    metadata: serde_json::Map<String, serde_json::Value>,
+    user: String,
+    /// Pending messages taken off the thread for this run.
+    claimed: Vec<ThreadMessage>,
+    created_at: i64,
+}
+
+impl RunContext {
+    fn object(&self, id: &str, status: &str) -> RunObject {
+        RunObject {
+            id: id.to_string(),
+            object: "thread.run".to_string(),
+            created_at: self.created_at,
+            thread_id: self.thread_id.clone(),
+            assistant_id: self.assistant_id.clone(),
+            status: status.to_string(),
+            model: self.assistant_id.clone(),
+            instructions: self.instructions.clone(),
+            started_at: Some(self.created_at),
+            completed_at: None,
+            failed_at: None,
+            last_error: None,
+            usage: None,
+            metadata: self.metadata.clone(),
+        }
+    }
+
+    async fn update(&self, update: impl FnOnce(&mut StoredThread)) {
+        if let Err(e) = self.threads.update(&self.owner, &self.thread_id, update).await {
+            warn!("Failed to update thread {}: {}", self.thread_id, e);
+        }
+    }
+
+    /// Binds the thread to the Dify conversation and records the run.
+    async fn started(&self, run_id: &str, conversation_id: &str) -> RunObject {
+        let run = self.object(run_id, "in_progress");
+        self.update(|thread| {
+            thread.assistant_id = Some(self.assistant_id.clone());
+            thread.user = Some(self.user.clone());
+            if !conversation_id.is_empty() {
+                thread.conversation_id = Some(conversation_id.to_string());
+            }
+            thread.runs.push(run.clone());
+        }).await;
+        run
+    }
+
+    /// Puts the claimed messages back ahead of any added since, for a run
+    /// that never reached Dify.
+    async fn release(&self) {
+        self.update(|thread| {
+            thread.pending.splice(0..0, self.claimed.iter().cloned());
+        }).await;
+    }
+
+    /// Records the outcome of the run.
+    async fn finished(&self, run_id: &str, outcome: Result<Option<Usage>, String>) -> RunObject {
+        let now = Utc::now().timestamp();
+        let mut run = self.object(run_id, "completed");
+        match outcome {
+            Ok(usage) => {
+                run.completed_at = Some(now);
+                run.usage = usage;
+            },
+            Err(message) => {
+                run.status = "failed".to_string();
+                run.failed_at = Some(now);
+                run.last_error = Some(RunError { code: "server_error".to_string(), message });
+            },
+        }
+        self.update(|thread| {
+            if let Some(stored) = thread.runs.iter_mut().find(|r| r.id == run.id) {
+                *stored = run.clone();
+            }
+        }).await;
+        run
+    }
+}
+
+struct StreamState {
+    run: RunContext,
+    id: Option<String>,
+    created: i64,
+    text: String,
+    citations: Citations,
+}
+
+impl StreamState {
+    fn message(&self, status: &str) -> ThreadMessage {
+        let id = self.id.clone().unwrap_or_default();
+        ThreadMessage {
+            id: id.clone(),
+            object: "thread.message".to_string(),
+            created_at: self.created,
+            thread_id: self.run.thread_id.clone(),
+            status: status.to_string(),
+            role: "assistant".to_string(),
+            content: match status {
+                "completed" => vec![cited_content(self.text.clone(), &self.citations)],
+                _ => Vec::new(),
+            },
+            assistant_id: Some(self.run.assistant_id.clone()),
+            run_id: Some(id),
+            attachments: Vec::new(),
+            metadata: serde_json::Map::new(),
+        }
+    }
+}
+
+/// Renders answer events as Assistants stream events (`thread.run.*`,
+/// `thread.message.*`), ending with `done`.
+fn run_stream(events: AnswerStream, run: RunContext) -> StreamResponse {
+    let state = StreamState { run, id: None, created: 0, text: String::new(), citations: Citations::default() };
+    // Each event is recorded on the thread before the next is read
+    Box::pin(stream::unfold((events, state), |(mut events, mut state)| async move {
+        let event = events.next().await?;
+        let out: Vec<Bytes> = match event {
+            AnswerEvent::Started { message_id, conversation_id, created } => {
+                let run = state.run.started(&message_id, &conversation_id).await;
+                state.id = Some(message_id);
+                state.created = created as i64;
+                vec![
+                    sse_named_event("thread.run.created", &RunObject { status: "queued".to_string(), ..run.clone() }),
+                    sse_named_event("thread.run.in_progress", &run),
+                    sse_named_event("thread.message.created", &state.message("in_progress")),
+                    sse_named_event("thread.message.in_progress", &state.message("in_progress")),
+                ]
+            },
+            AnswerEvent::Text(delta) => {
+                state.text.push_str(&delta);
+                vec![sse_named_event("thread.message.delta", &json!({
+                    "id": state.id,
+                    "object": "thread.message.delta",
+                    "delta": {
+                        "content": [{"index": 0, "type": "text", "text": {"value": delta, "annotations": []}}],
+                    },
+                }))]
+            },
+            AnswerEvent::Finished { usage, citations, .. } => {
+                state.citations = citations;
+                let run = state.run.finished(state.id.as_deref().unwrap_or_default(), Ok(usage)).await;
+                vec![
+                    sse_named_event("thread.message.completed", &state.message("completed")),
+                    sse_named_event("thread.run.completed", &run),
+                ]
+            },
+            AnswerEvent::Attachments { .. } | AnswerEvent::Audio(_) => Vec::new(),
+            AnswerEvent::Error(message) => match &state.id {
+                Some(id) => vec![sse_named_event("thread.run.failed", &state.run.finished(id, Err(message)).await)],
+                None => {
+                    state.run.release().await;
+                    vec![sse_named_event("error", &json!({"message": message, "type": "server_error"}))]
+                },
+            },
+        };
+        Some((stream::iter(out), (events, state)))
+    })
+    .flatten()
+    .chain(stream::once(future::ready(Bytes::from_static(b"event: done\ndata: [DONE]\n\n"))))
+    .map(Ok))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    use crate::features::common::types::ThreadMessageRequest;
+
+    fn store() -> (Arc<ThreadStore>, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("thread-runs-{}", uuid::Uuid::new_v4().simple()));
+        (Arc::new(ThreadStore::new(dir.clone(), 100, 100).unwrap()), dir)
+    }
+
+    /// A run of a new thread that has claimed its one pending message.
+    async fn claimed_run(threads: &Arc<ThreadStore>) -> RunContext {
+        let request = ThreadMessageRequest { role: "user".to_string(), content: MessageContent::String("Hi".to_string()), metadata: None };
+        let message = new_message("", &request).unwrap();
+        let thread = threads.create("key", serde_json::Map::new(), |_| Vec::new()).await.unwrap();
+        RunContext {
+            threads: threads.clone(),
+            owner: "key".to_string(),
+            thread_id: thread.object.id,
+            assistant_id: "m".to_string(),
+            instructions: None,
+            metadata: serde_json::Map::new(),
+            user: "u".to_string(),
+            claimed: vec![message],
+            created_at: 0,
+        }
+    }
+
+    async fn events(run: &RunContext, events: Vec<AnswerEvent>) -> Vec<String> {
+        let chunks: Vec<_> = run_stream(Box::pin(stream::iter(events)), run.clone()).collect().await;
+        chunks.into_iter()
+            .map(|chunk| String::from_utf8(chunk.unwrap().to_vec()).unwrap())
+            .map(|chunk| chunk.lines().next().unwrap().trim_start_matches("event: ").to_string())
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn streamed_runs_bind_the_thread_and_complete() {
+        let (threads, dir) = store();
+        let run = claimed_run(&threads).await;
+
+        let names = events(&run, vec![
+            AnswerEvent::Started { message_id: "m1".to_string(), conversation_id: "c1".to_string(), created: 7 },
+            AnswerEvent::Text("Hello".to_string()),
+            AnswerEvent::Finished { usage: None, stop: None, citations: Citations::default() },
+        ]).await;
+        assert_eq!(names, [
+            "thread.run.created",
+            "thread.run.in_progress",
+            "thread.message.created",
+            "thread.message.in_progress",
+            "thread.message.delta",
+            "thread.message.completed",
+            "thread.run.completed",
+            "done",
+        ]);
+
+        let thread = threads.get("key", &run.thread_id).await.unwrap();
+        assert_eq!(thread.conversation_id.as_deref(), Some("c1"));
+        assert_eq!((thread.assistant_id.as_deref(), thread.user.as_deref()), (Some("m"), Some("u")));
+        assert_eq!(thread.runs[0].status, "completed");
+        assert!(thread.pending.is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn runs_failing_before_dify_answers_return_their_messages() {
+        let (threads, dir) = store();
+        let run = claimed_run(&threads).await;
+
+        let names = events(&run, vec![AnswerEvent::Error("boom".to_string())]).await;
+        assert_eq!(names, ["error", "done"]);
+
+        let thread = threads.get("key", &run.thread_id).await.unwrap();
+        assert_eq!(thread.pending.iter().map(|m| m.id.clone()).collect::<Vec<_>>(), [run.claimed[0].id.clone()]);
+        assert!(thread.runs.is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn runs_failing_midway_are_marked_failed() {
+        let (threads, dir) = store();
+        let run = claimed_run(&threads).await;
+
+        events(&run, vec![
+            AnswerEvent::Started { message_id: "m1".to_string(), conversation_id: "c1".to_string(), created: 7 },
+            AnswerEvent::Error("boom".to_string()),
+        ]).await;
+
+        let thread = threads.get("key", &run.thread_id).await.unwrap();
+        assert_eq!(thread.runs[0].status, "failed");
+        assert_eq!(thread.runs[0].last_error.as_ref().unwrap().message, "boom");
+        assert!(thread.pending.is_empty());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
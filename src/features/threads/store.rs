@@ -0,0 +1,276 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use tokio::fs;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::features::common::types::{RunObject, ThreadMessage, ThreadObject};
+use crate::features::files::store::hash_key;
+
+/// A thread's state. Messages that have been run live in the Dify
+/// conversation; only messages added since the last run are kept here.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredThread {
+    #[serde(flatten)]
+    pub object: ThreadObject,
+    /// Hash of the bearer token that created the thread; only that token sees it.
+    pub owner: String,
+    /// Model alias of the Dify app the thread is bound to after its first run.
+    #[serde(default)]
+    pub assistant_id: Option<String>,
+    /// Dify user of the conversation.
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub conversation_id: Option<String>,
+    /// Messages not yet sent to Dify.
+    #[serde(default)]
+    pub pending: Vec<ThreadMessage>,
+    #[serde(default)]
+    pub runs: Vec<RunObject>,
+}
+
+/// Threads created through `/v1/threads`, one `{id}.json` per thread.
+pub struct ThreadStore {
+    dir: PathBuf,
+    /// Most threads one owner may keep.
+    max_owner_threads: usize,
+    /// Most messages a thread may hold until its next run.
+    max_pending: usize,
+    /// Thread count per owner hash. Also serializes read-modify-write updates.
+    counts: Mutex<HashMap<String, usize>>,
+}
+
+/// Whether `id` looks like one of our ids, which keeps it safe to use as a file name.
+fn valid_id(id: &str) -> bool {
+    id.strip_prefix("thread_").is_some_and(|rest| !rest.is_empty() && rest.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+fn quota_exceeded(message: String) -> Error {
+    Error::new(ErrorKind::QuotaExceeded, message)
+}
+
+impl ThreadStore {
+    /// Opens the store in `dir`, counting the threads already there.
+    pub fn new(dir: PathBuf, max_owner_threads: usize, max_pending: usize) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        let mut counts = HashMap::new();
+        let threads = std::fs::read_dir(&dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_str()
+                .and_then(|name| name.strip_suffix(".json"))
+                .is_some_and(valid_id))
+            .filter_map(|entry| std::fs::read_to_string(entry.path()).ok())
+            .filter_map(|raw| serde_json::from_str::<StoredThread>(&raw).ok());
+        for thread in threads {
+            *counts.entry(thread.owner).or_insert(0) += 1;
+        }
+        Ok(ThreadStore { dir, max_owner_threads, max_pending, counts: Mutex::new(counts) })
+    }
+
+    fn path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    async fn write(&self, thread: &StoredThread) -> std::io::Result<()> {
+        let raw = serde_json::to_vec(thread).map_err(std::io::Error::other)?;
+        fs::write(self.path(&thread.object.id), raw).await
+    }
+
+    async fn read(&self, id: &str) -> Option<StoredThread> {
+        if !valid_id(id) {
+            return None;
+        }
+        let raw = fs::read_to_string(self.path(id)).await.ok()?;
+        serde_json::from_str(&raw).ok()
+    }
+
+    /// Creates a thread; `messages` is built once the id is known. Fails with
+    /// `QuotaExceeded` if the owner has too many threads or there are too
+    /// many messages.
+    pub async fn create(
+        &self,
+        owner: &str,
+        metadata: serde_json::Map<String, serde_json::Value>,
+        messages: impl FnOnce(&str) -> Vec<ThreadMessage>,
+    ) -> std::io::Result<StoredThread> {
+        let mut counts = self.counts.lock().await;
+        let count = counts.get(&hash_key(owner)).copied().unwrap_or(0);
+        if count >= self.max_owner_threads {
+            return Err(quota_exceeded(format!(
+                "Thread quota exceeded: at most {} threads per key; delete threads to create more",
+                self.max_owner_threads
+            )));
+        }
+        let id = format!("thread_{}", Uuid::new_v4().simple());
+        let thread = StoredThread {
+            pending: messages(&id),
+            object: ThreadObject {
+                id,
+                object: "thread".to_string(),
+                created_at: Utc::now().timestamp(),
+                metadata,
+            },
+            owner: hash_key(owner),
+            assistant_id: None,
+            user: None,
+            conversation_id: None,
+            runs: Vec::new(),
+        };
+        self.check_pending(&thread)?;
+        self.write(&thread).await?;
+        counts.insert(thread.owner.clone(), count + 1);
+        Ok(thread)
+    }
+
+    fn check_pending(&self, thread: &StoredThread) -> std::io::Result<()> {
+        if thread.pending.len() > self.max_pending {
+            return Err(quota_exceeded(format!(
+                "A thread holds at most {} messages until its next run",
+                self.max_pending
+            )));
+        }
+        Ok(())
+    }
+
+    /// The thread `id` if `owner` created it.
+    pub async fn get(&self, owner: &str, id: &str) -> Option<StoredThread> {
+        self.read(id).await.filter(|thread| thread.owner == hash_key(owner))
+    }
+
+    /// Applies `update` to `owner`'s thread `id` and saves it. Returns the
+    /// updated thread, or `None` if there is no such thread.
+    pub async fn update(
+        &self,
+        owner: &str,
+        id: &str,
+        update: impl FnOnce(&mut StoredThread),
+    ) -> std::io::Result<Option<StoredThread>> {
+        let _guard = self.counts.lock().await;
+        let Some(mut thread) = self.get(owner, id).await else { return Ok(None) };
+        update(&mut thread);
+        self.write(&thread).await?;
+        Ok(Some(thread))
+    }
+
+    /// Adds a message to `owner`'s thread `id` for its next run, within the
+    /// thread's limit of pending messages.
+    pub async fn add_message(&self, owner: &str, id: &str, message: ThreadMessage) -> std::io::Result<Option<StoredThread>> {
+        let _guard = self.counts.lock().await;
+        let Some(mut thread) = self.get(owner, id).await else { return Ok(None) };
+        thread.pending.push(message);
+        self.check_pending(&thread)?;
+        self.write(&thread).await?;
+        Ok(Some(thread))
+    }
+
+    /// Deletes `owner`'s thread `id`, returning whether it existed. The Dify
+    /// conversation is left alone.
+    pub async fn delete(&self, owner: &str, id: &str) -> std::io::Result<bool> {
+        let mut counts = self.counts.lock().await;
+        let Some(thread) = self.get(owner, id).await else { return Ok(false) };
+        fs::remove_file(self.path(id)).await?;
+        if let Some(count) = counts.get_mut(&thread.owner) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&thread.owner);
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> (ThreadStore, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("thread-store-{}", Uuid::new_v4().simple()));
+        (ThreadStore::new(dir.clone(), 2, 2).unwrap(), dir)
+    }
+
+    #[actix_web::test]
+    async fn threads_are_scoped_to_their_owner() {
+        let (store, dir) = store();
+        let thread = store.create("key-a", serde_json::Map::new(), |_| Vec::new()).await.unwrap();
+
+        assert!(store.get("key-a", &thread.object.id).await.is_some());
+        assert!(store.get("key-b", &thread.object.id).await.is_none());
+        assert!(store.update("key-b", &thread.object.id, |t| t.user = Some("u".to_string())).await.unwrap().is_none());
+        assert_eq!(store.get("key-a", &thread.object.id).await.unwrap().user, None);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn updates_are_saved() {
+        let (store, dir) = store();
+        let thread = store.create("key", serde_json::Map::new(), |_| Vec::new()).await.unwrap();
+
+        let updated = store.update("key", &thread.object.id, |t| t.conversation_id = Some("c".to_string())).await.unwrap();
+        assert_eq!(updated.unwrap().conversation_id.as_deref(), Some("c"));
+        assert_eq!(store.get("key", &thread.object.id).await.unwrap().conversation_id.as_deref(), Some("c"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn owners_keep_to_their_thread_quota() {
+        let (store, dir) = store();
+        let first = store.create("key", serde_json::Map::new(), |_| Vec::new()).await.unwrap();
+        store.create("key", serde_json::Map::new(), |_| Vec::new()).await.unwrap();
+        let error = store.create("key", serde_json::Map::new(), |_| Vec::new()).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::QuotaExceeded);
+        store.create("other", serde_json::Map::new(), |_| Vec::new()).await.unwrap();
+
+        assert!(!store.delete("other", &first.object.id).await.unwrap());
+        assert!(store.delete("key", &first.object.id).await.unwrap());
+        assert!(store.get("key", &first.object.id).await.is_none());
+        store.create("key", serde_json::Map::new(), |_| Vec::new()).await.unwrap();
+
+        // Counts survive a restart
+        let reopened = ThreadStore::new(dir.clone(), 2, 2).unwrap();
+        let error = reopened.create("key", serde_json::Map::new(), |_| Vec::new()).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::QuotaExceeded);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn pending_messages_are_capped() {
+        let (store, dir) = store();
+        let thread = store.create("key", serde_json::Map::new(), |_| Vec::new()).await.unwrap();
+        let id = thread.object.id.clone();
+        let message = |n: usize| ThreadMessage {
+            id: format!("msg_{}", n),
+            object: "thread.message".to_string(),
+            created_at: 0,
+            thread_id: id.clone(),
+            status: "completed".to_string(),
+            role: "user".to_string(),
+            content: Vec::new(),
+            assistant_id: None,
+            run_id: None,
+            attachments: Vec::new(),
+            metadata: serde_json::Map::new(),
+        };
+        store.add_message("key", &id, message(1)).await.unwrap().unwrap();
+        store.add_message("key", &id, message(2)).await.unwrap().unwrap();
+        let error = store.add_message("key", &id, message(3)).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::QuotaExceeded);
+        assert_eq!(store.get("key", &id).await.unwrap().pending.len(), 2);
+        assert!(store.add_message("other", &id, message(3)).await.unwrap().is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn ids_outside_the_store_are_not_read() {
+        let (store, dir) = store();
+        std::fs::write(dir.join("other.json"), "{}").unwrap();
+
+        assert!(store.get("key", "other").await.is_none());
+        assert!(store.get("key", "thread_../other").await.is_none());
+        assert!(store.get("key", "thread_").await.is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
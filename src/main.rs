@@ -1,23 +1,53 @@
 use actix_web::{App, HttpServer, web};
 use dotenv::dotenv;
 use std::env;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(feature = "logging")]
 use env_logger::Env;
 use log::info;
-use actix_cors::Cors;
 use actix_web::middleware::Logger;
 use actix_web::web::Data;
 
 mod features;
 
+use crate::features::admin::handlers::{cached_parameters, refresh_parameters, reload_config, reload_status};
 use crate::features::app::app_state::AppState;
-use crate::features::dify::handlers::openai::chat_completion::chat_completion;
+use crate::features::audio::speech::speech;
+use crate::features::audio::transcriptions::transcriptions;
+use crate::features::cache::response::{CacheBackend, DiskBackend, MemoryBackend, ResponseCache};
+use crate::features::cache::semantic::SemanticCache;
+use crate::features::config::proxy_config::ProxyConfig;
+use crate::features::config::reload::{spawn_reload_watchers, ConfigHandle};
+use crate::features::conversations::handlers::{delete_conversation, list_conversations, rename_conversation};
+use crate::features::dify::handlers::anthropic::messages::messages;
+use crate::features::dify::handlers::native::workflow_run::workflow_run;
+use crate::features::dify::cancellation::ActiveStreams;
+use crate::features::dify::conversations::{ConversationTracker, MessageTracker};
+use crate::features::dify::parameters::ParametersCache;
+use crate::features::dify::handlers::openai::cancel::cancel_completion;
+use crate::features::dify::handlers::openai::chat_completion::{build_client, chat_completion};
+use crate::features::dify::handlers::openai::completion::completion;
+use crate::features::dify::handlers::openai::feedback::completion_feedback;
+use crate::features::dify::handlers::openai::responses::create_response;
+use crate::features::embeddings::handlers::embeddings;
+use crate::features::files::handlers::{delete_file, file_content, list_files, retrieve_file, upload_file};
+use crate::features::files::store::FileStore;
+use crate::features::threads::handlers::{create_message, create_thread, delete_thread, list_messages, retrieve_thread};
+use crate::features::threads::runs::{create_run, retrieve_run};
+use crate::features::threads::store::ThreadStore;
+use crate::features::knowledge::handlers::search_vector_store;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
 
     // Load configuration variables with better error handling
-    let dify_api_url = env::var("DIFY_API_URL").expect("DIFY_API_URL must be set");
+    // `DIFY_URL` is what chat completions read before the registry; still honoured
+    let dify_api_url = env::var("DIFY_API_URL")
+        .or_else(|_| env::var("DIFY_URL"))
+        .expect("DIFY_API_URL must be set");
     let host = env::var("HOST").unwrap_or_else(|_| "0.0.0.0".to_string());
     let port = env::var("PORT").unwrap_or_else(|_| "8223".to_string());
 
@@ -31,27 +61,169 @@ async fn main() -> std::io::Result<()> {
     }
 
     info!("Dify server URL: {}", dify_api_url);
+
+    // Optional JSON config with the model registry and proxy keys, hot reloaded on change/SIGHUP
+    let config_path = env::var("PROXY_CONFIG").ok().map(PathBuf::from);
+    let config = match &config_path {
+        Some(path) => ProxyConfig::load(path)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?,
+        None => ProxyConfig::default(),
+    };
+    let config_handle = Arc::new(ConfigHandle::new(config_path.clone(), config));
+    if config_path.is_some() {
+        let poll_secs = env::var("PROXY_CONFIG_POLL_SECS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(2);
+        spawn_reload_watchers(config_handle.clone(), Duration::from_secs(poll_secs));
+    }
     let server_addr = format!("{}:{}", host, port);
 
     println!("Starting server at http://{}", server_addr);
 
     // Create and clone the app state
+    // Dify app parameters are cached per app key and warmed for registered models
+    let parameters_ttl = env::var("DIFY_PARAMETERS_TTL_SECS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(300);
+    let parameters = Arc::new(ParametersCache::new(Duration::from_secs(parameters_ttl)));
+    let app_keys: Vec<String> = config_handle.snapshot().models.values().map(|m| m.app_key.clone()).collect();
+    if !app_keys.is_empty() {
+        let parameters = parameters.clone();
+        let dify_api_url = dify_api_url.clone();
+        actix_web::rt::spawn(async move {
+            if let Ok(client) = build_client() {
+                parameters.warm(&client, &dify_api_url, app_keys).await;
+            }
+        });
+    }
+
+    // Opt-in response cache for deterministic chat completions
+    let response_cache_ttl = env::var("RESPONSE_CACHE_TTL_SECS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(3600);
+    let response_cache_entries = env::var("RESPONSE_CACHE_ENTRIES")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(1000);
+    let response_cache_backend: Option<Box<dyn CacheBackend>> = match env::var("RESPONSE_CACHE").ok().as_deref() {
+        Some("memory") => Some(Box::new(MemoryBackend::new(response_cache_entries))),
+        Some("disk") => {
+            let dir = env::var("RESPONSE_CACHE_DIR").unwrap_or_else(|_| "./cache".to_string());
+            let max_bytes = env::var("RESPONSE_CACHE_MAX_BYTES")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(100 * 1024 * 1024);
+            Some(Box::new(DiskBackend::new(
+                PathBuf::from(dir),
+                Duration::from_secs(response_cache_ttl),
+                response_cache_entries,
+                max_bytes,
+            )?))
+        },
+        _ => None,
+    };
+    let response_cache = response_cache_backend
+        .map(|backend| Arc::new(ResponseCache::new(backend, Duration::from_secs(response_cache_ttl))));
+
+    // Files API storage; uploads reach Dify when a chat message references them
+    let files_dir = env::var("FILES_DIR").unwrap_or_else(|_| "./files".to_string());
+    let files_max_bytes = env::var("FILES_MAX_BYTES")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(100 * 1024 * 1024);
+    let files_per_key = env::var("FILES_PER_KEY")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(1000);
+    let files_bytes_per_key = env::var("FILES_BYTES_PER_KEY")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(1024 * 1024 * 1024);
+    let files = Arc::new(FileStore::new(PathBuf::from(files_dir), files_max_bytes, files_per_key, files_bytes_per_key)?);
+
+    // Assistants threads; their messages live in Dify conversations
+    let threads_dir = env::var("THREADS_DIR").unwrap_or_else(|_| "./threads".to_string());
+    let threads_per_key = env::var("THREADS_PER_KEY")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(1000);
+    let threads_max_pending = env::var("THREADS_MAX_PENDING")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(100);
+    let threads = Arc::new(ThreadStore::new(PathBuf::from(threads_dir), threads_per_key, threads_max_pending)?);
+
+    let conversations_max = env::var("CONVERSATIONS_MAX")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(10_000);
+
     let app_state = AppState {
         dify_api_url: dify_api_url.clone(),
+        config: config_handle,
+        parameters,
+        streams: Arc::new(ActiveStreams::default()),
+        response_cache,
+        semantic_cache: Arc::new(SemanticCache::default()),
+        files,
+        conversations: Arc::new(ConversationTracker::new(conversations_max)),
+        messages: Arc::new(MessageTracker::new(conversations_max)),
+        threads,
     };
+    let cors_config = app_state.config.snapshot().cors.clone();
     let app_data = Data::new(app_state);
 
     HttpServer::new(move || {
         App::new()
             .app_data(app_data.clone())
-            .wrap(Cors::default().allow_any_origin())
             .wrap(Logger::default())
             .service(
                 web::scope("/v1")
+                    .wrap(cors_config.policy_for("/v1").build())
                     .route("/chat/completions", web::post().to(chat_completion))
+                    .route("/chat/completions/{completion_id}/cancel", web::post().to(cancel_completion))
+                    .route("/chat/completions/{completion_id}/feedback", web::post().to(completion_feedback))
+                    .route("/completions", web::post().to(completion))
+                    .route("/messages", web::post().to(messages))
+                    .route("/responses", web::post().to(create_response))
+                    .route("/embeddings", web::post().to(embeddings))
+                    .route("/audio/speech", web::post().to(speech))
+                    .route("/audio/transcriptions", web::post().to(transcriptions))
+                    .route("/files", web::post().to(upload_file))
+                    .route("/files", web::get().to(list_files))
+                    .route("/files/{file_id}", web::get().to(retrieve_file))
+                    .route("/files/{file_id}", web::delete().to(delete_file))
+                    .route("/files/{file_id}/content", web::get().to(file_content))
+                    .route("/vector_stores/{vector_store_id}/search", web::post().to(search_vector_store))
+                    .route("/conversations", web::get().to(list_conversations))
+                    .route("/conversations/{conversation_id}/name", web::post().to(rename_conversation))
+                    .route("/conversations/{conversation_id}", web::delete().to(delete_conversation))
+                    .route("/threads", web::post().to(create_thread))
+                    .route("/threads/{thread_id}", web::get().to(retrieve_thread))
+                    .route("/threads/{thread_id}", web::delete().to(delete_thread))
+                    .route("/threads/{thread_id}/messages", web::post().to(create_message))
+                    .route("/threads/{thread_id}/messages", web::get().to(list_messages))
+                    .route("/threads/{thread_id}/runs", web::post().to(create_run))
+                    .route("/threads/{thread_id}/runs/{run_id}", web::get().to(retrieve_run))
                 // Uncomment and implement if needed
                 // .route("/images/generations", web::post().to(generate_image))
             )
+            .service(
+                web::scope("/dify/v1")
+                    .wrap(cors_config.policy_for("/dify/v1").build())
+                    .route("/workflows/run", web::post().to(workflow_run))
+            )
+            .service(
+                web::scope("/admin")
+                    .wrap(cors_config.policy_for("/admin").build())
+                    .route("/config/reload", web::post().to(reload_config))
+                    .route("/config/status", web::get().to(reload_status))
+                    .route("/parameters", web::get().to(cached_parameters))
+                    .route("/parameters/refresh", web::post().to(refresh_parameters))
+            )
     })
     .bind(server_addr)?
     .run()